@@ -84,6 +84,42 @@ fn call_add_function(c: &mut Criterion) {
     });
 }
 
+fn call_slice_loop(c: &mut Criterion) {
+    c.bench_function("call add function 1000000", |b| {
+        b.iter_with_setup(
+            || Lua::new(),
+            |lua| -> Lua {
+                {
+                    let add_function: LuaFunction =
+                        lua.eval("function(a, b) return a + b end", None).unwrap();
+                    for i in 0..1_000_000 {
+                        let _result: i64 = add_function.call((i, 1)).unwrap();
+                    }
+                }
+                lua
+            },
+        );
+    });
+
+    c.bench_function("call_slice add function 1000000", |b| {
+        b.iter_with_setup(
+            || Lua::new(),
+            |lua| -> Lua {
+                {
+                    let add_function: LuaFunction =
+                        lua.eval("function(a, b) return a + b end", None).unwrap();
+                    let mut args = [LuaValue::Integer(0), LuaValue::Integer(1)];
+                    for i in 0..1_000_000 {
+                        args[0] = LuaValue::Integer(i);
+                        let _result: i64 = add_function.call_slice(&args).unwrap();
+                    }
+                }
+                lua
+            },
+        );
+    });
+}
+
 fn call_add_callback(c: &mut Criterion) {
     c.bench_function("call callback add 2 10", |b| {
         b.iter_with_setup(
@@ -157,6 +193,74 @@ fn call_append_callback(c: &mut Criterion) {
     });
 }
 
+fn call_noop_callback(c: &mut Criterion) {
+    c.bench_function("call callback noop 100", |b| {
+        b.iter_with_setup(
+            || {
+                let lua = Lua::new();
+                let f = {
+                    let c: LuaFunction = lua.create_function(|_, ()| Ok(())).unwrap();
+                    lua.globals().set("callback", c).unwrap();
+                    let f: LuaFunction =
+                        lua.eval(
+                            r#"
+                            function()
+                                for _ = 1,100 do
+                                    callback()
+                                end
+                            end
+                        "#,
+                            None,
+                        ).unwrap();
+                    lua.create_registry_value(f).unwrap()
+                };
+                (lua, f)
+            },
+            |(lua, f)| -> Lua {
+                {
+                    let entry_function: LuaFunction = lua.registry_value(&f).unwrap();
+                    entry_function.call::<_, ()>(()).unwrap();
+                }
+                lua
+            },
+        );
+    });
+}
+
+fn call_simple_noop_callback(c: &mut Criterion) {
+    c.bench_function("call simple callback noop 100", |b| {
+        b.iter_with_setup(
+            || {
+                let lua = Lua::new();
+                let f = {
+                    let c: LuaFunction = lua.create_simple_function(|_| Ok(())).unwrap();
+                    lua.globals().set("callback", c).unwrap();
+                    let f: LuaFunction =
+                        lua.eval(
+                            r#"
+                            function()
+                                for _ = 1,100 do
+                                    callback()
+                                end
+                            end
+                        "#,
+                            None,
+                        ).unwrap();
+                    lua.create_registry_value(f).unwrap()
+                };
+                (lua, f)
+            },
+            |(lua, f)| -> Lua {
+                {
+                    let entry_function: LuaFunction = lua.registry_value(&f).unwrap();
+                    entry_function.call::<_, ()>(()).unwrap();
+                }
+                lua
+            },
+        );
+    });
+}
+
 fn create_registry_values(c: &mut Criterion) {
     c.bench_function("create registry 10", |b| {
         b.iter_with_setup(
@@ -192,6 +296,39 @@ fn create_userdata(c: &mut Criterion) {
     });
 }
 
+fn register_globals(c: &mut Criterion) {
+    const NAMES: [&str; 10] = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+
+    c.bench_function("set globals 10", |b| {
+        b.iter_with_setup(
+            || Lua::new(),
+            |lua| -> Lua {
+                {
+                    let globals = lua.globals();
+                    for (i, &name) in NAMES.iter().enumerate() {
+                        globals.raw_set(name, i).unwrap();
+                    }
+                }
+                lua
+            },
+        );
+    });
+
+    c.bench_function("register globals 10", |b| {
+        b.iter_with_setup(
+            || Lua::new(),
+            |lua| -> Lua {
+                lua.register(|api| {
+                    for (i, &name) in NAMES.iter().enumerate() {
+                        api.value(name, i);
+                    }
+                }).unwrap();
+                lua
+            },
+        );
+    });
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default()
@@ -202,10 +339,14 @@ criterion_group! {
         create_array,
         create_string_table,
         call_add_function,
+        call_slice_loop,
         call_add_callback,
         call_append_callback,
+        call_noop_callback,
+        call_simple_noop_callback,
         create_registry_values,
-        create_userdata
+        create_userdata,
+        register_globals
 }
 
 criterion_main!(benches);
@@ -3,49 +3,33 @@
 extern crate rlua;
 extern crate rustyline;
 
-use rlua::{Error, Lua, MultiValue};
+use rlua::{Lua, ReplSession, ReplStatus};
 use rustyline::Editor;
 
 fn main() {
     let lua = Lua::new();
+    let mut session = ReplSession::new(&lua);
     let mut editor = Editor::<()>::new();
 
     loop {
-        let mut prompt = "> ";
-        let mut line = String::new();
+        let prompt = if session.is_incomplete() { ">> " } else { "> " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        editor.add_history_entry(&line);
 
-        loop {
-            match editor.readline(prompt) {
-                Ok(input) => line.push_str(&input),
-                Err(_) => return,
-            }
-
-            match lua.eval::<MultiValue>(&line, None) {
-                Ok(values) => {
-                    editor.add_history_entry(&line);
-                    println!(
-                        "{}",
-                        values
-                            .iter()
-                            .map(|value| format!("{:?}", value))
-                            .collect::<Vec<_>>()
-                            .join("\t")
-                    );
-                    break;
-                }
-                Err(Error::SyntaxError {
-                    incomplete_input: true,
-                    ..
-                }) => {
-                    // continue reading input and append it to `line`
-                    line.push_str("\n"); // separate input lines
-                    prompt = ">> ";
-                }
-                Err(e) => {
-                    eprintln!("error: {}", e);
-                    break;
+        match session.feed(&line) {
+            Ok(ReplStatus::Complete(values)) => {
+                if values.len() > 0 {
+                    match session.format_values(values) {
+                        Ok(output) => println!("{}", output),
+                        Err(e) => eprintln!("error: {}", e),
+                    }
                 }
             }
+            Ok(ReplStatus::Incomplete) => {}
+            Err(e) => eprintln!("error: {}", e),
         }
     }
 }
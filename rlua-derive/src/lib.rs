@@ -0,0 +1,447 @@
+//! Custom derives for `rlua`.
+//!
+//! This crate should not be used directly, enable the `derive` feature of `rlua` instead, which
+//! re-exports everything here.
+
+#![recursion_limit = "128"]
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::{
+    Attribute, Data, DeriveInput, FnArg, Fields, Ident, ImplItem, ItemImpl, Lit, Meta, NestedMeta,
+    ReturnType, Type, Variant, Visibility,
+};
+
+/// Derives `FromLua` for an enum.
+///
+/// Unit variants are read from a Lua string holding the variant name, struct variants are read
+/// from a table with a single key holding the variant name, whose value is a table of the variant
+/// fields.  Variant names can be changed with `#[rlua(rename = "...")]`.
+#[proc_macro_derive(FromLua, attributes(rlua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match from_lua_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Derives `ToLua` for an enum.
+///
+/// This is the inverse of the `FromLua` derive, refer to it for the representation used.
+#[proc_macro_derive(ToLua, attributes(rlua))]
+pub fn derive_to_lua(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match to_lua_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Derives an empty `UserData` implementation.
+///
+/// Use `#[rlua_methods]` on an impl block instead to also register methods, the two cannot be
+/// combined on the same type.
+#[proc_macro_derive(UserData)]
+pub fn derive_user_data(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    if !input.generics.params.is_empty() {
+        return syn::Error::new(Span::call_site(), "rlua derives do not support generic types")
+            .to_compile_error()
+            .into();
+    }
+    let name = &input.ident;
+    (quote! {
+        impl ::rlua::UserData for #name {}
+    }).into()
+}
+
+/// Implements `UserData` for the type of an inherent impl block, registering every `pub` method
+/// that takes `&self` or `&mut self` as a userdata method.
+///
+/// Method arguments are converted with `FromLua` and return values with `ToLua`. A method
+/// returning a type named `Result` is assumed to return `rlua::Result`, and its error is raised as
+/// a Lua error. Individual methods can be renamed with `#[rlua(rename = "...")]` or left out with
+/// `#[rlua(skip)]`.
+#[proc_macro_attribute]
+pub fn rlua_methods(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as ItemImpl);
+    match methods_impl(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn methods_impl(mut input: ItemImpl) -> syn::Result<TokenStream2> {
+    if input.trait_.is_some() {
+        return Err(syn::Error::new_spanned(
+            &input.self_ty,
+            "#[rlua_methods] must be used on an inherent impl block",
+        ));
+    }
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "#[rlua_methods] does not support generic impl blocks",
+        ));
+    }
+
+    let mut registrations = Vec::new();
+    for item in &mut input.items {
+        let method = match *item {
+            ImplItem::Method(ref mut method) => method,
+            _ => continue,
+        };
+
+        let options = MethodOptions::take_from(&mut method.attrs)?;
+        match method.vis {
+            Visibility::Public(_) => {}
+            _ => continue,
+        }
+        if options.skip {
+            continue;
+        }
+
+        let mutable = match method.sig.decl.inputs.iter().next() {
+            Some(FnArg::SelfRef(receiver)) => receiver.mutability.is_some(),
+            _ => continue,
+        };
+
+        let mut arg_names = Vec::new();
+        let mut arg_types = Vec::new();
+        for (i, arg) in method.sig.decl.inputs.iter().skip(1).enumerate() {
+            match *arg {
+                FnArg::Captured(ref arg) => {
+                    arg_names.push(Ident::new(&format!("arg{}", i), Span::call_site()));
+                    arg_types.push(arg.ty.clone());
+                }
+                ref arg => {
+                    return Err(syn::Error::new_spanned(arg, "unsupported method argument"))
+                }
+            }
+        }
+
+        let (arg_names, arg_types) = (&arg_names, &arg_types);
+        let ident = &method.sig.ident;
+        let lua_name = options.rename.unwrap_or_else(|| ident.to_string());
+        let call = quote! { this.#ident(#(#arg_names),*) };
+        let call = if returns_result(&method.sig.decl.output) {
+            call
+        } else {
+            quote! { ::std::result::Result::Ok(#call) }
+        };
+        let add = if mutable {
+            quote! { add_method_mut }
+        } else {
+            quote! { add_method }
+        };
+        registrations.push(quote! {
+            methods.#add(#lua_name, |_, this, (#(#arg_names,)*): (#(#arg_types,)*)| #call);
+        });
+    }
+
+    let self_ty = &input.self_ty;
+    Ok(quote! {
+        #input
+
+        impl ::rlua::UserData for #self_ty {
+            fn add_methods(methods: &mut ::rlua::UserDataMethods<Self>) {
+                #(#registrations)*
+            }
+        }
+    })
+}
+
+#[derive(Default)]
+struct MethodOptions {
+    rename: Option<String>,
+    skip: bool,
+}
+
+impl MethodOptions {
+    // Parses and removes any `#[rlua(...)]` attributes, which are not valid on the emitted impl.
+    fn take_from(attrs: &mut Vec<Attribute>) -> syn::Result<MethodOptions> {
+        let mut options = MethodOptions::default();
+        let mut error = None;
+        attrs.retain(|attr| {
+            if !attr.path.is_ident("rlua") {
+                return true;
+            }
+            if let Err(err) = options.parse(attr) {
+                error = Some(err);
+            }
+            false
+        });
+        match error {
+            Some(err) => Err(err),
+            None => Ok(options),
+        }
+    }
+
+    fn parse(&mut self, attr: &Attribute) -> syn::Result<()> {
+        match attr.parse_meta()? {
+            Meta::List(ref list) => {
+                for nested in &list.nested {
+                    match *nested {
+                        NestedMeta::Meta(Meta::Word(ref word)) if word == "skip" => {
+                            self.skip = true
+                        }
+                        NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "rename" => {
+                            match nv.lit {
+                                Lit::Str(ref s) => self.rename = Some(s.value()),
+                                ref lit => {
+                                    return Err(syn::Error::new_spanned(
+                                        lit,
+                                        "expected a string literal",
+                                    ))
+                                }
+                            }
+                        }
+                        ref other => {
+                            return Err(syn::Error::new_spanned(
+                                other,
+                                "unknown rlua attribute",
+                            ))
+                        }
+                    }
+                }
+                Ok(())
+            }
+            meta => Err(syn::Error::new_spanned(meta, "expected #[rlua(...)]")),
+        }
+    }
+}
+
+fn returns_result(output: &ReturnType) -> bool {
+    match *output {
+        ReturnType::Type(_, ref ty) => match **ty {
+            Type::Path(ref path) => path
+                .path
+                .segments
+                .iter()
+                .last()
+                .map(|segment| segment.ident == "Result")
+                .unwrap_or(false),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    }
+}
+
+enum VariantKind<'a> {
+    Unit,
+    Struct(Vec<&'a Ident>),
+}
+
+struct EnumVariant<'a> {
+    ident: &'a Ident,
+    lua_name: String,
+    kind: VariantKind<'a>,
+}
+
+fn enum_variants<'a>(input: &'a DeriveInput) -> syn::Result<Vec<EnumVariant<'a>>> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "rlua derives do not support generic types",
+        ));
+    }
+
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        _ => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "rlua derives are only supported on enums",
+            ))
+        }
+    };
+
+    data.variants
+        .iter()
+        .map(|variant| {
+            let kind = match variant.fields {
+                Fields::Unit => VariantKind::Unit,
+                Fields::Named(ref fields) => VariantKind::Struct(
+                    fields
+                        .named
+                        .iter()
+                        .map(|f| f.ident.as_ref().unwrap())
+                        .collect(),
+                ),
+                Fields::Unnamed(_) => {
+                    return Err(syn::Error::new_spanned(
+                        variant,
+                        "rlua derives do not support tuple variants",
+                    ))
+                }
+            };
+            Ok(EnumVariant {
+                ident: &variant.ident,
+                lua_name: variant_name(variant)?,
+                kind,
+            })
+        })
+        .collect()
+}
+
+// Returns the Lua name of a variant, which is its Rust name unless overridden with
+// `#[rlua(rename = "...")]`.
+fn variant_name(variant: &Variant) -> syn::Result<String> {
+    let mut name = variant.ident.to_string();
+    for attr in &variant.attrs {
+        if !attr.path.is_ident("rlua") {
+            continue;
+        }
+        match attr.parse_meta()? {
+            Meta::List(ref list) => {
+                for nested in &list.nested {
+                    match *nested {
+                        NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "rename" => {
+                            match nv.lit {
+                                Lit::Str(ref s) => name = s.value(),
+                                ref lit => {
+                                    return Err(syn::Error::new_spanned(
+                                        lit,
+                                        "expected a string literal",
+                                    ))
+                                }
+                            }
+                        }
+                        ref other => {
+                            return Err(syn::Error::new_spanned(
+                                other,
+                                "unknown rlua attribute",
+                            ))
+                        }
+                    }
+                }
+            }
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[rlua(...)]")),
+        }
+    }
+    Ok(name)
+}
+
+fn from_lua_impl(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let type_name = name.to_string();
+    let variants = enum_variants(input)?;
+
+    let unit_arms = variants.iter().filter_map(|v| match v.kind {
+        VariantKind::Unit => {
+            let ident = v.ident;
+            let lua_name = &v.lua_name;
+            Some(quote! {
+                #lua_name => ::std::result::Result::Ok(#name::#ident),
+            })
+        }
+        VariantKind::Struct(_) => None,
+    });
+
+    let struct_checks = variants.iter().filter_map(|v| match v.kind {
+        VariantKind::Struct(ref fields) => {
+            let ident = v.ident;
+            let lua_name = &v.lua_name;
+            let field_names = fields.iter().map(|f| f.to_string());
+            Some(quote! {
+                if let ::std::option::Option::Some(fields) =
+                    table.get::<_, ::std::option::Option<::rlua::Table>>(#lua_name)?
+                {
+                    return ::std::result::Result::Ok(#name::#ident {
+                        #(#fields: fields.get(#field_names)?,)*
+                    });
+                }
+            })
+        }
+        VariantKind::Unit => None,
+    });
+
+    Ok(quote! {
+        impl<'lua> ::rlua::FromLua<'lua> for #name {
+            fn from_lua(
+                value: ::rlua::Value<'lua>,
+                _lua: &'lua ::rlua::Lua,
+            ) -> ::rlua::Result<Self> {
+                match value {
+                    ::rlua::Value::String(s) => {
+                        let s = s.to_str()?;
+                        match s {
+                            #(#unit_arms)*
+                            _ => ::std::result::Result::Err(
+                                ::rlua::Error::FromLuaConversionError {
+                                    from: "string",
+                                    to: #type_name,
+                                    message: ::std::option::Option::Some(
+                                        format!("unknown variant `{}`", s),
+                                    ),
+                                },
+                            ),
+                        }
+                    }
+                    ::rlua::Value::Table(table) => {
+                        #(#struct_checks)*
+                        ::std::result::Result::Err(::rlua::Error::FromLuaConversionError {
+                            from: "table",
+                            to: #type_name,
+                            message: ::std::option::Option::Some(
+                                "table does not contain a known variant".to_owned(),
+                            ),
+                        })
+                    }
+                    value => ::std::result::Result::Err(::rlua::Error::FromLuaConversionError {
+                        from: value.type_name(),
+                        to: #type_name,
+                        message: ::std::option::Option::Some(
+                            "expected string or table".to_owned(),
+                        ),
+                    }),
+                }
+            }
+        }
+    })
+}
+
+fn to_lua_impl(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let variants = enum_variants(input)?;
+
+    let arms = variants.iter().map(|v| {
+        let ident = v.ident;
+        let lua_name = &v.lua_name;
+        match v.kind {
+            VariantKind::Unit => quote! {
+                #name::#ident => ::rlua::ToLua::to_lua(#lua_name, lua),
+            },
+            VariantKind::Struct(ref fields) => {
+                let field_names = fields.iter().map(|f| f.to_string());
+                let bindings = fields.clone();
+                quote! {
+                    #name::#ident { #(#bindings),* } => {
+                        let fields = lua.create_table()?;
+                        #(fields.raw_set(#field_names, #fields)?;)*
+                        let table = lua.create_table()?;
+                        table.raw_set(#lua_name, fields)?;
+                        ::std::result::Result::Ok(::rlua::Value::Table(table))
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl<'lua> ::rlua::ToLua<'lua> for #name {
+            fn to_lua(self, lua: &'lua ::rlua::Lua) -> ::rlua::Result<::rlua::Value<'lua>> {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
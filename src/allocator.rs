@@ -0,0 +1,127 @@
+use std::os::raw::c_void;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use libc;
+
+/// A memory allocator used by Lua, set with [`Lua::new_with_allocator`].
+///
+/// All memory used by the Lua state, such as strings, tables and the memory for userdata, is
+/// requested through this trait.  Lua always passes the size of a block back when resizing or
+/// freeing it, so implementations do not need to store it.
+///
+/// # Safety
+///
+/// Implementations must behave like a correct allocator: returned pointers must be valid for the
+/// requested number of bytes and aligned for any type (like the pointers returned by `malloc`),
+/// `realloc` must preserve the contents of the block up to the smaller of the two sizes, and a
+/// block must stay valid until it is freed or reallocated.
+///
+/// `rlua` requires that allocations never fail, so if `alloc` or `realloc` returns a null pointer,
+/// or if any method panics, the process is aborted.  The allocator is dropped after the Lua state
+/// is closed, once every block has been freed.
+///
+/// [`Lua::new_with_allocator`]: struct.Lua.html#method.new_with_allocator
+pub unsafe trait LuaAllocator: Send {
+    /// Allocates a new block of `size` bytes, where `size` is never 0.
+    fn alloc(&mut self, size: usize) -> *mut u8;
+
+    /// Resizes the block at `ptr` of `old_size` bytes to `new_size` bytes, returning the possibly
+    /// moved block.  Neither size is ever 0.
+    fn realloc(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8;
+
+    /// Frees the block at `ptr` of `size` bytes.
+    fn free(&mut self, ptr: *mut u8, size: usize);
+}
+
+// The state passed as the `ud` pointer to the allocator function of every Lua state.
+pub(crate) struct AllocatorState {
+    // The user-provided allocator, or `None` to use the C allocator.
+    allocator: Option<Box<LuaAllocator>>,
+    // The number of bytes currently allocated, and the most that were allocated at once since the
+    // state was created or the peak was last reset.
+    pub(crate) used: usize,
+    pub(crate) peak: usize,
+}
+
+impl AllocatorState {
+    pub(crate) fn new(allocator: Option<Box<LuaAllocator>>) -> AllocatorState {
+        AllocatorState {
+            allocator,
+            used: 0,
+            peak: 0,
+        }
+    }
+}
+
+// The Lua allocator function of every Lua state, where `ud` is a pointer to its `AllocatorState`.
+pub(crate) unsafe extern "C" fn allocator_trampoline(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void {
+    let state = &mut *(ud as *mut AllocatorState);
+    let ptr = ptr as *mut u8;
+
+    let p = match state.allocator {
+        Some(ref mut allocator) => user_allocate(allocator, ptr, osize, nsize),
+        None => system_allocate(ptr, nsize),
+    };
+
+    // When `ptr` is null, `osize` is not a size but a code for the kind of object being allocated.
+    let old_size = if ptr.is_null() { 0 } else { osize };
+    state.used = state.used - old_size + nsize;
+    if state.used > state.peak {
+        state.peak = state.used;
+    }
+    p as *mut c_void
+}
+
+unsafe fn user_allocate(
+    allocator: &mut Box<LuaAllocator>,
+    ptr: *mut u8,
+    osize: usize,
+    nsize: usize,
+) -> *mut u8 {
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        if nsize == 0 {
+            if !ptr.is_null() {
+                allocator.free(ptr, osize);
+            }
+            ptr::null_mut()
+        } else if ptr.is_null() {
+            allocator.alloc(nsize)
+        } else {
+            allocator.realloc(ptr, osize, nsize)
+        }
+    }));
+
+    match res {
+        // See `system_allocate` for why failed allocations abort.
+        Ok(p) if p.is_null() && nsize != 0 => {
+            abort!("out of memory in Lua allocation, aborting!")
+        }
+        Ok(p) => p,
+        Err(_) => abort!("panic in Lua allocator, aborting!"),
+    }
+}
+
+unsafe fn system_allocate(ptr: *mut u8, nsize: usize) -> *mut u8 {
+    if nsize == 0 {
+        libc::free(ptr as *mut libc::c_void);
+        ptr::null_mut()
+    } else {
+        let p = libc::realloc(ptr as *mut libc::c_void, nsize);
+        if p.is_null() {
+            // We require that OOM results in an abort, and that the lua allocator function
+            // never errors.  Since this is what rust itself normally does on OOM, this is
+            // not really a huge loss.  Importantly, this allows us to turn off the gc, and
+            // then know that calling Lua API functions marked as 'm' will not result in a
+            // 'longjmp' error while the gc is off.
+            abort!("out of memory in Lua allocation, aborting!");
+        } else {
+            p as *mut u8
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeSet;
+use std::string::String as StdString;
+
+use function::Function;
+
+/// The result of statically analyzing a chunk with [`Lua::analyze`].
+///
+/// The analysis is a heuristic based on scanning the compiled bytecode of the chunk (and of every
+/// function defined inside of it) for accesses to fields of the `_ENV` upvalue using a constant
+/// name, which is how Lua compiles plain global variable accesses such as `os.exit()` or
+/// `x = 1`.  Accesses which are only known at runtime, such as `_ENV[name]`, `_G.os`, `rawget(_G,
+/// "os")` or `load("return os")()`, cannot be detected.  It is useful for rejecting obviously
+/// disallowed scripts early, but should never be the only line of defense in a sandbox.
+///
+/// [`Lua::analyze`]: struct.Lua.html#method.analyze
+#[derive(Clone, Debug)]
+pub struct ChunkAnalysis<'lua> {
+    /// The loaded chunk, ready to be called.
+    pub function: Function<'lua>,
+    /// Names of the global variables the chunk reads.
+    pub globals_read: BTreeSet<StdString>,
+    /// Names of the global variables the chunk assigns to.
+    pub globals_written: BTreeSet<StdString>,
+}
+
+impl<'lua> ChunkAnalysis<'lua> {
+    /// Returns true if the chunk reads or writes the global variable with the given name.
+    pub fn references(&self, name: &str) -> bool {
+        self.globals_read.contains(name) || self.globals_written.contains(name)
+    }
+}
+
+// Scans a binary chunk produced by `lua_dump` (without stripping debug information), returning the
+// sets of global names read and written.  Returns `None` if the chunk is not in the format
+// produced by the builtin Lua 5.3.
+pub(crate) fn scan_globals(chunk: &[u8]) -> Option<(BTreeSet<StdString>, BTreeSet<StdString>)> {
+    let mut reader = Reader {
+        data: chunk,
+        int_size: 0,
+        size_t_size: 0,
+        integer_size: 0,
+        number_size: 0,
+    };
+
+    if reader.bytes(4)? != b"\x1bLua" || reader.byte()? != 0x53 || reader.byte()? != 0 {
+        return None;
+    }
+    if reader.bytes(6)? != b"\x19\x93\r\n\x1a\n" {
+        return None;
+    }
+    reader.int_size = reader.byte()? as usize;
+    reader.size_t_size = reader.byte()? as usize;
+    if reader.byte()? != 4 {
+        return None;
+    }
+    reader.integer_size = reader.byte()? as usize;
+    reader.number_size = reader.byte()? as usize;
+    let check_size = reader.integer_size + reader.number_size;
+    reader.bytes(check_size)?;
+    // Number of upvalues of the main closure
+    reader.byte()?;
+
+    let mut reads = BTreeSet::new();
+    let mut writes = BTreeSet::new();
+    scan_function(&mut reader, &mut reads, &mut writes)?;
+    Some((reads, writes))
+}
+
+const OP_LOADK: u32 = 1;
+const OP_GETTABUP: u32 = 6;
+const OP_SETTABUP: u32 = 8;
+const BITRK: u32 = 1 << 8;
+
+fn scan_function(
+    reader: &mut Reader,
+    reads: &mut BTreeSet<StdString>,
+    writes: &mut BTreeSet<StdString>,
+) -> Option<()> {
+    // source, linedefined, lastlinedefined
+    reader.string()?;
+    reader.int()?;
+    reader.int()?;
+    // numparams, is_vararg, maxstacksize
+    reader.bytes(3)?;
+
+    let code_len = reader.int()?;
+    let mut code = Vec::with_capacity(code_len);
+    for _ in 0..code_len {
+        code.push(reader.instruction()?);
+    }
+
+    let constant_len = reader.int()?;
+    let mut constants = Vec::with_capacity(constant_len);
+    for _ in 0..constant_len {
+        constants.push(match reader.byte()? {
+            // nil
+            0 => None,
+            // boolean
+            1 => {
+                reader.byte()?;
+                None
+            }
+            // float
+            3 => {
+                let size = reader.number_size;
+                reader.bytes(size)?;
+                None
+            }
+            // integer
+            19 => {
+                let size = reader.integer_size;
+                reader.bytes(size)?;
+                None
+            }
+            // short and long strings
+            4 | 20 => reader.string()?,
+            _ => return None,
+        });
+    }
+
+    // instack, idx
+    let upvalue_len = reader.int()?;
+    reader.bytes(upvalue_len * 2)?;
+
+    let proto_len = reader.int()?;
+    for _ in 0..proto_len {
+        scan_function(reader, reads, writes)?;
+    }
+
+    // line info
+    let line_len = reader.int()?;
+    let line_size = line_len * reader.int_size;
+    reader.bytes(line_size)?;
+    // local variables
+    for _ in 0..reader.int()? {
+        reader.string()?;
+        reader.int()?;
+        reader.int()?;
+    }
+    // upvalue names
+    let mut env_upvalues = Vec::new();
+    for i in 0..reader.int()? {
+        if reader.string()? == Some(&b"_ENV"[..]) {
+            env_upvalues.push(i as u32);
+        }
+    }
+
+    // Resolves an RK operand to a constant string, looking through an immediately preceding LOADK
+    // when there are too many constants to encode the name directly in the instruction.
+    let constant_name = |pc: usize, rk: u32| -> Option<StdString> {
+        let index = if rk & BITRK != 0 {
+            (rk & !BITRK) as usize
+        } else if pc > 0 && code[pc - 1] & 0x3f == OP_LOADK && (code[pc - 1] >> 6) & 0xff == rk {
+            (code[pc - 1] >> 14) as usize
+        } else {
+            return None;
+        };
+        match constants.get(index) {
+            Some(&Some(name)) => Some(StdString::from_utf8_lossy(name).into_owned()),
+            _ => None,
+        }
+    };
+
+    for (pc, &i) in code.iter().enumerate() {
+        let a = (i >> 6) & 0xff;
+        let b = (i >> 23) & 0x1ff;
+        let c = (i >> 14) & 0x1ff;
+        match i & 0x3f {
+            OP_GETTABUP if env_upvalues.contains(&b) => {
+                if let Some(name) = constant_name(pc, c) {
+                    reads.insert(name);
+                }
+            }
+            OP_SETTABUP if env_upvalues.contains(&a) => {
+                if let Some(name) = constant_name(pc, b) {
+                    writes.insert(name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    int_size: usize,
+    size_t_size: usize,
+    integer_size: usize,
+    number_size: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn unsigned(&mut self, size: usize) -> Option<u64> {
+        let bytes = self.bytes(size)?;
+        if size > 8 {
+            return None;
+        }
+        let mut buf = [0; 8];
+        if cfg!(target_endian = "little") {
+            buf[..size].copy_from_slice(bytes);
+        } else {
+            buf[8 - size..].copy_from_slice(bytes);
+        }
+        Some(if cfg!(target_endian = "little") {
+            u64::from_le_bytes(buf)
+        } else {
+            u64::from_be_bytes(buf)
+        })
+    }
+
+    fn int(&mut self) -> Option<usize> {
+        let size = self.int_size;
+        Some(self.unsigned(size)? as u32 as usize)
+    }
+
+    fn instruction(&mut self) -> Option<u32> {
+        Some(self.unsigned(4)? as u32)
+    }
+
+    // Strings are stored as a one byte size (or 0xff followed by a `size_t` size), where the size
+    // includes a trailing nul which is not actually written, and a size of 0 means NULL.
+    fn string(&mut self) -> Option<Option<&'a [u8]>> {
+        let mut size = self.byte()? as u64;
+        if size == 0xff {
+            let size_t_size = self.size_t_size;
+            size = self.unsigned(size_t_size)?;
+        }
+        if size == 0 {
+            Some(None)
+        } else {
+            Some(Some(self.bytes(size as usize - 1)?))
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::os::raw::{c_char, c_int};
+use std::string::String as StdString;
+
+use error::{Error, Result};
+use ffi;
+use lua::Lua;
+use table::Table;
+use util::{check_stack, protect_lua_closure, StackGuard};
+use value::{FromLuaMulti, ToLua, ToLuaMulti, Value};
+
+/// Collects the functions, values and tables of an API to be registered as globals all at once.
+///
+/// Passed to the closure given to [`Lua::register`].  Entries are only accumulated while the
+/// closure runs, and are then stored into the globals with one protected call for each table,
+/// instead of one for each entry.  Registering the same name twice in a table keeps the last
+/// entry.
+///
+/// [`Lua::register`]: struct.Lua.html#method.register
+pub struct ApiBuilder<'lua> {
+    lua: &'lua Lua,
+    entries: Vec<(StdString, Entry<'lua>)>,
+    // The first error from creating an entry, returned by `Lua::register`.
+    error: Option<Error>,
+}
+
+enum Entry<'lua> {
+    Value(Value<'lua>),
+    Table(ApiBuilder<'lua>),
+}
+
+impl<'lua> ApiBuilder<'lua> {
+    pub(crate) fn new(lua: &'lua Lua) -> ApiBuilder<'lua> {
+        ApiBuilder {
+            lua,
+            entries: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Registers a Rust function under `name`, as created by [`Lua::create_function`].
+    ///
+    /// [`Lua::create_function`]: struct.Lua.html#method.create_function
+    pub fn function<'callback, A, R, F>(&mut self, name: &str, func: F) -> &mut ApiBuilder<'lua>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + Fn(&'callback Lua, A) -> Result<R>,
+    {
+        let func = self.lua.create_function(func);
+        self.add(name, func.map(Value::Function))
+    }
+
+    /// Registers an arbitrary value under `name`.
+    pub fn value<V: ToLua<'lua>>(&mut self, name: &str, value: V) -> &mut ApiBuilder<'lua> {
+        let value = value.to_lua(self.lua);
+        self.add(name, value)
+    }
+
+    /// Returns the builder of the table registered under `name`, so that entries can be added to
+    /// it.
+    ///
+    /// If the table already exists when the API is registered (such as the standard `string`
+    /// table), the entries are added to the existing table, otherwise a new table is created.
+    pub fn table(&mut self, name: &str) -> &mut ApiBuilder<'lua> {
+        let position = self
+            .entries
+            .iter()
+            .position(|&(ref n, ref entry)| n == name && is_table(entry));
+        let index = match position {
+            Some(index) => index,
+            None => {
+                self.remove(name);
+                self.entries
+                    .push((name.to_owned(), Entry::Table(ApiBuilder::new(self.lua))));
+                self.entries.len() - 1
+            }
+        };
+        match self.entries[index].1 {
+            Entry::Table(ref mut builder) => builder,
+            Entry::Value(_) => unreachable!(),
+        }
+    }
+
+    fn add(&mut self, name: &str, value: Result<Value<'lua>>) -> &mut ApiBuilder<'lua> {
+        match value {
+            Ok(value) => {
+                self.remove(name);
+                self.entries.push((name.to_owned(), Entry::Value(value)));
+            }
+            Err(err) => {
+                if self.error.is_none() {
+                    self.error = Some(err);
+                }
+            }
+        }
+        self
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.entries.retain(|&(ref n, _)| n != name);
+    }
+
+    fn take_error(&mut self) -> Option<Error> {
+        if let Some(err) = self.error.take() {
+            return Some(err);
+        }
+        for &mut (_, ref mut entry) in &mut self.entries {
+            if let Entry::Table(ref mut builder) = *entry {
+                if let Some(err) = builder.take_error() {
+                    return Some(err);
+                }
+            }
+        }
+        None
+    }
+
+    // Stores every entry into `table`, creating or extending the nested tables first.
+    pub(crate) fn apply(mut self, table: &Table<'lua>) -> Result<()> {
+        if let Some(err) = self.take_error() {
+            return Err(err);
+        }
+
+        let lua = self.lua;
+        let mut names = Vec::with_capacity(self.entries.len());
+        let mut values = Vec::with_capacity(self.entries.len());
+        for (name, entry) in self.entries {
+            let value = match entry {
+                Entry::Value(value) => value,
+                Entry::Table(builder) => {
+                    let nested = match table.raw_get::<_, Value>(name.as_str())? {
+                        Value::Table(nested) => nested,
+                        _ => lua.create_table()?,
+                    };
+                    builder.apply(&nested)?;
+                    Value::Table(nested)
+                }
+            };
+            names.push(name);
+            values.push(value);
+        }
+
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            let nargs = values.len() as c_int + 1;
+            check_stack(lua.state, nargs + 5)?;
+
+            lua.push_ref(&table.0);
+            for value in values {
+                lua.push_value(value)?;
+            }
+
+            // The names are created inside the protected call that stores the entries, rather than
+            // with a protected call each.
+            protect_lua_closure(lua.state, nargs, 0, |state| {
+                for (i, name) in names.iter().enumerate() {
+                    ffi::lua_pushlstring(state, name.as_ptr() as *const c_char, name.len());
+                    ffi::lua_pushvalue(state, i as c_int + 2);
+                    ffi::lua_rawset(state, 1);
+                }
+            })
+        }
+    }
+}
+
+fn is_table(entry: &Entry) -> bool {
+    match *entry {
+        Entry::Table(_) => true,
+        Entry::Value(_) => false,
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+// Cache files start with this magic number, followed by the `SourceStamp` of the source file, the
+// checksum of the bytecode, and the bytecode itself.
+const MAGIC: &[u8; 8] = b"RLUAC\0\0\x02";
+const STAMP_SIZE: usize = 28;
+const HEADER_SIZE: usize = MAGIC.len() + STAMP_SIZE + 8;
+
+// Identifies the version of a source file that a cached chunk was compiled from.  The hash of
+// the contents catches changes which keep the modification time and size, such as edits within
+// the timestamp granularity of the file system.
+pub struct SourceStamp {
+    modified_secs: u64,
+    modified_nanos: u32,
+    len: u64,
+    hash: u64,
+}
+
+impl SourceStamp {
+    // Returns `None` if the modification time of the file is not available, in which case it
+    // cannot be cached.
+    pub fn new(metadata: &fs::Metadata, source: &[u8]) -> Option<SourceStamp> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(SourceStamp {
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            len: metadata.len(),
+            hash: fnv1a(source),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; STAMP_SIZE] {
+        let mut bytes = [0; STAMP_SIZE];
+        bytes[..8].copy_from_slice(&self.modified_secs.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.modified_nanos.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.len.to_le_bytes());
+        bytes[20..].copy_from_slice(&self.hash.to_le_bytes());
+        bytes
+    }
+}
+
+// Returns the path of the cache file for the given source file.  The name of the cache file
+// includes a hash of the full path of the source, so that sources with the same file name in
+// different directories can share a cache directory.
+pub fn cache_path(source: &Path, cache_dir: &Path) -> PathBuf {
+    let full_path = source
+        .canonicalize()
+        .unwrap_or_else(|_| source.to_path_buf());
+    let stem = source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    cache_dir.join(format!(
+        "{}-{:016x}.luac",
+        stem,
+        fnv1a(full_path.to_string_lossy().as_bytes())
+    ))
+}
+
+// Returns the cached bytecode if the cache file exists, was compiled from the source version
+// identified by `stamp`, and is not corrupt.
+pub fn read(path: &Path, stamp: &SourceStamp) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .ok()?;
+    if data.len() < HEADER_SIZE || &data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let (header, bytecode) = data.split_at(HEADER_SIZE);
+    let (stamp_bytes, checksum) = header[MAGIC.len()..].split_at(STAMP_SIZE);
+    if stamp_bytes != stamp.to_bytes() || checksum != fnv1a(bytecode).to_le_bytes() {
+        return None;
+    }
+    Some(bytecode.to_vec())
+}
+
+// Writes the cache file through a temporary file, so that a partially written cache file is never
+// read, even by another process running the same script.
+pub fn write(path: &Path, stamp: &SourceStamp, bytecode: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = path.with_extension(format!("luac.{}.tmp", process::id()));
+    let res = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(MAGIC)?;
+            file.write_all(&stamp.to_bytes())?;
+            file.write_all(&fnv1a(bytecode).to_le_bytes())?;
+            file.write_all(bytecode)
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+// The 64 bit FNV-1a hash, which unlike the hashers of the standard library is guaranteed to be
+// stable, so it can be stored.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -1,7 +1,15 @@
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
 use std::hash::{BuildHasher, Hash};
+#[cfg(unix)]
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::string::String as StdString;
 
+use num_traits::cast;
+
 use error::{Error, Result};
 use function::Function;
 use lua::Lua;
@@ -203,6 +211,84 @@ impl<'lua, 'a> ToLua<'lua> for &'a str {
     }
 }
 
+impl<'lua, 'a> ToLua<'lua> for &'a StdString {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(lua.create_string(self)?))
+    }
+}
+
+impl<'lua, 'a> ToLua<'lua> for Cow<'a, str> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(lua.create_string(&self)?))
+    }
+}
+
+// Paths are converted to Lua strings holding the bytes of the path as the OS represents it, so on
+// Unix any path round-trips, including paths which are not valid UTF-8.  Elsewhere, paths must be
+// valid Unicode and are converted to and from UTF-8, failing rather than losing information.
+
+impl<'lua, 'a> ToLua<'lua> for &'a Path {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(lua.create_byte_string(&path_to_bytes(self)?)?))
+    }
+}
+
+impl<'lua> ToLua<'lua> for PathBuf {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        self.as_path().to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for PathBuf {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let s = lua.coerce_string(value)?;
+        // No OS accepts paths containing NUL bytes, which would truncate the path when passed to
+        // a C API.
+        if s.as_bytes().contains(&0) {
+            return Err(Error::FromLuaConversionError {
+                from: "string",
+                to: "PathBuf",
+                message: Some("path contains a NUL byte".to_owned()),
+            });
+        }
+        bytes_to_path(s.as_bytes())
+    }
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Result<Cow<[u8]>> {
+    Ok(Cow::Borrowed(path.as_os_str().as_bytes()))
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Result<Cow<[u8]>> {
+    match path.to_str() {
+        Some(s) => Ok(Cow::Borrowed(s.as_bytes())),
+        None => Err(Error::ToLuaConversionError {
+            from: "Path",
+            to: "string",
+            message: Some("path is not valid unicode".to_owned()),
+        }),
+    }
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> Result<PathBuf> {
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> Result<PathBuf> {
+    match ::std::str::from_utf8(bytes) {
+        Ok(s) => Ok(PathBuf::from(s)),
+        Err(e) => Err(Error::FromLuaConversionError {
+            from: "string",
+            to: "PathBuf",
+            message: Some(e.to_string()),
+        }),
+    }
+}
+
 macro_rules! lua_convert_int {
     ($x:ty) => {
         impl<'lua> ToLua<'lua> for $x {
@@ -213,7 +299,12 @@ macro_rules! lua_convert_int {
 
         impl<'lua> FromLua<'lua> for $x {
             fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
-                Ok(lua.coerce_integer(value)? as $x)
+                let ty = value.type_name();
+                cast(lua.coerce_integer(value)?).ok_or_else(|| Error::FromLuaConversionError {
+                    from: ty,
+                    to: stringify!($x),
+                    message: Some("out of range".to_owned()),
+                })
             }
         }
     };
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_void};
+use std::slice;
+use std::string::String as StdString;
+
+use error::Result;
+use ffi;
+use lua::Lua;
+use util::{check_stack, StackGuard};
+
+// Finds the reference cycles through userdata among the objects reachable from the registry, see
+// `Lua::report_cycles`.
+pub(crate) fn find_cycles(lua: &Lua) -> Result<Vec<StdString>> {
+    let mut finder = CycleFinder {
+        lua,
+        visited: HashMap::new(),
+        path: Vec::new(),
+        cycles: Vec::new(),
+    };
+    unsafe {
+        let _sg = StackGuard::new(lua.state);
+        check_stack(lua.state, 1)?;
+
+        ffi::lua_pushvalue(lua.state, ffi::LUA_REGISTRYINDEX);
+        finder.run()?;
+    }
+    Ok(finder.cycles)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+// What remains to be visited of an object on the path.
+#[derive(Clone, Copy)]
+enum Step {
+    Metatable,
+    // Starts the traversal of the pairs of a table.
+    Pairs,
+    // Visits the next pair of a table, whose previous key is on the stack.
+    NextPair,
+    // Visits the key of the pair on the stack, once its value has been visited.
+    Key,
+    UserValue,
+    Upvalue(c_int),
+    Finished,
+}
+
+// A node of the path from the registry to the object being visited.
+struct PathNode {
+    pointer: *const c_void,
+    ty: c_int,
+    // A description of the object, and of the reference to it from the previous node.
+    node: StdString,
+    edge: StdString,
+    step: Step,
+}
+
+struct CycleFinder<'a> {
+    lua: &'a Lua,
+    visited: HashMap<*const c_void, Visit>,
+    // Every object on the path is on the Lua stack, with the current key above it while the pairs
+    // of a table are visited, so the path is kept here rather than on the Rust stack to support
+    // arbitrarily deep nesting.
+    path: Vec<PathNode>,
+    cycles: Vec<StdString>,
+}
+
+impl<'a> CycleFinder<'a> {
+    // Visits the object on the top of the stack and everything reachable from it, consuming it.
+    unsafe fn run(&mut self) -> Result<()> {
+        let state = self.lua.state;
+        self.enter(StdString::new())?;
+        while let Some(node) = self.path.last_mut() {
+            match node.step {
+                Step::Metatable => {
+                    node.step = match node.ty {
+                        ffi::LUA_TTABLE => Step::Pairs,
+                        ffi::LUA_TUSERDATA => Step::UserValue,
+                        _ => Step::Upvalue(1),
+                    };
+                    if node.ty != ffi::LUA_TFUNCTION && ffi::lua_getmetatable(state, -1) != 0 {
+                        self.enter("metatable".to_owned())?;
+                    }
+                }
+                Step::Pairs => {
+                    node.step = Step::NextPair;
+                    ffi::lua_pushnil(state);
+                }
+                Step::NextPair => {
+                    if ffi::lua_next(state, -2) != 0 {
+                        node.step = Step::Key;
+                        let key = describe_key(state, -2);
+                        self.enter(format!("[{}]", key))?;
+                    } else {
+                        node.step = Step::Finished;
+                    }
+                }
+                Step::Key => {
+                    node.step = Step::NextPair;
+                    let key = describe_key(state, -1);
+                    ffi::lua_pushvalue(state, -1);
+                    self.enter(format!("key {}", key))?;
+                }
+                Step::UserValue => {
+                    node.step = Step::Finished;
+                    ffi::lua_getuservalue(state, -1);
+                    self.enter("user value".to_owned())?;
+                }
+                Step::Upvalue(index) => {
+                    let name = ffi::lua_getupvalue(state, -1, index);
+                    if name.is_null() {
+                        node.step = Step::Finished;
+                    } else {
+                        node.step = Step::Upvalue(index + 1);
+                        let name = CStr::from_ptr(name).to_string_lossy();
+                        let edge = if name.is_empty() {
+                            format!("upvalue {}", index)
+                        } else {
+                            format!("upvalue {}", name)
+                        };
+                        self.enter(edge)?;
+                    }
+                }
+                Step::Finished => {
+                    self.visited.insert(node.pointer, Visit::Done);
+                    self.path.pop();
+                    ffi::lua_pop(state, 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the object on the top of the stack, referenced as `edge` from the last object on the
+    // path, to the path if it has not been visited yet, and pops it otherwise.
+    unsafe fn enter(&mut self, edge: StdString) -> Result<()> {
+        let state = self.lua.state;
+        let ty = ffi::lua_type(state, -1);
+        if ty == ffi::LUA_TTABLE || ty == ffi::LUA_TUSERDATA || ty == ffi::LUA_TFUNCTION {
+            let pointer = ffi::lua_topointer(state, -1);
+            match self.visited.get(&pointer).cloned() {
+                Some(Visit::InProgress) => self.report(pointer, edge),
+                Some(Visit::Done) => {}
+                None => {
+                    check_stack(state, 3)?;
+                    self.visited.insert(pointer, Visit::InProgress);
+                    let node = self.describe_object(ty);
+                    self.path.push(PathNode {
+                        pointer,
+                        ty,
+                        node,
+                        edge,
+                        step: Step::Metatable,
+                    });
+                    return Ok(());
+                }
+            }
+        }
+        ffi::lua_pop(state, 1);
+        Ok(())
+    }
+
+    // Records the cycle from `pointer` on the path back to itself through `edge`, if the cycle
+    // goes through a userdata.
+    fn report(&mut self, pointer: *const c_void, edge: StdString) {
+        let start = match self.path.iter().position(|node| node.pointer == pointer) {
+            Some(start) => start,
+            None => return,
+        };
+        let cycle = &self.path[start..];
+        if !cycle.iter().any(|node| node.node.starts_with("userdata")) {
+            return;
+        }
+
+        let mut description = cycle[0].node.clone();
+        for node in &cycle[1..] {
+            description.push_str(&format!(" -{}-> {}", node.edge, node.node));
+        }
+        description.push_str(&format!(" -{}-> {}", edge, cycle[0].node));
+        self.cycles.push(description);
+    }
+
+    unsafe fn describe_object(&self, ty: c_int) -> StdString {
+        let state = self.lua.state;
+        match ty {
+            ffi::LUA_TTABLE => "table".to_owned(),
+            ffi::LUA_TFUNCTION => "function".to_owned(),
+            _ => {
+                let mut name = None;
+                if ffi::lua_getmetatable(state, -1) != 0 {
+                    name = self.lua.userdata_type_name(-1);
+                    ffi::lua_pop(state, 1);
+                }
+                match name {
+                    Some(name) => format!("userdata {}", name),
+                    None => "userdata".to_owned(),
+                }
+            }
+        }
+    }
+}
+
+// Describes the table key at `index` without converting it, which would confuse `lua_next`.
+unsafe fn describe_key(state: *mut ffi::lua_State, index: c_int) -> StdString {
+    match ffi::lua_type(state, index) {
+        ffi::LUA_TSTRING => {
+            let mut len = 0;
+            let data = ffi::lua_tolstring(state, index, &mut len);
+            let bytes = slice::from_raw_parts(data as *const u8, len);
+            format!("{:?}", StdString::from_utf8_lossy(bytes))
+        }
+        ffi::LUA_TNUMBER => {
+            if ffi::lua_isinteger(state, index) != 0 {
+                ffi::lua_tointeger(state, index).to_string()
+            } else {
+                ffi::lua_tonumber(state, index).to_string()
+            }
+        }
+        ffi::LUA_TBOOLEAN => (ffi::lua_toboolean(state, index) != 0).to_string(),
+        ffi::LUA_TTABLE => "<table>".to_owned(),
+        ffi::LUA_TFUNCTION => "<function>".to_owned(),
+        ffi::LUA_TTHREAD => "<thread>".to_owned(),
+        _ => "<userdata>".to_owned(),
+    }
+}
@@ -16,6 +16,24 @@ pub enum Error {
         /// is set.
         incomplete_input: bool,
     },
+    /// A chunk of Lua source code was rejected before being parsed because it is larger than the
+    /// limit set with [`Lua::set_max_chunk_size`].
+    ///
+    /// [`Lua::set_max_chunk_size`]: struct.Lua.html#method.set_max_chunk_size
+    ChunkTooLarge {
+        /// Size of the rejected chunk in bytes.
+        size: usize,
+        /// The maximum chunk size in bytes at the time the chunk was loaded.
+        limit: usize,
+    },
+    /// Lua source code was rejected before being parsed because it is not encoded in UTF-8.
+    ///
+    /// Returned when loading source code which starts like UTF-16 text, such as files saved as
+    /// "Unicode" by some editors, instead of a confusing syntax error on the first line.
+    UnsupportedEncoding {
+        /// The detected encoding, `"UTF-16LE"` or `"UTF-16BE"`.
+        encoding: &'static str,
+    },
     /// Lua runtime error, aka `LUA_ERRRUN`.
     ///
     /// The Lua VM returns this error when a builtin operation is performed on incompatible types.
@@ -31,17 +49,54 @@ pub enum Error {
     /// This is an error because a mutable callback can only be borrowed mutably once.
     RecursiveMutCallback,
     /// Either a callback or a userdata method has been called, but the callback or userdata has
-    /// been destructed.
+    /// been destructed in a previous __gc.
     ///
-    /// This can happen either due to to being destructed in a previous __gc, or due to being
-    /// destructed from exiting a `Lua::scope` call.
+    /// Callbacks and userdata destructed from exiting a `Lua::scope` call produce a
+    /// `ScopeExpired` error instead.
     CallbackDestructed,
+    /// A function or userdata created by a [`Scope`] was used after the scope ended.
+    ///
+    /// This usually means that a scoped handle was stored somewhere that outlives the scope, such
+    /// as a global variable or a table owned by a longer-lived object.
+    ///
+    /// [`Scope`]: struct.Scope.html
+    ScopeExpired {
+        /// The kind of scoped value, either `"function"` or `"userdata"`.
+        kind: &'static str,
+        /// The Rust type name of the expired userdata, if known.
+        type_name: Option<&'static str>,
+    },
+    /// The `Drop` implementation of one or more values created by a [`Scope`] panicked when the
+    /// scope ended at the end of [`Lua::try_scope`].
+    ///
+    /// Every value created by the scope is still invalidated and dropped.
+    ///
+    /// [`Scope`]: struct.Scope.html
+    /// [`Lua::try_scope`]: struct.Lua.html#method.try_scope
+    ScopeDropPanic {
+        /// The number of values whose `Drop` implementation panicked.
+        count: usize,
+        /// The message of the first panic, if it was a string.
+        message: Option<String>,
+    },
+    /// A function created with [`Lua::create_async_function`], or the future it returned,
+    /// panicked.
+    ///
+    /// The panic is caught inside the callback and raised as this error in the calling coroutine,
+    /// like any other error returned by the function.  Only returned with the `async` feature.
+    ///
+    /// [`Lua::create_async_function`]: struct.Lua.html#method.create_async_function
+    AsyncPanic {
+        /// The message of the panic, if it was a string.
+        message: Option<String>,
+    },
     /// Not enough stack space to place arguments to Lua functions or return values from callbacks.
     ///
     /// Due to the way `rlua` works, it should not be directly possible to run out of stack space
     /// during normal use. The only way that this error can be triggered is if a `Function` is
     /// called with a huge number of arguments, or a rust callback returns a huge number of return
-    /// values.
+    /// values.  It is also returned from the call of a Rust callback which left values on its
+    /// Lua stack, as that indicates a bug in code using the Lua C API directly.
     StackError,
     /// Too many arguments to `Function::bind`
     BindError,
@@ -74,6 +129,15 @@ pub enum Error {
     /// [`Thread::resume`]: struct.Thread.html#method.resume
     /// [`Thread::status`]: struct.Thread.html#method.status
     CoroutineInactive,
+    /// A thread iterated with [`Thread::yields`] was forced to yield by the limit set with
+    /// [`Thread::set_resume_limit`], rather than yielding a value.
+    ///
+    /// The thread can still be resumed, and the iterator resumes it again on the next call to
+    /// `next`.
+    ///
+    /// [`Thread::yields`]: struct.Thread.html#method.yields
+    /// [`Thread::set_resume_limit`]: struct.Thread.html#method.set_resume_limit
+    CoroutinePreempted,
     /// An [`AnyUserData`] is not the expected type in a borrow.
     ///
     /// This error can only happen when manually using [`AnyUserData`], or when implementing
@@ -82,7 +146,15 @@ pub enum Error {
     ///
     /// [`AnyUserData`]: struct.AnyUserData.html
     /// [`UserDataMethods`]: struct.UserDataMethods.html
-    UserDataTypeMismatch,
+    UserDataTypeMismatch {
+        /// Name of the Rust type that was expected.
+        expected: &'static str,
+        /// Name of the Rust type the userdata actually holds, if it is a userdata type created by
+        /// rlua.  See [`AnyUserData::type_name`].
+        ///
+        /// [`AnyUserData::type_name`]: struct.AnyUserData.html#method.type_name
+        found: Option<&'static str>,
+    },
     /// An [`AnyUserData`] immutable borrow failed because it is already borrowed mutably.
     ///
     /// This error can occur when a method on a [`UserData`] type calls back into Lua, which then
@@ -101,8 +173,63 @@ pub enum Error {
     /// [`AnyUserData`]: struct.AnyUserData.html
     /// [`UserData`]: trait.UserData.html
     UserDataBorrowMutError,
+    /// A resource managed by rlua could not be borrowed because it is already borrowed.
+    ///
+    /// This almost always means that a callback holding a borrow called back into Lua, and the Lua
+    /// code then called another callback which tried to borrow the same resource.  This covers
+    /// application data borrowed through [`Lua::app_data_ref`] and [`Lua::app_data_mut`], which
+    /// is also checked when the data is replaced or removed.
+    ///
+    /// The other resources borrowed by callbacks keep their older, more specific errors: a
+    /// callback created with [`Lua::create_function_mut`] which is called again while it is
+    /// running fails with [`RecursiveMutCallback`], and a userdata which is already borrowed by a
+    /// running method fails with [`UserDataBorrowError`] or [`UserDataBorrowMutError`].  None of
+    /// these reentrancy errors panics.
+    ///
+    /// [`Lua::app_data_ref`]: struct.Lua.html#method.app_data_ref
+    /// [`Lua::app_data_mut`]: struct.Lua.html#method.app_data_mut
+    /// [`Lua::create_function_mut`]: struct.Lua.html#method.create_function_mut
+    /// [`RecursiveMutCallback`]: #variant.RecursiveMutCallback
+    /// [`UserDataBorrowError`]: #variant.UserDataBorrowError
+    /// [`UserDataBorrowMutError`]: #variant.UserDataBorrowMutError
+    RecursiveCallback {
+        /// Description of the resource that was borrowed twice, such as ``app data `Type` ``.
+        resource: String,
+        /// `true` if the failed borrow was a mutable borrow.
+        mutable: bool,
+    },
+    /// A host callback needed application data which has not been stored with
+    /// [`Lua::set_app_data`].
+    ///
+    /// [`Lua::set_app_data`]: struct.Lua.html#method.set_app_data
+    MissingAppData {
+        /// Name of the type of the missing application data.
+        type_name: &'static str,
+    },
     /// A `RegistryKey` produced from a different Lua state was used.
     MismatchedRegistryKey,
+    /// A handle such as a `Table` or `Function` created by a `Lua` instance was used with another
+    /// `Lua` instance which does not share the same main state.
+    ///
+    /// This can be checked in advance with [`Lua::compatible_with`].
+    ///
+    /// [`Lua::compatible_with`]: struct.Lua.html#method.compatible_with
+    MismatchedLuaState,
+    /// An argument passed to a Rust function created with [`Lua::create_named_function`] could
+    /// not be converted to the type expected by the function.
+    ///
+    /// It is displayed like the errors of the Lua standard library, such as
+    /// `bad argument #2 to 'add' (...)`.
+    ///
+    /// [`Lua::create_named_function`]: struct.Lua.html#method.create_named_function
+    BadArgument {
+        /// The name the function was created with, if known.
+        function: Option<String>,
+        /// The position of the argument, starting at 1.
+        position: usize,
+        /// The error from converting the argument.
+        cause: Arc<Error>,
+    },
     /// A Rust callback returned `Err`, raising the contained `Error` as a Lua error.
     CallbackError {
         /// Lua call stack backtrace.
@@ -127,6 +254,16 @@ impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::SyntaxError { ref message, .. } => write!(fmt, "syntax error: {}", message),
+            Error::ChunkTooLarge { size, limit } => write!(
+                fmt,
+                "chunk of {} bytes exceeds the maximum chunk size of {} bytes",
+                size, limit
+            ),
+            Error::UnsupportedEncoding { encoding } => write!(
+                fmt,
+                "source code is encoded in {}, only UTF-8 is supported",
+                encoding
+            ),
             Error::RuntimeError(ref msg) => write!(fmt, "runtime error: {}", msg),
             Error::GarbageCollectorError(ref msg) => {
                 write!(fmt, "garbage collector error: {}", msg)
@@ -136,6 +273,27 @@ impl fmt::Display for Error {
                 fmt,
                 "a destructed callback or destructed userdata method was called"
             ),
+            Error::ScopeExpired { kind, type_name } => {
+                write!(fmt, "scoped {}", kind)?;
+                if let Some(type_name) = type_name {
+                    write!(fmt, " of type {}", type_name)?;
+                }
+                write!(fmt, " was used after its scope ended")
+            }
+            Error::ScopeDropPanic { count, ref message } => {
+                write!(fmt, "{} scoped value(s) panicked when dropped", count)?;
+                match *message {
+                    None => Ok(()),
+                    Some(ref message) => write!(fmt, " (first panic: {})", message),
+                }
+            }
+            Error::AsyncPanic { ref message } => {
+                write!(fmt, "async function panicked")?;
+                match *message {
+                    None => Ok(()),
+                    Some(ref message) => write!(fmt, ": {}", message),
+                }
+            }
             Error::StackError => write!(
                 fmt,
                 "out of Lua stack, too many arguments to a Lua function or too many return values from a callback"
@@ -167,12 +325,52 @@ impl fmt::Display for Error {
                 }
             }
             Error::CoroutineInactive => write!(fmt, "cannot resume inactive coroutine"),
-            Error::UserDataTypeMismatch => write!(fmt, "userdata is not expected type"),
+            Error::CoroutinePreempted => {
+                write!(fmt, "coroutine was preempted by its resume limit")
+            }
+            Error::UserDataTypeMismatch { expected, found } => {
+                write!(fmt, "userdata is not expected type {}", expected)?;
+                match found {
+                    None => Ok(()),
+                    Some(found) => write!(fmt, " (found {})", found),
+                }
+            }
             Error::UserDataBorrowError => write!(fmt, "userdata already mutably borrowed"),
             Error::UserDataBorrowMutError => write!(fmt, "userdata already borrowed"),
+            Error::RecursiveCallback {
+                ref resource,
+                mutable,
+            } => {
+                if mutable {
+                    write!(fmt, "cannot mutably borrow {}, it is already borrowed", resource)
+                } else {
+                    write!(
+                        fmt,
+                        "cannot borrow {}, it is already mutably borrowed",
+                        resource
+                    )
+                }
+            }
+            Error::MissingAppData { type_name } => {
+                write!(fmt, "app data `{}` has not been set", type_name)
+            }
             Error::MismatchedRegistryKey => {
                 write!(fmt, "RegistryKey used from different Lua state")
             }
+            Error::MismatchedLuaState => {
+                write!(fmt, "handle used with a Lua instance from a different main state")
+            }
+            Error::BadArgument {
+                ref function,
+                position,
+                ref cause,
+            } => {
+                write!(fmt, "bad argument #{}", position)?;
+                if let Some(ref function) = *function {
+                    write!(fmt, " to '{}'", function)?;
+                }
+                write!(fmt, " ({})", cause)
+            }
             Error::CallbackError { ref traceback, .. } => {
                 write!(fmt, "callback error: {}", traceback)
             }
@@ -184,6 +382,7 @@ impl fmt::Display for Error {
 impl failure::Fail for Error {
     fn cause(&self) -> Option<&failure::Fail> {
         match *self {
+            Error::BadArgument { ref cause, .. } => Some(cause.as_ref()),
             Error::CallbackError { ref cause, .. } => Some(cause.as_ref()),
             Error::ExternalError(ref err) => err.as_fail().cause(),
             _ => None,
@@ -202,6 +401,159 @@ impl Error {
     pub fn external<T: Into<failure::Error>>(err: T) -> Error {
         Error::ExternalError(Arc::new(err.into()))
     }
+
+    /// Returns the position in the Lua source code that this error refers to, if any.
+    ///
+    /// For a `SyntaxError`, this is where the parser detected the error.  For a `RuntimeError`, it
+    /// is the position included in the error message (such as the line that called `error`), or
+    /// else the innermost Lua function in the traceback.  For a `CallbackError`, it is the
+    /// position of the cause if it has one, or else the line that called the Rust callback.
+    ///
+    /// Lua only tracks lines, so no column is available.  The position is extracted from the
+    /// messages and tracebacks generated by Lua, so errors raised with a non-string value or with
+    /// `error(msg, 0)` may not have a position.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{ErrorPosition, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let err = lua.exec::<()>("local x = 1\nlocal y = = 2", Some("config")).unwrap_err();
+    /// assert_eq!(
+    ///     err.position(),
+    ///     Some(ErrorPosition { chunk_name: "config".to_owned(), line: 2 })
+    /// );
+    ///
+    /// let err = lua.exec::<()>("\n\nerror('boom')", Some("script")).unwrap_err();
+    /// assert_eq!(err.position().unwrap().line, 3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn position(&self) -> Option<ErrorPosition> {
+        match *self {
+            Error::SyntaxError { ref message, .. } => parse_position(message),
+            Error::RuntimeError(ref message) => runtime_position(message),
+            Error::CallbackError {
+                ref traceback,
+                ref cause,
+            } => cause.position().or_else(|| traceback_position(traceback)),
+            _ => None,
+        }
+    }
+}
+
+/// A position in Lua source code, see [`Error::position`].
+///
+/// [`Error::position`]: enum.Error.html#method.position
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorPosition {
+    /// The name of the chunk, as passed to `Lua::load`, or the path of a file loaded from disk.
+    ///
+    /// Lua shortens long chunk names in its messages, in which case they end with `...`.
+    pub chunk_name: String,
+    /// The line number, starting at 1.
+    pub line: u32,
+}
+
+/// A non-fatal warning reported with [`Lua::warn`] while running code with
+/// [`Lua::exec_with_diagnostics`].
+///
+/// [`Lua::warn`]: struct.Lua.html#method.warn
+/// [`Lua::exec_with_diagnostics`]: struct.Lua.html#method.exec_with_diagnostics
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The message passed to `Lua::warn`.
+    pub message: String,
+    /// The position of the innermost running Lua code when the diagnostic was reported, if any.
+    pub position: Option<ErrorPosition>,
+}
+
+// Parses the `chunk:line:` prefix that Lua adds to error messages, where the chunk is formatted as
+// by `luaO_chunkid`.
+pub(crate) fn parse_position(message: &str) -> Option<ErrorPosition> {
+    parse_prefix(message).map(|(_, position)| position)
+}
+
+// Parses the `chunk:line:` prefix of `message` like `parse_position`, also returning the chunk as
+// formatted by Lua.
+fn parse_prefix(message: &str) -> Option<(&str, ErrorPosition)> {
+    let string_prefix = "[string \"";
+    if message.starts_with(string_prefix) {
+        let end = message.find("\"]:")?;
+        let line = parse_line(&message[end + 3..])?;
+        return Some((
+            &message[..end + 2],
+            ErrorPosition {
+                chunk_name: message[string_prefix.len()..end].to_owned(),
+                line,
+            },
+        ));
+    }
+
+    // File names may contain colons themselves, so look for the first `:line:`.
+    for (i, _) in message.match_indices(':') {
+        if i == 0 || message[..i].contains('\n') {
+            break;
+        }
+        if let Some(line) = parse_line(&message[i + 1..]) {
+            return Some((
+                &message[..i],
+                ErrorPosition {
+                    chunk_name: message[..i].to_owned(),
+                    line,
+                },
+            ));
+        }
+    }
+    None
+}
+
+// Parses `line:` at the start of `s`, which Lua always follows with a space or the end of the
+// message.
+fn parse_line(s: &str) -> Option<u32> {
+    let end = s.find(':')?;
+    let digits = &s[..end];
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match s[end + 1..].chars().next() {
+        None | Some(' ') | Some('\n') => digits.parse().ok(),
+        Some(_) => None,
+    }
+}
+
+// Returns the position of a runtime error.  The message can be any text passed to `error`, such as
+// an URL with a port, so a `chunk:line:` prefix is only trusted if the traceback generated by Lua,
+// if any, also shows that chunk.
+fn runtime_position(message: &str) -> Option<ErrorPosition> {
+    let traceback = message.find("\nstack traceback:\n").map(|i| &message[i..]);
+    match (parse_prefix(message), traceback) {
+        (Some((chunk, position)), Some(traceback)) => {
+            if traceback.contains(&format!("\n\t{}:", chunk)) {
+                Some(position)
+            } else {
+                traceback_position(message)
+            }
+        }
+        (Some((_, position)), None) => Some(position),
+        (None, _) => traceback_position(message),
+    }
+}
+
+// Returns the position of the first Lua function in a traceback generated by `luaL_traceback`.
+fn traceback_position(message: &str) -> Option<ErrorPosition> {
+    let traceback = &message[message.find("stack traceback:\n")?..];
+    traceback
+        .lines()
+        .skip(1)
+        .filter_map(|line| parse_position(line.trim_start()))
+        .next()
 }
 
 pub trait ExternalError {
@@ -17,6 +17,10 @@ pub type lua_KContext = *mut c_void;
 pub type lua_KFunction =
     unsafe extern "C" fn(state: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int;
 pub type lua_CFunction = unsafe extern "C" fn(state: *mut lua_State) -> c_int;
+pub type lua_Writer =
+    unsafe extern "C" fn(state: *mut lua_State, p: *const c_void, sz: usize, ud: *mut c_void)
+        -> c_int;
+pub type lua_Hook = unsafe extern "C" fn(state: *mut lua_State, ar: *mut lua_Debug);
 
 #[repr(C)]
 pub struct lua_Debug {
@@ -78,9 +82,23 @@ pub const LUA_GCSETPAUSE: c_int = 6;
 pub const LUA_GCSETSTEPMUL: c_int = 7;
 pub const LUA_GCISRUNNING: c_int = 9;
 
+pub const LUA_OPEQ: c_int = 0;
+
+pub const LUA_HOOKCALL: c_int = 0;
+pub const LUA_HOOKRET: c_int = 1;
+pub const LUA_HOOKLINE: c_int = 2;
+pub const LUA_HOOKCOUNT: c_int = 3;
+pub const LUA_HOOKTAILCALL: c_int = 4;
+
+pub const LUA_MASKCALL: c_int = 1 << LUA_HOOKCALL;
+pub const LUA_MASKRET: c_int = 1 << LUA_HOOKRET;
+pub const LUA_MASKLINE: c_int = 1 << LUA_HOOKLINE;
+pub const LUA_MASKCOUNT: c_int = 1 << LUA_HOOKCOUNT;
+
 #[link(name = "lua5.3")]
 extern "C" {
     pub fn lua_newstate(alloc: lua_Alloc, ud: *mut c_void) -> *mut lua_State;
+    pub fn lua_getallocf(state: *mut lua_State, ud: *mut *mut c_void) -> lua_Alloc;
     pub fn lua_close(state: *mut lua_State);
 
     pub fn lua_callk(
@@ -100,6 +118,13 @@ extern "C" {
     ) -> c_int;
     pub fn lua_resume(state: *mut lua_State, from: *mut lua_State, nargs: c_int) -> c_int;
     pub fn lua_status(state: *mut lua_State) -> c_int;
+    pub fn lua_yieldk(
+        state: *mut lua_State,
+        nresults: c_int,
+        ctx: lua_KContext,
+        k: Option<lua_KFunction>,
+    ) -> c_int;
+    pub fn lua_isyieldable(state: *mut lua_State) -> c_int;
 
     pub fn lua_pushnil(state: *mut lua_State);
     pub fn lua_pushvalue(state: *mut lua_State, index: c_int);
@@ -109,6 +134,7 @@ extern "C" {
     pub fn lua_pushlstring(state: *mut lua_State, s: *const c_char, len: usize) -> *const c_char;
     pub fn lua_pushstring(state: *mut lua_State, s: *const c_char) -> *const c_char;
     pub fn lua_pushlightuserdata(state: *mut lua_State, data: *mut c_void);
+    pub fn lua_pushthread(state: *mut lua_State) -> c_int;
     pub fn lua_pushcclosure(state: *mut lua_State, function: lua_CFunction, n: c_int);
 
     pub fn lua_tointegerx(state: *mut lua_State, index: c_int, isnum: *mut c_int) -> lua_Integer;
@@ -116,7 +142,9 @@ extern "C" {
     pub fn lua_toboolean(state: *mut lua_State, index: c_int) -> c_int;
     pub fn lua_tonumberx(state: *mut lua_State, index: c_int, isnum: *mut c_int) -> lua_Number;
     pub fn lua_touserdata(state: *mut lua_State, index: c_int) -> *mut c_void;
+    pub fn lua_topointer(state: *mut lua_State, index: c_int) -> *const c_void;
     pub fn lua_tothread(state: *mut lua_State, index: c_int) -> *mut lua_State;
+    pub fn lua_tocfunction(state: *mut lua_State, index: c_int) -> Option<lua_CFunction>;
 
     pub fn lua_gettop(state: *const lua_State) -> c_int;
     pub fn lua_settop(state: *mut lua_State, n: c_int);
@@ -149,6 +177,8 @@ extern "C" {
 
     pub fn lua_getupvalue(state: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char;
     pub fn lua_setupvalue(state: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char;
+    pub fn lua_upvalueid(state: *mut lua_State, funcindex: c_int, n: c_int) -> *mut c_void;
+    pub fn lua_upvaluejoin(state: *mut lua_State, f1: c_int, n1: c_int, f2: c_int, n2: c_int);
 
     pub fn lua_settable(state: *mut lua_State, index: c_int);
     pub fn lua_rawset(state: *mut lua_State, index: c_int);
@@ -158,11 +188,27 @@ extern "C" {
     pub fn lua_rawlen(state: *mut lua_State, index: c_int) -> usize;
     pub fn lua_next(state: *mut lua_State, index: c_int) -> c_int;
     pub fn lua_rawequal(state: *mut lua_State, index1: c_int, index2: c_int) -> c_int;
+    pub fn lua_compare(state: *mut lua_State, index1: c_int, index2: c_int, op: c_int) -> c_int;
+
+    pub fn lua_dump(
+        state: *mut lua_State,
+        writer: lua_Writer,
+        data: *mut c_void,
+        strip: c_int,
+    ) -> c_int;
 
     pub fn lua_error(state: *mut lua_State) -> !;
+    pub fn lua_concat(state: *mut lua_State, n: c_int);
     pub fn lua_atpanic(state: *mut lua_State, panic: lua_CFunction) -> lua_CFunction;
     pub fn lua_gc(state: *mut lua_State, what: c_int, data: c_int) -> c_int;
+    pub fn lua_getstack(state: *mut lua_State, level: c_int, ar: *mut lua_Debug) -> c_int;
     pub fn lua_getinfo(state: *mut lua_State, what: *const c_char, ar: *mut lua_Debug) -> c_int;
+    pub fn lua_getlocal(state: *mut lua_State, ar: *const lua_Debug, n: c_int) -> *const c_char;
+    pub fn lua_setlocal(state: *mut lua_State, ar: *const lua_Debug, n: c_int) -> *const c_char;
+    pub fn lua_sethook(state: *mut lua_State, f: Option<lua_Hook>, mask: c_int, count: c_int);
+    pub fn lua_gethook(state: *mut lua_State) -> Option<lua_Hook>;
+    pub fn lua_gethookmask(state: *mut lua_State) -> c_int;
+    pub fn lua_gethookcount(state: *mut lua_State) -> c_int;
 
     pub fn luaopen_base(state: *mut lua_State) -> c_int;
     pub fn luaopen_coroutine(state: *mut lua_State) -> c_int;
@@ -200,6 +246,8 @@ extern "C" {
         level: c_int,
     );
     pub fn luaL_len(push_state: *mut lua_State, index: c_int) -> lua_Integer;
+    pub fn luaL_tolstring(state: *mut lua_State, index: c_int, len: *mut usize) -> *const c_char;
+    pub fn luaL_where(state: *mut lua_State, level: c_int);
 }
 
 // The following are re-implementations of what are macros in the Lua C API
@@ -305,6 +353,10 @@ pub unsafe fn lua_call(state: *mut lua_State, nargs: c_int, nresults: c_int) {
     lua_callk(state, nargs, nresults, ptr::null_mut(), None)
 }
 
+pub unsafe fn lua_yield(state: *mut lua_State, nresults: c_int) -> c_int {
+    lua_yieldk(state, nresults, ptr::null_mut(), None)
+}
+
 pub unsafe fn lua_pcall(
     state: *mut lua_State,
     nargs: c_int,
@@ -1,13 +1,21 @@
-use std::os::raw::c_int;
-use std::ptr;
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_void};
+use std::result::Result as StdResult;
+use std::string::String as StdString;
+use std::{mem, ptr};
 
 use error::{Error, Result};
 use ffi;
+#[cfg(feature = "async")]
+use future::AsyncCall;
+use table::Table;
 use types::LuaRef;
+use userdata::{AnyUserData, UserData};
 use util::{
-    assert_stack, check_stack, error_traceback, pop_error, protect_lua_closure, StackGuard,
+    assert_stack, check_stack, dump_function, error_traceback, is_wrapped_panic, pop_error,
+    protect_lua_closure, StackGuard,
 };
-use value::{FromLuaMulti, MultiValue, ToLuaMulti};
+use value::{FromLuaMulti, MultiValue, ToLuaMulti, Value};
 
 /// Handle to an internal Lua function.
 #[derive(Clone, Debug)]
@@ -66,8 +74,61 @@ impl<'lua> Function<'lua> {
         let lua = self.0.lua;
 
         let args = args.to_lua_multi(lua)?;
-        let nargs = args.len() as c_int;
+        self.call_impl(args.len() as c_int, || {
+            for arg in args {
+                unsafe { lua.push_value(arg)? };
+            }
+            Ok(())
+        })
+    }
+
+    /// Calls the function, passing the values in `args` as function arguments.
+    ///
+    /// This is like [`call`], but borrows already converted arguments instead of converting them
+    /// into a new `MultiValue`, so that a loop calling the same function many times can keep one
+    /// buffer of arguments and update it in place between calls.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let step: Function = lua.eval("function(n, d) return n + d end", None)?;
+    ///
+    /// let mut args = [Value::Integer(0), Value::Integer(3)];
+    /// let mut total = 0;
+    /// for _ in 0..4 {
+    ///     total = step.call_slice(&args)?;
+    ///     args[0] = Value::Integer(total);
+    /// }
+    /// assert_eq!(total, 12);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    pub fn call_slice<R: FromLuaMulti<'lua>>(&self, args: &[Value<'lua>]) -> Result<R> {
+        let lua = self.0.lua;
+        self.call_impl(args.len() as c_int, || {
+            for arg in args {
+                unsafe { lua.push_value_ref(arg)? };
+            }
+            Ok(())
+        })
+    }
 
+    // Calls the function with the `nargs` arguments pushed onto the stack by `push_args`.
+    fn call_impl<R, F>(&self, nargs: c_int, push_args: F) -> Result<R>
+    where
+        R: FromLuaMulti<'lua>,
+        F: FnOnce() -> Result<()>,
+    {
+        let lua = self.0.lua;
         let results = unsafe {
             let _sg = StackGuard::new(lua.state);
             check_stack(lua.state, nargs + 3)?;
@@ -75,9 +136,7 @@ impl<'lua> Function<'lua> {
             ffi::lua_pushcfunction(lua.state, error_traceback);
             let stack_start = ffi::lua_gettop(lua.state);
             lua.push_ref(&self.0);
-            for arg in args {
-                lua.push_value(arg);
-            }
+            push_args()?;
             let ret = ffi::lua_pcall(lua.state, nargs, ffi::LUA_MULTRET, stack_start);
             if ret != ffi::LUA_OK {
                 return Err(pop_error(lua.state, ret));
@@ -94,6 +153,429 @@ impl<'lua> Function<'lua> {
         R::from_lua_multi(results, lua)
     }
 
+    /// Calls the function inside a new coroutine, returning a future which completes with the
+    /// results of the function.
+    ///
+    /// Functions created with [`Lua::create_async_function`] yield this coroutine while their
+    /// future is pending, in which case the returned future is pending as well, until the waker of
+    /// the task polling it is woken.  Values yielded by the function itself with
+    /// `coroutine.yield` are discarded, and the future wakes itself and returns pending so that
+    /// other tasks can run before the coroutine is resumed.
+    ///
+    /// See [`Lua::create_async_function`] for an example.  This is only available with the `async`
+    /// feature enabled.
+    ///
+    /// [`Lua::create_async_function`]: struct.Lua.html#method.create_async_function
+    #[cfg(feature = "async")]
+    pub fn call_async<A, R>(&self, args: A) -> AsyncCall<'lua, R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        AsyncCall::new(self, args)
+    }
+
+    /// Calls the function with `env` as its environment, without compiling it again.
+    ///
+    /// The value of the function's `_ENV` upvalue is replaced by `env` for the duration of the
+    /// call, and restored afterwards, also when the call results in an error or a panic.  This
+    /// makes it cheap to run a chunk loaded once with a different environment for each call, such
+    /// as one per request.
+    ///
+    /// Like all upvalues, the `_ENV` variable is shared by the functions defined inside the chunk,
+    /// so these functions also use `env` during the call, but the restored environment when they
+    /// are called afterwards.  Use a [`ScriptTemplate`] for functions which keep the environment
+    /// they were defined in.  Functions without an `_ENV` upvalue, such as Rust functions or
+    /// functions which do not access global variables, are called with their environment
+    /// unchanged.
+    ///
+    /// The first upvalue of a main chunk is always its `_ENV`, even when the chunk was loaded from
+    /// a binary chunk without debug information, such as one dumped with `strip` set to `true`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` without calling the function if it is a function defined inside a
+    /// binary chunk without debug information and has upvalues, since its `_ENV` upvalue cannot
+    /// be told apart from the others.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let greet = lua.load("return greeting .. ', ' .. name", None)?;
+    ///
+    /// for &(greeting, name) in &[("Hello", "Ann"), ("Hi", "Bob")] {
+    ///     let env = lua.create_table()?;
+    ///     env.set("greeting", greeting)?;
+    ///     env.set("name", name)?;
+    ///     let message = greet.call_with_env::<_, String>(env, ())?;
+    ///     assert_eq!(message, format!("{}, {}", greeting, name));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ScriptTemplate`]: struct.ScriptTemplate.html
+    pub fn call_with_env<A, R>(&self, env: Table<'lua>, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let index = match unsafe { self.env_upvalue()? } {
+            Some(index) => index,
+            None => return self.call(args),
+        };
+        let previous = unsafe { self.swap_upvalue(index, Value::Table(env))? };
+        let _restore = RestoreEnv {
+            function: self,
+            index,
+            previous: Some(previous),
+        };
+        self.call(args)
+    }
+
+    // Returns the index of the `_ENV` upvalue, if any, or an error if the upvalues of a function
+    // from a stripped binary chunk have no names.  The index is cached per function, so the
+    // upvalues are only scanned the first time.
+    unsafe fn env_upvalue(&self) -> Result<Option<c_int>> {
+        let lua = self.0.lua;
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 6);
+
+        push_env_upvalue_cache(lua.state)?;
+        lua.push_ref(&self.0);
+        if ffi::lua_rawget(lua.state, -2) == ffi::LUA_TNUMBER {
+            let index = ffi::lua_tointeger(lua.state, -1) as c_int;
+            return Ok(if index == 0 { None } else { Some(index) });
+        }
+        ffi::lua_pop(lua.state, 1);
+
+        let index = self.find_env_upvalue()?;
+        lua.push_ref(&self.0);
+        ffi::lua_pushinteger(lua.state, index.unwrap_or(0) as ffi::lua_Integer);
+        protect_lua_closure(lua.state, 3, 0, |state| ffi::lua_rawset(state, -3))?;
+        Ok(index)
+    }
+
+    // Scans the upvalue names of the function for `_ENV`, see `env_upvalue`.
+    unsafe fn find_env_upvalue(&self) -> Result<Option<c_int>> {
+        let lua = self.0.lua;
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 2);
+
+        lua.push_ref(&self.0);
+        let mut unnamed = false;
+        let mut index = 1;
+        loop {
+            let name = ffi::lua_getupvalue(lua.state, -1, index);
+            if name.is_null() {
+                break;
+            }
+            ffi::lua_pop(lua.state, 1);
+            match CStr::from_ptr(name).to_bytes() {
+                b"_ENV" => return Ok(Some(index)),
+                b"(*no name)" => unnamed = true,
+                _ => {}
+            }
+            index += 1;
+        }
+        if !unnamed {
+            return Ok(None);
+        }
+
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        ffi::lua_getinfo(lua.state, cstr!(">S"), &mut ar);
+        if CStr::from_ptr(ar.what).to_bytes() == b"main" {
+            Ok(Some(1))
+        } else {
+            Err(Error::RuntimeError(
+                "cannot find the _ENV upvalue of a function without debug information".to_owned(),
+            ))
+        }
+    }
+
+    // Sets the value of the upvalue `index`, returning its previous value.
+    unsafe fn swap_upvalue(&self, index: c_int, value: Value<'lua>) -> Result<Value<'lua>> {
+        let lua = self.0.lua;
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 3);
+
+        lua.push_ref(&self.0);
+        ffi::lua_getupvalue(lua.state, -1, index);
+        let previous = lua.pop_value();
+        lua.push_value(value)?;
+        ffi::lua_setupvalue(lua.state, -2, index);
+        Ok(previous)
+    }
+
+    /// Calls the function like [`call`], but returns errors raised by Lua as values instead of
+    /// converting them to an [`Error`].
+    ///
+    /// If the function raises an error, the inner result is `Err` with the error object exactly as
+    /// it was passed to `error`, which may be a table carrying structured fields rather than a
+    /// message string.  Errors returned from Rust callbacks are caught as [`Value::Error`], with
+    /// no traceback attached.  The outer result is only an error for failures on the Rust side,
+    /// such as a failed conversion of the arguments or results, or an error in a `__gc`
+    /// metamethod during the call.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let fail: Function = lua.eval(r#"
+    ///     function()
+    ///         error({ code = 42 })
+    ///     end
+    /// "#, None)?;
+    ///
+    /// match fail.call_catching::<_, ()>(())? {
+    ///     Ok(()) => panic!("expected an error"),
+    ///     Err(rlua::Value::Table(error)) => assert_eq!(error.get::<_, i64>("code")?, 42),
+    ///     Err(_) => panic!("expected an error table"),
+    /// }
+    ///
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`Error`]: enum.Error.html
+    /// [`Value::Error`]: enum.Value.html#variant.Error
+    pub fn call_catching<A: ToLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
+        &self,
+        args: A,
+    ) -> Result<StdResult<R, Value<'lua>>> {
+        let lua = self.0.lua;
+
+        let args = args.to_lua_multi(lua)?;
+        let nargs = args.len() as c_int;
+
+        let results = unsafe {
+            let _sg = StackGuard::new(lua.state);
+            check_stack(lua.state, nargs + 2)?;
+
+            let stack_start = ffi::lua_gettop(lua.state);
+            lua.push_ref(&self.0);
+            for arg in args {
+                lua.push_value(arg)?;
+            }
+            let ret = ffi::lua_pcall(lua.state, nargs, ffi::LUA_MULTRET, 0);
+            if ret == ffi::LUA_ERRRUN && !is_wrapped_panic(lua.state, -1) {
+                return Ok(Err(lua.pop_value()));
+            } else if ret != ffi::LUA_OK {
+                return Err(pop_error(lua.state, ret));
+            }
+            let nresults = ffi::lua_gettop(lua.state) - stack_start;
+            let mut results = MultiValue::new();
+            assert_stack(lua.state, 2);
+            for _ in 0..nresults {
+                results.push_front(lua.pop_value());
+            }
+            results
+        };
+        R::from_lua_multi(results, lua).map(Ok)
+    }
+
+    /// Calls the function like [`call`], from a frame labeled with `label` which is visible in
+    /// Lua tracebacks.
+    ///
+    /// The function is called through a small Lua function from a chunk named `label`, so
+    /// tracebacks (including the ones of `CallbackError`s and `debug.traceback`) taken during the
+    /// call contain lines such as `label:1: in function <label:1>`.  This can be used to tell
+    /// which entry point from Rust some Lua code was run from, for example to attribute the
+    /// samples of a profiler.
+    ///
+    /// The labeling function is compiled once for each distinct label, and kept for as long as the
+    /// Lua state, for up to 256 labels.  Labels beyond that are compiled again on every call, so
+    /// labels should come from a small fixed set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, Function, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let on_update: Function = lua.eval("function() error('oops') end", None)?;
+    /// match on_update.call_labeled::<_, ()>("entry:on_update", ()) {
+    ///     Err(Error::RuntimeError(msg)) => assert!(msg.contains("entry:on_update:1:")),
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    pub fn call_labeled<A, R>(&self, label: &str, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let factory = self.0.lua.labeled_call_factory(label)?;
+        let labeled: Function = factory.call(self.clone())?;
+        labeled.call(args)
+    }
+
+    /// Calls the function, passing `userdata` as the first argument followed by `args`, so that
+    /// the function can fill it in as an out-parameter.
+    ///
+    /// This allows a script to write a large result into a preallocated buffer through the
+    /// userdata's methods instead of building and returning a new value.  Before the call, this
+    /// checks that `userdata` is of type `T` and is not currently borrowed, so that methods taking
+    /// `&mut self` can be called from Lua.  Any `Ref` or `RefMut` obtained from `userdata` must
+    /// therefore be dropped before calling this, and the results can be read back with
+    /// [`AnyUserData::borrow`] after it returns.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `UserDataTypeMismatch` if the userdata is not of type `T`, a `ScopeExpired` if it
+    /// is a scoped userdata whose scope has ended, and a `UserDataBorrowMutError` if the userdata
+    /// is borrowed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result, UserData, UserDataMethods};
+    /// # fn try_main() -> Result<()> {
+    /// struct Buffer(Vec<i64>);
+    ///
+    /// impl UserData for Buffer {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_method_mut("push", |_, buffer, value: i64| {
+    ///             buffer.0.push(value);
+    ///             Ok(())
+    ///         });
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let fill: Function = lua.eval(r#"
+    ///     function(out, count)
+    ///         for i = 1, count do
+    ///             out:push(i * i)
+    ///         end
+    ///     end
+    /// "#, None)?;
+    ///
+    /// let buffer = lua.create_userdata(Buffer(Vec::with_capacity(4)))?;
+    /// fill.call_mutating::<Buffer, _, ()>(4, &buffer)?;
+    /// assert_eq!(buffer.borrow::<Buffer>()?.0, vec![1, 4, 9, 16]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`AnyUserData::borrow`]: struct.AnyUserData.html#method.borrow
+    pub fn call_mutating<T, A, R>(&self, args: A, userdata: &AnyUserData<'lua>) -> Result<R>
+    where
+        T: UserData,
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        userdata.borrow_mut::<T>()?;
+
+        let mut args = args.to_lua_multi(self.0.lua)?;
+        args.push_front(Value::UserData(userdata.clone()));
+        self.call(args)
+    }
+
+    /// Calls the function, passing the values of the sequence table `args` as separate arguments.
+    ///
+    /// This is like calling `f(table.unpack(args))` in Lua, without having to go through
+    /// `table.unpack`.  The arguments are read like [`Table::sequence_values`] does, starting at
+    /// index 1 and stopping at the first `nil` value, so any values after a hole in the table are
+    /// not passed.  Unlike `table.unpack`, this does not depend on the length of a table with
+    /// holes, which Lua does not define.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let max: Function = lua.globals().get::<_, Table>("math")?.get("max")?;
+    /// let args = lua.create_sequence_from(vec![3, 9, 4])?;
+    /// assert_eq!(max.apply::<i64>(args)?, 9);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Table::sequence_values`]: struct.Table.html#method.sequence_values
+    pub fn apply<R: FromLuaMulti<'lua>>(&self, args: Table<'lua>) -> Result<R> {
+        let args = args
+            .sequence_values::<Value>()
+            .collect::<Result<MultiValue>>()?;
+        self.call(args)
+    }
+
+    /// Calls the function, packing all of its results into a new table.
+    ///
+    /// Like `table.pack(f(...))` in Lua, the results are stored at the indices 1 to `n` of the
+    /// table, and the number of results is stored in the field `n`, so that `nil` results are
+    /// accounted for.  This is useful when the number of results is not known in advance, and they
+    /// need to be stored or passed on as a single value.
+    ///
+    /// Note that passing the table to [`apply`] does not always pass the same values on, since
+    /// `apply` ignores the field `n` and stops at the first `nil` result.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let f: Function = lua.eval("function(x) return x, nil, x * 2 end", None)?;
+    /// let results = f.call_packed(21)?;
+    /// assert_eq!(results.get::<_, i64>("n")?, 3);
+    /// assert_eq!(results.get::<_, i64>(1)?, 21);
+    /// assert_eq!(results.get::<_, Option<i64>>(2)?, None);
+    /// assert_eq!(results.get::<_, i64>(3)?, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`apply`]: #method.apply
+    pub fn call_packed<A: ToLuaMulti<'lua>>(&self, args: A) -> Result<Table<'lua>> {
+        let results = self.call::<_, MultiValue>(args)?;
+        let table = self.0.lua.create_table()?;
+        let n = results.len();
+        for (i, value) in results.into_iter().enumerate() {
+            table.raw_set(i + 1, value)?;
+        }
+        table.raw_set("n", n)?;
+        Ok(table)
+    }
+
     /// Returns a function that, when called, calls `self`, passing `args` as the first set of
     /// arguments.
     ///
@@ -161,7 +643,7 @@ impl<'lua> Function<'lua> {
             lua.push_ref(&self.0);
             ffi::lua_pushinteger(lua.state, nargs as ffi::lua_Integer);
             for arg in args {
-                lua.push_value(arg);
+                lua.push_value(arg)?;
             }
 
             protect_lua_closure(lua.state, nargs + 2, 1, |state| {
@@ -171,4 +653,120 @@ impl<'lua> Function<'lua> {
             Ok(Function(lua.pop_ref()))
         }
     }
+
+    /// Returns a line (starting at 1) of the source code this function was defined in.
+    ///
+    /// This only works for Lua functions from named chunks loaded while source retention was
+    /// enabled with [`Lua::set_source_retention`], and returns `None` otherwise, or if the line
+    /// does not exist.  Combined with the line number of a runtime error, this can be used to show
+    /// the offending line of code.
+    ///
+    /// The source is found through the function returned by `load`, so it is only available while
+    /// that function is alive.  Functions defined inside the chunk are matched with it by their
+    /// shared `_ENV` upvalue, so this returns `None` for nested functions which do not access any
+    /// global variable.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_source_retention(true);
+    ///
+    /// let func = lua.load("local a = 1\nerror('oops')", Some("script"))?;
+    /// assert_eq!(func.source_line(2).as_ref().map(|s| s.as_str()), Some("error('oops')"));
+    /// assert_eq!(func.source_line(3), None);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Lua::set_source_retention`]: struct.Lua.html#method.set_source_retention
+    pub fn source_line(&self, line: u32) -> Option<StdString> {
+        let lua = self.0.lua;
+        let source = unsafe {
+            let env_index = self.env_upvalue().ok()?;
+            lua.retained_source(&self.0, env_index)?
+        };
+        source
+            .lines()
+            .nth(line.checked_sub(1)? as usize)
+            .map(|line| line.to_owned())
+    }
+
+    /// Dumps the function to a binary chunk, like `string.dump`.
+    ///
+    /// If `strip` is true, the debug information (the chunk name, line numbers and the names of
+    /// locals and upvalues) is left out of the chunk.  The chunk can be loaded again with Lua's
+    /// `load` function, and only in a Lua state with the same Lua version, number types and byte
+    /// order.  Functions defined in Rust or C cannot be dumped and produce an empty chunk.
+    ///
+    /// The Lua compiler does not embed timestamps or any other varying data into the chunk, so
+    /// dumping functions compiled from the same source with the same chunk name produces the same
+    /// bytes.  See [`Lua::load_reproducible`] for normalizing the chunk name.
+    ///
+    /// [`Lua::load_reproducible`]: struct.Lua.html#method.load_reproducible
+    pub fn dump(&self, strip: bool) -> Vec<u8> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 1);
+
+            lua.push_ref(&self.0);
+            dump_function(lua.state, strip)
+        }
+    }
+}
+
+// Restores the environment replaced by `Function::call_with_env` when dropped.
+struct RestoreEnv<'a, 'lua: 'a> {
+    function: &'a Function<'lua>,
+    index: c_int,
+    previous: Option<Value<'lua>>,
 }
+
+impl<'a, 'lua> Drop for RestoreEnv<'a, 'lua> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // Pushing a value only fails for values from another state, and this one was taken
+            // from the function itself.
+            let _ = unsafe { self.function.swap_upvalue(self.index, previous) };
+        }
+    }
+}
+
+// Pushes the table caching the `_ENV` upvalue index of functions, creating it on first use.  A
+// function without an `_ENV` upvalue is cached as 0.  The table has weak keys, so it does not keep
+// the functions alive.  Uses 3 stack spaces, does not call checkstack.
+unsafe fn push_env_upvalue_cache(state: *mut ffi::lua_State) -> Result<()> {
+    ffi::lua_pushlightuserdata(
+        state,
+        &ENV_UPVALUE_CACHE_REGISTRY_KEY as *const u8 as *mut c_void,
+    );
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TTABLE {
+        return Ok(());
+    }
+    ffi::lua_pop(state, 1);
+
+    protect_lua_closure(state, 0, 1, |state| {
+        ffi::lua_newtable(state);
+        ffi::lua_newtable(state);
+        ffi::lua_pushstring(state, cstr!("__mode"));
+        ffi::lua_pushstring(state, cstr!("k"));
+        ffi::lua_rawset(state, -3);
+        ffi::lua_setmetatable(state, -2);
+
+        ffi::lua_pushlightuserdata(
+            state,
+            &ENV_UPVALUE_CACHE_REGISTRY_KEY as *const u8 as *mut c_void,
+        );
+        ffi::lua_pushvalue(state, -2);
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+    })
+}
+
+static ENV_UPVALUE_CACHE_REGISTRY_KEY: u8 = 0;
@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use error::{Error, Result};
+use ffi;
+use function::Function;
+use lua::{call_panic_hook, Lua};
+use thread::{Resumed, Thread};
+use types::LightUserData;
+use util::{assert_stack, panic_message, StackGuard};
+use value::{FromLuaMulti, MultiValue, ToLuaMulti, Value};
+
+// Yielded by the functions created with `Lua::create_async_function` while their future is
+// pending, to tell these yields apart from the yields of the script itself.
+static PENDING_KEY: u8 = 0;
+
+// Calls the function which creates the future, then polls it with the function this returns
+// until it is ready, yielding in between.
+const ASYNC_WRAPPER: &str = r#"
+    local start, yield_pending = ...
+    local function resolve(poll, ready, ...)
+        if ready then
+            return ...
+        end
+        yield_pending()
+        return resolve(poll, poll())
+    end
+    return function(...)
+        local poll = start(...)
+        return resolve(poll, poll())
+    end
+"#;
+
+pub(crate) fn create_async_function<'lua, 'callback, A, R, F, FR>(
+    lua: &'lua Lua,
+    func: F,
+) -> Result<Function<'lua>>
+where
+    A: FromLuaMulti<'callback>,
+    R: ToLuaMulti<'callback>,
+    F: 'static + Send + Fn(&'callback Lua, A) -> FR,
+    FR: 'static + Send + Future<Output = Result<R>>,
+{
+    unsafe extern "C" fn yield_pending(state: *mut ffi::lua_State) -> c_int {
+        ffi::lua_pushlightuserdata(state, &PENDING_KEY as *const u8 as *mut c_void);
+        ffi::lua_yield(state, 1)
+    }
+
+    let start = lua.create_function(move |lua, args: A| {
+        let future = catch_panic(lua, || func(lua, args))?;
+        let future = RefCell::new(Box::pin(future));
+        lua.create_function(move |lua, ()| {
+            let waker = lua.current_waker().ok_or_else(|| {
+                Error::RuntimeError(
+                    "async function called outside of the coroutine of Function::call_async"
+                        .to_owned(),
+                )
+            })?;
+            let mut future = future
+                .try_borrow_mut()
+                .map_err(|_| Error::RecursiveMutCallback)?;
+            let poll = catch_panic(lua, || {
+                future.as_mut().poll(&mut Context::from_waker(&waker))
+            })?;
+            match poll {
+                Poll::Ready(results) => Ok((true, results?.to_lua_multi(lua)?)),
+                Poll::Pending => Ok((false, MultiValue::new())),
+            }
+        })
+    })?;
+    let yield_pending = unsafe {
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 1);
+        ffi::lua_pushcfunction(lua.state, yield_pending);
+        Function(lua.pop_ref())
+    };
+    lua.load(ASYNC_WRAPPER, Some("async function"))?
+        .call((start, yield_pending))
+}
+
+// Runs `f`, turning a panic into an `Error::AsyncPanic`.  The panic is caught before it reaches
+// the Lua callback boundary, so it never unwinds through the coroutine being resumed.
+fn catch_panic<T, F: FnOnce() -> T>(lua: &Lua, f: F) -> Result<T> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|panic| {
+        unsafe {
+            call_panic_hook(lua.state, &*panic);
+        }
+        Error::AsyncPanic {
+            message: panic_message(&*panic),
+        }
+    })
+}
+
+/// A future which calls a Lua function inside a new coroutine, returned by
+/// [`Function::call_async`].
+///
+/// Each poll resumes the coroutine, until the function returns or raises an error.  Polling the
+/// future again after it has completed panics.
+///
+/// [`Function::call_async`]: struct.Function.html#method.call_async
+#[must_use = "futures do nothing unless polled"]
+pub struct AsyncCall<'lua, R> {
+    thread: Option<Thread<'lua>>,
+    args: MultiValue<'lua>,
+    // An error from creating the coroutine or converting the arguments, returned by the first poll.
+    error: Option<Error>,
+    _phantom: PhantomData<fn() -> R>,
+}
+
+impl<'lua, R> AsyncCall<'lua, R> {
+    pub(crate) fn new<A: ToLuaMulti<'lua>>(func: &Function<'lua>, args: A) -> AsyncCall<'lua, R> {
+        let lua = func.0.lua;
+        let call = lua
+            .create_thread(func.clone())
+            .and_then(|thread| Ok((thread, args.to_lua_multi(lua)?)));
+        match call {
+            Ok((thread, args)) => AsyncCall {
+                thread: Some(thread),
+                args,
+                error: None,
+                _phantom: PhantomData,
+            },
+            Err(err) => AsyncCall {
+                thread: None,
+                args: MultiValue::new(),
+                error: Some(err),
+                _phantom: PhantomData,
+            },
+        }
+    }
+}
+
+impl<'lua, R: FromLuaMulti<'lua>> Future for AsyncCall<'lua, R> {
+    type Output = Result<R>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<R>> {
+        let this = self.get_mut();
+        if let Some(err) = this.error.take() {
+            return Poll::Ready(Err(err));
+        }
+
+        let resumed = {
+            let thread = this
+                .thread
+                .as_ref()
+                .expect("AsyncCall polled after completion");
+            let lua = thread.0.lua;
+            let thread_state = unsafe {
+                let _sg = StackGuard::new(lua.state);
+                assert_stack(lua.state, 1);
+                lua.push_ref(&thread.0);
+                ffi::lua_tothread(lua.state, -1)
+            };
+            let args = mem::replace(&mut this.args, MultiValue::new());
+            // Restored afterwards, in case this is polled from within another `AsyncCall`.
+            let previous = lua.set_current_waker(Some((cx.waker().clone(), thread_state)));
+            let resumed = thread.resume_step::<_, MultiValue>(args);
+            lua.set_current_waker(previous);
+            resumed
+        };
+
+        match resumed {
+            Ok(Resumed::Yielded(values)) => {
+                // The script yielded by itself, which gives other tasks a chance to run.
+                if !is_pending(&values) {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+            Ok(Resumed::Returned(values)) => {
+                let lua = this.thread.take().unwrap().0.lua;
+                Poll::Ready(R::from_lua_multi(values, lua))
+            }
+            Err(err) => {
+                this.thread = None;
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+fn is_pending(values: &MultiValue) -> bool {
+    if values.len() != 1 {
+        return false;
+    }
+    match values.iter().next() {
+        Some(&Value::LightUserData(LightUserData(ptr))) => {
+            ptr as *const u8 == &PENDING_KEY as *const u8
+        }
+        _ => false,
+    }
+}
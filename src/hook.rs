@@ -0,0 +1,293 @@
+use std::cmp;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::string::String as StdString;
+
+use error::Result;
+use ffi;
+use lua::Lua;
+use util::{assert_stack, StackGuard};
+use value::{ToLua, Value};
+
+/// Determines when a hook set with [`Lua::set_hook`] is called.
+///
+/// [`Lua::set_hook`]: struct.Lua.html#method.set_hook
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HookTriggers {
+    /// Call the hook when a function is called, including tail calls.
+    pub on_calls: bool,
+    /// Call the hook when a function is about to return.
+    pub on_returns: bool,
+    /// Call the hook when the interpreter starts executing a new line of code, or jumps back
+    /// within the same line (such as in a loop).
+    pub every_line: bool,
+    /// Call the hook after every `n` VM instructions.  `Some(0)` is treated as `Some(1)`.
+    pub every_nth_instruction: Option<u32>,
+}
+
+impl HookTriggers {
+    pub(crate) fn mask(&self) -> c_int {
+        let mut mask = 0;
+        if self.on_calls {
+            mask |= ffi::LUA_MASKCALL;
+        }
+        if self.on_returns {
+            mask |= ffi::LUA_MASKRET;
+        }
+        if self.every_line {
+            mask |= ffi::LUA_MASKLINE;
+        }
+        if self.every_nth_instruction.is_some() {
+            mask |= ffi::LUA_MASKCOUNT;
+        }
+        mask
+    }
+
+    pub(crate) fn count(&self) -> c_int {
+        self.every_nth_instruction.map_or(0, |n| {
+            cmp::min(cmp::max(n, 1), c_int::max_value() as u32) as c_int
+        })
+    }
+}
+
+/// The event which caused a hook to be called.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HookEvent {
+    /// A function is being called.
+    Call,
+    /// A function is being called as a tail call, so there will be no matching `Return` event.
+    TailCall,
+    /// A function is about to return.
+    Return,
+    /// A new line of code is about to be executed.
+    Line,
+    /// The configured number of instructions has been executed.
+    Count,
+}
+
+/// Information about the function running when a hook is called.
+///
+/// This exposes the information that Lua gathers with `lua_getinfo`, along with the local
+/// variables and upvalues of the running function, without the rest of the debug library.  A
+/// `Debug` is only valid for the duration of the hook call it was passed to, so locals can only be
+/// inspected while execution is suspended in the hook.
+pub struct Debug<'lua> {
+    lua: &'lua Lua,
+    ar: *mut ffi::lua_Debug,
+}
+
+/// Source information about the function running when a hook is called, see [`Debug::source`].
+///
+/// [`Debug::source`]: struct.Debug.html#method.source
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DebugSource {
+    /// The name of the chunk that defined the function, as passed to `Lua::load` (`None` for
+    /// functions defined in Rust or C).
+    pub source: Option<StdString>,
+    /// A shortened, printable version of `source`, as used in error messages.
+    pub short_src: StdString,
+    /// `"Lua"` for a Lua function, `"main"` for the main function of a chunk, and `"C"` for a
+    /// function defined in Rust or C.
+    pub what: &'static str,
+    /// The line where the function definition starts, if the function is defined in Lua.
+    pub line_defined: Option<u32>,
+    /// The line where the function definition ends, if the function is defined in Lua.
+    pub last_line_defined: Option<u32>,
+}
+
+impl<'lua> Debug<'lua> {
+    pub(crate) unsafe fn new(lua: &'lua Lua, ar: *mut ffi::lua_Debug) -> Debug<'lua> {
+        Debug { lua, ar }
+    }
+
+    /// Returns the `Lua` instance running the hook.
+    pub fn lua(&self) -> &'lua Lua {
+        self.lua
+    }
+
+    /// Returns the event which caused the hook to be called.
+    pub fn event(&self) -> HookEvent {
+        match unsafe { (*self.ar).event } {
+            ffi::LUA_HOOKCALL => HookEvent::Call,
+            ffi::LUA_HOOKTAILCALL => HookEvent::TailCall,
+            ffi::LUA_HOOKRET => HookEvent::Return,
+            ffi::LUA_HOOKLINE => HookEvent::Line,
+            ffi::LUA_HOOKCOUNT => HookEvent::Count,
+            _ => rlua_panic!("unrecognized hook event"),
+        }
+    }
+
+    /// Returns the line currently being executed, or `None` if the running function is not
+    /// defined in Lua.
+    pub fn current_line(&self) -> Option<u32> {
+        unsafe {
+            self.get_info(cstr!("l"));
+            line((*self.ar).currentline)
+        }
+    }
+
+    /// Returns the name of the running function, as far as Lua can tell from the code that called
+    /// it (such as the name of the global or field the function was called through).
+    pub fn name(&self) -> Option<StdString> {
+        unsafe {
+            self.get_info(cstr!("n"));
+            to_string((*self.ar).name)
+        }
+    }
+
+    /// Returns information about where the running function was defined.
+    pub fn source(&self) -> DebugSource {
+        unsafe {
+            self.get_info(cstr!("S"));
+            let ar = &*self.ar;
+            let what = match CStr::from_ptr(ar.what).to_bytes() {
+                b"Lua" => "Lua",
+                b"main" => "main",
+                _ => "C",
+            };
+            DebugSource {
+                source: if what == "C" {
+                    None
+                } else {
+                    to_string(ar.source)
+                },
+                short_src: to_string(ar.short_src.as_ptr()).unwrap_or_default(),
+                what,
+                line_defined: line(ar.linedefined),
+                last_line_defined: line(ar.lastlinedefined),
+            }
+        }
+    }
+
+    /// Returns true if the running function was called as a tail call.
+    pub fn is_tail_call(&self) -> bool {
+        unsafe {
+            self.get_info(cstr!("t"));
+            (*self.ar).istailcall != 0
+        }
+    }
+
+    /// Returns the names and values of the local variables of the running function which are
+    /// active at the current line, in the order in which they were declared.
+    ///
+    /// Internal variables of the Lua compiler (such as loop control variables, whose names start
+    /// with `(`) are skipped.  Functions defined in Rust or C have no local variables.
+    pub fn locals(&self) -> Result<Vec<(StdString, Value<'lua>)>> {
+        let state = self.lua.state;
+        let mut locals = Vec::new();
+        unsafe {
+            let _sg = StackGuard::new(state);
+            assert_stack(state, 2);
+
+            if self.source().what == "C" {
+                return Ok(locals);
+            }
+            for n in 1.. {
+                let name = ffi::lua_getlocal(state, self.ar, n);
+                if name.is_null() {
+                    break;
+                }
+                let value = self.lua.pop_value();
+                let name = CStr::from_ptr(name).to_string_lossy();
+                if !name.starts_with('(') {
+                    locals.push((name.into_owned(), value));
+                }
+            }
+        }
+        Ok(locals)
+    }
+
+    /// Assigns to the active local variable with the given name in the running function, as if
+    /// the function itself had assigned to it.  If several active locals have the same name, the
+    /// innermost one (the one visible at the current line) is assigned.
+    ///
+    /// Internal variables of the Lua compiler are skipped like in [`locals`], since the virtual
+    /// machine relies on the types of their values.
+    ///
+    /// Returns false if there is no such local variable.
+    ///
+    /// [`locals`]: #method.locals
+    pub fn set_local<V: ToLua<'lua>>(&self, name: &str, value: V) -> Result<bool> {
+        let value = value.to_lua(self.lua)?;
+        let state = self.lua.state;
+        unsafe {
+            let _sg = StackGuard::new(state);
+            assert_stack(state, 2);
+
+            if self.source().what == "C" {
+                return Ok(false);
+            }
+            let mut found = None;
+            for n in 1.. {
+                let local = ffi::lua_getlocal(state, self.ar, n);
+                if local.is_null() {
+                    break;
+                }
+                ffi::lua_pop(state, 1);
+                let local = CStr::from_ptr(local).to_bytes();
+                if !local.starts_with(b"(") && local == name.as_bytes() {
+                    found = Some(n);
+                }
+            }
+
+            match found {
+                Some(n) => {
+                    self.lua.push_value(value)?;
+                    ffi::lua_setlocal(state, self.ar, n);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+    }
+
+    /// Returns the names and values of the upvalues of the running function.
+    ///
+    /// Functions defined in Rust or C have no upvalues as far as this method is concerned.
+    pub fn upvalues(&self) -> Result<Vec<(StdString, Value<'lua>)>> {
+        let state = self.lua.state;
+        let mut upvalues = Vec::new();
+        unsafe {
+            let _sg = StackGuard::new(state);
+            assert_stack(state, 2);
+
+            if self.source().what == "C" {
+                return Ok(upvalues);
+            }
+            self.get_info(cstr!("f"));
+            for n in 1.. {
+                let name = ffi::lua_getupvalue(state, -1, n);
+                if name.is_null() {
+                    break;
+                }
+                let value = self.lua.pop_value();
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                upvalues.push((name, value));
+            }
+        }
+        Ok(upvalues)
+    }
+
+    unsafe fn get_info(&self, what: *const c_char) {
+        rlua_assert!(
+            ffi::lua_getinfo(self.lua.state, what, self.ar) != 0,
+            "lua_getinfo failed with the activation record of a hook"
+        );
+    }
+}
+
+fn line(line: c_int) -> Option<u32> {
+    if line > 0 {
+        Some(line as u32)
+    } else {
+        None
+    }
+}
+
+unsafe fn to_string(s: *const c_char) -> Option<StdString> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
@@ -0,0 +1,74 @@
+use std::any;
+
+use error::{Error, Result};
+use lua::Lua;
+
+/// A handle to the host application, passed to callbacks created with
+/// [`Lua::create_host_function`].
+///
+/// Host services are stored as application data with [`Lua::set_app_data`], and callbacks access
+/// them through [`with`] and [`with_mut`].  The host service is only borrowed while the closure
+/// passed to these methods runs, rather than for the whole callback, so the callback can call back
+/// into Lua outside of the closure without holding the borrow.  If the closure itself calls back
+/// into Lua, the borrow is held during that call, and another host callback which needs the same
+/// service receives a `RecursiveCallback` error instead of causing a panic.
+///
+/// [`Lua::create_host_function`]: struct.Lua.html#method.create_host_function
+/// [`Lua::set_app_data`]: struct.Lua.html#method.set_app_data
+/// [`with`]: #method.with
+/// [`with_mut`]: #method.with_mut
+#[derive(Clone, Copy)]
+pub struct HostContext<'lua> {
+    lua: &'lua Lua,
+}
+
+impl<'lua> HostContext<'lua> {
+    pub(crate) fn new(lua: &'lua Lua) -> HostContext<'lua> {
+        HostContext { lua }
+    }
+
+    /// Returns the `Lua` instance the callback was called from.
+    pub fn lua(&self) -> &'lua Lua {
+        self.lua
+    }
+
+    /// Calls `f` with a shared borrow of the host service of type `T`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MissingAppData` if no value of type `T` has been stored with `set_app_data`, and
+    /// `RecursiveCallback` if the value is currently borrowed mutably.
+    pub fn with<T, R, F>(&self, f: F) -> Result<R>
+    where
+        T: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        match self.lua.app_data_ref::<T>()? {
+            Some(data) => Ok(f(&data)),
+            None => Err(missing::<T>()),
+        }
+    }
+
+    /// Calls `f` with a mutable borrow of the host service of type `T`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MissingAppData` if no value of type `T` has been stored with `set_app_data`, and
+    /// `RecursiveCallback` if the value is currently borrowed.
+    pub fn with_mut<T, R, F>(&self, f: F) -> Result<R>
+    where
+        T: 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        match self.lua.app_data_mut::<T>()? {
+            Some(mut data) => Ok(f(&mut data)),
+            None => Err(missing::<T>()),
+        }
+    }
+}
+
+fn missing<T: 'static>() -> Error {
+    Error::MissingAppData {
+        type_name: any::type_name::<T>(),
+    }
+}
@@ -0,0 +1,121 @@
+use std::os::raw::c_void;
+use std::string::String as StdString;
+
+use serde_json::{Map, Number as JsonNumber, Value as JsonValue};
+
+use error::{Error, Result};
+use ffi;
+use lua::Lua;
+use table::Table;
+use util::{assert_stack, StackGuard};
+use value::Value;
+
+pub(crate) fn from_json<'lua>(lua: &'lua Lua, json: &JsonValue) -> Result<Value<'lua>> {
+    Ok(match *json {
+        JsonValue::Null => Value::Nil,
+        JsonValue::Bool(b) => Value::Boolean(b),
+        JsonValue::Number(ref n) => {
+            if let Some(i) = n.as_i64() {
+                Value::Integer(i)
+            } else {
+                // Always succeeds unless serde_json is built with arbitrary precision numbers, in
+                // which case this is lossy anyway.
+                Value::Number(n.as_f64().unwrap_or(0.0))
+            }
+        }
+        JsonValue::String(ref s) => Value::String(lua.create_string(s)?),
+        JsonValue::Array(ref array) => {
+            let table = lua.create_table()?;
+            for (i, v) in array.iter().enumerate() {
+                table.raw_set(i + 1, from_json(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+        JsonValue::Object(ref object) => {
+            let table = lua.create_table()?;
+            for (k, v) in object {
+                table.raw_set(k.as_str(), from_json(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+pub(crate) fn to_json<'lua>(value: Value<'lua>) -> Result<JsonValue> {
+    let mut visiting = Vec::new();
+    value_to_json(value, &mut visiting)
+}
+
+// `visiting` holds the tables between the root and the value being converted, so that cycles are
+// detected while a table referenced several times without a cycle is still accepted.
+fn value_to_json<'lua>(value: Value<'lua>, visiting: &mut Vec<*const c_void>) -> Result<JsonValue> {
+    Ok(match value {
+        Value::Nil => JsonValue::Null,
+        Value::Boolean(b) => JsonValue::Bool(b),
+        Value::Integer(i) => JsonValue::Number(i.into()),
+        Value::Number(n) => {
+            JsonValue::Number(JsonNumber::from_f64(n).ok_or_else(|| {
+                conversion_error("number", "JSON numbers cannot be NaN or infinite")
+            })?)
+        }
+        Value::String(s) => JsonValue::String(s.to_str()?.to_owned()),
+        Value::Table(table) => table_to_json(table, visiting)?,
+        value => {
+            return Err(conversion_error(
+                value.type_name(),
+                "value has no JSON representation",
+            ))
+        }
+    })
+}
+
+fn table_to_json<'lua>(table: Table<'lua>, visiting: &mut Vec<*const c_void>) -> Result<JsonValue> {
+    let pointer = unsafe {
+        let lua = table.0.lua;
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 1);
+
+        lua.push_ref(&table.0);
+        ffi::lua_topointer(lua.state, -1)
+    };
+    if visiting.contains(&pointer) {
+        return Err(conversion_error("table", "table contains a cycle"));
+    }
+    visiting.push(pointer);
+
+    let json = if table.raw_len() > 0 && table.is_sequence()? {
+        let mut array = Vec::new();
+        for v in table.sequence_values::<Value>() {
+            array.push(value_to_json(v?, visiting)?);
+        }
+        JsonValue::Array(array)
+    } else {
+        let mut object = Map::new();
+        for pair in table.pairs::<Value, Value>() {
+            let (k, v) = pair?;
+            let key = match k {
+                Value::String(s) => s.to_str()?.to_owned(),
+                Value::Integer(i) => i.to_string(),
+                k => {
+                    return Err(conversion_error(
+                        "table",
+                        &format!("{} keys cannot be converted to JSON", k.type_name()),
+                    ))
+                }
+            };
+            object.insert(key, value_to_json(v, visiting)?);
+        }
+        JsonValue::Object(object)
+    };
+
+    visiting.pop();
+    Ok(json)
+}
+
+fn conversion_error(from: &'static str, message: &str) -> Error {
+    Error::FromLuaConversionError {
+        from,
+        to: "JSON",
+        message: Some(StdString::from(message)),
+    }
+}
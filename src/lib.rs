@@ -24,6 +24,17 @@
 //! The [`UserData`] trait can be implemented by user-defined types to make them available to Lua.
 //! Methods and operators to be used from Lua can be added using the [`UserDataMethods`] API.
 //!
+//! # Derives
+//!
+//! With the `derive` feature enabled, `#[derive(FromLua, ToLua)]` can be used on enums. Unit
+//! variants are represented as strings holding the variant name, and struct variants as a table
+//! with a single key holding the variant name, whose value is a table of the variant fields.
+//! Variant names can be changed with `#[rlua(rename = "...")]`.
+//!
+//! The `#[rlua_methods]` attribute can be placed on an inherent impl block to implement
+//! [`UserData`] for the type, registering every `pub` method taking `&self` or `&mut self`. Types
+//! without methods can use `#[derive(UserData)]` instead.
+//!
 //! [Lua programming language]: https://www.lua.org/
 //! [`Lua`]: struct.Lua.html
 //! [executing]: struct.Lua.html#method.exec
@@ -43,18 +54,38 @@
 #[cfg_attr(test, macro_use)]
 extern crate failure;
 extern crate libc;
+extern crate num_traits;
+#[cfg(feature = "derive")]
+extern crate rlua_derive;
+#[cfg(feature = "json")]
+extern crate serde_json;
 
 mod error;
 mod ffi;
 #[macro_use]
 mod macros;
+mod allocator;
+mod analysis;
+mod api_builder;
+mod bytecode_cache;
 mod conversion;
+mod cycles;
 mod function;
+#[cfg(feature = "async")]
+mod future;
+mod hook;
+mod host;
+#[cfg(feature = "json")]
+mod json;
 mod lua;
 mod multi;
+mod repl;
 mod scope;
+mod serialize;
+mod source_map;
 mod string;
 mod table;
+mod template;
 mod thread;
 mod types;
 mod userdata;
@@ -64,16 +95,31 @@ mod value;
 #[cfg(test)]
 mod tests;
 
-pub use error::{Error, ExternalError, ExternalResult, Result};
+pub use allocator::LuaAllocator;
+pub use analysis::ChunkAnalysis;
+pub use api_builder::ApiBuilder;
+pub use error::{Diagnostic, Error, ErrorPosition, ExternalError, ExternalResult, Result};
 pub use function::Function;
+#[cfg(feature = "async")]
+pub use future::AsyncCall;
+pub use hook::{Debug, DebugSource, HookEvent, HookTriggers};
+pub use host::HostContext;
 pub use lua::Lua;
-pub use multi::Variadic;
+pub use multi::{TupleTable, Variadic};
+pub use repl::{ReplSession, ReplStatus};
 pub use scope::Scope;
+pub use source_map::SourceMap;
 pub use string::String;
-pub use table::{Table, TablePairs, TableSequence};
-pub use thread::{Thread, ThreadStatus};
+pub use table::{Table, TableIndexChain, TablePairs, TableSequence};
+pub use template::ScriptTemplate;
+pub use thread::{Resumed, Thread, ThreadIter, ThreadStatus};
 pub use types::{Integer, LightUserData, Number, RegistryKey};
-pub use userdata::{AnyUserData, MetaMethod, UserData, UserDataMethods};
-pub use value::{FromLua, FromLuaMulti, MultiValue, Nil, ToLua, ToLuaMulti, Value};
+pub use userdata::{
+    AnyUserData, MetaMethod, UserData, UserDataBorrowState, UserDataInfo, UserDataMethods,
+};
+pub use value::{FromLua, FromLuaMulti, HashableValue, MultiValue, Nil, ToLua, ToLuaMulti, Value};
+
+#[cfg(feature = "derive")]
+pub use rlua_derive::{rlua_methods, FromLua, ToLua, UserData};
 
 pub mod prelude;
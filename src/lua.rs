@@ -1,29 +1,56 @@
-use std::any::TypeId;
-use std::cell::{RefCell, UnsafeCell};
-use std::collections::HashMap;
-use std::ffi::CString;
+use std::any::{self, Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut, UnsafeCell};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::fs;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::marker::PhantomData;
 use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::rc::Rc;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::string::String as StdString;
 use std::sync::{Arc, Mutex};
-use std::{mem, ptr, str};
+#[cfg(feature = "async")]
+use std::task::Waker;
+use std::{mem, ptr, slice, str};
 
 use libc;
 
-use error::{Error, Result};
+use allocator::{allocator_trampoline, AllocatorState, LuaAllocator};
+use analysis::{scan_globals, ChunkAnalysis};
+use api_builder::ApiBuilder;
+use bytecode_cache::{self, SourceStamp};
+use cycles;
+use error::{Diagnostic, Error, ErrorPosition, Result};
 use ffi;
 use function::Function;
+#[cfg(feature = "async")]
+use future;
+use hook::{Debug, HookTriggers};
+use host::HostContext;
+#[cfg(feature = "json")]
+use json;
 use scope::Scope;
+use serialize;
+use source_map::{translate_positions, SourceMap};
 use string::String;
 use table::Table;
+use template::ScriptTemplate;
 use thread::Thread;
-use types::{Callback, Integer, LightUserData, LuaRef, Number, RegistryKey};
-use userdata::{AnyUserData, MetaMethod, UserData, UserDataMethods};
+use types::{Callback, HookCallback, Integer, LightUserData, LuaRef, Number, RegistryKey};
+use userdata::{
+    describe_userdata, AnyUserData, MetaMethod, UserData, UserDataInfo, UserDataMethods,
+};
 use util::{
-    assert_stack, callback_error, check_stack, gc_guard, get_userdata, get_wrapped_error,
-    init_error_metatables, main_state, pop_error, protect_lua, protect_lua_closure, push_string,
-    push_userdata, push_wrapped_error, safe_pcall, safe_xpcall, userdata_destructor, StackGuard,
+    assert_stack, callback_error, check_callback_stack, check_stack,
+    check_userdata_cell_unborrowed, dump_function, gc_guard, get_userdata, get_wrapped_error,
+    init_error_metatables, main_state, panic_message, pop_error, protect_lua, protect_lua_closure,
+    push_string, push_userdata, push_wrapped_error, safe_pcall, safe_xpcall,
+    set_error_metamethods, userdata_cell_destructor, userdata_destructor, StackGuard,
 };
-use value::{FromLua, FromLuaMulti, MultiValue, Nil, ToLua, ToLuaMulti, Value};
+use value::{FromLua, FromLuaMulti, HashableValue, MultiValue, Nil, ToLua, ToLuaMulti, Value};
 
 /// Top level Lua struct which holds the Lua state itself.
 pub struct Lua {
@@ -47,9 +74,16 @@ impl Drop for Lua {
                     "reference leak detected"
                 );
                 *(*extra).registry_unref_list.lock().unwrap() = None;
+                // `lua_close` calls the `__gc` metamethods of all remaining userdata, which must
+                // not use the extra data any more.
+                *(ffi::lua_getextraspace(self.state) as *mut *mut ExtraData) = ptr::null_mut();
                 Box::from_raw(extra);
 
+                // The allocator is still needed by `lua_close`, so it is only dropped afterwards.
+                let mut allocator = ptr::null_mut();
+                ffi::lua_getallocf(self.state, &mut allocator);
                 ffi::lua_close(self.state);
+                drop(Box::from_raw(allocator as *mut AllocatorState));
             }
         }
     }
@@ -58,7 +92,60 @@ impl Drop for Lua {
 impl Lua {
     /// Creates a new Lua state and loads standard library without the `debug` library.
     pub fn new() -> Lua {
-        unsafe { create_lua(false) }
+        unsafe { create_lua(false, None) }
+    }
+
+    /// Creates a new Lua state which uses the given allocator for all of its memory, and loads the
+    /// standard library without the `debug` library.
+    ///
+    /// This allows integrating the memory used by Lua into a host's own memory management, such as
+    /// an arena or an allocator which keeps track of memory usage.  The allocator is dropped when
+    /// the Lua state is closed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate libc;
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, LuaAllocator, Result};
+    /// # use std::sync::atomic::{AtomicUsize, Ordering};
+    /// # use std::sync::Arc;
+    /// # fn try_main() -> Result<()> {
+    /// struct Tracking(Arc<AtomicUsize>);
+    ///
+    /// unsafe impl LuaAllocator for Tracking {
+    ///     fn alloc(&mut self, size: usize) -> *mut u8 {
+    ///         self.0.fetch_add(size, Ordering::Relaxed);
+    ///         unsafe { libc::malloc(size) as *mut u8 }
+    ///     }
+    ///
+    ///     fn realloc(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    ///         self.0.fetch_add(new_size, Ordering::Relaxed);
+    ///         self.0.fetch_sub(old_size, Ordering::Relaxed);
+    ///         unsafe { libc::realloc(ptr as *mut libc::c_void, new_size) as *mut u8 }
+    ///     }
+    ///
+    ///     fn free(&mut self, ptr: *mut u8, size: usize) {
+    ///         self.0.fetch_sub(size, Ordering::Relaxed);
+    ///         unsafe { libc::free(ptr as *mut libc::c_void) }
+    ///     }
+    /// }
+    ///
+    /// let used = Arc::new(AtomicUsize::new(0));
+    /// let lua = Lua::new_with_allocator(Box::new(Tracking(used.clone())));
+    /// lua.exec::<()>("big = string.rep('x', 100000)", None)?;
+    /// assert!(used.load(Ordering::Relaxed) > 100000);
+    ///
+    /// drop(lua);
+    /// assert_eq!(used.load(Ordering::Relaxed), 0);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn new_with_allocator(allocator: Box<LuaAllocator>) -> Lua {
+        unsafe { create_lua(false, Some(allocator)) }
     }
 
     /// Creates a new Lua state and loads the standard library including the `debug` library.
@@ -66,7 +153,35 @@ impl Lua {
     /// The debug library is very unsound, loading it and using it breaks all the guarantees of
     /// rlua.
     pub unsafe fn new_with_debug() -> Lua {
-        create_lua(true)
+        create_lua(true, None)
+    }
+
+    /// Creates a new Lua state and loads the standard library with a restricted `debug` library,
+    /// which only contains the functions that cannot be used to break the guarantees of rlua.
+    ///
+    /// The `debug` table of this state contains:
+    ///
+    /// - `debug.traceback`, unchanged.
+    /// - `debug.getinfo`, which reports information about functions but never returns the
+    ///   functions themselves: the `f` option (the `func` field), and any unknown option, is
+    ///   ignored.  Stack levels are counted as usual, from the function calling `getinfo`.
+    ///
+    /// The rest of the library is left out.  `getlocal`, `setlocal`, `getupvalue`, `setupvalue`
+    /// and `upvaluejoin`, as well as the `f` option of `getinfo`, give access to the functions and
+    /// values internal to rlua, such as the ones on the stack of a Rust callback or a finalizer.
+    /// `getmetatable` and `setmetatable` bypass the protected metatables of userdata, `getregistry`
+    /// exposes the registry that rlua keeps its own state in, `getuservalue` and `setuservalue`
+    /// reach the user values of rlua's internal userdata, and `sethook` can replace the hook set
+    /// with [`set_hook`].
+    ///
+    /// [`set_hook`]: #method.set_hook
+    pub fn new_with_safe_debug() -> Lua {
+        unsafe {
+            let lua = create_lua(false, None);
+            ffi::luaL_requiref(lua.state, cstr!("debug"), open_safe_debug, 1);
+            ffi::lua_pop(lua.state, 1);
+            lua
+        }
     }
 
     /// Loads a chunk of Lua code and returns it as a function.
@@ -74,8 +189,43 @@ impl Lua {
     /// The source can be named by setting the `name` parameter. This is generally recommended as it
     /// results in better error traces.
     ///
-    /// Equivalent to Lua's `load` function.
+    /// Equivalent to Lua's `load` function, except that like the standalone `lua` interpreter, a
+    /// leading UTF-8 byte order mark is skipped, and a first line starting with `#` (such as
+    /// `#!/usr/bin/env lua`) is ignored.  The line is replaced by an empty line rather than
+    /// removed, so line numbers in error messages and tracebacks still match the original source.
+    ///
+    /// Source code starting like UTF-16 text, with a UTF-16 byte order mark or a NUL byte in one
+    /// of its first two bytes, is rejected with an `UnsupportedEncoding` error.
+    ///
+    /// Returns a `ChunkTooLarge` error without parsing the source if it is larger than the limit
+    /// set with [`set_max_chunk_size`].
+    ///
+    /// The parser is recursive, but it limits the nesting of syntactic constructs (such as table
+    /// constructors, parenthesized expressions or function definitions) to 200 levels, including
+    /// the levels of Rust and C calls the chunk is loaded from.  Sources nested more deeply are
+    /// rejected with a `SyntaxError` ("too many C levels") instead of overflowing the stack, so
+    /// loading untrusted source code cannot crash the process this way.
+    ///
+    /// [`set_max_chunk_size`]: #method.set_max_chunk_size
     pub fn load(&self, source: &str, name: Option<&str>) -> Result<Function> {
+        self.load_retaining(source, name, None)
+    }
+
+    // Loads a chunk like `load`, but retains `retained_source` rather than `source` when source
+    // retention is enabled, for callers which load a rewritten form of the given source (such as
+    // the "return" form of `eval`) without changing its lines.
+    pub(crate) fn load_retaining(
+        &self,
+        source: &str,
+        name: Option<&str>,
+        retained_source: Option<&str>,
+    ) -> Result<Function> {
+        self.check_chunk_size(source.len() as u64)?;
+
+        if let Some(encoding) = utf16_encoding(source.as_bytes()) {
+            return Err(Error::UnsupportedEncoding { encoding });
+        }
+        let source = skip_shebang(skip_bom(source));
         unsafe {
             let _sg = StackGuard::new(self.state);
             assert_stack(self.state, 1);
@@ -101,154 +251,495 @@ impl Lua {
                     ptr::null(),
                 )
             } {
-                ffi::LUA_OK => Ok(Function(self.pop_ref())),
+                ffi::LUA_OK => {
+                    let function = Function(self.pop_ref());
+                    if (*extra_data(self.state)).retain_sources && name.is_some() {
+                        self.retain_source(&function, retained_source.unwrap_or(source))?;
+                    }
+                    Ok(function)
+                }
                 err => Err(pop_error(self.state, err)),
             }
         }
     }
 
-    /// Execute a chunk of Lua code.
+    /// Loads a chunk of Lua code like [`load`], normalizing the chunk name so that the compiled
+    /// function dumps to the same bytes wherever the source file is located.
     ///
-    /// This is equivalent to simply loading the source with `load` and then calling the resulting
-    /// function with no arguments.
+    /// The chunk name is the only input to the compiler besides the source which ends up in the
+    /// binary chunk made by [`Function::dump`], as part of its debug information.  For names of
+    /// the form `@path`, which name a source file, the directories of the path are removed and
+    /// only `@file_name` is kept, so that building from different checkouts or temporary
+    /// directories does not change the bytecode.  Other names are used as they are.  Error
+    /// messages and tracebacks still show the file name.
     ///
-    /// Returns the values returned by the chunk.
-    pub fn exec<'lua, R: FromLuaMulti<'lua>>(
+    /// Together with a stripped dump, which does not contain the chunk name at all, this makes the
+    /// bytecode suitable for content-addressed caches and reproducible builds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let a = lua.load_reproducible("return 1", Some("@/tmp/build-1/init.lua"))?;
+    /// let b = lua.load_reproducible("return 1", Some("@/tmp/build-2/init.lua"))?;
+    /// assert_eq!(a.dump(false), b.dump(false));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`load`]: #method.load
+    /// [`Function::dump`]: struct.Function.html#method.dump
+    pub fn load_reproducible(&self, source: &str, name: Option<&str>) -> Result<Function> {
+        match name {
+            Some(name) if name.starts_with('@') => {
+                let file_name = name[1..].rsplit(|c| c == '/' || c == '\\').next();
+                self.load(source, Some(&format!("@{}", file_name.unwrap_or(""))))
+            }
+            _ => self.load(source, name),
+        }
+    }
+
+    /// Loads a chunk of Lua code like [`load`], using `env` instead of the global environment as
+    /// the chunk's `_ENV`.
+    ///
+    /// Global variables read and assigned by the chunk, and by every function it defines, are
+    /// looked up in `env`.  Tables made with [`create_environment`] are suitable for running
+    /// scripts which should not see each other's globals.
+    ///
+    /// [`load`]: #method.load
+    /// [`create_environment`]: #method.create_environment
+    pub fn load_with_env<'lua>(
         &'lua self,
         source: &str,
         name: Option<&str>,
-    ) -> Result<R> {
-        self.load(source, name)?.call(())
+        env: Table<'lua>,
+    ) -> Result<Function<'lua>> {
+        let function = self.load(source, name)?;
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 2);
+
+            self.push_ref(&function.0);
+            self.push_checked_ref(&env.0)?;
+            // The only upvalue of a freshly loaded chunk is its `_ENV`.
+            ffi::lua_setupvalue(self.state, -2, 1);
+        }
+        Ok(function)
     }
 
-    /// Evaluate the given expression or chunk inside this Lua state.
+    /// Loads a chunk of Lua code like [`load`], as a template which runs the chunk in a fresh
+    /// environment each time.
     ///
-    /// If `source` is an expression, returns the value it evaluates to. Otherwise, returns the
-    /// values returned by the chunk (if any).
-    pub fn eval<'lua, R: FromLuaMulti<'lua>>(
+    /// The environment of every run falls back to the globals for reading, and the global
+    /// variables assigned by a run are discarded afterwards.  See [`ScriptTemplate`] for details.
+    ///
+    /// [`load`]: #method.load
+    /// [`ScriptTemplate`]: struct.ScriptTemplate.html
+    pub fn load_template(&self, source: &str, name: Option<&str>) -> Result<ScriptTemplate> {
+        self.load_template_with_env(source, name, self.globals())
+    }
+
+    /// Loads a chunk of Lua code as a template like [`load_template`], with `base` instead of the
+    /// globals as the environment that every run falls back to.
+    ///
+    /// [`load_template`]: #method.load_template
+    pub fn load_template_with_env<'lua>(
         &'lua self,
         source: &str,
         name: Option<&str>,
-    ) -> Result<R> {
-        // First, try interpreting the lua as an expression by adding
-        // "return", then as a statement.  This is the same thing the
-        // actual lua repl does.
-        self.load(&format!("return {}", source), name)
-            .or_else(|_| self.load(source, name))?
-            .call(())
+        base: Table<'lua>,
+    ) -> Result<ScriptTemplate<'lua>> {
+        let chunk = self.load(source, name)?;
+        ScriptTemplate::new(self, chunk, base)
     }
 
-    /// Pass a `&str` slice to Lua, creating and returning an interned Lua string.
-    pub fn create_string(&self, s: &str) -> Result<String> {
+    /// Sets the source map of the chunks named `chunk_name`, replacing the previous one.
+    ///
+    /// From then on, the positions in the messages and tracebacks of errors raised by these chunks
+    /// are translated with the map when the errors are returned to Rust, so they refer to the
+    /// source the chunks were compiled from (see [`SourceMap`]).  This includes the positions of
+    /// syntax errors when loading chunks with that name, and the positions reported by
+    /// [`Error::position`] and in diagnostics.  Error messages seen by Lua code, such as the ones
+    /// caught by `pcall`, are not translated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, SourceMap};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// // Lua code compiled from a single line of `app.moon`, starting at its line 7.
+    /// let mut map = SourceMap::new("app.moon");
+    /// map.add_line(1, 7);
+    /// lua.set_source_map("app", map);
+    ///
+    /// let err = lua
+    ///     .exec::<()>("local x = nil\nreturn x.field", Some("app"))
+    ///     .unwrap_err();
+    /// assert_eq!(err.position().unwrap().chunk_name, "app.moon");
+    /// assert!(err.to_string().contains("app.moon:7: attempt to index"));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`SourceMap`]: struct.SourceMap.html
+    /// [`Error::position`]: enum.Error.html#method.position
+    pub fn set_source_map(&self, chunk_name: &str, map: SourceMap) {
         unsafe {
-            let _sg = StackGuard::new(self.state);
-            assert_stack(self.state, 4);
-            push_string(self.state, s)?;
-            Ok(String(self.pop_ref()))
+            (*extra_data(self.state))
+                .source_maps
+                .insert(chunk_name.to_owned(), map);
         }
     }
 
-    /// Creates and returns a new table.
-    pub fn create_table(&self) -> Result<Table> {
-        unsafe {
-            let _sg = StackGuard::new(self.state);
-            assert_stack(self.state, 3);
-            unsafe extern "C" fn new_table(state: *mut ffi::lua_State) -> c_int {
-                ffi::lua_newtable(state);
-                1
+    /// Removes and returns the source map of the chunks named `chunk_name`, if any.
+    pub fn remove_source_map(&self, chunk_name: &str) -> Option<SourceMap> {
+        unsafe { (*extra_data(self.state)).source_maps.remove(chunk_name) }
+    }
+
+    /// Loads the Lua source file at `path` like [`load`], caching its compiled bytecode in
+    /// `cache_dir` to speed up loading it again.
+    ///
+    /// The cache file records the modification time, size and a hash of the contents of the source
+    /// file it was compiled from, and is only used while all of them still match, so changing the
+    /// source invalidates it.  The source file is therefore read on every call, but only compiled
+    /// when the cache cannot be used.  A missing, outdated or corrupt cache file is replaced by
+    /// compiling the source again, and the cache directory is created if needed.  Since the cache
+    /// is only an optimization, failing to write it is not an error.  The chunk is named `@`
+    /// followed by `path`, so that error messages refer to the file name like the standalone `lua`
+    /// interpreter does.
+    ///
+    /// Lua does not verify bytecode, and loading maliciously crafted bytecode can crash the
+    /// process, so `cache_dir` must not be writable by anyone who is not trusted to run arbitrary
+    /// code.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `ExternalError` wrapping the I/O error if the source file cannot be read, and
+    /// the same errors as [`load`] otherwise.  The [`set_max_chunk_size`] limit applies to the
+    /// size of the source file, even when the cache is used.
+    ///
+    /// [`load`]: #method.load
+    /// [`set_max_chunk_size`]: #method.set_max_chunk_size
+    pub fn load_cached_file(&self, path: &Path, cache_dir: &Path) -> Result<Function> {
+        let metadata = fs::metadata(path).map_err(Error::external)?;
+        self.check_chunk_size(metadata.len())?;
+
+        let source = fs::read(path).map_err(Error::external)?;
+        let name = format!("@{}", path.display());
+        let stamp = SourceStamp::new(&metadata, &source);
+        let cache_path = bytecode_cache::cache_path(path, cache_dir);
+
+        if let Some(ref stamp) = stamp {
+            if let Some(bytecode) = bytecode_cache::read(&cache_path, stamp) {
+                if let Ok(function) = self.load_bytecode(&bytecode, &name) {
+                    return Ok(function);
+                }
             }
-            protect_lua(self.state, 0, new_table)?;
-            Ok(Table(self.pop_ref()))
         }
+
+        // Checked before decoding, since a UTF-16 byte order mark is not valid UTF-8.
+        if let Some(encoding) = utf16_encoding(&source) {
+            return Err(Error::UnsupportedEncoding { encoding });
+        }
+        let source = StdString::from_utf8(source).map_err(Error::external)?;
+        let function = self.load(&source, Some(&name))?;
+        if let Some(ref stamp) = stamp {
+            let bytecode = unsafe {
+                let _sg = StackGuard::new(self.state);
+                assert_stack(self.state, 1);
+
+                self.push_ref(&function.0);
+                dump_function(self.state, false)
+            };
+            let _ = bytecode_cache::write(&cache_path, stamp, &bytecode);
+        }
+        Ok(function)
     }
 
-    /// Creates a table and fills it with values from an iterator.
-    pub fn create_table_from<'lua, K, V, I>(&'lua self, cont: I) -> Result<Table<'lua>>
-    where
-        K: ToLua<'lua>,
-        V: ToLua<'lua>,
-        I: IntoIterator<Item = (K, V)>,
-    {
+    // Loads a binary chunk, such as one produced by `dump_function`.
+    fn load_bytecode(&self, bytecode: &[u8], name: &str) -> Result<Function> {
+        let name = CString::new(name).map_err(|e| Error::ToLuaConversionError {
+            from: "&str",
+            to: "string",
+            message: Some(e.to_string()),
+        })?;
         unsafe {
             let _sg = StackGuard::new(self.state);
-            // `Lua` instance assumes that on any callback, the Lua stack has at least LUA_MINSTACK
-            // slots available to avoid panics.
-            check_stack(self.state, 5 + ffi::LUA_MINSTACK)?;
+            assert_stack(self.state, 1);
 
-            unsafe extern "C" fn new_table(state: *mut ffi::lua_State) -> c_int {
-                ffi::lua_newtable(state);
-                1
+            match ffi::luaL_loadbufferx(
+                self.state,
+                bytecode.as_ptr() as *const c_char,
+                bytecode.len(),
+                name.as_ptr(),
+                cstr!("b"),
+            ) {
+                ffi::LUA_OK => Ok(Function(self.pop_ref())),
+                err => Err(pop_error(self.state, err)),
             }
-            protect_lua(self.state, 0, new_table)?;
+        }
+    }
 
-            for (k, v) in cont {
-                self.push_value(k.to_lua(self)?);
-                self.push_value(v.to_lua(self)?);
-                unsafe extern "C" fn raw_set(state: *mut ffi::lua_State) -> c_int {
-                    ffi::lua_rawset(state, -3);
-                    1
-                }
-                protect_lua(self.state, 3, raw_set)?;
-            }
-            Ok(Table(self.pop_ref()))
+    fn check_chunk_size(&self, size: u64) -> Result<()> {
+        let limit = unsafe { (*extra_data(self.state)).max_chunk_size };
+        if limit != 0 && size > limit as u64 {
+            Err(Error::ChunkTooLarge {
+                size: size as usize,
+                limit,
+            })
+        } else {
+            Ok(())
         }
     }
 
-    /// Creates a table from an iterator of values, using `1..` as the keys.
-    pub fn create_sequence_from<'lua, T, I>(&'lua self, cont: I) -> Result<Table<'lua>>
-    where
-        T: ToLua<'lua>,
-        I: IntoIterator<Item = T>,
-    {
-        self.create_table_from(cont.into_iter().enumerate().map(|(k, v)| (k + 1, v)))
+    /// Sets the maximum size in bytes of the source code accepted by [`load`], or removes the limit
+    /// if `bytes` is 0.
+    ///
+    /// Chunks larger than the limit are rejected with a `ChunkTooLarge` error before being parsed,
+    /// which is cheaper and gives a clearer error than letting the parser run out of memory when
+    /// loading untrusted code.  This applies to every method loading source code, such as
+    /// [`exec`] and [`eval`].  There is no limit by default.
+    ///
+    /// [`load`]: #method.load
+    /// [`exec`]: #method.exec
+    /// [`eval`]: #method.eval
+    pub fn set_max_chunk_size(&self, bytes: usize) {
+        unsafe {
+            (*extra_data(self.state)).max_chunk_size = bytes;
+        }
     }
 
-    /// Wraps a Rust function or closure, creating a callable Lua function handle to it.
+    /// Sets `package.path`, the search path used by `require` to find Lua modules.
     ///
-    /// The function's return value is always a `Result`: If the function returns `Err`, the error
-    /// is raised as a Lua error, which can be caught using `(x)pcall` or bubble up to the Rust code
-    /// that invoked the Lua code. This allows using the `?` operator to propagate errors through
-    /// intermediate Lua code.
+    /// The path is a list of templates separated by `;`, in which `?` is replaced by the module
+    /// name, such as `"./?.lua;./?/init.lua"`.  The `package` table is found through
+    /// `package.loaded`, so this works even if scripts cannot see the `package` global.
     ///
-    /// If the function returns `Ok`, the contained value will be converted to one or more Lua
-    /// values. For details on Rust-to-Lua conversions, refer to the [`ToLua`] and [`ToLuaMulti`]
-    /// traits.
+    /// # Errors
     ///
-    /// # Examples
+    /// Returns a `RuntimeError` if the `package` library is not loaded.
+    pub fn set_package_path(&self, path: &str) -> Result<()> {
+        self.package_library()?.raw_set("path", path)
+    }
+
+    /// Sets `package.cpath`, the search path used by `require` to find C modules.
     ///
-    /// Create a function which prints its argument:
+    /// See [`set_package_path`] for the format of the path.  Loading a C module runs arbitrary
+    /// native code, so the path should only name directories which are trusted.
+    ///
+    /// [`set_package_path`]: #method.set_package_path
+    pub fn set_package_cpath(&self, cpath: &str) -> Result<()> {
+        self.package_library()?.raw_set("cpath", cpath)
+    }
+
+    /// Adds a directory to the front of `package.path`, so that `require` looks for Lua modules
+    /// in it first.
+    ///
+    /// Both `dir/?.lua` and `dir/?/init.lua` are added, like the default entries for the current
+    /// directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ToLuaConversionError` if `dir` is not valid UTF-8 or contains a `;` or `?`, which
+    /// have a special meaning in `package.path`, and a `RuntimeError` if the `package` library is
+    /// not loaded.
+    ///
+    /// # Examples
     ///
     /// ```
     /// # extern crate rlua;
     /// # use rlua::{Lua, Result};
+    /// # use std::path::Path;
     /// # fn try_main() -> Result<()> {
     /// let lua = Lua::new();
+    /// lua.set_package_path("./?.lua")?;
+    /// lua.prepend_package_path(Path::new("scripts"))?;
     ///
-    /// let greet = lua.create_function(|_, name: String| {
-    ///     println!("Hello, {}!", name);
-    ///     Ok(())
-    /// });
-    /// # let _ = greet;    // used
+    /// let path: String = lua.eval("package.path", None)?;
+    /// assert!(path.starts_with("scripts"));
+    /// assert!(path.ends_with(";./?.lua"));
     /// # Ok(())
     /// # }
     /// # fn main() {
     /// #     try_main().unwrap();
     /// # }
     /// ```
+    pub fn prepend_package_path(&self, dir: &Path) -> Result<()> {
+        if dir.to_string_lossy().contains(|c| c == ';' || c == '?') {
+            return Err(Error::ToLuaConversionError {
+                from: "Path",
+                to: "string",
+                message: Some("path contains a `;` or `?`".to_owned()),
+            });
+        }
+
+        let entries = [dir.join("?.lua"), dir.join("?").join("init.lua")];
+        let mut path = StdString::new();
+        for entry in &entries {
+            path.push_str(entry.to_str().ok_or_else(|| Error::ToLuaConversionError {
+                from: "Path",
+                to: "string",
+                message: Some("path is not valid UTF-8".to_owned()),
+            })?);
+            path.push(';');
+        }
+
+        let package = self.package_library()?;
+        let current: Option<StdString> = package.raw_get("path")?;
+        path.push_str(&current.unwrap_or_default());
+        package.raw_set("path", path)
+    }
+
+    // Returns the table of the `package` library from `package.loaded`.
+    fn package_library(&self) -> Result<Table> {
+        let loaded: Option<Table> = self.named_registry_value("_LOADED")?;
+        let package = match loaded {
+            Some(loaded) => loaded.raw_get::<_, Option<Table>>("package")?,
+            None => None,
+        };
+        package.ok_or_else(|| Error::RuntimeError("the package library is not loaded".to_owned()))
+    }
+
+    /// Sets whether the source code of chunks loaded from now on is retained.
     ///
-    /// Use tuples to accept multiple arguments:
+    /// When enabled, the source code passed to [`load`] (and the methods built on it, such as
+    /// [`exec`] and [`eval`]) is kept in memory for each loaded chunk, so that
+    /// [`Function::source_line`] can show the code around a runtime error.  Only chunks loaded with
+    /// a name are retained.  The source of a chunk is kept while the function returned by `load`
+    /// is alive, and is discarded when that function is garbage collected, so loading another
+    /// chunk with the same name does not change the source of functions loaded before.  This is
+    /// disabled by default to avoid the memory overhead, and disabling it again discards all
+    /// retained source code.
+    ///
+    /// [`load`]: #method.load
+    /// [`exec`]: #method.exec
+    /// [`eval`]: #method.eval
+    /// [`Function::source_line`]: struct.Function.html#method.source_line
+    pub fn set_source_retention(&self, enabled: bool) {
+        unsafe {
+            let extra = extra_data(self.state);
+            (*extra).retain_sources = enabled;
+            if !enabled {
+                if let Some(id) = (*extra).retained_sources.take() {
+                    ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, id);
+                }
+            }
+        }
+    }
+
+    // Stores the source code of a loaded chunk in the retained sources table, which is keyed by
+    // the chunk's function and has weak keys, so that the source is discarded with the function.
+    unsafe fn retain_source(&self, function: &Function, source: &str) -> Result<()> {
+        let _sg = StackGuard::new(self.state);
+        assert_stack(self.state, 6);
+
+        let extra = extra_data(self.state);
+        match (*extra).retained_sources {
+            Some(id) => {
+                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+            }
+            None => {
+                protect_lua_closure(self.state, 0, 1, |state| {
+                    ffi::lua_newtable(state);
+                    ffi::lua_newtable(state);
+                    ffi::lua_pushstring(state, cstr!("__mode"));
+                    ffi::lua_pushstring(state, cstr!("k"));
+                    ffi::lua_rawset(state, -3);
+                    ffi::lua_setmetatable(state, -2);
+                })?;
+                ffi::lua_pushvalue(self.state, -1);
+                let id = gc_guard(self.state, || {
+                    ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
+                });
+                (*extra).retained_sources = Some(id);
+            }
+        }
+
+        self.push_ref(&function.0);
+        push_string(self.state, source)?;
+        protect_lua_closure(self.state, 3, 0, |state| {
+            ffi::lua_rawset(state, -3);
+        })
+    }
+
+    // Returns the retained source code of the chunk the given Lua function was loaded from.  This
+    // is either the function returned by `load` itself, or a function defined inside the chunk,
+    // whose `_ENV` upvalue (at `env_index`) is shared with the chunk's function.
+    pub(crate) unsafe fn retained_source(
+        &self,
+        function: &LuaRef,
+        env_index: Option<c_int>,
+    ) -> Option<StdString> {
+        let id = (*extra_data(self.state)).retained_sources?;
+        let _sg = StackGuard::new(self.state);
+        assert_stack(self.state, 5);
+
+        ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+        self.push_ref(function);
+        ffi::lua_pushvalue(self.state, -1);
+        if ffi::lua_rawget(self.state, -3) == ffi::LUA_TSTRING {
+            return Some(self.pop_source_string());
+        }
+        ffi::lua_pop(self.state, 1);
+
+        let env = ffi::lua_upvalueid(self.state, -1, env_index?);
+        ffi::lua_pushnil(self.state);
+        while ffi::lua_next(self.state, -3) != 0 {
+            // The main function of a chunk has `_ENV` as its only upvalue.
+            if ffi::lua_upvalueid(self.state, -2, 1) == env {
+                return Some(self.pop_source_string());
+            }
+            ffi::lua_pop(self.state, 1);
+        }
+        None
+    }
+
+    // Pops a retained source string off of the stack.
+    unsafe fn pop_source_string(&self) -> StdString {
+        let mut len = 0;
+        let data = ffi::lua_tolstring(self.state, -1, &mut len);
+        let source = slice::from_raw_parts(data as *const u8, len);
+        let source = StdString::from_utf8_lossy(source).into_owned();
+        ffi::lua_pop(self.state, 1);
+        source
+    }
+
+    /// Loads a chunk of Lua code like [`load`], and also reports which global variables it
+    /// references.
+    ///
+    /// This is intended for sandbox policy enforcement, such as refusing to run scripts which
+    /// mention `os` or `io`.  The chunk is not run.  The detection is a best-effort heuristic, see
+    /// [`ChunkAnalysis`] for its limitations.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`load`] if the chunk cannot be loaded, and a `RuntimeError` if
+    /// the bytecode format of the linked Lua library is not the one of the bundled Lua 5.3.
+    ///
+    /// # Examples
     ///
     /// ```
     /// # extern crate rlua;
     /// # use rlua::{Lua, Result};
     /// # fn try_main() -> Result<()> {
     /// let lua = Lua::new();
+    /// let analysis = lua.analyze("counter = (counter or 0) + 1; os.exit()", None)?;
     ///
-    /// let print_person = lua.create_function(|_, (name, age): (String, u8)| {
-    ///     println!("{} is {} years old!", name, age);
-    ///     Ok(())
-    /// });
-    /// # let _ = print_person;    // used
+    /// assert!(analysis.references("os"));
+    /// assert!(analysis.globals_read.contains("counter"));
+    /// assert!(analysis.globals_written.contains("counter"));
     /// # Ok(())
     /// # }
     /// # fn main() {
@@ -256,77 +747,1654 @@ impl Lua {
     /// # }
     /// ```
     ///
-    /// [`ToLua`]: trait.ToLua.html
-    /// [`ToLuaMulti`]: trait.ToLuaMulti.html
-    pub fn create_function<'lua, 'callback, A, R, F>(&'lua self, func: F) -> Result<Function<'lua>>
+    /// [`load`]: #method.load
+    /// [`ChunkAnalysis`]: struct.ChunkAnalysis.html
+    pub fn analyze<'lua>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<ChunkAnalysis<'lua>> {
+        let function = self.load(source, name)?;
+        let chunk = unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 1);
+
+            self.push_ref(&function.0);
+            dump_function(self.state, false)
+        };
+        let (globals_read, globals_written) = match scan_globals(&chunk) {
+            Some(globals) => globals,
+            None => {
+                return Err(Error::RuntimeError(
+                    "cannot analyze the bytecode format of the linked Lua library".to_owned(),
+                ))
+            }
+        };
+
+        Ok(ChunkAnalysis {
+            function,
+            globals_read,
+            globals_written,
+        })
+    }
+
+    /// Execute a chunk of Lua code.
+    ///
+    /// This is equivalent to simply loading the source with `load` and then calling the resulting
+    /// function with no arguments.
+    ///
+    /// Returns the values returned by the chunk.
+    pub fn exec<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<R> {
+        self.load(source, name)?.call(())
+    }
+
+    /// Executes a chunk of Lua code like [`exec`], also returning the diagnostics reported with
+    /// [`warn`] while it runs.
+    ///
+    /// This is intended for validating scripts: host functions called by the script can flag
+    /// non-fatal problems, such as the use of deprecated APIs, without interrupting it.  Lua 5.3
+    /// has no `warn` function of its own, but one can be provided as shown below.  Diagnostics
+    /// reported by a nested call to `exec_with_diagnostics` are only returned by that call.
+    ///
+    /// The diagnostics are returned alongside the result of the chunk, so the diagnostics reported
+    /// before the chunk failed are still available when it returns an error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let warn = lua.create_function(|lua, message: String| {
+    ///     lua.warn(&message);
+    ///     Ok(())
+    /// })?;
+    /// lua.globals().set("warn", warn)?;
+    ///
+    /// let (result, diagnostics) = lua.exec_with_diagnostics::<i64>(
+    ///     r#"
+    ///         warn("computing the answer")
+    ///         return 42
+    ///     "#,
+    ///     Some("script"),
+    /// );
+    /// assert_eq!(result?, 42);
+    /// assert_eq!(diagnostics[0].message, "computing the answer");
+    /// assert_eq!(diagnostics[0].position.as_ref().unwrap().line, 2);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`exec`]: #method.exec
+    /// [`warn`]: #method.warn
+    pub fn exec_with_diagnostics<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> (Result<R>, Vec<Diagnostic>) {
+        let previous = unsafe { (*extra_data(self.state)).diagnostics.replace(Vec::new()) };
+        let result = self.exec(source, name);
+        let diagnostics =
+            unsafe { mem::replace(&mut (*extra_data(self.state)).diagnostics, previous) };
+        (result, diagnostics.unwrap_or_default())
+    }
+
+    /// Executes a chunk of Lua code like [`exec`], returning a table of the global variables it
+    /// defined.
+    ///
+    /// This is intended for declarative configuration files, which assign their settings to
+    /// globals such as `width = 100` instead of returning a table.  The globals are compared
+    /// before and after the chunk runs, and every global which was added or assigned a different
+    /// value (compared like `rawequal`) is copied to the returned table.  Globals which were
+    /// removed are not reported, and neither are changes made inside tables which were already
+    /// global.  The values returned by the chunk are ignored.
+    ///
+    /// Only the globals table itself is compared, without invoking its metamethods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config = lua.exec_capturing_globals(
+    ///     r#"
+    ///         width = 100
+    ///         height = width * 2
+    ///         local margin = 10
+    ///     "#,
+    ///     Some("config"),
+    /// )?;
+    ///
+    /// assert_eq!(config.get::<_, i64>("width")?, 100);
+    /// assert_eq!(config.get::<_, i64>("height")?, 200);
+    /// assert!(!config.contains_key("margin")?);
+    /// assert!(!config.contains_key("print")?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`exec`]: #method.exec
+    pub fn exec_capturing_globals(&self, source: &str, name: Option<&str>) -> Result<Table> {
+        let globals = self.globals();
+        let mut before = HashMap::new();
+        for pair in globals.clone().pairs::<HashableValue, Value>() {
+            let (key, value) = pair?;
+            before.insert(key, HashableValue::new(value));
+        }
+
+        self.exec::<()>(source, name)?;
+
+        let defined = self.create_table()?;
+        for pair in globals.pairs::<HashableValue, Value>() {
+            let (key, value) = pair?;
+            let value = HashableValue::new(value);
+            if before.get(&key) != Some(&value) {
+                defined.raw_set(key.into_value(), value.into_value())?;
+            }
+        }
+        Ok(defined)
+    }
+
+    /// Reports a non-fatal diagnostic from the running code.
+    ///
+    /// The diagnostic records the position of the innermost Lua function on the stack, which is
+    /// the caller when this is called from a Rust callback.  It is collected by the innermost
+    /// running [`exec_with_diagnostics`], and ignored when there is none.
+    ///
+    /// [`exec_with_diagnostics`]: #method.exec_with_diagnostics
+    pub fn warn(&self, message: &str) {
+        unsafe {
+            if (*extra_data(self.state)).diagnostics.is_none() {
+                return;
+            }
+            let diagnostic = Diagnostic {
+                message: message.to_owned(),
+                position: self.current_position(),
+            };
+            if let Some(ref mut diagnostics) = (*extra_data(self.state)).diagnostics {
+                diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    /// Loads a chunk of Lua code and wraps it in a new thread (coroutine) without running it.
+    ///
+    /// Top-level code run with [`exec`] cannot call `coroutine.yield`, since it is not running
+    /// inside of a coroutine.  The chunk loaded by this method starts running on the first call to
+    /// [`Thread::resume`], and may yield back to the host at any point, which is convenient for
+    /// scripts structured as long-running tasks.  Arguments to the first `resume` are available
+    /// in the chunk as `...`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, ThreadStatus};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let task = lua.exec_in_coroutine(r#"
+    ///     local step = ...
+    ///     for i = 1, 3 do
+    ///         step = coroutine.yield(step * 2)
+    ///     end
+    ///     return "done"
+    /// "#, Some("task"))?;
+    ///
+    /// assert_eq!(task.resume::<_, i32>(1)?, 2);
+    /// assert_eq!(task.resume::<_, i32>(5)?, 10);
+    /// assert_eq!(task.resume::<_, i32>(7)?, 14);
+    /// assert_eq!(task.resume::<_, String>(0)?, "done");
+    /// assert_eq!(task.status(), ThreadStatus::Unresumable);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`exec`]: #method.exec
+    /// [`Thread::resume`]: struct.Thread.html#method.resume
+    pub fn exec_in_coroutine<'lua>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<Thread<'lua>> {
+        self.create_thread(self.load(source, name)?)
+    }
+
+    /// Evaluate the given expression or chunk inside this Lua state.
+    ///
+    /// If `source` is an expression, returns the value it evaluates to. Otherwise, returns the
+    /// values returned by the chunk (if any).
+    pub fn eval<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<R> {
+        // First, try interpreting the lua as an expression by adding
+        // "return", then as a statement.  This is the same thing the
+        // actual lua repl does.  Only syntax errors fall back to the statement form, other
+        // errors (such as `ChunkTooLarge`) are returned as they are.  The encoding is checked
+        // first, since the "return" prefix would hide a byte order mark from `load`.
+        if let Some(encoding) = utf16_encoding(source.as_bytes()) {
+            return Err(Error::UnsupportedEncoding { encoding });
+        }
+        let source = skip_bom(source);
+        let expression = format!("return {}", source);
+        let function = match self.load_retaining(&expression, name, Some(source)) {
+            Err(Error::SyntaxError { .. }) => self.load(source, name)?,
+            loaded => loaded?,
+        };
+        function.call(())
+    }
+
+    /// Pass a `&str` slice to Lua, creating and returning an interned Lua string.
+    ///
+    /// If the string cache is enabled with [`set_string_cache_capacity`], repeated calls with the
+    /// same string are served from the cache.
+    ///
+    /// [`set_string_cache_capacity`]: #method.set_string_cache_capacity
+    pub fn create_string(&self, s: &str) -> Result<String> {
+        unsafe {
+            let extra = extra_data(self.state);
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 4);
+
+            if let Some(&id) = (*extra).string_cache.get(s) {
+                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+                return Ok(String(self.pop_ref()));
+            }
+
+            push_string(self.state, s)?;
+
+            if (*extra).string_cache.len() < (*extra).string_cache_capacity {
+                ffi::lua_pushvalue(self.state, -1);
+                let id = gc_guard(self.state, || {
+                    ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
+                });
+                (*extra).string_cache.insert(s.to_owned(), id);
+            }
+
+            Ok(String(self.pop_ref()))
+        }
+    }
+
+    // Creates a Lua string from arbitrary bytes, bypassing the string cache.
+    pub(crate) fn create_byte_string(&self, bytes: &[u8]) -> Result<String> {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 4);
+            push_string(self.state, bytes)?;
+            Ok(String(self.pop_ref()))
+        }
+    }
+
+    /// Sets the maximum number of distinct strings kept in the `create_string` cache.
+    ///
+    /// Lua already interns short strings, but every call to `create_string` still has to go
+    /// through a protected call to create the string.  When the cache is enabled, strings passed to
+    /// `create_string` are pinned in the registry the first time they are seen, and later calls
+    /// with the same string simply fetch the pinned value.  This trades memory for speed when the
+    /// same strings (such as table keys) are created over and over again.
+    ///
+    /// Once the cache holds `capacity` strings, new strings are no longer added to it, so programs
+    /// creating an unbounded number of distinct strings cannot grow it without limit.  Setting a
+    /// capacity of 0 (the default) disables the cache.  Lowering the capacity below the number of
+    /// cached strings clears the cache, otherwise the cached strings are kept.
+    ///
+    /// The cache is shared by all `Lua` instances which share the same main state.
+    pub fn set_string_cache_capacity(&self, capacity: usize) {
+        unsafe {
+            let extra = extra_data(self.state);
+            if capacity < (*extra).string_cache.len() {
+                for (_, id) in (*extra).string_cache.drain() {
+                    ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, id);
+                }
+            }
+            (*extra).string_cache_capacity = capacity;
+        }
+    }
+
+    /// Creates and returns a new table.
+    pub fn create_table(&self) -> Result<Table> {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 3);
+            unsafe extern "C" fn new_table(state: *mut ffi::lua_State) -> c_int {
+                ffi::lua_newtable(state);
+                1
+            }
+            protect_lua(self.state, 0, new_table)?;
+            Ok(Table(self.pop_ref()))
+        }
+    }
+
+    /// Creates a new table with `metatable` as its metatable.
+    ///
+    /// This is the same as calling [`create_table`] and then [`Table::set_metatable`], in a single
+    /// step, which is convenient when creating many instances sharing the same metatable, such as
+    /// the objects of a class.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `MismatchedLuaState` error if `metatable` was created by a `Lua` instance which
+    /// does not share the main state of this one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let class: Table = lua.eval(r#"
+    ///     local Point = {}
+    ///     Point.__index = Point
+    ///     function Point:length2() return self.x * self.x + self.y * self.y end
+    ///     return Point
+    /// "#, None)?;
+    ///
+    /// let point = lua.create_table_with_metatable(&class)?;
+    /// point.set("x", 3)?;
+    /// point.set("y", 4)?;
+    /// lua.globals().set("point", point)?;
+    /// assert_eq!(lua.eval::<i64>("point:length2()", None)?, 25);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_table`]: #method.create_table
+    /// [`Table::set_metatable`]: struct.Table.html#method.set_metatable
+    pub fn create_table_with_metatable<'lua>(
+        &'lua self,
+        metatable: &Table<'lua>,
+    ) -> Result<Table<'lua>> {
+        unsafe extern "C" fn new_table_with_metatable(state: *mut ffi::lua_State) -> c_int {
+            ffi::lua_newtable(state);
+            ffi::lua_insert(state, -2);
+            ffi::lua_setmetatable(state, -2);
+            1
+        }
+
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 3);
+            self.push_checked_ref(&metatable.0)?;
+            protect_lua(self.state, 1, new_table_with_metatable)?;
+            Ok(Table(self.pop_ref()))
+        }
+    }
+
+    /// Creates a table and fills it with values from an iterator.
+    ///
+    /// If converting any of the keys or values fails, the error is returned and the partially
+    /// filled table is discarded.
+    pub fn create_table_from<'lua, K, V, I>(&'lua self, cont: I) -> Result<Table<'lua>>
+    where
+        K: ToLua<'lua>,
+        V: ToLua<'lua>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            // `Lua` instance assumes that on any callback, the Lua stack has at least LUA_MINSTACK
+            // slots available to avoid panics.
+            check_stack(self.state, 5 + ffi::LUA_MINSTACK)?;
+
+            unsafe extern "C" fn new_table(state: *mut ffi::lua_State) -> c_int {
+                ffi::lua_newtable(state);
+                1
+            }
+            protect_lua(self.state, 0, new_table)?;
+
+            // On error, the stack guard pops the partially filled table, and every converted value
+            // has either already been pushed or is dropped, so no references are leaked.
+            for (k, v) in cont {
+                let k = k.to_lua(self)?;
+                let v = v.to_lua(self)?;
+                self.push_value(k)?;
+                self.push_value(v)?;
+                unsafe extern "C" fn raw_set(state: *mut ffi::lua_State) -> c_int {
+                    ffi::lua_rawset(state, -3);
+                    1
+                }
+                protect_lua(self.state, 3, raw_set)?;
+            }
+            Ok(Table(self.pop_ref()))
+        }
+    }
+
+    /// Creates a table from an iterator of values, using `1..` as the keys.
+    pub fn create_sequence_from<'lua, T, I>(&'lua self, cont: I) -> Result<Table<'lua>>
+    where
+        T: ToLua<'lua>,
+        I: IntoIterator<Item = T>,
+    {
+        self.create_table_from(cont.into_iter().enumerate().map(|(k, v)| (k + 1, v)))
+    }
+
+    /// Wraps a Rust function or closure, creating a callable Lua function handle to it.
+    ///
+    /// The function's return value is always a `Result`: If the function returns `Err`, the error
+    /// is raised as a Lua error, which can be caught using `(x)pcall` or bubble up to the Rust code
+    /// that invoked the Lua code. This allows using the `?` operator to propagate errors through
+    /// intermediate Lua code.
+    ///
+    /// If the function returns `Ok`, the contained value will be converted to one or more Lua
+    /// values. For details on Rust-to-Lua conversions, refer to the [`ToLua`] and [`ToLuaMulti`]
+    /// traits.
+    ///
+    /// # Examples
+    ///
+    /// Create a function which prints its argument:
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let greet = lua.create_function(|_, name: String| {
+    ///     println!("Hello, {}!", name);
+    ///     Ok(())
+    /// });
+    /// # let _ = greet;    // used
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// Use tuples to accept multiple arguments:
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let print_person = lua.create_function(|_, (name, age): (String, u8)| {
+    ///     println!("{} is {} years old!", name, age);
+    ///     Ok(())
+    /// });
+    /// # let _ = print_person;    // used
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ToLua`]: trait.ToLua.html
+    /// [`ToLuaMulti`]: trait.ToLuaMulti.html
+    pub fn create_function<'lua, 'callback, A, R, F>(&'lua self, func: F) -> Result<Function<'lua>>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + Fn(&'callback Lua, A) -> Result<R>,
+    {
+        self.create_callback(Box::new(move |lua, args| {
+            func(lua, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
+        }))
+    }
+
+    /// Wraps a Rust function or closure like [`create_function`], giving it a name to report in
+    /// argument errors.
+    ///
+    /// When an argument passed from Lua cannot be converted to the type expected by the function,
+    /// the function raises an [`Error::BadArgument`] with `name` and the position of the argument,
+    /// which reads like the errors of the Lua standard library: `bad argument #1 to 'name' (...)`.
+    /// The name is usually the one the function is registered under.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let add = lua.create_named_function("add", |_, (a, b): (i64, i64)| Ok(a + b))?;
+    /// lua.globals().set("add", add)?;
+    ///
+    /// let message = lua.eval::<String>("tostring(select(2, pcall(add, 1, {})))", None)?;
+    /// assert!(message.starts_with("bad argument #2 to 'add'"));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function`]: #method.create_function
+    /// [`Error::BadArgument`]: enum.Error.html#variant.BadArgument
+    pub fn create_named_function<'lua, 'callback, A, R, F>(
+        &'lua self,
+        name: &str,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + Fn(&'callback Lua, A) -> Result<R>,
+    {
+        let name = name.to_owned();
+        self.create_callback(Box::new(move |lua, args| {
+            let args = A::from_lua_args(args, 1, lua).map_err(|err| match err {
+                Error::BadArgument {
+                    position, cause, ..
+                } => Error::BadArgument {
+                    function: Some(name.clone()),
+                    position,
+                    cause,
+                },
+                err => err,
+            })?;
+            func(lua, args)?.to_lua_multi(lua)
+        }))
+    }
+
+    /// Wraps a Rust function or closure returning a future, creating a callable Lua function handle
+    /// to it.
+    ///
+    /// When called from Lua, the function calls `func` and polls the returned future, yielding the
+    /// running coroutine while the future is pending and resuming it with the results of the
+    /// future once it is ready.  This means that the function can only be called from Lua code
+    /// running inside a coroutine driven by [`Function::call_async`], which supplies the waker of
+    /// the task polling it.  Calling it anywhere else, including from a plain `Function::call`,
+    /// from a coroutine resumed with `Thread::resume`, or from a coroutine which the script itself
+    /// created inside the `call_async` coroutine, raises an error.  Like any yield, the
+    /// pending future cannot be waited for across a Rust callback, such as a Rust function which
+    /// called back into Lua.
+    ///
+    /// A panic in `func` or while polling its future is caught inside the function, before it
+    /// reaches the Lua coroutine being resumed, and raised there as an `Error::AsyncPanic` instead
+    /// (after calling the hook set with [`set_panic_hook`]).  Like any other error, the script can
+    /// catch it with `pcall`, and otherwise it ends the coroutine and is returned by the
+    /// `call_async` future, so one panicking handler does not take down the other tasks using the
+    /// same `Lua`.  The panicked future is not polled again, and is only dropped once the
+    /// coroutine is garbage collected.  Panics in other Rust callbacks called from the coroutine
+    /// are still resumed on the Rust side, as with `Function::call`.
+    ///
+    /// This is only available with the `async` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::future::Future;
+    /// # use std::pin::Pin;
+    /// # use std::sync::Arc;
+    /// # use std::task::{Context, Poll, Wake};
+    /// # struct NoopWaker;
+    /// # impl Wake for NoopWaker {
+    /// #     fn wake(self: Arc<Self>) {}
+    /// # }
+    /// # fn block_on<F: Future>(mut future: F) -> F::Output {
+    /// #     let waker = Arc::new(NoopWaker).into();
+    /// #     let mut cx = Context::from_waker(&waker);
+    /// #     let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    /// #     loop {
+    /// #         if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+    /// #             return output;
+    /// #         }
+    /// #     }
+    /// # }
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let fetch = lua.create_async_function(|_, key: String| {
+    ///     async_lookup(key)
+    /// })?;
+    /// lua.globals().set("fetch", fetch)?;
+    ///
+    /// let handler = lua.load("return fetch('answer') + 1", None)?;
+    /// assert_eq!(block_on(handler.call_async::<_, i64>(()))?, 43);
+    /// # Ok(())
+    /// # }
+    /// # fn async_lookup(_key: String) -> impl Future<Output = Result<i64>> {
+    /// #     std::future::ready(Ok(42))
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Function::call_async`]: struct.Function.html#method.call_async
+    /// [`set_panic_hook`]: #method.set_panic_hook
+    #[cfg(feature = "async")]
+    pub fn create_async_function<'lua, 'callback, A, R, F, FR>(
+        &'lua self,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + Fn(&'callback Lua, A) -> FR,
+        FR: 'static + Send + Future<Output = Result<R>>,
+    {
+        future::create_async_function(self, func)
+    }
+
+    /// Wraps a Rust function which takes no arguments and returns nothing, creating a callable Lua
+    /// function handle to it.
+    ///
+    /// This is a faster version of [`create_function`] for callbacks such as event handlers which
+    /// are called very frequently: any arguments passed from Lua are discarded without being
+    /// converted, and the function always returns no values to Lua, so there is no conversion
+    /// overhead at all besides the call itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::sync::atomic::{AtomicUsize, Ordering};
+    /// # use std::sync::Arc;
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let ticks = Arc::new(AtomicUsize::new(0));
+    ///
+    /// let counter = ticks.clone();
+    /// let on_tick = lua.create_simple_function(move |_| {
+    ///     counter.fetch_add(1, Ordering::Relaxed);
+    ///     Ok(())
+    /// })?;
+    /// lua.globals().set("on_tick", on_tick)?;
+    ///
+    /// lua.exec::<()>("for i = 1, 100 do on_tick(i) end", None)?;
+    /// assert_eq!(ticks.load(Ordering::Relaxed), 100);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function`]: #method.create_function
+    pub fn create_simple_function<F>(&self, func: F) -> Result<Function>
+    where
+        F: 'static + Send + Fn(&Lua) -> Result<()>,
+    {
+        unsafe extern "C" fn call_simple_callback(state: *mut ffi::lua_State) -> c_int {
+            callback_error(state, || {
+                ffi::lua_settop(state, 0);
+
+                let lua = Lua {
+                    state: state,
+                    main_state: main_state(state),
+                    ephemeral: true,
+                    _phantom: PhantomData,
+                };
+
+                let func = get_userdata::<Callback>(state, ffi::lua_upvalueindex(1));
+                (*func)(&lua, MultiValue::new())?;
+                check_callback_stack(state)?;
+                Ok(0)
+            })
+        }
+
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 4);
+
+            self.push_callback(
+                Box::new(move |lua, _| {
+                    func(lua)?;
+                    Ok(MultiValue::new())
+                }),
+                call_simple_callback,
+            )?;
+            Ok(Function(self.pop_ref()))
+        }
+    }
+
+    /// Wraps a Rust mutable closure, creating a callable Lua function handle to it.
+    ///
+    /// This is a version of [`create_function`] that accepts a FnMut argument.  Refer to
+    /// [`create_function`] for more information about the implementation.
+    ///
+    /// [`create_function`]: #method.create_function
+    pub fn create_function_mut<'lua, 'callback, A, R, F>(
+        &'lua self,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + FnMut(&'callback Lua, A) -> Result<R>,
+    {
+        let func = RefCell::new(func);
+        self.create_function(move |lua, args| {
+            (&mut *func
+                .try_borrow_mut()
+                .map_err(|_| Error::RecursiveMutCallback)?)(lua, args)
+        })
+    }
+
+    /// Wraps a Rust function which needs access to host services, creating a callable Lua
+    /// function handle to it.
+    ///
+    /// This is like [`create_function`], except that the callback receives a [`HostContext`]
+    /// instead of a `&Lua`.  Host services are stored as application data with [`set_app_data`],
+    /// and the context borrows them only for the duration of a closure, which makes it hard to
+    /// hold a borrow while calling back into Lua.  Reentrant access to a service which is already
+    /// borrowed, as well as access to a service which was never stored, result in clean errors
+    /// (`RecursiveCallback` and `MissingAppData`) that are raised as Lua errors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{HostContext, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// struct Inventory {
+    ///     items: Vec<String>,
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// lua.set_app_data(Inventory { items: Vec::new() })?;
+    ///
+    /// let add_item = lua.create_host_function(|host: HostContext, item: String| {
+    ///     host.with_mut(|inventory: &mut Inventory| {
+    ///         inventory.items.push(item);
+    ///         inventory.items.len()
+    ///     })
+    /// })?;
+    /// lua.globals().set("add_item", add_item)?;
+    ///
+    /// assert_eq!(lua.eval::<usize>("add_item('sword'); return add_item('shield')", None)?, 2);
+    /// assert_eq!(lua.app_data_ref::<Inventory>()?.unwrap().items, vec!["sword", "shield"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function`]: #method.create_function
+    /// [`HostContext`]: struct.HostContext.html
+    /// [`set_app_data`]: #method.set_app_data
+    pub fn create_host_function<'lua, 'callback, A, R, F>(
+        &'lua self,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + Fn(HostContext<'callback>, A) -> Result<R>,
+    {
+        self.create_function(move |lua, args| func(HostContext::new(lua), args))
+    }
+
+    /// Wraps a Lua function into a new thread (or coroutine).
+    ///
+    /// Equivalent to `coroutine.create`.
+    pub fn create_thread<'lua>(&'lua self, func: Function<'lua>) -> Result<Thread<'lua>> {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 2);
+
+            let thread_state =
+                protect_lua_closure(self.state, 0, 1, |state| ffi::lua_newthread(state))?;
+            self.push_checked_ref(&func.0)?;
+            ffi::lua_xmove(self.state, thread_state, 1);
+
+            Ok(Thread(self.pop_ref()))
+        }
+    }
+
+    /// Create a Lua userdata object from a custom userdata type.
+    pub fn create_userdata<T>(&self, data: T) -> Result<AnyUserData>
     where
-        A: FromLuaMulti<'callback>,
-        R: ToLuaMulti<'callback>,
-        F: 'static + Send + Fn(&'callback Lua, A) -> Result<R>,
+        T: Send + UserData,
     {
-        self.create_callback(Box::new(move |lua, args| {
-            func(lua, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
-        }))
+        unsafe { self.make_userdata(data) }
+    }
+
+    /// Stores a value of type `T` as application data, returning the previously stored value of
+    /// the same type, if any.
+    ///
+    /// Application data is arbitrary Rust state owned by the Lua state, at most one value per type.
+    /// It is shared by all `Lua` instances which share the same main state, so it is the easiest
+    /// way to give callbacks (which only receive a shared `&Lua`) access to mutable host state
+    /// through [`app_data_ref`] and [`app_data_mut`].  It is dropped when the Lua state is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `RecursiveCallback` if a value of type `T` is stored and is currently borrowed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// struct Score(u32);
+    ///
+    /// let lua = Lua::new();
+    /// lua.set_app_data(Score(0))?;
+    ///
+    /// let add_points = lua.create_function(|lua, points: u32| {
+    ///     let mut score = lua.app_data_mut::<Score>()?.expect("score is set");
+    ///     score.0 += points;
+    ///     Ok(score.0)
+    /// })?;
+    /// lua.globals().set("add_points", add_points)?;
+    ///
+    /// lua.exec::<()>("add_points(10); add_points(5)", None)?;
+    /// assert_eq!(lua.app_data_ref::<Score>()?.unwrap().0, 15);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`app_data_ref`]: #method.app_data_ref
+    /// [`app_data_mut`]: #method.app_data_mut
+    pub fn set_app_data<T: 'static + Send>(&self, data: T) -> Result<Option<T>> {
+        unsafe {
+            let app_data = &mut (*extra_data(self.state)).app_data;
+            if let Some(cell) = app_data.get(&TypeId::of::<T>()) {
+                if cell.try_borrow_mut().is_err() {
+                    return Err(app_data_borrow_error::<T>(true));
+                }
+            }
+
+            let cell = Box::new(RefCell::new(Box::new(data) as Box<Any + Send>));
+            Ok(app_data
+                .insert(TypeId::of::<T>(), cell)
+                .map(|cell| take_app_data(*cell)))
+        }
+    }
+
+    /// Borrows the application data of type `T`, if any has been stored with [`set_app_data`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `RecursiveCallback` if the value is currently borrowed mutably.
+    ///
+    /// [`set_app_data`]: #method.set_app_data
+    pub fn app_data_ref<T: 'static>(&self) -> Result<Option<Ref<T>>> {
+        unsafe {
+            match (*extra_data(self.state)).app_data.get(&TypeId::of::<T>()) {
+                Some(cell) => {
+                    let data = cell
+                        .try_borrow()
+                        .map_err(|_| app_data_borrow_error::<T>(false))?;
+                    Ok(Some(Ref::map(data, |data| {
+                        data.downcast_ref::<T>()
+                            .unwrap_or_else(|| rlua_panic!("app data type mismatch"))
+                    })))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Mutably borrows the application data of type `T`, if any has been stored with
+    /// [`set_app_data`].
+    ///
+    /// The borrow is checked at runtime, so a callback which holds this borrow while calling back
+    /// into Lua code that in turn calls another callback borrowing the same data receives a clean
+    /// error instead of causing a panic.
+    ///
+    /// # Errors
+    ///
+    /// Returns `RecursiveCallback` if the value is currently borrowed.
+    ///
+    /// [`set_app_data`]: #method.set_app_data
+    pub fn app_data_mut<T: 'static>(&self) -> Result<Option<RefMut<T>>> {
+        unsafe {
+            match (*extra_data(self.state)).app_data.get(&TypeId::of::<T>()) {
+                Some(cell) => {
+                    let data = cell
+                        .try_borrow_mut()
+                        .map_err(|_| app_data_borrow_error::<T>(true))?;
+                    Ok(Some(RefMut::map(data, |data| {
+                        data.downcast_mut::<T>()
+                            .unwrap_or_else(|| rlua_panic!("app data type mismatch"))
+                    })))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Removes and returns the application data of type `T`, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns `RecursiveCallback` if the value is currently borrowed.
+    pub fn remove_app_data<T: 'static>(&self) -> Result<Option<T>> {
+        unsafe {
+            let app_data = &mut (*extra_data(self.state)).app_data;
+            if let Some(cell) = app_data.get(&TypeId::of::<T>()) {
+                if cell.try_borrow_mut().is_err() {
+                    return Err(app_data_borrow_error::<T>(true));
+                }
+            }
+
+            Ok(app_data
+                .remove(&TypeId::of::<T>())
+                .map(|cell| take_app_data(*cell)))
+        }
+    }
+
+    /// Sets a hook which is called whenever a Rust callback panics.
+    ///
+    /// Panics inside callbacks are caught, carried through the Lua code that called the callback,
+    /// and then resumed once control returns to Rust.  The hook is called right after the panic is
+    /// caught, before any of that happens, which makes it possible to log or record which callback
+    /// panicked.  It receives the panic message (if the panic payload is a string) and the name of
+    /// the callback as Lua sees it (if Lua can determine one, see `debug.getinfo`).
+    ///
+    /// This does not change how the panic is propagated.  If the hook itself panics, that panic is
+    /// ignored.  The hook is shared by all `Lua` instances which share the same main state, and
+    /// replaces any previously set hook.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::panic::{catch_unwind, AssertUnwindSafe};
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let last_panic = Arc::new(Mutex::new(None));
+    ///
+    /// let recorder = last_panic.clone();
+    /// lua.set_panic_hook(move |message, name| {
+    ///     *recorder.lock().unwrap() = Some((message.to_owned(), name.map(|n| n.to_owned())));
+    /// });
+    ///
+    /// let explode = lua.create_function(|_, ()| -> Result<()> { panic!("boom") })?;
+    /// lua.globals().set("explode", explode)?;
+    ///
+    /// assert!(catch_unwind(AssertUnwindSafe(|| lua.exec::<()>("explode()", None))).is_err());
+    /// assert_eq!(
+    ///     *last_panic.lock().unwrap(),
+    ///     Some(("boom".to_owned(), Some("explode".to_owned())))
+    /// );
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where
+        F: 'static + Send + Fn(&str, Option<&str>),
+    {
+        unsafe {
+            (*extra_data(self.state)).panic_hook = Some(Box::new(hook));
+        }
+    }
+
+    /// Removes the hook set with [`set_panic_hook`].
+    ///
+    /// [`set_panic_hook`]: #method.set_panic_hook
+    pub fn remove_panic_hook(&self) {
+        unsafe {
+            (*extra_data(self.state)).panic_hook = None;
+        }
+    }
+
+    /// Replaces the global `print` function with one which passes each printed line to `handler`
+    /// instead of writing it to the standard output.
+    ///
+    /// The arguments of `print` are converted like the standard `print` does, respecting
+    /// `__tostring` and `__name` metatable fields, and joined with tabs.  The line is passed
+    /// without the trailing newline.
+    ///
+    /// The handler is called synchronously from within each call to `print`, before the call
+    /// returns to the script, and no output is buffered.  A host can therefore display the output
+    /// of a long running script as it runs, such as progress messages.  If the handler returns an
+    /// error, the error is raised from the call to `print`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let output = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// let lines = output.clone();
+    /// lua.set_print_handler(move |line| {
+    ///     lines.lock().unwrap().push(line.to_owned());
+    ///     Ok(())
+    /// })?;
+    ///
+    /// lua.exec::<()>("print('step', 1) print('done')", None)?;
+    /// assert_eq!(*output.lock().unwrap(), vec!["step\t1", "done"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_print_handler<F>(&self, mut handler: F) -> Result<()>
+    where
+        F: 'static + Send + FnMut(&str) -> Result<()>,
+    {
+        let print = self.create_function_mut(move |lua, args: MultiValue| {
+            let mut line = StdString::new();
+            for (i, value) in args.into_iter().enumerate() {
+                if i > 0 {
+                    line.push('\t');
+                }
+                line.push_str(&lua.to_display_string(value)?);
+            }
+            handler(&line)
+        })?;
+        self.globals().set("print", print)
+    }
+
+    /// Sets a hook which is called while Lua code runs, as configured by `triggers`.
+    ///
+    /// This is the foundation for debuggers and profilers: the hook receives a [`Debug`] describing
+    /// the event and the running function, such as the current line for line events.  If the hook
+    /// returns an error, that error is raised as a Lua error at the point where the hook was
+    /// called.
+    ///
+    /// The hook is not called recursively for code run by the hook itself.  It is set on the main
+    /// thread and inherited by every coroutine created afterwards, and it replaces any previously
+    /// set hook, including the breakpoint handler set with [`set_breakpoint_handler`], which is
+    /// itself a hook.  [`Thread::set_resume_limit`] installs its own hook on the thread it is
+    /// called on, replacing this one for that thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{HookEvent, HookTriggers, Lua, Result};
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let lines = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// let recorder = lines.clone();
+    /// lua.set_hook(
+    ///     HookTriggers {
+    ///         every_line: true,
+    ///         ..Default::default()
+    ///     },
+    ///     move |debug| {
+    ///         assert_eq!(debug.event(), HookEvent::Line);
+    ///         recorder.lock().unwrap().push(debug.current_line().unwrap());
+    ///         Ok(())
+    ///     },
+    /// );
+    /// lua.exec::<()>("local x = 1\nlocal y = 2\n\nlocal z = 3", None)?;
+    /// lua.remove_hook();
+    ///
+    /// assert_eq!(*lines.lock().unwrap(), vec![1, 2, 4]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Debug`]: struct.Debug.html
+    /// [`set_breakpoint_handler`]: #method.set_breakpoint_handler
+    /// [`Thread::set_resume_limit`]: struct.Thread.html#method.set_resume_limit
+    pub fn set_hook<F>(&self, triggers: HookTriggers, callback: F)
+    where
+        F: 'static + Send + FnMut(&Debug) -> Result<()>,
+    {
+        unsafe {
+            let extra = extra_data(self.state);
+            (*extra).hook_callback = Some(Rc::new(RefCell::new(callback)));
+            (*extra).hook_triggers = triggers;
+            ffi::lua_sethook(
+                self.main_state,
+                Some(hook_proc),
+                triggers.mask(),
+                triggers.count(),
+            );
+        }
+    }
+
+    /// Removes the hook set with [`set_hook`] from the main thread.
+    ///
+    /// Coroutines which inherited the hook stop calling it as well.
+    ///
+    /// [`set_hook`]: #method.set_hook
+    pub fn remove_hook(&self) {
+        unsafe {
+            (*extra_data(self.state)).hook_callback = None;
+            ffi::lua_sethook(self.main_state, None, 0, 0);
+        }
+    }
+
+    /// Sets the handler which is called whenever execution reaches a breakpoint set with
+    /// [`set_breakpoint`].
+    ///
+    /// Execution is paused while the handler runs, so it can inspect the state through the
+    /// [`Debug`] it receives (or through the globals) and decide how to continue: returning `Ok`
+    /// resumes execution, and returning an error raises that error as a Lua error at the
+    /// breakpoint, which can be used to abort the script.
+    ///
+    /// Breakpoints are implemented with a line hook, so this and [`set_hook`] replace each other:
+    /// setting the handler replaces any hook set with `set_hook`, setting a hook afterwards
+    /// removes the handler, and so does [`remove_hook`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let hits = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// let recorder = hits.clone();
+    /// lua.set_breakpoint_handler(move |debug| {
+    ///     // Breakpoints are hit before the line runs.
+    ///     let x: Option<i64> = debug.lua().globals().get("x")?;
+    ///     recorder.lock().unwrap().push((debug.current_line().unwrap(), x));
+    ///     Ok(())
+    /// });
+    /// lua.set_breakpoint("script", 3);
+    ///
+    /// lua.exec::<()>(r#"
+    ///     for i = 1, 2 do
+    ///         x = i * 10
+    ///     end
+    /// "#, Some("script"))?;
+    /// assert_eq!(*hits.lock().unwrap(), vec![(3, None), (3, Some(10))]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_breakpoint`]: #method.set_breakpoint
+    /// [`Debug`]: struct.Debug.html
+    /// [`set_hook`]: #method.set_hook
+    /// [`remove_hook`]: #method.remove_hook
+    pub fn set_breakpoint_handler<F>(&self, mut handler: F)
+    where
+        F: 'static + Send + FnMut(&Debug) -> Result<()>,
+    {
+        let triggers = HookTriggers {
+            every_line: true,
+            ..Default::default()
+        };
+        self.set_hook(triggers, move |debug| {
+            let hit = {
+                let line = match debug.current_line() {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                let breakpoints = unsafe { &(*extra_data(debug.lua().state)).breakpoints };
+                // Avoid looking up the source of every line unless some breakpoint is on it.
+                if !breakpoints.values().any(|lines| lines.contains(&line)) {
+                    return Ok(());
+                }
+                debug.source().source.map_or(false, |source| {
+                    breakpoints
+                        .get(&source)
+                        .map_or(false, |lines| lines.contains(&line))
+                })
+            };
+            if hit {
+                handler(debug)
+            } else {
+                Ok(())
+            }
+        });
+    }
+
+    /// Sets a breakpoint on the given line of the chunk with the given source name.
+    ///
+    /// The source name is the name the chunk was loaded with, as reported by `debug.getinfo` (such
+    /// as the `name` passed to [`load`], or `"@path"` for files loaded with
+    /// [`load_cached_file`]).  Breakpoints only take effect once a handler is set with
+    /// [`set_breakpoint_handler`].
+    ///
+    /// [`load`]: #method.load
+    /// [`load_cached_file`]: #method.load_cached_file
+    /// [`set_breakpoint_handler`]: #method.set_breakpoint_handler
+    pub fn set_breakpoint(&self, source: &str, line: u32) {
+        unsafe {
+            (*extra_data(self.state))
+                .breakpoints
+                .entry(source.to_owned())
+                .or_insert_with(HashSet::new)
+                .insert(line);
+        }
+    }
+
+    /// Removes a breakpoint set with [`set_breakpoint`], returning false if there was no such
+    /// breakpoint.
+    ///
+    /// [`set_breakpoint`]: #method.set_breakpoint
+    pub fn clear_breakpoint(&self, source: &str, line: u32) -> bool {
+        unsafe {
+            let breakpoints = &mut (*extra_data(self.state)).breakpoints;
+            let removed = breakpoints
+                .get_mut(source)
+                .map_or(false, |lines| lines.remove(&line));
+            if breakpoints.get(source).map_or(false, |lines| lines.is_empty()) {
+                breakpoints.remove(source);
+            }
+            removed
+        }
+    }
+
+    /// Returns a handle to the global environment.
+    pub fn globals(&self) -> Table {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 2);
+            ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+            Table(self.pop_ref())
+        }
+    }
+
+    /// Registers an API as globals, all at once.
+    ///
+    /// The closure adds the functions, values and nested tables of the API to an [`ApiBuilder`],
+    /// and the entries are then stored into the globals in one pass, which avoids the overhead of
+    /// a separate protected call for each `Table::set`.  Entries are stored without invoking
+    /// metamethods.  Tables which already exist, such as `string`, are extended rather than
+    /// replaced.
+    ///
+    /// If creating any entry failed, the error is returned and no entry is registered.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.register(|api| {
+    ///     api.value("VERSION", "1.0");
+    ///     api.table("math2")
+    ///         .function("double", |_, x: i64| Ok(x * 2))
+    ///         .function("square", |_, x: i64| Ok(x * x));
+    ///     api.table("string")
+    ///         .function("shout", |_, s: String| Ok(s.to_uppercase()));
+    /// })?;
+    ///
+    /// assert_eq!(lua.eval::<i64>("math2.double(math2.square(3))", None)?, 18);
+    /// assert_eq!(lua.eval::<String>("('hi'):shout() .. VERSION", None)?, "HI1.0");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ApiBuilder`]: struct.ApiBuilder.html
+    pub fn register<'lua, F>(&'lua self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut ApiBuilder<'lua>),
+    {
+        let mut builder = ApiBuilder::new(self);
+        f(&mut builder);
+        builder.apply(&self.globals())
+    }
+
+    /// Creates a new environment table for running a script in isolation from other scripts.
+    ///
+    /// Reading a variable missing from the returned table falls through to the global environment
+    /// through an `__index` metamethod, while assigning a global variable only ever changes the
+    /// returned table.  The `_G` field of the environment refers to the environment itself, so that
+    /// `_G.name = value` cannot be used to reach the shared globals either.  Use it with
+    /// [`load_with_env`] to give each script its own set of globals.
+    ///
+    /// Note that the values of the global environment are shared rather than copied, so a script
+    /// can still modify shared tables such as `string` by assigning to their fields.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.globals().set("greeting", "hello")?;
+    ///
+    /// let env = lua.create_environment()?;
+    /// lua.load_with_env("greeting = greeting .. ' world'", None, env.clone())?
+    ///     .call::<_, ()>(())?;
+    ///
+    /// assert_eq!(env.get::<_, String>("greeting")?, "hello world");
+    /// assert_eq!(lua.globals().get::<_, String>("greeting")?, "hello");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`load_with_env`]: #method.load_with_env
+    pub fn create_environment(&self) -> Result<Table> {
+        let env = self.create_table()?;
+        let metatable = self.create_table()?;
+        metatable.raw_set("__index", self.globals())?;
+        env.raw_set("_G", env.clone())?;
+        env.set_metatable(Some(metatable))?;
+        Ok(env)
     }
 
-    /// Wraps a Rust mutable closure, creating a callable Lua function handle to it.
+    /// Freezes the global environment, so that scripts can neither add new global variables nor
+    /// change existing ones.
     ///
-    /// This is a version of [`create_function`] that accepts a FnMut argument.  Refer to
-    /// [`create_function`] for more information about the implementation.
+    /// The current globals are moved into a hidden table, and the globals table is given a locked
+    /// metatable whose `__index` reads from the hidden table and whose `__newindex` raises an error
+    /// for every assignment.  Reading globals from scripts or with [`Table::get`] keeps working,
+    /// but the metatable of the globals table can no longer be read or changed from Lua.  Calling
+    /// this more than once has no further effect.
     ///
-    /// [`create_function`]: #method.create_function
-    pub fn create_function_mut<'lua, 'callback, A, R, F>(
-        &'lua self,
-        func: F,
-    ) -> Result<Function<'lua>>
-    where
-        A: FromLuaMulti<'callback>,
-        R: ToLuaMulti<'callback>,
-        F: 'static + Send + FnMut(&'callback Lua, A) -> Result<R>,
-    {
-        let func = RefCell::new(func);
-        self.create_function(move |lua, args| {
-            (&mut *func
-                .try_borrow_mut()
-                .map_err(|_| Error::RecursiveMutCallback)?)(lua, args)
-        })
-    }
-
-    /// Wraps a Lua function into a new thread (or coroutine).
+    /// Rust code can still add or replace globals with [`Table::raw_set`] on [`globals`], which
+    /// shadows the frozen value of the same name.  Note that [`Table::raw_get`] and
+    /// [`Table::pairs`] on the globals table only see such values, and not the frozen ones.
     ///
-    /// Equivalent to `coroutine.create`.
-    pub fn create_thread<'lua>(&'lua self, func: Function<'lua>) -> Result<Thread<'lua>> {
+    /// Scripts that use global variables as scratch space, including functions declared with
+    /// `function name() end` instead of `local function name() end`, fail with a runtime error
+    /// once the globals are frozen.  Such scripts should either be changed to use locals, or be
+    /// loaded with [`load_with_env`] and an environment from [`create_environment`], in which case
+    /// their globals are written to the environment and the frozen globals are still readable
+    /// through it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.globals().set("limit", 10)?;
+    /// lua.freeze_globals()?;
+    ///
+    /// assert!(lua.exec::<()>("limit = 20", None).is_err());
+    /// assert!(lua.exec::<()>("new_global = 1", None).is_err());
+    /// assert_eq!(lua.eval::<i64>("local doubled = limit * 2; return doubled", None)?, 20);
+    ///
+    /// lua.globals().raw_set("limit", 30)?;
+    /// assert_eq!(lua.eval::<i64>("limit", None)?, 30);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Table::get`]: struct.Table.html#method.get
+    /// [`Table::raw_set`]: struct.Table.html#method.raw_set
+    /// [`Table::raw_get`]: struct.Table.html#method.raw_get
+    /// [`Table::pairs`]: struct.Table.html#method.pairs
+    /// [`globals`]: #method.globals
+    /// [`load_with_env`]: #method.load_with_env
+    /// [`create_environment`]: #method.create_environment
+    pub fn freeze_globals(&self) -> Result<()> {
+        unsafe extern "C" fn frozen_newindex(state: *mut ffi::lua_State) -> c_int {
+            ffi::luaL_checkstack(state, 4, ptr::null());
+
+            ffi::luaL_where(state, 1);
+            ffi::lua_pushstring(state, cstr!("attempt to assign to frozen global '"));
+            ffi::luaL_tolstring(state, 2, ptr::null_mut());
+            ffi::lua_pushstring(state, cstr!("'"));
+            ffi::lua_concat(state, 4);
+            ffi::lua_error(state)
+        }
+
         unsafe {
             let _sg = StackGuard::new(self.state);
-            assert_stack(self.state, 2);
+            assert_stack(self.state, 7);
 
-            let thread_state =
-                protect_lua_closure(self.state, 0, 1, |state| ffi::lua_newthread(state))?;
-            self.push_ref(&func.0);
-            ffi::lua_xmove(self.state, thread_state, 1);
+            ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
 
-            Ok(Thread(self.pop_ref()))
+            if ffi::lua_getmetatable(self.state, -1) != 0 {
+                ffi::lua_pushlightuserdata(
+                    self.state,
+                    &FROZEN_GLOBALS_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+                );
+                ffi::lua_rawget(self.state, ffi::LUA_REGISTRYINDEX);
+                if ffi::lua_rawequal(self.state, -1, -2) != 0 {
+                    return Ok(());
+                }
+                ffi::lua_pop(self.state, 2);
+            }
+
+            protect_lua_closure(self.state, 1, 0, |state| {
+                // The hidden table keeps any previous metatable of the globals, so that existing
+                // fallbacks for missing globals still work.
+                ffi::lua_newtable(state);
+                if ffi::lua_getmetatable(state, -2) != 0 {
+                    ffi::lua_setmetatable(state, -2);
+                }
+
+                // Clearing fields during a traversal is allowed, so the globals can be moved while
+                // iterating over them.
+                ffi::lua_pushnil(state);
+                while ffi::lua_next(state, -3) != 0 {
+                    ffi::lua_pushvalue(state, -2);
+                    ffi::lua_insert(state, -2);
+                    ffi::lua_rawset(state, -4);
+
+                    ffi::lua_pushvalue(state, -1);
+                    ffi::lua_pushnil(state);
+                    ffi::lua_rawset(state, -5);
+                }
+
+                ffi::lua_newtable(state);
+
+                ffi::lua_pushstring(state, cstr!("__index"));
+                ffi::lua_pushvalue(state, -3);
+                ffi::lua_rawset(state, -3);
+
+                ffi::lua_pushstring(state, cstr!("__newindex"));
+                ffi::lua_pushcfunction(state, frozen_newindex);
+                ffi::lua_rawset(state, -3);
+
+                ffi::lua_pushstring(state, cstr!("__metatable"));
+                ffi::lua_pushboolean(state, 0);
+                ffi::lua_rawset(state, -3);
+
+                // Remembered so that freezing the globals again can be detected.
+                ffi::lua_pushlightuserdata(
+                    state,
+                    &FROZEN_GLOBALS_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+                );
+                ffi::lua_pushvalue(state, -2);
+                ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+
+                ffi::lua_setmetatable(state, -3);
+                ffi::lua_pop(state, 2);
+            })
         }
     }
 
-    /// Create a Lua userdata object from a custom userdata type.
-    pub fn create_userdata<T>(&self, data: T) -> Result<AnyUserData>
-    where
-        T: Send + UserData,
-    {
-        unsafe { self.make_userdata(data) }
-    }
+    /// Replaces the given globals with placeholders which raise a descriptive error when used,
+    /// such as "`os` is disabled in this sandbox".
+    ///
+    /// When sandboxing scripts by removing globals such as `os` or `io`, a script using them
+    /// otherwise fails with a cryptic "attempt to index a nil value" error.  Instead, each named
+    /// global is set to an empty table with a locked metatable whose `__index`, `__newindex`,
+    /// `__call`, `__len` and `__pairs` metamethods raise an error naming the global.  Globals do
+    /// not need to exist before being disabled.
+    ///
+    /// Libraries loaded by the standard library or by `require` are also kept in `package.loaded`,
+    /// from which `require` returns them, so a name which has an entry there has that entry
+    /// replaced with the same placeholder.  Other references to the library, such as a local
+    /// copied before the call, are not affected.
+    ///
+    /// Note that a disabled global is not `nil`, so scripts testing whether a library is available
+    /// with `if os then ... end` treat it as present.  The globals are set with a raw assignment,
+    /// so this also works after [`freeze_globals`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.disable_globals(&["os", "io"])?;
+    ///
+    /// match lua.exec::<()>("os.exit()", Some("script")) {
+    ///     Err(Error::RuntimeError(msg)) => {
+    ///         assert!(msg.starts_with("[string \"script\"]:1: `os` is disabled in this sandbox"))
+    ///     }
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`freeze_globals`]: #method.freeze_globals
+    pub fn disable_globals(&self, names: &[&str]) -> Result<()> {
+        unsafe extern "C" fn disabled_error(state: *mut ffi::lua_State) -> c_int {
+            ffi::luaL_checkstack(state, 4, ptr::null());
+
+            ffi::luaL_where(state, 1);
+            ffi::lua_pushstring(state, cstr!("`"));
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+            ffi::lua_pushstring(state, cstr!("` is disabled in this sandbox"));
+            ffi::lua_concat(state, 4);
+            ffi::lua_error(state)
+        }
 
-    /// Returns a handle to the global environment.
-    pub fn globals(&self) -> Table {
         unsafe {
             let _sg = StackGuard::new(self.state);
-            assert_stack(self.state, 2);
-            ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
-            Table(self.pop_ref())
+            assert_stack(self.state, 7);
+
+            for name in names {
+                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+                push_string(self.state, name)?;
+                protect_lua_closure(self.state, 2, 0, |state| {
+                    ffi::lua_newtable(state);
+                    ffi::lua_newtable(state);
+                    for &method in &[
+                        cstr!("__index"),
+                        cstr!("__newindex"),
+                        cstr!("__call"),
+                        cstr!("__len"),
+                        cstr!("__pairs"),
+                    ] {
+                        ffi::lua_pushstring(state, method);
+                        ffi::lua_pushvalue(state, -4);
+                        ffi::lua_pushcclosure(state, disabled_error, 1);
+                        ffi::lua_rawset(state, -3);
+                    }
+                    ffi::lua_pushstring(state, cstr!("__metatable"));
+                    ffi::lua_pushboolean(state, 0);
+                    ffi::lua_rawset(state, -3);
+                    ffi::lua_setmetatable(state, -2);
+
+                    ffi::lua_pushstring(state, cstr!("_LOADED"));
+                    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TTABLE {
+                        ffi::lua_pushvalue(state, -3);
+                        if ffi::lua_rawget(state, -2) != ffi::LUA_TNIL {
+                            ffi::lua_pushvalue(state, -4);
+                            ffi::lua_pushvalue(state, -4);
+                            ffi::lua_rawset(state, -4);
+                        }
+                        ffi::lua_pop(state, 1);
+                    }
+                    ffi::lua_pop(state, 1);
+
+                    ffi::lua_rawset(state, -3);
+                    ffi::lua_pop(state, 1);
+                })?;
+            }
+            Ok(())
         }
     }
 
+    /// Replaces the global `collectgarbage` function with a restricted version which scripts
+    /// cannot use to interfere with the host's garbage collection, and returns the original.
+    ///
+    /// The restricted function still answers the `"count"` and `"isrunning"` queries, but the
+    /// options which control the collector (such as `"collect"`, `"stop"` or `"setpause"`) do
+    /// nothing and return 0.  This prevents scripts from running full collections in a loop or
+    /// changing the collector's tuning, while the host keeps control with [`gc_collect`].  Invalid
+    /// options still raise an error.
+    ///
+    /// The returned function is the unrestricted `collectgarbage`, which can be given to trusted
+    /// code, for example through the environment of [`load_with_env`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let original = lua.restrict_collectgarbage()?;
+    ///
+    /// lua.exec::<()>("collectgarbage('stop')", None)?;
+    /// assert!(lua.eval::<bool>("collectgarbage('isrunning')", None)?);
+    ///
+    /// original.call::<_, ()>("collect")?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`gc_collect`]: #method.gc_collect
+    /// [`load_with_env`]: #method.load_with_env
+    pub fn restrict_collectgarbage(&self) -> Result<Function> {
+        let globals = self.globals();
+        let original: Function = globals.raw_get("collectgarbage")?;
+        let restricted: Function = self
+            .load(
+                r#"
+                    local collectgarbage = ...
+                    local controls = {
+                        collect = true, stop = true, restart = true, step = true,
+                        setpause = true, setstepmul = true,
+                    }
+                    return function(opt, ...)
+                        if controls[opt == nil and "collect" or opt] then
+                            return 0
+                        end
+                        return collectgarbage(opt, ...)
+                    end
+                "#,
+                Some("=collectgarbage"),
+            )?
+            .call(original.clone())?;
+        globals.raw_set("collectgarbage", restricted)?;
+        Ok(original)
+    }
+
     /// Calls the given function with a `Scope` parameter, giving the function the ability to create
     /// userdata from rust types that are !Send, and rust callbacks that are !Send and not 'static.
     ///
@@ -342,8 +2410,21 @@ impl Lua {
     /// the handles from escaping the callback.  However, this is not the only way for values to
     /// escape the callback, as they can be smuggled through Lua itself.  This is safe to do, but
     /// not very useful, because after the scope is dropped, all references to scoped values,
-    /// whether in Lua or in rust, are invalidated.  `Function` types will error when called, and
-    /// `AnyUserData` types will be typeless.
+    /// whether in Lua or in rust, are invalidated.  Calling such a function or using such a
+    /// userdata results in an [`Error::ScopeExpired`], which names the type of the userdata.
+    ///
+    /// Calls to `scope` may be nested.  Callbacks created through an inner `Scope` may capture
+    /// handles created through an outer `Scope`, because the inner scope is always dropped before
+    /// the outer one, so such handles are guaranteed to be valid for as long as the inner callbacks
+    /// can be called.
+    ///
+    /// When the scope ends, every value created through it is first invalidated in Lua, and only
+    /// then are the Rust values dropped, each one separately.  If the `Drop` implementation of
+    /// some of them panics, the remaining ones are still dropped, and the first panic is resumed
+    /// once they all have been.  Use [`try_scope`] to get an error instead.
+    ///
+    /// [`Error::ScopeExpired`]: enum.Error.html#variant.ScopeExpired
+    /// [`try_scope`]: #method.try_scope
     pub fn scope<'scope, 'lua: 'scope, F, R>(&'lua self, f: F) -> R
     where
         F: FnOnce(&Scope<'scope>) -> R,
@@ -354,6 +2435,60 @@ impl Lua {
         r
     }
 
+    /// Like [`scope`], but for a function returning a `Result`, and reporting the failure of any
+    /// value created through the `Scope` to drop as an error.
+    ///
+    /// If dropping one or more of the values created by the scope panics, this returns an
+    /// `Error::ScopeDropPanic` after every value has been invalidated and dropped.  An error
+    /// returned by `f` takes precedence over such an error.  A panic inside `f` itself is not
+    /// caught, and the scope is ended as usual while unwinding.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, Lua, Result, UserData};
+    /// # fn try_main() -> Result<()> {
+    /// struct Handle;
+    ///
+    /// impl UserData for Handle {}
+    ///
+    /// impl Drop for Handle {
+    ///     fn drop(&mut self) {
+    ///         panic!("failed to release handle");
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let res = lua.try_scope(|scope| {
+    ///     lua.globals().set("handle", scope.create_userdata(Handle)?)?;
+    ///     lua.exec::<()>("assert(handle ~= nil)", None)
+    /// });
+    ///
+    /// match res {
+    ///     Err(Error::ScopeDropPanic { count: 1, .. }) => {}
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`scope`]: #method.scope
+    pub fn try_scope<'scope, 'lua: 'scope, F, R>(&'lua self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Scope<'scope>) -> Result<R>,
+    {
+        let scope = Scope::new(self);
+        let r = f(&scope);
+        let finished = scope.finish();
+        let r = r?;
+        finished?;
+        Ok(r)
+    }
+
     /// Coerces a Lua value to a string.
     ///
     /// The value must be a string (in which case this is a no-op) or a number.
@@ -365,7 +2500,7 @@ impl Lua {
                 assert_stack(self.state, 4);
 
                 let ty = v.type_name();
-                self.push_value(v);
+                self.push_value(v)?;
                 let s =
                     protect_lua_closure(self.state, 1, 1, |state| ffi::lua_tostring(state, -1))?;
                 if s.is_null() {
@@ -385,22 +2520,38 @@ impl Lua {
     ///
     /// The value must be an integer, or a floating point number, or a string that can be converted
     /// to an integer. Refer to the Lua manual for details.
+    ///
+    /// NaN and infinite numbers (including strings such as `"1e400"` which convert to them) have no
+    /// integer representation and always result in a `FromLuaConversionError`.  If you need to map
+    /// such values to some sentinel instead, use [`coerce_number`] and handle them yourself.
+    ///
+    /// [`coerce_number`]: #method.coerce_number
     pub fn coerce_integer(&self, v: Value) -> Result<Integer> {
         match v {
             Value::Integer(i) => Ok(i),
+            Value::Number(n) if !n.is_finite() => Err(Error::FromLuaConversionError {
+                from: "number",
+                to: "integer",
+                message: Some(non_finite_message(n)),
+            }),
             v => unsafe {
                 let _sg = StackGuard::new(self.state);
                 assert_stack(self.state, 2);
 
                 let ty = v.type_name();
-                self.push_value(v);
+                self.push_value(v)?;
                 let mut isint = 0;
                 let i = ffi::lua_tointegerx(self.state, -1, &mut isint);
                 if isint == 0 {
+                    let n = ffi::lua_tonumberx(self.state, -1, ptr::null_mut());
                     Err(Error::FromLuaConversionError {
                         from: ty,
                         to: "integer",
-                        message: None,
+                        message: if n.is_finite() {
+                            None
+                        } else {
+                            Some(non_finite_message(n))
+                        },
                     })
                 } else {
                     Ok(i)
@@ -421,7 +2572,7 @@ impl Lua {
                 assert_stack(self.state, 2);
 
                 let ty = v.type_name();
-                self.push_value(v);
+                self.push_value(v)?;
                 let mut isnum = 0;
                 let n = ffi::lua_tonumberx(self.state, -1, &mut isnum);
                 if isnum == 0 {
@@ -437,6 +2588,65 @@ impl Lua {
         }
     }
 
+    /// Coerces a Lua value to a boolean, following Lua's truthiness rules.
+    ///
+    /// Only `nil` and `false` are falsy, every other value is truthy, including `0` and the empty
+    /// string.
+    pub fn coerce_boolean(&self, v: Value) -> bool {
+        match v {
+            Value::Nil | Value::Boolean(false) => false,
+            _ => true,
+        }
+    }
+
+    /// Formats a floating point number exactly like Lua's `tostring` does.
+    ///
+    /// Lua formats floats with the `"%.14g"` format of C, and adds `".0"` to floats which would
+    /// otherwise look like integers, so that for example `1e15` is formatted as `1e+15` and `2.0`
+    /// as `2.0`.  This is useful to generate Lua source code or messages consistent with the ones
+    /// produced by scripts.
+    ///
+    /// Note that the 14 significant digits of this format are not always enough to read back the
+    /// exact same number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::Lua;
+    /// # fn main() {
+    /// assert_eq!(Lua::number_to_string(2.0), "2.0");
+    /// assert_eq!(Lua::number_to_string(0.1 + 0.2), "0.3");
+    /// assert_eq!(Lua::number_to_string(1e100), "1e+100");
+    /// # }
+    /// ```
+    pub fn number_to_string(n: Number) -> StdString {
+        let mut buf = [0 as c_char; 64];
+        let len = unsafe { libc::snprintf(buf.as_mut_ptr(), buf.len(), cstr!("%.14g"), n) };
+        rlua_debug_assert!(
+            len > 0 && (len as usize) < buf.len(),
+            "number format truncated"
+        );
+        let bytes = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes();
+        let mut s = str::from_utf8(bytes)
+            .expect("number format is not ascii")
+            .to_owned();
+        if s.bytes().all(|b| b == b'-' || (b >= b'0' && b <= b'9')) {
+            s.push_str(".0");
+        }
+        s
+    }
+
+    /// Formats an integer exactly like Lua's `tostring` does.
+    ///
+    /// Lua integers are simply formatted in decimal, so this is the same as `i.to_string()`, and
+    /// is provided for symmetry with [`number_to_string`].
+    ///
+    /// [`number_to_string`]: #method.number_to_string
+    pub fn integer_to_string(i: Integer) -> StdString {
+        i.to_string()
+    }
+
     /// Converts a value that implements `ToLua` into a `Value` instance.
     pub fn pack<'lua, T: ToLua<'lua>>(&'lua self, t: T) -> Result<Value<'lua>> {
         t.to_lua(self)
@@ -455,9 +2665,171 @@ impl Lua {
     /// Converts a `MultiValue` instance into a value that implements `FromLuaMulti`.
     pub fn unpack_multi<'lua, T: FromLuaMulti<'lua>>(
         &'lua self,
-        value: MultiValue<'lua>,
-    ) -> Result<T> {
-        T::from_lua_multi(value, self)
+        value: MultiValue<'lua>,
+    ) -> Result<T> {
+        T::from_lua_multi(value, self)
+    }
+
+    /// Converts a JSON value into a Lua value.
+    ///
+    /// Arrays become sequence tables and objects become tables with string keys.  Numbers which
+    /// fit in an `i64` become integers, all other numbers become floats.  JSON `null` becomes
+    /// `nil`, so a `null` inside of an array leaves a hole in the resulting table, and an object
+    /// field holding `null` is omitted.
+    ///
+    /// This is only available with the `json` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # extern crate serde_json;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let payload = serde_json::json!({ "user": "alice", "scores": [3, 4.5] });
+    /// lua.globals().set("payload", lua.from_json(&payload)?)?;
+    ///
+    /// let total: f64 = lua.eval("payload.scores[1] + payload.scores[2]", None)?;
+    /// assert_eq!(total, 7.5);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn from_json<'lua>(&'lua self, json: &serde_json::Value) -> Result<Value<'lua>> {
+        json::from_json(self, json)
+    }
+
+    /// Converts a Lua value into a JSON value.
+    ///
+    /// `nil` becomes `null`, and integers and floats become JSON numbers, preserving the
+    /// distinction between them.  A non-empty table which is a proper sequence according to
+    /// [`Table::is_sequence`] becomes an array, and any other table, including the empty table,
+    /// becomes an object.  Object keys must be strings or integers, integer keys are converted to
+    /// strings.  Metamethods are not invoked.
+    ///
+    /// Returns a `FromLuaConversionError` if the value contains a table cycle, a string which is
+    /// not valid UTF-8, a NaN or infinite number, a table key of any other type, or any value
+    /// without a JSON representation such as a function or userdata.
+    ///
+    /// This is only available with the `json` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # extern crate serde_json;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let value: Value = lua.eval("{ name = 'report', pages = { 1, 2, 3 } }", None)?;
+    /// let json = lua.to_json(value)?;
+    /// assert_eq!(json, serde_json::json!({ "name": "report", "pages": [1, 2, 3] }));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Table::is_sequence`]: struct.Table.html#method.is_sequence
+    #[cfg(feature = "json")]
+    pub fn to_json<'lua>(&'lua self, value: Value<'lua>) -> Result<serde_json::Value> {
+        json::to_json(value)
+    }
+
+    /// Serializes a value into a compact, self-describing binary format, which can be turned back
+    /// into a value with [`deserialize_value`], such as in another process.
+    ///
+    /// Nil, booleans, integers, floats, strings (which may contain arbitrary bytes) and tables of
+    /// such values are supported, keeping integers and floats distinct.  Tables referenced several
+    /// times, including through cycles, are encoded once and referenced afterwards, so that the
+    /// deserialized value has the same structure.  Metatables are not serialized and metamethods
+    /// are not invoked.
+    ///
+    /// Returns a `FromLuaConversionError` if the value contains a function, userdata, thread or
+    /// error, or tables nested more than 200 levels deep.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let worker = Lua::new();
+    /// let result: Value = worker.eval("{ status = 'done', items = { 1, 2.5, 'three' } }", None)?;
+    /// let bytes = worker.serialize_value(result)?;
+    ///
+    /// let parent = Lua::new();
+    /// let result: Table = parent.unpack(parent.deserialize_value(&bytes)?)?;
+    /// assert_eq!(result.get::<_, String>("status")?, "done");
+    /// assert_eq!(result.get::<_, Table>("items")?.raw_len(), 3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`deserialize_value`]: #method.deserialize_value
+    pub fn serialize_value<'lua>(&'lua self, value: Value<'lua>) -> Result<Vec<u8>> {
+        serialize::serialize(value)
+    }
+
+    /// Deserializes a value serialized with [`serialize_value`].
+    ///
+    /// Returns a `ToLuaConversionError` if `bytes` is not a valid serialized value.  Malformed
+    /// input is always detected without panicking, but deserializing untrusted input can still
+    /// create large tables.
+    ///
+    /// [`serialize_value`]: #method.serialize_value
+    pub fn deserialize_value<'lua>(&'lua self, bytes: &[u8]) -> Result<Value<'lua>> {
+        serialize::deserialize(self, bytes)
+    }
+
+    /// Deep-copies a value from the `source` state into this one.
+    ///
+    /// This allows passing results between independent `Lua` instances, such as from a worker
+    /// state running on another thread, without sharing any memory between them.  The same values
+    /// as with [`serialize_value`] are supported: strings and tables are recreated in this state,
+    /// with tables referenced several times, including through cycles, copied only once.
+    /// Metatables are not copied.
+    ///
+    /// Returns a `FromLuaConversionError` if the value contains a function, userdata, thread or
+    /// error, and `MismatchedLuaState` if it does not belong to `source`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let worker = Lua::new();
+    /// let result: Value = worker.eval("local t = { total = 42 } t.self = t return t", None)?;
+    ///
+    /// let main = Lua::new();
+    /// let result: Table = main.unpack(main.import_value(&worker, result)?)?;
+    /// assert_eq!(result.get::<_, i64>("total")?, 42);
+    /// assert_eq!(result.get::<_, Table>("self")?.get::<_, i64>("total")?, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`serialize_value`]: #method.serialize_value
+    pub fn import_value<'lua, 'src>(
+        &'lua self,
+        source: &'src Lua,
+        value: Value<'src>,
+    ) -> Result<Value<'lua>> {
+        serialize::import(self, source, value)
     }
 
     /// Set a value in the Lua registry based on a string name.
@@ -475,7 +2847,7 @@ impl Lua {
             assert_stack(self.state, 5);
 
             push_string(self.state, name)?;
-            self.push_value(t);
+            self.push_value(t)?;
 
             unsafe extern "C" fn set_registry(state: *mut ffi::lua_State) -> c_int {
                 ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
@@ -508,6 +2880,45 @@ impl Lua {
         T::from_lua(value, self)
     }
 
+    /// Gets a value from the Lua registry based on a string name like [`named_registry_value`], or
+    /// `default` if there is no such value.
+    ///
+    /// Only a missing (`nil`) value results in `default`.  A value which cannot be converted to
+    /// `T` is still an error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// assert_eq!(lua.named_registry_value_or("max_retries", 3)?, 3);
+    ///
+    /// lua.set_named_registry_value("max_retries", 5)?;
+    /// assert_eq!(lua.named_registry_value_or("max_retries", 3)?, 5);
+    ///
+    /// lua.set_named_registry_value("max_retries", "many")?;
+    /// assert!(lua.named_registry_value_or("max_retries", 3).is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`named_registry_value`]: #method.named_registry_value
+    pub fn named_registry_value_or<'lua, T: FromLua<'lua>>(
+        &'lua self,
+        name: &str,
+        default: T,
+    ) -> Result<T> {
+        match self.named_registry_value::<Value>(name)? {
+            Nil => Ok(default),
+            value => T::from_lua(value, self),
+        }
+    }
+
     /// Removes a named value in the Lua registry.
     ///
     /// Equivalent to calling [`set_named_registry_value`] with a value of Nil.
@@ -521,13 +2932,74 @@ impl Lua {
     ///
     /// This value will be available to rust from all `Lua` instances which share the same main
     /// state.
+    ///
+    /// Since `RegistryKey` is `Send` and `'static`, this is the way to keep handles such as
+    /// functions created with [`create_function`] beyond the `'lua` lifetime: a library of native
+    /// functions can be created once, stored here, and retrieved later with [`registry_value`],
+    /// including from within callbacks.
+    ///
+    /// [`create_function`]: #method.create_function
+    /// [`registry_value`]: #method.registry_value
     pub fn create_registry_value<'lua, T: ToLua<'lua>>(&'lua self, t: T) -> Result<RegistryKey> {
         let t = t.to_lua(self)?;
         unsafe {
             let _sg = StackGuard::new(self.state);
             assert_stack(self.state, 2);
 
-            self.push_value(t);
+            self.push_value(t)?;
+            let registry_id = gc_guard(self.state, || {
+                ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
+            });
+
+            Ok(RegistryKey {
+                registry_id,
+                unref_list: (*extra_data(self.state)).registry_unref_list.clone(),
+            })
+        }
+    }
+
+    /// Wraps a Rust function like [`create_function`] and places it in the Lua registry, like
+    /// [`create_registry_value`].
+    ///
+    /// The returned key is a regular `RegistryKey`, so the function is retrieved with
+    /// [`registry_value`] and removed with [`remove_registry_value`].  This is convenient for
+    /// setting up callbacks at startup which are kept for the whole lifetime of the Lua state.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let key = lua.create_registered_function(|_, (a, b): (i64, i64)| Ok(a + b))?;
+    ///
+    /// let add: Function = lua.registry_value(&key)?;
+    /// assert_eq!(add.call::<_, i64>((1, 2))?, 3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function`]: #method.create_function
+    /// [`create_registry_value`]: #method.create_registry_value
+    /// [`registry_value`]: #method.registry_value
+    /// [`remove_registry_value`]: #method.remove_registry_value
+    pub fn create_registered_function<'callback, A, R, F>(&self, func: F) -> Result<RegistryKey>
+    where
+        A: FromLuaMulti<'callback>,
+        R: ToLuaMulti<'callback>,
+        F: 'static + Send + Fn(&'callback Lua, A) -> Result<R>,
+    {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 4);
+
+            self.push_function_callback(Box::new(move |lua, args| {
+                func(lua, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
+            }))?;
             let registry_id = gc_guard(self.state, || {
                 ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
             });
@@ -564,6 +3036,141 @@ impl Lua {
         T::from_lua(value, self)
     }
 
+    /// Places arbitrary Rust data in the Lua registry, returning a key to get it back.
+    ///
+    /// This is a keyed alternative to [`set_app_data`]: each call creates an independent slot, so
+    /// there can be any number of values of the same type, such as one context per plugin.  The
+    /// data is stored as an opaque userdata that is never exposed to Lua code, and can be borrowed
+    /// with [`registry_data_ref`] and [`registry_data_mut`] from any `Lua` instance which shares
+    /// the same main state, including from within callbacks.  It is removed like any other registry
+    /// value, with [`remove_registry_value`] or [`expire_registry_values`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// struct PluginContext {
+    ///     name: &'static str,
+    ///     calls: u32,
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let first = lua.create_registry_data(PluginContext { name: "first", calls: 0 })?;
+    /// let second = lua.create_registry_data(PluginContext { name: "second", calls: 0 })?;
+    ///
+    /// lua.registry_data_mut::<PluginContext>(&first)?.calls += 1;
+    /// assert_eq!(lua.registry_data_ref::<PluginContext>(&first)?.calls, 1);
+    /// assert_eq!(lua.registry_data_ref::<PluginContext>(&second)?.calls, 0);
+    /// assert_eq!(lua.registry_data_ref::<PluginContext>(&second)?.name, "second");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_app_data`]: #method.set_app_data
+    /// [`registry_data_ref`]: #method.registry_data_ref
+    /// [`registry_data_mut`]: #method.registry_data_mut
+    /// [`remove_registry_value`]: #method.remove_registry_value
+    /// [`expire_registry_values`]: #method.expire_registry_values
+    pub fn create_registry_data<T: 'static + Send>(&self, data: T) -> Result<RegistryKey> {
+        let userdata = self.create_userdata(RegistryData(data))?;
+        self.create_registry_value(userdata)
+    }
+
+    /// Borrows the Rust data placed in the registry by [`create_registry_data`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `MismatchedRegistryKey` if the key was not created by a `Lua` which shares the
+    /// underlying main state with this one, `UserDataTypeMismatch` if the key does not refer to
+    /// data of type `T`, and `UserDataBorrowError` if the data is currently borrowed mutably.
+    ///
+    /// [`create_registry_data`]: #method.create_registry_data
+    pub fn registry_data_ref<'a, T: 'static + Send>(
+        &'a self,
+        key: &'a RegistryKey,
+    ) -> Result<Ref<'a, T>> {
+        let userdata: AnyUserData = self.registry_value(key)?;
+        let data = userdata.borrow::<RegistryData<T>>()?;
+        // The userdata stays in the registry, where Lua code cannot reach it, for as long as the
+        // key is borrowed, so the borrow can outlive the handle.
+        let data =
+            unsafe { mem::transmute::<Ref<RegistryData<T>>, Ref<'a, RegistryData<T>>>(data) };
+        Ok(Ref::map(data, |data| &data.0))
+    }
+
+    /// Mutably borrows the Rust data placed in the registry by [`create_registry_data`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `MismatchedRegistryKey` if the key was not created by a `Lua` which shares the
+    /// underlying main state with this one, `UserDataTypeMismatch` if the key does not refer to
+    /// data of type `T`, and `UserDataBorrowMutError` if the data is currently borrowed.
+    ///
+    /// [`create_registry_data`]: #method.create_registry_data
+    pub fn registry_data_mut<'a, T: 'static + Send>(
+        &'a self,
+        key: &'a RegistryKey,
+    ) -> Result<RefMut<'a, T>> {
+        let userdata: AnyUserData = self.registry_value(key)?;
+        let data = userdata.borrow_mut::<RegistryData<T>>()?;
+        // See `registry_data_ref`.
+        let data = unsafe {
+            mem::transmute::<RefMut<RegistryData<T>>, RefMut<'a, RegistryData<T>>>(data)
+        };
+        Ok(RefMut::map(data, |data| &mut data.0))
+    }
+
+    /// Returns the type name of the value stored in the Lua registry under the given key.
+    ///
+    /// This is a lightweight debugging aid for tracking down which value is actually behind a
+    /// `RegistryKey`, without converting it.  The names are the same as the ones returned by
+    /// [`Value::type_name`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `MismatchedRegistryKey` if the key was not created by a `Lua` which shares the
+    /// underlying main state with this one.
+    ///
+    /// [`Value::type_name`]: enum.Value.html#method.type_name
+    pub fn registry_value_type(&self, key: &RegistryKey) -> Result<&'static str> {
+        unsafe {
+            if !self.owns_registry_value(key) {
+                return Err(Error::MismatchedRegistryKey);
+            }
+
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 1);
+
+            Ok(
+                match ffi::lua_rawgeti(
+                    self.state,
+                    ffi::LUA_REGISTRYINDEX,
+                    key.registry_id as ffi::lua_Integer,
+                ) {
+                    ffi::LUA_TNIL => "nil",
+                    ffi::LUA_TBOOLEAN => "boolean",
+                    ffi::LUA_TLIGHTUSERDATA => "light userdata",
+                    ffi::LUA_TNUMBER => if ffi::lua_isinteger(self.state, -1) != 0 {
+                        "integer"
+                    } else {
+                        "number"
+                    },
+                    ffi::LUA_TSTRING => "string",
+                    ffi::LUA_TTABLE => "table",
+                    ffi::LUA_TFUNCTION => "function",
+                    ffi::LUA_TTHREAD => "thread",
+                    ffi::LUA_TUSERDATA => "userdata",
+                    _ => rlua_panic!("LUA_TNONE in registry_value_type"),
+                },
+            )
+        }
+    }
+
     /// Removes a value from the Lua registry.
     ///
     /// You may call this function to manually remove a value placed in the registry with
@@ -571,57 +3178,263 @@ impl Lua {
     /// [`expire_registry_values`] to automatically remove values from the registry whose
     /// `RegistryKey`s have been dropped.
     ///
-    /// [`create_registry_value`]: #method.create_registry_value
-    /// [`expire_registry_values`]: #method.expire_registry_values
-    pub fn remove_registry_value(&self, key: RegistryKey) -> Result<()> {
-        unsafe {
-            if !self.owns_registry_value(&key) {
-                return Err(Error::MismatchedRegistryKey);
-            }
-
-            ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, key.take());
-            Ok(())
-        }
+    /// [`create_registry_value`]: #method.create_registry_value
+    /// [`expire_registry_values`]: #method.expire_registry_values
+    pub fn remove_registry_value(&self, key: RegistryKey) -> Result<()> {
+        unsafe {
+            if !self.owns_registry_value(&key) {
+                return Err(Error::MismatchedRegistryKey);
+            }
+
+            ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, key.take());
+            Ok(())
+        }
+    }
+
+    /// Returns true if the given `RegistryKey` was created by a `Lua` which shares the underlying
+    /// main state with this `Lua` instance.
+    ///
+    /// Other than this, methods that accept a `RegistryKey` will return
+    /// `Error::MismatchedRegistryKey` if passed a `RegistryKey` that was not created with a
+    /// matching `Lua` state.
+    pub fn owns_registry_value(&self, key: &RegistryKey) -> bool {
+        unsafe {
+            Arc::ptr_eq(
+                &key.unref_list,
+                &(*extra_data(self.state)).registry_unref_list,
+            )
+        }
+    }
+
+    /// Returns true if this `Lua` instance and `other` share the same underlying main state.
+    ///
+    /// Handles such as `Table` or `Function` created by one `Lua` can only be used with another
+    /// `Lua` if the two are compatible, such as a `Lua` and the one passed to a callback it runs.
+    /// Otherwise, methods that are passed handles from an incompatible `Lua` return
+    /// `Error::MismatchedLuaState`.
+    pub fn compatible_with(&self, other: &Lua) -> bool {
+        self.main_state == other.main_state
+    }
+
+    /// Remove any registry values whose `RegistryKey`s have all been dropped.
+    ///
+    /// Unlike normal handle values, `RegistryKey`s do not automatically remove themselves on Drop,
+    /// but you can call this method to remove any unreachable registry values not manually removed
+    /// by `Lua::remove_registry_value`.
+    pub fn expire_registry_values(&self) {
+        unsafe {
+            let unref_list = mem::replace(
+                &mut *(*extra_data(self.state))
+                    .registry_unref_list
+                    .lock()
+                    .unwrap(),
+                Some(Vec::new()),
+            );
+            for id in unref_list.unwrap() {
+                ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, id);
+            }
+        }
+    }
+
+    /// Finds reference cycles through userdata among the values reachable from Lua, for debugging
+    /// values which are never collected.
+    ///
+    /// This walks all tables, userdata and functions reachable from the registry (which includes
+    /// the globals and the values of every [`RegistryKey`]) through table keys and values,
+    /// metatables, user values and upvalues, and returns a description of each cycle which goes
+    /// through a userdata, such as
+    /// `userdata app::Node -user value-> table -["parent"]-> userdata app::Node`.  Cycles made of
+    /// tables and functions only, such as `_G._G`, are left out.
+    ///
+    /// The Lua garbage collector collects such cycles, but a cycle is kept alive entirely as soon
+    /// as one of its values is referenced from Rust, for example through a `RegistryKey` stored
+    /// in another userdata of the cycle, which Lua cannot see.  References held by the Rust
+    /// values of userdata are not visible here either, so the report shows the Lua side of such
+    /// cycles.  Only one cycle is reported for each reference closing a cycle, so the report is
+    /// not exhaustive when cycles overlap.
+    ///
+    /// This is a read-only diagnostic which visits every reachable value, so it should not be
+    /// used on hot paths.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, UserData};
+    /// # fn try_main() -> Result<()> {
+    /// struct Node;
+    /// impl UserData for Node {}
+    ///
+    /// let lua = Lua::new();
+    /// let node = lua.create_userdata(Node)?;
+    /// let fields = lua.create_table()?;
+    /// fields.set("owner", node.clone())?;
+    /// node.set_user_value(fields)?;
+    /// lua.globals().set("node", node)?;
+    ///
+    /// let cycles = lua.report_cycles()?;
+    /// assert_eq!(cycles.len(), 1);
+    /// assert!(cycles[0].contains(r#"-user value-> table -["owner"]->"#));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`RegistryKey`]: struct.RegistryKey.html
+    pub fn report_cycles(&self) -> Result<Vec<StdString>> {
+        cycles::find_cycles(self)
+    }
+
+    /// Returns the amount of memory (in bytes) currently used inside this Lua state.
+    pub fn used_memory(&self) -> usize {
+        unsafe {
+            let kbytes = ffi::lua_gc(self.state, ffi::LUA_GCCOUNT, 0) as usize;
+            let bytes = ffi::lua_gc(self.state, ffi::LUA_GCCOUNTB, 0) as usize;
+            kbytes * 1024 + bytes
+        }
+    }
+
+    /// Returns the most memory (in bytes) used at once inside this Lua state since it was
+    /// created, or since the last call to [`reset_peak`].
+    ///
+    /// The peak is tracked on every allocation, so it also accounts for garbage which was
+    /// collected before [`used_memory`] could be observed.  Running scripts after resetting the
+    /// peak shows how much memory they need at worst, which helps to choose memory budgets.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.reset_peak();
+    ///
+    /// lua.exec::<()>("local temporary = string.rep('x', 100000)", None)?;
+    /// lua.gc_collect()?;
+    /// assert!(lua.peak_memory() >= lua.used_memory() + 100000);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`reset_peak`]: #method.reset_peak
+    /// [`used_memory`]: #method.used_memory
+    pub fn peak_memory(&self) -> usize {
+        unsafe { (*self.allocator_state()).peak }
+    }
+
+    /// Resets the peak reported by [`peak_memory`] to the memory currently in use.
+    ///
+    /// [`peak_memory`]: #method.peak_memory
+    pub fn reset_peak(&self) {
+        unsafe {
+            let state = self.allocator_state();
+            (*state).peak = (*state).used;
+        }
+    }
+
+    unsafe fn allocator_state(&self) -> *mut AllocatorState {
+        let mut ud = ptr::null_mut();
+        ffi::lua_getallocf(self.state, &mut ud);
+        ud as *mut AllocatorState
+    }
+
+    /// Performs a full garbage-collection cycle.
+    ///
+    /// It may be necessary to call this function twice to collect all currently unreachable
+    /// objects.  Once to finish the current gc cycle, and once to start and finish the next cycle.
+    ///
+    /// Returns a `GarbageCollectorError` if a `__gc` metamethod raises an error.
+    pub fn gc_collect(&self) -> Result<()> {
+        unsafe {
+            protect_lua_closure(self.state, 0, 0, |state| {
+                ffi::lua_gc(state, ffi::LUA_GCCOLLECT, 0);
+            })
+        }
+    }
+
+    /// Performs a full garbage-collection cycle like [`gc_collect`], and returns the number of
+    /// bytes it freed.
+    ///
+    /// This is the difference between [`used_memory`] before and after the collection, which is
+    /// useful for profiling how much garbage scripts produce, for example over a frame.  Memory
+    /// allocated by `__gc` metamethods during the collection is subtracted, and if the state ends
+    /// up using more memory than before, 0 is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.gc_collect()?;
+    ///
+    /// lua.exec::<()>("local garbage = string.rep('x', 100000)", None)?;
+    /// assert!(lua.gc_collect_reporting()? >= 100000);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`gc_collect`]: #method.gc_collect
+    /// [`used_memory`]: #method.used_memory
+    pub fn gc_collect_reporting(&self) -> Result<usize> {
+        let before = self.used_memory();
+        self.gc_collect()?;
+        Ok(before.saturating_sub(self.used_memory()))
     }
 
-    /// Returns true if the given `RegistryKey` was created by a `Lua` which shares the underlying
-    /// main state with this `Lua` instance.
+    /// Runs the `__gc` metamethods of all unreachable userdata now, dropping their Rust values,
+    /// instead of leaving them to later garbage collections or to the `Drop` of this `Lua`.
     ///
-    /// Other than this, methods that accept a `RegistryKey` will return
-    /// `Error::MismatchedRegistryKey` if passed a `RegistryKey` that was not created with a
-    /// matching `Lua` state.
-    pub fn owns_registry_value(&self, key: &RegistryKey) -> bool {
-        unsafe {
-            Arc::ptr_eq(
-                &key.unref_list,
-                &(*extra_data(self.state)).registry_unref_list,
-            )
-        }
+    /// This first expires the registry values of dropped [`RegistryKey`]s like
+    /// [`expire_registry_values`], then performs two full garbage-collection cycles, so that
+    /// objects which only become unreachable when the finalizers of the first cycle have run are
+    /// finalized as well.  Userdata which are still reachable, such as from a global variable or
+    /// through a live handle, are not affected, so to drop a specific userdata before the state is
+    /// closed, remove every reference to it first.
+    ///
+    /// Lua runs the finalizers of the userdata collected in a cycle in the reverse order in which
+    /// they were marked for finalization, which for userdata created by rlua is the reverse order
+    /// of their creation.  When the `Lua` is dropped, `lua_close` runs the finalizers of all the
+    /// remaining userdata in the same order, so a userdata created after another one is always
+    /// dropped first, unless it becomes unreachable and is collected earlier.
+    ///
+    /// Returns a `GarbageCollectorError` if a `__gc` metamethod raises an error, in which case the
+    /// remaining finalizers run during a later collection.
+    ///
+    /// [`RegistryKey`]: struct.RegistryKey.html
+    /// [`expire_registry_values`]: #method.expire_registry_values
+    pub fn finalize(&self) -> Result<()> {
+        self.expire_registry_values();
+        self.gc_collect()?;
+        self.gc_collect()
     }
 
-    /// Remove any registry values whose `RegistryKey`s have all been dropped.
-    ///
-    /// Unlike normal handle values, `RegistryKey`s do not automatically remove themselves on Drop,
-    /// but you can call this method to remove any unreachable registry values not manually removed
-    /// by `Lua::remove_registry_value`.
-    pub fn expire_registry_values(&self) {
-        unsafe {
-            let unref_list = mem::replace(
-                &mut *(*extra_data(self.state))
-                    .registry_unref_list
-                    .lock()
-                    .unwrap(),
-                Some(Vec::new()),
-            );
-            for id in unref_list.unwrap() {
-                ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, id);
+    // Uses 2 stack spaces, does not call checkstack.  Returns `Error::MismatchedLuaState` if the
+    // value is a handle from an unrelated Lua state.
+    pub(crate) unsafe fn push_value(&self, value: Value) -> Result<()> {
+        match value {
+            // Avoids cloning the error, which `push_value_ref` has to do.
+            Value::Error(e) => {
+                push_wrapped_error(self.state, e);
+                Ok(())
             }
+            value => self.push_value_ref(&value),
         }
     }
 
-    // Uses 2 stack spaces, does not call checkstack
-    pub(crate) unsafe fn push_value(&self, value: Value) {
-        match value {
+    // Like `push_value`, but leaves the value to the caller, who may push it again.
+    pub(crate) unsafe fn push_value_ref(&self, value: &Value) -> Result<()> {
+        match *value {
             Value::Nil => {
                 ffi::lua_pushnil(self.state);
             }
@@ -642,30 +3455,31 @@ impl Lua {
                 ffi::lua_pushnumber(self.state, n);
             }
 
-            Value::String(s) => {
-                self.push_ref(&s.0);
+            Value::String(ref s) => {
+                self.push_checked_ref(&s.0)?;
             }
 
-            Value::Table(t) => {
-                self.push_ref(&t.0);
+            Value::Table(ref t) => {
+                self.push_checked_ref(&t.0)?;
             }
 
-            Value::Function(f) => {
-                self.push_ref(&f.0);
+            Value::Function(ref f) => {
+                self.push_checked_ref(&f.0)?;
             }
 
-            Value::Thread(t) => {
-                self.push_ref(&t.0);
+            Value::Thread(ref t) => {
+                self.push_checked_ref(&t.0)?;
             }
 
-            Value::UserData(ud) => {
-                self.push_ref(&ud.0);
+            Value::UserData(ref ud) => {
+                self.push_checked_ref(&ud.0)?;
             }
 
-            Value::Error(e) => {
-                push_wrapped_error(self.state, e);
+            Value::Error(ref e) => {
+                push_wrapped_error(self.state, e.clone());
             }
         }
+        Ok(())
     }
 
     // Uses 2 stack spaces, does not call checkstack
@@ -734,6 +3548,17 @@ impl Lua {
         ffi::lua_xmove((*extra).ref_thread, self.state, 1);
     }
 
+    // Like `push_ref`, but returns `Error::MismatchedLuaState` instead of panicking if the
+    // reference belongs to a Lua instance with a different main state.  Used for handles which are
+    // passed in by the user, and so are not guaranteed to come from this state.
+    pub(crate) unsafe fn push_checked_ref<'lua>(&'lua self, lref: &LuaRef<'lua>) -> Result<()> {
+        if lref.lua.main_state != self.main_state {
+            return Err(Error::MismatchedLuaState);
+        }
+        self.push_ref(lref);
+        Ok(())
+    }
+
     // Pops the topmost element of the stack and stores a reference to it.  This pins the object,
     // preventing garbage collection until the returned `LuaRef` is dropped.
     //
@@ -820,7 +3645,7 @@ impl Lua {
 
             for (k, m) in methods.methods {
                 push_string(self.state, &k)?;
-                self.push_value(Value::Function(self.create_callback(m)?));
+                self.push_value(Value::Function(self.create_callback(m)?))?;
                 protect_lua_closure(self.state, 3, 1, |state| {
                     ffi::lua_rawset(state, -3);
                 })?;
@@ -831,12 +3656,13 @@ impl Lua {
             })?;
         }
 
+        let finalizer = methods.meta_methods.remove(&MetaMethod::Gc);
         for (k, m) in methods.meta_methods {
             if k == MetaMethod::Index && has_methods {
                 push_string(self.state, "__index")?;
                 ffi::lua_pushvalue(self.state, -1);
                 ffi::lua_gettable(self.state, -3);
-                self.push_value(Value::Function(self.create_callback(m)?));
+                self.push_value(Value::Function(self.create_callback(m)?))?;
                 protect_lua_closure(self.state, 2, 1, |state| {
                     ffi::lua_pushcclosure(state, meta_index_impl, 2);
                 })?;
@@ -869,17 +3695,32 @@ impl Lua {
                     MetaMethod::NewIndex => "__newindex",
                     MetaMethod::Call => "__call",
                     MetaMethod::ToString => "__tostring",
+                    MetaMethod::Gc => rlua_panic!("finalizer is set as a regular metamethod"),
                 };
                 push_string(self.state, name)?;
-                self.push_value(Value::Function(self.create_callback(m)?));
+                self.push_value(Value::Function(self.create_callback(m)?))?;
                 protect_lua_closure(self.state, 3, 1, |state| {
                     ffi::lua_rawset(state, -3);
                 })?;
             }
         }
 
+        let type_name = any::type_name::<T>();
+        push_string(self.state, "__name")?;
+        push_string(self.state, type_name)?;
+        protect_lua_closure(self.state, 3, 1, |state| {
+            ffi::lua_rawset(state, -3);
+        })?;
+
         push_string(self.state, "__gc")?;
-        ffi::lua_pushcfunction(self.state, userdata_destructor::<RefCell<T>>);
+        if let Some(finalizer) = finalizer {
+            self.push_value(Value::Function(self.create_callback(finalizer)?))?;
+            protect_lua_closure(self.state, 1, 1, |state| {
+                ffi::lua_pushcclosure(state, userdata_finalizer::<T>, 1);
+            })?;
+        } else {
+            ffi::lua_pushcfunction(self.state, userdata_cell_destructor::<T>);
+        }
         protect_lua_closure(self.state, 3, 1, |state| {
             ffi::lua_rawset(state, -3);
         })?;
@@ -890,23 +3731,298 @@ impl Lua {
             ffi::lua_rawset(state, -3);
         })?;
 
+        let metatable = ffi::lua_topointer(self.state, -1);
         let id = gc_guard(self.state, || {
             ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
         });
+        let extra = extra_data(self.state);
+        (*extra).registered_userdata.insert(TypeId::of::<T>(), id);
+        (*extra)
+            .registered_userdata_info
+            .insert(metatable, (type_name, describe_userdata::<T>));
+        Ok(id)
+    }
+
+    // Returns the position of the innermost Lua function on the stack which has line information.
+    unsafe fn current_position(&self) -> Option<ErrorPosition> {
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        let mut level = 0;
+        while ffi::lua_getstack(self.state, level, &mut ar) != 0 {
+            if ffi::lua_getinfo(self.state, cstr!("Sl"), &mut ar) != 0 && ar.currentline > 0 {
+                // Unlike the chunk names in error messages, the source is never shortened.
+                let source = CStr::from_ptr(ar.source).to_string_lossy();
+                let chunk_name = if source.starts_with('=') || source.starts_with('@') {
+                    &source[1..]
+                } else {
+                    &source[..]
+                };
+                let line = ar.currentline as u32;
+                if let Some(map) = (*extra_data(self.state)).source_maps.get(&*source) {
+                    if let Some(line) = map.source_line(line) {
+                        return Some(ErrorPosition {
+                            chunk_name: map.source_name().to_owned(),
+                            line,
+                        });
+                    }
+                }
+                return Some(ErrorPosition {
+                    chunk_name: chunk_name.to_owned(),
+                    line,
+                });
+            }
+            level += 1;
+        }
+        None
+    }
+
+    // Returns the Rust type name of the userdata type whose metatable is at the given index, if the
+    // metatable is one created by `userdata_metatable`.
+    pub(crate) unsafe fn userdata_type_name(&self, index: c_int) -> Option<&'static str> {
+        let metatable = ffi::lua_topointer(self.state, index);
         (*extra_data(self.state))
-            .registered_userdata
+            .registered_userdata_info
+            .get(&metatable)
+            .map(|&(name, _)| name)
+    }
+
+    // Returns the registry id of the type's own metatable if the metatable at the given index was
+    // derived from it with `AnyUserData::derive_metatable`.
+    pub(crate) unsafe fn derived_metatable_base(&self, index: c_int) -> Option<c_int> {
+        let metatable = ffi::lua_topointer(self.state, index);
+        (*extra_data(self.state))
+            .derived_metatables
+            .get(&metatable)
+            .cloned()
+    }
+
+    // Registers the table at the top of the stack, which must be a copy of the metatable at
+    // `base_index`, as a metatable derived from it.  The table is kept alive for as long as the
+    // Lua state, so that its address is never reused by another table.  Uses 2 stack spaces, does
+    // not call checkstack.
+    pub(crate) unsafe fn register_derived_metatable(&self, base_index: c_int) {
+        let extra = extra_data(self.state);
+        let base = ffi::lua_topointer(self.state, base_index);
+        let base_id = match (*extra).derived_metatables.get(&base) {
+            Some(&id) => id,
+            None => *(*extra)
+                .registered_userdata
+                .values()
+                .find(|&&id| {
+                    ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+                    let found = ffi::lua_topointer(self.state, -1) == base;
+                    ffi::lua_pop(self.state, 1);
+                    found
+                })
+                .unwrap_or_else(|| rlua_panic!("metatable derived from an unregistered type")),
+        };
+        let info = (*extra).registered_userdata_info.get(&base).cloned();
+
+        let metatable = ffi::lua_topointer(self.state, -1);
+        ffi::lua_pushvalue(self.state, -1);
+        gc_guard(self.state, || {
+            ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
+        });
+        (*extra).derived_metatables.insert(metatable, base_id);
+        if let Some(info) = info {
+            (*extra).registered_userdata_info.insert(metatable, info);
+        }
+    }
+
+    // Like `userdata_type_name`, but returns the function describing userdata of that type.
+    pub(crate) unsafe fn userdata_describe_fn(&self, index: c_int) -> Option<DescribeUserData> {
+        let metatable = ffi::lua_topointer(self.state, index);
+        (*extra_data(self.state))
+            .registered_userdata_info
+            .get(&metatable)
+            .map(|&(_, describe)| describe)
+    }
+
+    // Returns a function which, given a function `f`, returns a function calling `f` from a frame
+    // of a Lua chunk named `label`, creating it on first use for each label.  Up to
+    // `MAX_LABELED_CALL_FACTORIES` of these are cached.
+    pub(crate) fn labeled_call_factory(&self, label: &str) -> Result<Function> {
+        // `pass` keeps the call to `f` from being a tail call, which would remove the frame.
+        const FACTORY_SOURCE: &str = "local f, pass = ..., function(...) return ... end \
+                                      return function(...) return pass(f(...)) end";
+
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 2);
+
+            let extra = extra_data(self.state);
+            if let Some(&id) = (*extra).labeled_call_factories.get(label) {
+                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+                return Ok(Function(self.pop_ref()));
+            }
+
+            let name = CString::new(format!("={}", label)).map_err(|e| {
+                Error::ToLuaConversionError {
+                    from: "&str",
+                    to: "string",
+                    message: Some(e.to_string()),
+                }
+            })?;
+            match ffi::luaL_loadbufferx(
+                self.state,
+                FACTORY_SOURCE.as_ptr() as *const c_char,
+                FACTORY_SOURCE.len(),
+                name.as_ptr(),
+                cstr!("t"),
+            ) {
+                ffi::LUA_OK => {}
+                err => return Err(pop_error(self.state, err)),
+            }
+
+            // Once the cache is full, factories for new labels are compiled on every call rather
+            // than kept, so that the cache cannot grow without bound.
+            if (*extra).labeled_call_factories.len() < MAX_LABELED_CALL_FACTORIES {
+                ffi::lua_pushvalue(self.state, -1);
+                let id = gc_guard(self.state, || {
+                    ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
+                });
+                (*extra)
+                    .labeled_call_factories
+                    .insert(label.to_owned(), id);
+            }
+            Ok(Function(self.pop_ref()))
+        }
+    }
+
+    // Returns the registry id of the metatable given to scoped userdata of type `T` once their
+    // scope ends, creating it on first use.  Its metamethods raise an `Error::ScopeExpired` naming
+    // the type.
+    pub(crate) unsafe fn scope_expired_metatable<T: 'static>(&self) -> Result<c_int> {
+        unsafe extern "C" fn scope_expired_error(state: *mut ffi::lua_State) -> c_int {
+            ffi::luaL_checkstack(state, 2, ptr::null());
+
+            callback_error(state, || {
+                // The expired userdata is not necessarily the first argument of a binary
+                // metamethod, so look for the first argument with an expired metatable.
+                let mut type_name = None;
+                for i in 1..=ffi::lua_gettop(state) {
+                    if type_name.is_none() && ffi::lua_getmetatable(state, i) != 0 {
+                        let metatable = ffi::lua_topointer(state, -1);
+                        type_name = (*extra_data(state))
+                            .scope_expired_names
+                            .get(&metatable)
+                            .cloned();
+                        ffi::lua_pop(state, 1);
+                    }
+                }
+                Err(Error::ScopeExpired {
+                    kind: "userdata",
+                    type_name,
+                })
+            })
+        }
+
+        if let Some(table_id) = (*extra_data(self.state))
+            .scope_expired_metatables
+            .get(&TypeId::of::<T>())
+        {
+            return Ok(*table_id);
+        }
+
+        let _sg = StackGuard::new(self.state);
+        assert_stack(self.state, 3);
+
+        protect_lua_closure(self.state, 0, 1, |state| {
+            ffi::lua_newtable(state);
+            set_error_metamethods(state, scope_expired_error);
+
+            ffi::lua_pushstring(state, cstr!("__metatable"));
+            ffi::lua_pushboolean(state, 0);
+            ffi::lua_rawset(state, -3);
+        })?;
+
+        let metatable = ffi::lua_topointer(self.state, -1);
+        let id = gc_guard(self.state, || {
+            ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX)
+        });
+        let extra = extra_data(self.state);
+        (*extra)
+            .scope_expired_metatables
             .insert(TypeId::of::<T>(), id);
+        (*extra)
+            .scope_expired_names
+            .insert(metatable, any::type_name::<T>());
         Ok(id)
     }
 
+    // Returns the Rust type name of the scoped userdata type whose expired metatable is at the
+    // given index, if the metatable is one created by `scope_expired_metatable`.
+    pub(crate) unsafe fn scope_expired_type_name(&self, index: c_int) -> Option<&'static str> {
+        let metatable = ffi::lua_topointer(self.state, index);
+        (*extra_data(self.state))
+            .scope_expired_names
+            .get(&metatable)
+            .cloned()
+    }
+
+    // Returns the waker of the `AsyncCall` being polled, if this is the state of its coroutine.
+    #[cfg(feature = "async")]
+    pub(crate) fn current_waker(&self) -> Option<Waker> {
+        unsafe {
+            match (*extra_data(self.state)).waker {
+                Some((ref waker, thread)) if thread == self.state => Some(waker.clone()),
+                _ => None,
+            }
+        }
+    }
+
+    // Sets the waker of the `AsyncCall` being polled, along with the state of its coroutine,
+    // returning the previous ones.
+    #[cfg(feature = "async")]
+    pub(crate) fn set_current_waker(
+        &self,
+        waker: Option<(Waker, *mut ffi::lua_State)>,
+    ) -> Option<(Waker, *mut ffi::lua_State)> {
+        unsafe { mem::replace(&mut (*extra_data(self.state)).waker, waker) }
+    }
+
+    // Converts a value to a string like the Lua `tostring` function, respecting `__tostring` and
+    // `__name` metatable fields.
+    pub(crate) fn to_display_string(&self, value: Value) -> Result<StdString> {
+        let string = unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 4);
+
+            self.push_value(value)?;
+            protect_lua_closure(self.state, 1, 1, |state| {
+                ffi::luaL_tolstring(state, -1, ptr::null_mut());
+            })?;
+            String(self.pop_ref())
+        };
+        Ok(StdString::from_utf8_lossy(string.as_bytes()).into_owned())
+    }
+
     pub(crate) fn create_callback<'lua, 'callback>(
         &'lua self,
         func: Callback<'callback, 'static>,
     ) -> Result<Function<'lua>> {
+        unsafe {
+            let _sg = StackGuard::new(self.state);
+            assert_stack(self.state, 4);
+
+            self.push_function_callback(func)?;
+            Ok(Function(self.pop_ref()))
+        }
+    }
+
+    // Pushes a Lua function calling `func` with the converted arguments.  Uses 4 stack spaces, does
+    // not call checkstack.
+    unsafe fn push_function_callback<'callback>(
+        &self,
+        func: Callback<'callback, 'static>,
+    ) -> Result<()> {
         unsafe extern "C" fn call_callback(state: *mut ffi::lua_State) -> c_int {
             callback_error(state, || {
+                // The upvalue is only cleared when the scope that created the callback ends.
                 if ffi::lua_type(state, ffi::lua_upvalueindex(1)) == ffi::LUA_TNIL {
-                    return Err(Error::CallbackDestructed);
+                    return Err(Error::ScopeExpired {
+                        kind: "function",
+                        type_name: None,
+                    });
                 }
 
                 let nargs = ffi::lua_gettop(state);
@@ -930,36 +4046,40 @@ impl Lua {
                 let func = get_userdata::<Callback>(state, ffi::lua_upvalueindex(1));
 
                 let results = (*func)(&lua, args)?;
+                check_callback_stack(state)?;
                 let nresults = results.len() as c_int;
 
                 check_stack(state, nresults)?;
                 for r in results {
-                    lua.push_value(r);
+                    lua.push_value(r)?;
                 }
 
                 Ok(nresults)
             })
         }
 
-        unsafe {
-            let _sg = StackGuard::new(self.state);
-            assert_stack(self.state, 4);
-
-            push_userdata::<Callback>(self.state, func)?;
+        self.push_callback(func, call_callback)
+    }
 
-            ffi::lua_pushlightuserdata(
-                self.state,
-                &FUNCTION_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
-            );
-            ffi::lua_rawget(self.state, ffi::LUA_REGISTRYINDEX);
-            ffi::lua_setmetatable(self.state, -2);
+    // Pushes a Lua function created from `func` and the C function `call`, which receives the
+    // callback userdata as its first upvalue.  Uses 4 stack spaces, does not call checkstack.
+    unsafe fn push_callback<'callback>(
+        &self,
+        func: Callback<'callback, 'static>,
+        call: ffi::lua_CFunction,
+    ) -> Result<()> {
+        push_userdata::<Callback>(self.state, func)?;
 
-            protect_lua_closure(self.state, 1, 1, |state| {
-                ffi::lua_pushcclosure(state, call_callback, 1);
-            })?;
+        ffi::lua_pushlightuserdata(
+            self.state,
+            &FUNCTION_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+        );
+        ffi::lua_rawget(self.state, ffi::LUA_REGISTRYINDEX);
+        ffi::lua_setmetatable(self.state, -2);
 
-            Ok(Function(self.pop_ref()))
-        }
+        protect_lua_closure(self.state, 1, 1, |state| {
+            ffi::lua_pushcclosure(state, call, 1);
+        })
     }
 
     // Does not require Send bounds, which can lead to unsafety.
@@ -984,47 +4104,294 @@ impl Lua {
     }
 }
 
+// The Lua hook function used for hooks set with `Lua::set_hook`.  Lua disables hooks on a thread
+// while its hook is running, but the callback could still be reentered from a coroutine resumed by
+// the callback, in which case the nested call is skipped.
+unsafe extern "C" fn hook_proc(state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+    callback_error(state, || {
+        // Cloned so that the callback stays alive if it replaces or removes the hook.
+        let callback = match (*extra_data(state)).hook_callback {
+            Some(ref callback) => callback.clone(),
+            None => return Ok(()),
+        };
+        let mut callback = match callback.try_borrow_mut() {
+            Ok(callback) => callback,
+            Err(_) => return Ok(()),
+        };
+
+        let lua = Lua {
+            state: state,
+            main_state: main_state(state),
+            ephemeral: true,
+            _phantom: PhantomData,
+        };
+        (&mut *callback)(&Debug::new(&lua, ar))
+    })
+}
+
+pub(crate) type DescribeUserData = unsafe fn(*const c_void) -> UserDataInfo;
+
 // Data associated with the main lua_State via lua_getextraspace.
 struct ExtraData {
     registered_userdata: HashMap<TypeId, c_int>,
+    // The name and `describe_userdata` function of every registered type, by metatable address.
+    registered_userdata_info: HashMap<*const c_void, (&'static str, DescribeUserData)>,
+    // The registry id of the type's metatable, by address of every metatable derived from it
+    // with `AnyUserData::derive_metatable`.
+    derived_metatables: HashMap<*const c_void, c_int>,
+    scope_expired_metatables: HashMap<TypeId, c_int>,
+    scope_expired_names: HashMap<*const c_void, &'static str>,
+    // Boxed so that borrows of app data stay valid while other types are inserted.
+    app_data: HashMap<TypeId, Box<RefCell<Box<Any + Send>>>>,
+
+    panic_hook: Option<Box<Fn(&str, Option<&str>) + Send>>,
+    hook_callback: Option<HookCallback>,
+    hook_triggers: HookTriggers,
+    // The threads running `Table::get_with_fuel` or `Table::set_with_fuel`, and whether their fuel
+    // has run out.
+    fueled_threads: HashMap<*mut ffi::lua_State, bool>,
+    breakpoints: HashMap<StdString, HashSet<u32>>,
+    // The diagnostics collected by the innermost running `exec_with_diagnostics`, if any.
+    diagnostics: Option<Vec<Diagnostic>>,
+    // The waker of the innermost `AsyncCall` being polled, if any, and the state of its coroutine.
+    #[cfg(feature = "async")]
+    waker: Option<(Waker, *mut ffi::lua_State)>,
+    source_maps: HashMap<StdString, SourceMap>,
+
+    max_chunk_size: usize,
+    retain_sources: bool,
+    // The registry id of the table of retained sources, if any source has been retained.
+    retained_sources: Option<c_int>,
     registry_unref_list: Arc<Mutex<Option<Vec<c_int>>>>,
 
+    labeled_call_factories: HashMap<StdString, c_int>,
+    string_cache: HashMap<StdString, c_int>,
+    string_cache_capacity: usize,
+
     ref_thread: *mut ffi::lua_State,
     ref_stack_size: c_int,
     ref_stack_max: c_int,
     ref_free: Vec<c_int>,
 }
 
+// Opens the restricted `debug` library of `Lua::new_with_safe_debug`.
+unsafe extern "C" fn open_safe_debug(state: *mut ffi::lua_State) -> c_int {
+    // `debug.getinfo` without the `f` option, and with its stack levels adjusted so that it can be
+    // called through this function.
+    unsafe extern "C" fn safe_getinfo(state: *mut ffi::lua_State) -> c_int {
+        let arg = if ffi::lua_type(state, 1) == ffi::LUA_TTHREAD {
+            1
+        } else {
+            0
+        };
+        ffi::lua_settop(state, arg + 2);
+
+        if ffi::lua_isnil(state, arg + 2) != 0 {
+            ffi::lua_pushstring(state, cstr!("lnStu"));
+            ffi::lua_replace(state, arg + 2);
+        } else {
+            let mut len = 0;
+            let options = ffi::lua_tolstring(state, arg + 2, &mut len);
+            if !options.is_null() {
+                let options = slice::from_raw_parts(options as *const u8, len);
+                let mut safe = [0; 6];
+                let mut safe_len = 0;
+                for &option in options {
+                    if b"SlnutL".contains(&option) && !safe[..safe_len].contains(&option) {
+                        safe[safe_len] = option;
+                        safe_len += 1;
+                    }
+                }
+                ffi::lua_pushlstring(state, safe.as_ptr() as *const c_char, safe_len);
+                ffi::lua_replace(state, arg + 2);
+            }
+        }
+
+        if arg == 0 && ffi::lua_type(state, 1) == ffi::LUA_TNUMBER {
+            let mut isnum = 0;
+            let level = ffi::lua_tointegerx(state, 1, &mut isnum);
+            if isnum != 0 && level >= 0 && level < ffi::lua_Integer::max_value() {
+                ffi::lua_pushinteger(state, level + 1);
+                ffi::lua_replace(state, 1);
+            }
+        }
+
+        ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+        ffi::lua_insert(state, 1);
+        ffi::lua_call(state, arg + 2, 1);
+        1
+    }
+
+    ffi::luaopen_debug(state);
+    ffi::lua_createtable(state, 0, 2);
+
+    ffi::lua_pushstring(state, cstr!("traceback"));
+    ffi::lua_pushstring(state, cstr!("traceback"));
+    ffi::lua_rawget(state, -4);
+    ffi::lua_rawset(state, -3);
+
+    ffi::lua_pushstring(state, cstr!("getinfo"));
+    ffi::lua_pushstring(state, cstr!("getinfo"));
+    ffi::lua_rawget(state, -4);
+    ffi::lua_pushcclosure(state, safe_getinfo, 1);
+    ffi::lua_rawset(state, -3);
+
+    1
+}
+
+// The `__gc` metamethod of userdata types with a `MetaMethod::Gc` finalizer, which is the first
+// upvalue.  The finalizer is skipped while the Lua state is being closed, and its errors are
+// ignored, since an error in `__gc` would otherwise be raised from whatever triggered the
+// collection.  The userdata is dropped either way, unless it is still borrowed.
+unsafe extern "C" fn userdata_finalizer<T>(state: *mut ffi::lua_State) -> c_int {
+    callback_error(state, || check_userdata_cell_unborrowed::<T>(state, 1));
+    if !extra_data(state).is_null() {
+        ffi::luaL_checkstack(state, 2, ptr::null());
+        ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+        ffi::lua_pushvalue(state, 1);
+        if ffi::lua_pcall(state, 1, 0, 0) != ffi::LUA_OK {
+            ffi::lua_pop(state, 1);
+        }
+    }
+    ffi::lua_settop(state, 1);
+    userdata_cell_destructor::<T>(state)
+}
+
+// Lua copies the extra space of the main thread into every new thread, so this returns the same
+// `ExtraData` for the main state and for every thread (such as the state of an ephemeral `Lua` in
+// a callback running in a coroutine) created after `create_lua` set it up.
 unsafe fn extra_data(state: *mut ffi::lua_State) -> *mut ExtraData {
     *(ffi::lua_getextraspace(state) as *mut *mut ExtraData)
 }
 
-unsafe fn create_lua(load_debug: bool) -> Lua {
-    unsafe extern "C" fn allocator(
-        _: *mut c_void,
-        ptr: *mut c_void,
-        _: usize,
-        nsize: usize,
-    ) -> *mut c_void {
-        if nsize == 0 {
-            libc::free(ptr as *mut libc::c_void);
-            ptr::null_mut()
-        } else {
-            let p = libc::realloc(ptr as *mut libc::c_void, nsize);
-            if p.is_null() {
-                // We require that OOM results in an abort, and that the lua allocator function
-                // never errors.  Since this is what rust itself normally does on OOM, this is
-                // not really a huge loss.  Importantly, this allows us to turn off the gc, and
-                // then know that calling Lua API functions marked as 'm' will not result in a
-                // 'longjmp' error while the gc is off.
-                abort!("out of memory in Lua allocation, aborting!");
-            } else {
-                p as *mut c_void
-            }
+// Translates the positions in an error message or traceback of chunks with a source map set with
+// `Lua::set_source_map`.
+pub(crate) unsafe fn translate_source_positions(
+    state: *mut ffi::lua_State,
+    message: StdString,
+) -> StdString {
+    let extra = extra_data(state);
+    if extra.is_null() || (*extra).source_maps.is_empty() {
+        message
+    } else {
+        translate_positions(&(*extra).source_maps, message)
+    }
+}
+
+// Replaces the hook of a coroutine which inherited a hook meant only for the thread it was created
+// in, such as the count hooks of `Table::get_with_fuel` and `Thread::set_resume_limit`, with the
+// hook set with `Lua::set_hook`, if any.
+pub(crate) unsafe fn reset_inherited_hook(state: *mut ffi::lua_State) {
+    let extra = extra_data(state);
+    if (*extra).hook_callback.is_some() {
+        let triggers = (*extra).hook_triggers;
+        ffi::lua_sethook(state, Some(hook_proc), triggers.mask(), triggers.count());
+    } else {
+        ffi::lua_sethook(state, None, 0, 0);
+    }
+}
+
+// Returns the threads running `Table::get_with_fuel` or `Table::set_with_fuel`, and whether their
+// fuel has run out.
+pub(crate) unsafe fn fueled_threads(
+    state: *mut ffi::lua_State,
+) -> *mut HashMap<*mut ffi::lua_State, bool> {
+    &mut (*extra_data(state)).fueled_threads
+}
+
+// Calls the panic hook (if any) for a panic caught inside the callback currently running on the
+// given state.  Never panics or triggers a Lua error.
+pub(crate) unsafe fn call_panic_hook(state: *mut ffi::lua_State, payload: &(Any + Send)) {
+    let extra = extra_data(state);
+    if extra.is_null() {
+        return;
+    }
+    let hook = match (*extra).panic_hook {
+        Some(ref hook) => hook,
+        None => return,
+    };
+
+    let message = panic_message(payload);
+    let message = message.as_ref().map_or("Box<Any>", |message| message.as_str());
+
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    let name = if ffi::lua_getstack(state, 0, &mut ar) != 0
+        && ffi::lua_getinfo(state, cstr!("n"), &mut ar) != 0
+        && !ar.name.is_null()
+    {
+        CStr::from_ptr(ar.name).to_str().ok()
+    } else {
+        None
+    };
+
+    let _ = catch_unwind(AssertUnwindSafe(|| hook(message, name)));
+}
+
+// The userdata wrapper for data stored with `Lua::create_registry_data`.
+struct RegistryData<T>(T);
+
+impl<T: 'static + Send> UserData for RegistryData<T> {}
+
+fn app_data_borrow_error<T: 'static>(mutable: bool) -> Error {
+    Error::RecursiveCallback {
+        resource: format!("app data `{}`", any::type_name::<T>()),
+        mutable,
+    }
+}
+
+fn take_app_data<T: 'static>(cell: RefCell<Box<Any + Send>>) -> T {
+    match cell.into_inner().downcast::<T>() {
+        Ok(data) => *data,
+        Err(_) => rlua_panic!("app data type mismatch"),
+    }
+}
+
+// Skips a UTF-8 byte order mark, which Lua would otherwise reject as an unexpected symbol.
+fn skip_bom(source: &str) -> &str {
+    if source.starts_with('\u{feff}') {
+        &source['\u{feff}'.len_utf8()..]
+    } else {
+        source
+    }
+}
+
+// Recognizes UTF-16 text by its byte order mark, or by the NUL byte that encodes the high or low
+// half of an ASCII character, which cannot start a Lua chunk.
+fn utf16_encoding(source: &[u8]) -> Option<&'static str> {
+    match source {
+        [0xff, 0xfe, ..] => Some("UTF-16LE"),
+        [0xfe, 0xff, ..] => Some("UTF-16BE"),
+        [c, 0, ..] if *c != 0 => Some("UTF-16LE"),
+        [0, c, ..] if *c != 0 => Some("UTF-16BE"),
+        _ => None,
+    }
+}
+
+// Skips a first line starting with '#', keeping its newline so that line numbers are unchanged.
+fn skip_shebang(source: &str) -> &str {
+    if source.starts_with('#') {
+        match source.find('\n') {
+            Some(i) => &source[i..],
+            None => "",
         }
+    } else {
+        source
+    }
+}
+
+fn non_finite_message(n: Number) -> StdString {
+    if n.is_nan() {
+        "NaN has no integer representation".to_owned()
+    } else {
+        "infinity has no integer representation".to_owned()
     }
+}
 
-    let state = ffi::lua_newstate(allocator, ptr::null_mut());
+unsafe fn create_lua(load_debug: bool, allocator: Option<Box<LuaAllocator>>) -> Lua {
+    let state = ffi::lua_newstate(
+        allocator_trampoline,
+        Box::into_raw(Box::new(AllocatorState::new(allocator))) as *mut c_void,
+    );
 
     // Ignores or `unwrap()`s 'm' errors, because we are making the assumption that nothing in
     // the lua standard library will have a `__gc` metamethod error.
@@ -1091,7 +4458,27 @@ unsafe fn create_lua(load_debug: bool) -> Lua {
 
     let extra = Box::into_raw(Box::new(ExtraData {
         registered_userdata: HashMap::new(),
+        registered_userdata_info: HashMap::new(),
+        derived_metatables: HashMap::new(),
+        scope_expired_metatables: HashMap::new(),
+        scope_expired_names: HashMap::new(),
+        app_data: HashMap::new(),
+        panic_hook: None,
+        hook_callback: None,
+        hook_triggers: HookTriggers::default(),
+        fueled_threads: HashMap::new(),
+        breakpoints: HashMap::new(),
+        diagnostics: None,
+        #[cfg(feature = "async")]
+        waker: None,
+        source_maps: HashMap::new(),
+        max_chunk_size: 0,
+        retain_sources: false,
+        retained_sources: None,
         registry_unref_list: Arc::new(Mutex::new(Some(Vec::new()))),
+        labeled_call_factories: HashMap::new(),
+        string_cache: HashMap::new(),
+        string_cache_capacity: 0,
         ref_thread,
         // We need 1 extra stack space to move values in and out of the ref stack.
         ref_stack_size: ffi::LUA_MINSTACK - 1,
@@ -1129,4 +4516,8 @@ unsafe fn ref_stack_pop(extra: *mut ExtraData) -> c_int {
     }
 }
 
+// The number of labels whose `Function::call_labeled` factory is cached.
+const MAX_LABELED_CALL_FACTORIES: usize = 256;
+
 static FUNCTION_METATABLE_REGISTRY_KEY: u8 = 0;
+static FROZEN_GLOBALS_METATABLE_REGISTRY_KEY: u8 = 0;
@@ -1,10 +1,11 @@
 use std::iter::FromIterator;
 use std::ops::{Deref, DerefMut};
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
-use error::Result;
+use error::{Error, Result};
 use lua::Lua;
-use value::{FromLua, FromLuaMulti, MultiValue, Nil, ToLua, ToLuaMulti};
+use value::{FromLua, FromLuaMulti, MultiValue, Nil, ToLua, ToLuaMulti, Value};
 
 /// Result is convertible to `MultiValue` following the common Lua idiom of returning the result
 /// on success, or in the case of an error, returning `nil` and an error message.
@@ -24,6 +25,24 @@ impl<'lua, T: ToLua<'lua>, E: ToLua<'lua>> ToLuaMulti<'lua> for StdResult<T, E>
     }
 }
 
+/// The inverse of the `ToLuaMulti` implementation for `Result`, for calling functions which follow
+/// the Lua idiom of returning `nil` and an error message on failure.
+///
+/// Converts to `Err` if the first value is `nil` and the second one is not, and to `Ok` with the
+/// first value otherwise, so that a successful `nil` result (as an `Option`) is not mistaken for an
+/// error.
+impl<'lua, T: FromLua<'lua>, E: FromLua<'lua>> FromLuaMulti<'lua> for StdResult<T, E> {
+    fn from_lua_multi(mut values: MultiValue<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let first = values.pop_front().unwrap_or(Nil);
+        let second = values.pop_front().unwrap_or(Nil);
+        match (first, second) {
+            (Nil, Nil) => Ok(Ok(T::from_lua(Nil, lua)?)),
+            (Nil, err) => Ok(Err(E::from_lua(err, lua)?)),
+            (value, _) => Ok(Ok(T::from_lua(value, lua)?)),
+        }
+    }
+}
+
 impl<'lua, T: ToLua<'lua>> ToLuaMulti<'lua> for T {
     fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
         let mut v = MultiValue::new();
@@ -36,6 +55,14 @@ impl<'lua, T: FromLua<'lua>> FromLuaMulti<'lua> for T {
     fn from_lua_multi(mut values: MultiValue<'lua>, lua: &'lua Lua) -> Result<Self> {
         Ok(T::from_lua(values.pop_front().unwrap_or(Nil), lua)?)
     }
+
+    fn from_lua_args(
+        mut values: MultiValue<'lua>,
+        position: usize,
+        lua: &'lua Lua,
+    ) -> Result<Self> {
+        from_lua_arg(values.pop_front().unwrap_or(Nil), position, lua)
+    }
 }
 
 impl<'lua> ToLuaMulti<'lua> for MultiValue<'lua> {
@@ -134,6 +161,69 @@ impl<'lua, T: FromLua<'lua>> FromLuaMulti<'lua> for Variadic<T> {
             .collect::<Result<Vec<T>>>()
             .map(Variadic)
     }
+
+    fn from_lua_args(values: MultiValue<'lua>, position: usize, lua: &'lua Lua) -> Result<Self> {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| from_lua_arg(e, position + i, lua))
+            .collect::<Result<Vec<T>>>()
+            .map(Variadic)
+    }
+}
+
+/// Wraps a tuple to convert it to and from a single sequence table, rather than multiple values.
+///
+/// Tuples themselves implement [`ToLuaMulti`] and [`FromLuaMulti`], and are spread into (or
+/// collected from) several separate values, such as the arguments of a function.  A
+/// `TupleTable` instead implements [`ToLua`] and [`FromLua`]: `TupleTable((a, b, c))` becomes
+/// the one table `{a, b, c}`, which is convenient for passing a fixed-length record as a single
+/// argument or storing it in a table.
+///
+/// Converting from Lua requires a table whose length (as given by the `#` operator, without
+/// metamethods) is exactly the arity of the tuple, and fails with a `FromLuaConversionError`
+/// otherwise.  Since a `nil` element ends the sequence, elements converting to `nil` (such as
+/// `None`) make the length ambiguous and should be avoided.  Tuples of up to 12 elements are
+/// supported.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, Result, TupleTable};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let describe = lua.create_function(|_, TupleTable((name, age)): TupleTable<(String, u32)>| {
+///     Ok(format!("{} is {}", name, age))
+/// })?;
+/// lua.globals().set("describe", describe)?;
+///
+/// assert_eq!(lua.eval::<String>("describe({'Ada', 36})", None)?, "Ada is 36");
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`ToLua`]: trait.ToLua.html
+/// [`FromLua`]: trait.FromLua.html
+/// [`ToLuaMulti`]: trait.ToLuaMulti.html
+/// [`FromLuaMulti`]: trait.FromLuaMulti.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TupleTable<T>(pub T);
+
+// Converts the argument at `position`, reporting a failure as a `BadArgument` error.
+fn from_lua_arg<'lua, T: FromLua<'lua>>(
+    value: Value<'lua>,
+    position: usize,
+    lua: &'lua Lua,
+) -> Result<T> {
+    T::from_lua(value, lua).map_err(|err| Error::BadArgument {
+        function: None,
+        position,
+        cause: Arc::new(err),
+    })
 }
 
 macro_rules! impl_tuple {
@@ -178,6 +268,71 @@ macro_rules! impl_tuple {
                 let $last = FromLuaMulti::from_lua_multi(values, lua)?;
                 Ok(($(FromLua::from_lua($name, lua)?,)* $last,))
             }
+
+            #[allow(unused_mut)]
+            #[allow(non_snake_case)]
+            fn from_lua_args(
+                mut values: MultiValue<'lua>,
+                mut position: usize,
+                lua: &'lua Lua,
+            ) -> Result<Self> {
+                $(let $name = values.pop_front().unwrap_or(Nil);)*
+                $(
+                    let $name = from_lua_arg($name, position, lua)?;
+                    position += 1;
+                )*
+                let $last = FromLuaMulti::from_lua_args(values, position, lua)?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    );
+}
+
+macro_rules! impl_tuple_table {
+    ($len:expr; $($name:ident)+) => (
+        impl<'lua, $($name: ToLua<'lua>,)+> ToLua<'lua> for TupleTable<($($name,)+)> {
+            #[allow(non_snake_case)]
+            fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+                let ($($name,)+) = self.0;
+                let table = lua.create_table()?;
+                let mut index = 0;
+                $(
+                    index += 1;
+                    table.raw_set(index, $name)?;
+                )+
+                Ok(Value::Table(table))
+            }
+        }
+
+        impl<'lua, $($name: FromLua<'lua>,)+> FromLua<'lua> for TupleTable<($($name,)+)> {
+            fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+                let table = match value {
+                    Value::Table(table) => table,
+                    value => {
+                        return Err(Error::FromLuaConversionError {
+                            from: value.type_name(),
+                            to: "TupleTable",
+                            message: Some("expected table".to_string()),
+                        })
+                    }
+                };
+                let len = table.raw_len();
+                if len != $len {
+                    return Err(Error::FromLuaConversionError {
+                        from: "table",
+                        to: "TupleTable",
+                        message: Some(format!(
+                            "expected a sequence of length {}, got length {}",
+                            $len, len
+                        )),
+                    });
+                }
+                let mut index = 0;
+                Ok(TupleTable(($({
+                    index += 1;
+                    table.raw_get::<_, $name>(index)?
+                },)+)))
+            }
         }
     );
 }
@@ -208,3 +363,16 @@ impl_tuple!{A B C D E F G H I}
 impl_tuple!{A B C D E F G H I J}
 impl_tuple!{A B C D E F G H I J K}
 impl_tuple!{A B C D E F G H I J K L}
+
+impl_tuple_table!{1; A}
+impl_tuple_table!{2; A B}
+impl_tuple_table!{3; A B C}
+impl_tuple_table!{4; A B C D}
+impl_tuple_table!{5; A B C D E}
+impl_tuple_table!{6; A B C D E F}
+impl_tuple_table!{7; A B C D E F G}
+impl_tuple_table!{8; A B C D E F G H}
+impl_tuple_table!{9; A B C D E F G H I}
+impl_tuple_table!{10; A B C D E F G H I J}
+impl_tuple_table!{11; A B C D E F G H I J K}
+impl_tuple_table!{12; A B C D E F G H I J K L}
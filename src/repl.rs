@@ -0,0 +1,135 @@
+use std::string::String as StdString;
+
+use error::{Error, Result};
+use lua::Lua;
+use value::MultiValue;
+
+/// The result of feeding a line of input to a [`ReplSession`].
+///
+/// [`ReplSession`]: struct.ReplSession.html
+#[derive(Debug)]
+pub enum ReplStatus<'lua> {
+    /// The input so far is an incomplete statement, such as the first line of a function
+    /// definition.  It has been kept, and more input is needed before it can be run.
+    Incomplete,
+    /// The input formed a complete expression or chunk, which was run with the given results.
+    Complete(MultiValue<'lua>),
+}
+
+/// An interactive session, feeding Lua code to a `Lua` state one line at a time.
+///
+/// This implements the behavior of the standalone `lua` interpreter's prompt: lines are
+/// accumulated until they form a complete chunk, expressions are evaluated and their values
+/// returned, and statements are executed.  All input runs in the global environment, so variables
+/// assigned in one input are visible to the following ones, like cells in a notebook.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, ReplSession, ReplStatus, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let mut session = ReplSession::new(&lua);
+///
+/// for line in &["function square(x)", "  return x * x", "end"] {
+///     session.feed(line)?;
+/// }
+/// match session.feed("square(7), square(8)")? {
+///     ReplStatus::Complete(values) => assert_eq!(session.format_values(values)?, "49\t64"),
+///     ReplStatus::Incomplete => unreachable!(),
+/// }
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+pub struct ReplSession<'lua> {
+    lua: &'lua Lua,
+    name: Option<StdString>,
+    pending: StdString,
+}
+
+impl<'lua> ReplSession<'lua> {
+    /// Creates a new session running code in the given `Lua` state.
+    pub fn new(lua: &'lua Lua) -> ReplSession<'lua> {
+        ReplSession {
+            lua,
+            name: None,
+            pending: StdString::new(),
+        }
+    }
+
+    /// Sets the chunk name used for the code run by this session, which appears in error messages
+    /// and tracebacks.
+    pub fn set_name(&mut self, name: Option<&str>) {
+        self.name = name.map(|name| name.to_owned());
+    }
+
+    /// Feeds a line of input to the session.
+    ///
+    /// The line is appended to any pending input.  If the pending input is now a complete
+    /// expression or chunk, it is run and its results are returned.  If it is an incomplete
+    /// statement, `ReplStatus::Incomplete` is returned and the input is kept until the next call.
+    ///
+    /// As in the standalone `lua` interpreter, the input is first tried as an expression whose
+    /// value is returned, and then as a sequence of statements.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `SyntaxError` if the input can not be completed by adding more lines, or the
+    /// error raised when running the input.  In both cases the pending input is discarded.
+    pub fn feed(&mut self, line: &str) -> Result<ReplStatus<'lua>> {
+        if !self.pending.is_empty() {
+            self.pending.push('\n');
+        }
+        self.pending.push_str(line);
+
+        let name = self.name.as_ref().map(|name| name.as_str());
+        let expression = format!("return {}", self.pending);
+        let loaded = match self.lua.load_retaining(&expression, name, Some(&self.pending)) {
+            Err(Error::SyntaxError { .. }) => self.lua.load(&self.pending, name),
+            loaded => loaded,
+        };
+        let function = match loaded {
+            Ok(function) => function,
+            Err(Error::SyntaxError {
+                incomplete_input: true,
+                ..
+            }) => return Ok(ReplStatus::Incomplete),
+            Err(err) => {
+                self.pending.clear();
+                return Err(err);
+            }
+        };
+
+        self.pending.clear();
+        Ok(ReplStatus::Complete(function.call(())?))
+    }
+
+    /// Returns true if the session holds an incomplete statement waiting for more input.
+    ///
+    /// This can be used to choose between a primary and a continuation prompt.
+    pub fn is_incomplete(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Discards any pending incomplete input.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Formats the results of a completed input for display, separated by tabs.
+    ///
+    /// Each value is converted like the Lua `tostring` function would, respecting `__tostring`
+    /// and `__name` metatable fields, so the output matches what the standalone `lua` interpreter
+    /// prints.  Returns an error if a `__tostring` metamethod fails.
+    pub fn format_values(&self, values: MultiValue<'lua>) -> Result<StdString> {
+        let mut formatted = Vec::with_capacity(values.len());
+        for value in values {
+            formatted.push(self.lua.to_display_string(value)?);
+        }
+        Ok(formatted.join("\t"))
+    }
+}
@@ -1,15 +1,20 @@
 use std::any::Any;
 use std::cell::RefCell;
 use std::marker::PhantomData;
-use std::mem;
+use std::os::raw::c_int;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::{mem, thread};
 
 use error::{Error, Result};
 use ffi;
 use function::Function;
 use lua::Lua;
+use thread::Thread;
 use types::Callback;
 use userdata::{AnyUserData, UserData};
-use util::{assert_stack, take_userdata, StackGuard};
+use util::{
+    assert_stack, panic_message, take_userdata, take_userdata_with_metatable, StackGuard,
+};
 use value::{FromLuaMulti, ToLuaMulti};
 
 /// Constructed by the [`Lua::scope`] method, allows temporarily passing to Lua userdata that is
@@ -80,6 +85,61 @@ impl<'scope> Scope<'scope> {
     /// This is a version of [`Lua::create_function_mut`] that creates a callback which expires on
     /// scope drop.  See [`Lua::scope`] and [`Scope::create_function`] for more details.
     ///
+    /// Because the closure only has to live as long as the scope, it can capture mutable
+    /// references to state on the Rust stack, such as a `&mut Vec` a script appends to.  The
+    /// closure is mutably borrowed for the duration of each call, so it may be called any number
+    /// of times, but calling it again while it is already running (for example through a script
+    /// function it calls) returns an `Error::RecursiveMutCallback`.
+    ///
+    /// Only one closure can capture a given `&mut` reference.  If several callbacks need to modify
+    /// the same state, put it in a `RefCell` and have each closure capture a shared reference to
+    /// it, borrowing it mutably only for the duration of a single call.  The state is available
+    /// again once `Lua::scope` returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::cell::RefCell;
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let mut events = Vec::new();
+    /// let totals = RefCell::new((0, 0));
+    ///
+    /// lua.scope(|scope| {
+    ///     let globals = lua.globals();
+    ///     globals.set("emit", scope.create_function_mut(|_, event: String| {
+    ///         events.push(event);
+    ///         Ok(())
+    ///     })?)?;
+    ///     globals.set("add_left", scope.create_function(|_, n: i32| {
+    ///         totals.borrow_mut().0 += n;
+    ///         Ok(())
+    ///     })?)?;
+    ///     globals.set("add_right", scope.create_function(|_, n: i32| {
+    ///         totals.borrow_mut().1 += n;
+    ///         Ok(())
+    ///     })?)?;
+    ///
+    ///     lua.exec::<()>(r#"
+    ///         for i = 1, 3 do
+    ///             emit("tick " .. i)
+    ///             add_left(i)
+    ///             add_right(i * 10)
+    ///         end
+    ///     "#, None)
+    /// })?;
+    ///
+    /// assert_eq!(events, vec!["tick 1", "tick 2", "tick 3"]);
+    /// assert_eq!(totals.into_inner(), (6, 60));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
     /// [`Lua::create_function_mut`]: struct.Lua.html#method.create_function_mut
     /// [`Lua::scope`]: struct.Lua.html#method.scope
     /// [`Scope::create_function`]: #method.create_function
@@ -97,6 +157,78 @@ impl<'scope> Scope<'scope> {
         })
     }
 
+    /// Creates a Lua thread (coroutine) which drives a Rust closure as a generator, and expires on
+    /// scope drop.
+    ///
+    /// Each resume of the thread calls `func` with the arguments passed to `resume`, and the
+    /// thread then yields the values returned by `func`, so the closure can keep its own state
+    /// between resumes.  The thread never returns on its own.  Like with [`create_function_mut`],
+    /// the closure only has to live as long as the scope.
+    ///
+    /// When the scope ends the closure is dropped, and resuming the thread afterwards results in a
+    /// `ScopeExpired` error, which also makes the thread dead.  See [`Lua::scope`] for more
+    /// details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let mut total = 0;
+    ///
+    /// lua.scope(|scope| {
+    ///     let counter = scope.create_thread(|_, n: i64| {
+    ///         total += n;
+    ///         Ok(total)
+    ///     })?;
+    ///     assert_eq!(counter.resume::<_, i64>(1)?, 1);
+    ///     assert_eq!(counter.resume::<_, i64>(2)?, 3);
+    ///     Ok(())
+    /// })?;
+    ///
+    /// assert_eq!(total, 3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function_mut`]: #method.create_function_mut
+    /// [`Lua::scope`]: struct.Lua.html#method.scope
+    pub fn create_thread<'lua, A, R, F>(&'lua self, func: F) -> Result<Thread<'lua>>
+    where
+        A: FromLuaMulti<'lua>,
+        R: ToLuaMulti<'lua>,
+        F: 'scope + FnMut(&'lua Lua, A) -> Result<R>,
+    {
+        unsafe extern "C" fn yield_all(state: *mut ffi::lua_State) -> c_int {
+            ffi::lua_yield(state, ffi::lua_gettop(state))
+        }
+
+        let func = self.create_function_mut(func)?;
+        let yield_all = unsafe {
+            let _sg = StackGuard::new(self.lua.state);
+            assert_stack(self.lua.state, 1);
+            ffi::lua_pushcfunction(self.lua.state, yield_all);
+            Function(self.lua.pop_ref())
+        };
+        // The body only refers to the scoped function, so the closure is not reachable from the
+        // thread once the scope has dropped it.
+        let body: Function = self
+            .lua
+            .load(
+                "local func, yield = ... \
+                 local function step(...) return step(yield(func(...))) end \
+                 return step",
+                Some("scoped thread"),
+            )?
+            .call((func, yield_all))?;
+        self.lua.create_thread(body)
+    }
+
     /// Create a Lua userdata object from a custom userdata type.
     ///
     /// This is a version of [`Lua::create_userdata`] that creates a userdata which expires on scope
@@ -110,23 +242,43 @@ impl<'scope> Scope<'scope> {
         T: UserData,
     {
         unsafe {
+            let expired_metatable = self.lua.scope_expired_metatable::<T>()?;
             let u = self.lua.make_userdata(data)?;
             let mut destructors = self.destructors.borrow_mut();
             let u_destruct = u.0.clone();
             destructors.push(Box::new(move || {
                 let state = u_destruct.lua.state;
                 let _sg = StackGuard::new(state);
-                assert_stack(state, 1);
+                assert_stack(state, 2);
                 u_destruct.lua.push_ref(&u_destruct);
-                Box::new(take_userdata::<RefCell<T>>(state))
+                ffi::lua_rawgeti(
+                    state,
+                    ffi::LUA_REGISTRYINDEX,
+                    expired_metatable as ffi::lua_Integer,
+                );
+                Box::new(take_userdata_with_metatable::<RefCell<T>>(state))
             }));
             Ok(u)
         }
     }
 }
 
-impl<'scope> Drop for Scope<'scope> {
-    fn drop(&mut self) {
+impl<'scope> Scope<'scope> {
+    // Ends the scope, returning an error if any of the values created by it panicked when dropped.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        let panics = self.destroy();
+        match panics.first() {
+            None => Ok(()),
+            Some(panic) => Err(Error::ScopeDropPanic {
+                count: panics.len(),
+                message: panic_message(&**panic),
+            }),
+        }
+    }
+
+    // Invalidates and drops every value created by this scope, returning the panic payloads of the
+    // values whose `Drop` panicked.
+    fn destroy(&mut self) -> Vec<Box<Any + Send>> {
         // We separate the action of invalidating the userdata in Lua and actually dropping the
         // userdata type into two phases.  This is so that, in the event a userdata drop panics, we
         // can be sure that all of the userdata in Lua is actually invalidated.
@@ -137,6 +289,24 @@ impl<'scope> Drop for Scope<'scope> {
             .drain(..)
             .map(|destructor| destructor())
             .collect::<Vec<_>>();
-        drop(to_drop);
+
+        // Each value is dropped separately, so that a panicking drop neither prevents the
+        // remaining values from being dropped nor causes an abort by panicking again while the
+        // next value is dropped.
+        to_drop
+            .into_iter()
+            .filter_map(|value| catch_unwind(AssertUnwindSafe(move || drop(value))).err())
+            .collect()
+    }
+}
+
+impl<'scope> Drop for Scope<'scope> {
+    fn drop(&mut self) {
+        let mut panics = self.destroy();
+        // Resuming a panic while the thread is already panicking would abort, so in that case the
+        // panics from dropping the values are discarded in favor of the original one.
+        if !panics.is_empty() && !thread::panicking() {
+            resume_unwind(panics.swap_remove(0));
+        }
     }
 }
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::os::raw::c_void;
+use std::string::String as StdString;
+
+use error::{Error, Result};
+use lua::Lua;
+use table::{table_pointer, Table};
+use types::Integer;
+use value::{Nil, Value};
+
+// Serialized values start with the format version, followed by a single encoded value.  Each value
+// is a tag byte followed by:
+//
+// - `NIL`, `FALSE`, `TRUE`: nothing
+// - `INTEGER`: the zigzag encoded integer as a LEB128 varint
+// - `NUMBER`: the 8 bytes of the float, little endian
+// - `STRING`: the length as a varint, followed by the bytes of the string
+// - `TABLE`: the length `n` of the sequence part as a varint followed by the values for the keys 1
+//   to `n`, then the number of remaining pairs as a varint followed by each key and value
+// - `TABLE_REF`: the index as a varint of a previously encoded table, in the order in which the
+//   tables were first encoded
+const FORMAT_VERSION: u8 = 1;
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const NUMBER: u8 = 4;
+const STRING: u8 = 5;
+const TABLE: u8 = 6;
+const TABLE_REF: u8 = 7;
+
+// Limits the recursion when encoding and decoding nested tables, so that deeply nested values
+// cannot overflow the Rust stack.
+const MAX_DEPTH: usize = 200;
+
+pub(crate) fn serialize<'lua>(value: Value<'lua>) -> Result<Vec<u8>> {
+    let mut serializer = Serializer {
+        out: vec![FORMAT_VERSION],
+        tables: HashMap::new(),
+    };
+    serializer.value(value, 0)?;
+    Ok(serializer.out)
+}
+
+pub(crate) fn deserialize<'lua>(lua: &'lua Lua, bytes: &[u8]) -> Result<Value<'lua>> {
+    match bytes.split_first() {
+        Some((&FORMAT_VERSION, rest)) => {
+            let mut deserializer = Deserializer {
+                lua,
+                input: rest,
+                tables: Vec::new(),
+            };
+            let value = deserializer.value(0)?;
+            if !deserializer.input.is_empty() {
+                return Err(malformed("trailing bytes after value"));
+            }
+            Ok(value)
+        }
+        Some(_) => Err(malformed("unsupported format version")),
+        None => Err(malformed("empty input")),
+    }
+}
+
+// Deep-copies `value`, which belongs to `source`, into `lua`.  This is equivalent to serializing
+// and then deserializing the value, without going through the intermediate buffer.
+pub(crate) fn import<'lua, 'src>(
+    lua: &'lua Lua,
+    source: &'src Lua,
+    value: Value<'src>,
+) -> Result<Value<'lua>> {
+    Importer {
+        lua,
+        source,
+        tables: HashMap::new(),
+    }
+    .value(value, 0)
+}
+
+struct Importer<'lua, 'src> {
+    lua: &'lua Lua,
+    source: &'src Lua,
+    // The copy of every table imported so far, by the address of the original table.
+    tables: HashMap<*const c_void, Table<'lua>>,
+}
+
+impl<'lua, 'src> Importer<'lua, 'src> {
+    fn value(&mut self, value: Value<'src>, depth: usize) -> Result<Value<'lua>> {
+        Ok(match value {
+            Value::Nil => Nil,
+            Value::Boolean(b) => Value::Boolean(b),
+            Value::Integer(i) => Value::Integer(i),
+            Value::Number(n) => Value::Number(n),
+            Value::String(s) => {
+                self.check_source(s.0.lua)?;
+                Value::String(self.lua.create_byte_string(s.as_bytes())?)
+            }
+            Value::Table(table) => Value::Table(self.table(table, depth)?),
+            value => {
+                return Err(Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "imported value",
+                    message: Some(format!("cannot import a {}", value.type_name())),
+                })
+            }
+        })
+    }
+
+    fn table(&mut self, table: Table<'src>, depth: usize) -> Result<Table<'lua>> {
+        self.check_source(table.0.lua)?;
+        let pointer = table_pointer(&table);
+        if let Some(copy) = self.tables.get(&pointer) {
+            return Ok(copy.clone());
+        }
+        if depth >= MAX_DEPTH {
+            return Err(Error::FromLuaConversionError {
+                from: "table",
+                to: "imported value",
+                message: Some(format!("tables nested more than {} levels deep", MAX_DEPTH)),
+            });
+        }
+        let copy = self.lua.create_table()?;
+        self.tables.insert(pointer, copy.clone());
+
+        for pair in table.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let key = self.value(key, depth + 1)?;
+            let value = self.value(value, depth + 1)?;
+            copy.raw_set(key, value)?;
+        }
+        Ok(copy)
+    }
+
+    fn check_source(&self, lua: &Lua) -> Result<()> {
+        if lua.compatible_with(self.source) {
+            Ok(())
+        } else {
+            Err(Error::MismatchedLuaState)
+        }
+    }
+}
+
+struct Serializer {
+    out: Vec<u8>,
+    // The index of every table encoded so far, by address.
+    tables: HashMap<*const c_void, usize>,
+}
+
+impl Serializer {
+    fn value<'lua>(&mut self, value: Value<'lua>, depth: usize) -> Result<()> {
+        match value {
+            Value::Nil => self.out.push(NIL),
+            Value::Boolean(false) => self.out.push(FALSE),
+            Value::Boolean(true) => self.out.push(TRUE),
+            Value::Integer(i) => {
+                self.out.push(INTEGER);
+                self.varint(((i << 1) ^ (i >> 63)) as u64);
+            }
+            Value::Number(n) => {
+                self.out.push(NUMBER);
+                self.out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Value::String(s) => {
+                let bytes = s.as_bytes();
+                self.out.push(STRING);
+                self.varint(bytes.len() as u64);
+                self.out.extend_from_slice(bytes);
+            }
+            Value::Table(table) => self.table(table, depth)?,
+            value => {
+                return Err(Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "serialized value",
+                    message: Some(format!("cannot serialize a {}", value.type_name())),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn table<'lua>(&mut self, table: Table<'lua>, depth: usize) -> Result<()> {
+        let pointer = table_pointer(&table);
+        if let Some(&index) = self.tables.get(&pointer) {
+            self.out.push(TABLE_REF);
+            self.varint(index as u64);
+            return Ok(());
+        }
+        if depth >= MAX_DEPTH {
+            return Err(Error::FromLuaConversionError {
+                from: "table",
+                to: "serialized value",
+                message: Some(format!("tables nested more than {} levels deep", MAX_DEPTH)),
+            });
+        }
+        let index = self.tables.len();
+        self.tables.insert(pointer, index);
+
+        let mut len: Integer = 0;
+        while !table.raw_get::<_, Value>(len + 1)?.is_nil() {
+            len += 1;
+        }
+        let mut pairs = Vec::new();
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            match key {
+                Value::Integer(i) if i >= 1 && i <= len => {}
+                key => pairs.push((key, value)),
+            }
+        }
+
+        self.out.push(TABLE);
+        self.varint(len as u64);
+        for i in 1..=len {
+            self.value(table.raw_get(i)?, depth + 1)?;
+        }
+        self.varint(pairs.len() as u64);
+        for (key, value) in pairs {
+            self.value(key, depth + 1)?;
+            self.value(value, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.out.push(v as u8);
+    }
+}
+
+struct Deserializer<'lua, 'a> {
+    lua: &'lua Lua,
+    input: &'a [u8],
+    // Every table decoded so far, in order, for resolving references.
+    tables: Vec<Table<'lua>>,
+}
+
+impl<'lua, 'a> Deserializer<'lua, 'a> {
+    fn value(&mut self, depth: usize) -> Result<Value<'lua>> {
+        Ok(match self.byte()? {
+            NIL => Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            INTEGER => {
+                let v = self.varint()?;
+                Value::Integer(((v >> 1) as i64) ^ -((v & 1) as i64))
+            }
+            NUMBER => {
+                let mut bits = [0; 8];
+                bits.copy_from_slice(self.bytes(8)?);
+                Value::Number(f64::from_bits(u64::from_le_bytes(bits)))
+            }
+            STRING => {
+                let len = self.length()?;
+                let bytes = self.bytes(len)?;
+                Value::String(self.lua.create_byte_string(bytes)?)
+            }
+            TABLE => {
+                if depth >= MAX_DEPTH {
+                    return Err(malformed("tables nested too deeply"));
+                }
+                let table = self.lua.create_table()?;
+                self.tables.push(table.clone());
+
+                let len = self.length()?;
+                for i in 1..=len {
+                    let value = self.value(depth + 1)?;
+                    table.raw_set(i, value)?;
+                }
+                let pairs = self.length()?;
+                for _ in 0..pairs {
+                    let key = self.value(depth + 1)?;
+                    let value = self.value(depth + 1)?;
+                    match key {
+                        Nil => return Err(malformed("nil table key")),
+                        Value::Number(n) if n.is_nan() => return Err(malformed("NaN table key")),
+                        key => table.raw_set(key, value)?,
+                    }
+                }
+                Value::Table(table)
+            }
+            TABLE_REF => {
+                let index = self.varint()?;
+                match self.tables.get(index as usize) {
+                    Some(table) => Value::Table(table.clone()),
+                    None => return Err(malformed("invalid table reference")),
+                }
+            }
+            _ => return Err(malformed("unknown value tag")),
+        })
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.input.len() {
+            return Err(malformed("unexpected end of input"));
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            v |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(malformed("varint too long"))
+    }
+
+    // Reads a length or count, which can never be larger than the remaining input since every
+    // element takes at least one byte.
+    fn length(&mut self) -> Result<usize> {
+        let len = self.varint()?;
+        if len > self.input.len() as u64 {
+            return Err(malformed("length exceeds input size"));
+        }
+        Ok(len as usize)
+    }
+}
+
+fn malformed(message: &str) -> Error {
+    Error::ToLuaConversionError {
+        from: "serialized value",
+        to: "Value",
+        message: Some(StdString::from(message)),
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+use std::string::String as StdString;
+
+// The maximum size of a chunk id as formatted by Lua, including the terminating nul.
+const LUA_IDSIZE: usize = 60;
+
+/// Maps the lines of a chunk of Lua code to positions in the source it was compiled from.
+///
+/// Languages which compile to Lua can register a source map for each compiled chunk with
+/// [`Lua::set_source_map`], so that the positions in the error messages and tracebacks of the
+/// chunk refer to the original source instead of the generated Lua code.
+///
+/// A Lua line without a mapping of its own is mapped like the closest preceding line which has
+/// one, since a single line of the original source usually compiles to several lines of Lua.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::SourceMap;
+/// # fn main() {
+/// let mut map = SourceMap::new("app.moon");
+/// map.add_line(1, 1);
+/// map.add_line(4, 2);
+///
+/// assert_eq!(map.source_line(3), Some(1));
+/// assert_eq!(map.source_line(5), Some(2));
+/// # }
+/// ```
+///
+/// [`Lua::set_source_map`]: struct.Lua.html#method.set_source_map
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceMap {
+    source_name: StdString,
+    lines: BTreeMap<u32, u32>,
+}
+
+impl SourceMap {
+    /// Creates an empty source map for a chunk compiled from the source named `source_name`.
+    ///
+    /// The name replaces the chunk name in translated positions, so it is usually the path of the
+    /// original source file.
+    pub fn new(source_name: &str) -> SourceMap {
+        SourceMap {
+            source_name: source_name.to_owned(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    /// Maps the line `lua_line` of the compiled chunk to the line `source_line` of the original
+    /// source, replacing any previous mapping of that line.
+    pub fn add_line(&mut self, lua_line: u32, source_line: u32) {
+        self.lines.insert(lua_line, source_line);
+    }
+
+    /// Returns the name of the original source.
+    pub fn source_name(&self) -> &str {
+        &self.source_name
+    }
+
+    /// Returns the line of the original source that the line `lua_line` of the compiled chunk
+    /// maps to, if any.
+    pub fn source_line(&self, lua_line: u32) -> Option<u32> {
+        self.lines
+            .range(..=lua_line)
+            .next_back()
+            .map(|(_, &line)| line)
+    }
+}
+
+// Replaces every `chunk:line:` position of a chunk with a source map in `message` with the mapped
+// position.  Positions on lines without a mapping are left unchanged.  Like in the messages of Lua,
+// a position must start the message or follow whitespace, so that a chunk named `a` does not
+// rewrite `data:12:`.
+pub(crate) fn translate_positions(
+    source_maps: &HashMap<StdString, SourceMap>,
+    message: StdString,
+) -> StdString {
+    let mut prefixes: Vec<_> = source_maps
+        .iter()
+        .map(|(chunk_name, map)| (format!("{}:", chunk_id(chunk_name)), map))
+        .filter(|&(ref prefix, _)| message.contains(prefix.as_str()))
+        .collect();
+    if prefixes.is_empty() {
+        return message;
+    }
+    // Longest prefix first, so that overlapping chunk names translate the same way every time.
+    prefixes.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+
+    let mut translated = StdString::with_capacity(message.len());
+    let mut rest = message.as_str();
+    let mut at_boundary = true;
+    while let Some(c) = rest.chars().next() {
+        if at_boundary {
+            if let Some((len, position)) = translate_position(rest, &prefixes) {
+                translated.push_str(&position);
+                rest = &rest[len..];
+                at_boundary = false;
+                continue;
+            }
+        }
+        translated.push(c);
+        rest = &rest[c.len_utf8()..];
+        at_boundary = c.is_whitespace();
+    }
+    translated
+}
+
+// Translates the `chunk:line` position at the start of `s`, returning the length of the translated
+// text and the mapped position.
+fn translate_position(s: &str, prefixes: &[(StdString, &SourceMap)]) -> Option<(usize, StdString)> {
+    for &(ref prefix, map) in prefixes {
+        if !s.starts_with(prefix.as_str()) {
+            continue;
+        }
+        let after = &s[prefix.len()..];
+        let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+        if !after[digits..].starts_with(':') {
+            continue;
+        }
+        let line = after[..digits]
+            .parse()
+            .ok()
+            .and_then(|line| map.source_line(line))?;
+        return Some((
+            prefix.len() + digits,
+            format!("{}:{}", map.source_name, line),
+        ));
+    }
+    None
+}
+
+// Formats a chunk name the way Lua does in error messages and tracebacks (see `luaO_chunkid`).
+fn chunk_id(chunk_name: &str) -> StdString {
+    let max_len = LUA_IDSIZE - 1;
+    if chunk_name.starts_with('=') {
+        truncate(&chunk_name[1..], max_len).to_owned()
+    } else if chunk_name.starts_with('@') {
+        let path = &chunk_name[1..];
+        if path.len() <= max_len {
+            path.to_owned()
+        } else {
+            let mut start = path.len() - (max_len - 3);
+            while !path.is_char_boundary(start) {
+                start += 1;
+            }
+            format!("...{}", &path[start..])
+        }
+    } else {
+        // Room for the name within `[string "..."]` and the terminating nul.
+        let max_len = LUA_IDSIZE - 15;
+        let first_line = chunk_name.split('\n').next().unwrap_or("");
+        if first_line.len() == chunk_name.len() && chunk_name.len() < max_len {
+            format!("[string \"{}\"]", chunk_name)
+        } else {
+            format!("[string \"{}...\"]", truncate(first_line, max_len))
+        }
+    }
+}
+
+fn truncate(s: &str, max_len: usize) -> &str {
+    let mut end = s.len().min(max_len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
@@ -1,11 +1,20 @@
+use std::cmp::{self, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::marker::PhantomData;
-use std::os::raw::c_int;
+use std::os::raw::{c_int, c_void};
+use std::string::String as StdString;
 
-use error::Result;
+use error::{Error, Result};
 use ffi;
+use lua::{fueled_threads, reset_inherited_hook};
 use types::{Integer, LuaRef};
 use util::{assert_stack, protect_lua, protect_lua_closure, StackGuard};
-use value::{FromLua, Nil, ToLua, Value};
+use value::{FromLua, HashableValue, Nil, ToLua, Value};
+
+// Limits the recursion of `Table::deep_equals`, so that deeply nested tables cannot overflow the
+// Rust stack.
+const MAX_DEEP_EQUALS_DEPTH: usize = 200;
 
 /// Handle to an internal Lua table.
 #[derive(Clone, Debug)]
@@ -58,8 +67,8 @@ impl<'lua> Table<'lua> {
             assert_stack(lua.state, 6);
 
             lua.push_ref(&self.0);
-            lua.push_value(key);
-            lua.push_value(value);
+            lua.push_value(key)?;
+            lua.push_value(value)?;
 
             unsafe extern "C" fn set_table(state: *mut ffi::lua_State) -> c_int {
                 ffi::lua_settable(state, -3);
@@ -105,7 +114,7 @@ impl<'lua> Table<'lua> {
             assert_stack(lua.state, 5);
 
             lua.push_ref(&self.0);
-            lua.push_value(key);
+            lua.push_value(key)?;
 
             unsafe extern "C" fn get_table(state: *mut ffi::lua_State) -> c_int {
                 ffi::lua_gettable(state, -2);
@@ -117,6 +126,203 @@ impl<'lua> Table<'lua> {
         V::from_lua(value, lua)
     }
 
+    /// Gets the value associated to `key` like [`get`], limiting the work done by metamethods to
+    /// `fuel` Lua instructions.
+    ///
+    /// An `__index` function may loop forever, or make a chain of proxies which index each other
+    /// too long to finish in reasonable time.  Reading from untrusted tables with this method
+    /// instead of [`get`] returns a `RuntimeError` ("metamethod chain too deep") once the
+    /// metamethods have run `fuel` instructions, rather than hanging.  Chains of `__index` tables
+    /// do not run any instructions, Lua itself gives up on them after 2000 tables.
+    ///
+    /// The limit is enforced by a count hook installed on the current thread for the duration of
+    /// the call, which temporarily replaces any hook set with [`Lua::set_hook`].  Once the fuel
+    /// has run out, every further instruction raises the error until the call returns, so
+    /// catching it with `pcall` does not help.  Coroutines run by the metamethods get `fuel`
+    /// instructions of their own.  Rust callbacks called by the metamethods are not limited.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let hostile: Table = lua.eval(
+    ///     "setmetatable({}, { __index = function() while true do end end })",
+    ///     None,
+    /// )?;
+    ///
+    /// match hostile.get_with_fuel::<_, i64>("key", 10_000) {
+    ///     Err(Error::RuntimeError(_)) => {}
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`get`]: #method.get
+    /// [`Lua::set_hook`]: struct.Lua.html#method.set_hook
+    pub fn get_with_fuel<K: ToLua<'lua>, V: FromLua<'lua>>(&self, key: K, fuel: u32) -> Result<V> {
+        let value = self.with_fuel(fuel, || self.get::<_, Value>(key))?;
+        V::from_lua(value, self.0.lua)
+    }
+
+    /// Sets a key-value pair like [`set`], limiting the work done by metamethods to `fuel` Lua
+    /// instructions.
+    ///
+    /// This is the counterpart of [`get_with_fuel`] for the `__newindex` metamethod.
+    ///
+    /// [`set`]: #method.set
+    /// [`get_with_fuel`]: #method.get_with_fuel
+    pub fn set_with_fuel<K: ToLua<'lua>, V: ToLua<'lua>>(
+        &self,
+        key: K,
+        value: V,
+        fuel: u32,
+    ) -> Result<()> {
+        self.with_fuel(fuel, || self.set(key, value))
+    }
+
+    // Calls `f` with a count hook raising an error after `fuel` instructions, and then before
+    // every instruction, restoring the previous hook afterwards.
+    fn with_fuel<R, F: FnOnce() -> Result<R>>(&self, fuel: u32, f: F) -> Result<R> {
+        // Coroutines inherit the hook of the thread they are created in.  While some thread is
+        // running with fuel, they are limited the same way, and afterwards the hook removes
+        // itself from them.
+        unsafe extern "C" fn fuel_hook(state: *mut ffi::lua_State, _ar: *mut ffi::lua_Debug) {
+            let fueled = fueled_threads(state);
+            match (*fueled).get_mut(&state) {
+                Some(exhausted) => *exhausted = true,
+                None if !(*fueled).is_empty() => {}
+                None => return reset_inherited_hook(state),
+            }
+            ffi::lua_sethook(state, Some(fuel_hook), ffi::LUA_MASKCOUNT, 1);
+            ffi::lua_pushstring(state, cstr!("metamethod chain too deep"));
+            ffi::lua_error(state);
+        }
+
+        let state = self.0.lua.state;
+        let _restore = unsafe {
+            let restore = RestoreHook {
+                state,
+                hook: ffi::lua_gethook(state),
+                mask: ffi::lua_gethookmask(state),
+                count: ffi::lua_gethookcount(state),
+                exhausted: (*fueled_threads(state)).insert(state, false),
+            };
+            let count = cmp::min(cmp::max(fuel, 1), c_int::max_value() as u32) as c_int;
+            ffi::lua_sethook(state, Some(fuel_hook), ffi::LUA_MASKCOUNT, count);
+            restore
+        };
+        f()
+    }
+
+    /// Gets the value at a path of keys separated by dots, such as `"server.port"`, from nested
+    /// tables.
+    ///
+    /// This is the same as calling [`get`] once for each key of the path, every value except the
+    /// last one being a table.  Use [`get_path_parts`] if the keys themselves may contain dots.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FromLuaConversionError` naming the part of the path that is `nil` or not a table
+    /// if the path cannot be followed, or naming the path if the final value cannot be converted
+    /// to `V`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config: Table = lua.eval("{ server = { host = 'localhost', port = 8080 } }", None)?;
+    ///
+    /// assert_eq!(config.get_path::<u16>("server.port")?, 8080);
+    /// assert_eq!(config.get_path::<Option<u16>>("server.timeout")?, None);
+    /// assert!(config.get_path::<Option<u16>>("client.timeout").is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`get`]: #method.get
+    /// [`get_path_parts`]: #method.get_path_parts
+    pub fn get_path<V: FromLua<'lua>>(&self, path: &str) -> Result<V> {
+        self.get_path_parts(&path.split('.').collect::<Vec<_>>())
+    }
+
+    /// Gets the value at a path of keys from nested tables, like [`get_path`] but with the keys
+    /// given separately, so that they may contain dots.
+    ///
+    /// An empty path refers to this table itself.
+    ///
+    /// [`get_path`]: #method.get_path
+    pub fn get_path_parts<V: FromLua<'lua>>(&self, parts: &[&str]) -> Result<V> {
+        let lua = self.0.lua;
+        let mut value = Value::Table(self.clone());
+        for (i, part) in parts.iter().enumerate() {
+            value = match value {
+                Value::Table(table) => table.get(*part)?,
+                value => {
+                    return Err(Error::FromLuaConversionError {
+                        from: value.type_name(),
+                        to: "table",
+                        message: Some(format!(
+                            "at {:?} in path {:?}",
+                            parts[..i].join("."),
+                            parts.join(".")
+                        )),
+                    })
+                }
+            };
+        }
+        V::from_lua(value, lua)
+            .map_err(|e| with_context(e, &format!("value at path {:?}", parts.join("."))))
+    }
+
+    /// Gets the value associated to `key`, returning `None` if there is no such value.
+    ///
+    /// Unlike `get::<_, V>`, a missing value (`nil`) is never converted to `V`, and unlike
+    /// `get::<_, Option<V>>`, the intent is explicit: `Ok(None)` means the key is absent, while a
+    /// value which is present but cannot be converted to `V` results in an error.  Any other value,
+    /// including `false`, results in `Some`.
+    ///
+    /// This might invoke the `__index` metamethod, like [`get`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config: Table = lua.eval("{ verbose = false, port = 'eighty' }", None)?;
+    ///
+    /// assert_eq!(config.try_get::<_, bool>("verbose")?, Some(false));
+    /// assert_eq!(config.try_get::<_, String>("host")?, None);
+    /// assert!(config.try_get::<_, u16>("port").is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`get`]: #method.get
+    pub fn try_get<K: ToLua<'lua>, V: FromLua<'lua>>(&self, key: K) -> Result<Option<V>> {
+        match self.get::<_, Value>(key)? {
+            Value::Nil => Ok(None),
+            value => V::from_lua(value, self.0.lua).map(Some),
+        }
+    }
+
     /// Checks whether the table contains a non-nil value for `key`.
     pub fn contains_key<K: ToLua<'lua>>(&self, key: K) -> Result<bool> {
         let lua = self.0.lua;
@@ -127,7 +333,7 @@ impl<'lua> Table<'lua> {
             assert_stack(lua.state, 5);
 
             lua.push_ref(&self.0);
-            lua.push_value(key);
+            lua.push_value(key)?;
 
             unsafe extern "C" fn get_table(state: *mut ffi::lua_State) -> c_int {
                 ffi::lua_gettable(state, -2);
@@ -151,8 +357,8 @@ impl<'lua> Table<'lua> {
             assert_stack(lua.state, 6);
 
             lua.push_ref(&self.0);
-            lua.push_value(key);
-            lua.push_value(value);
+            lua.push_value(key)?;
+            lua.push_value(value)?;
 
             unsafe extern "C" fn raw_set(state: *mut ffi::lua_State) -> c_int {
                 ffi::lua_rawset(state, -3);
@@ -164,6 +370,98 @@ impl<'lua> Table<'lua> {
         }
     }
 
+    /// Sets every key-value pair from an iterator into this table without invoking metamethods.
+    ///
+    /// This is the counterpart of [`Lua::create_table_from`] for updating an existing table, such
+    /// as when merging layers of configuration.  It is equivalent to calling [`raw_set`] for every
+    /// pair, but keeps the table on the stack for the whole operation.  Use [`extend_with_meta`] if
+    /// the `__newindex` metamethod should be respected.
+    ///
+    /// If converting or setting a pair fails, the error is returned and the pairs set before it
+    /// remain in the table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config: Table = lua.eval("{ width = 800, height = 600 }", None)?;
+    ///
+    /// config.extend(vec![("height", 720), ("depth", 32)])?;
+    ///
+    /// assert_eq!(config.get::<_, u32>("width")?, 800);
+    /// assert_eq!(config.get::<_, u32>("height")?, 720);
+    /// assert_eq!(config.get::<_, u32>("depth")?, 32);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Lua::create_table_from`]: struct.Lua.html#method.create_table_from
+    /// [`raw_set`]: #method.raw_set
+    /// [`extend_with_meta`]: #method.extend_with_meta
+    pub fn extend<K, V, I>(&self, iter: I) -> Result<()>
+    where
+        K: ToLua<'lua>,
+        V: ToLua<'lua>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        unsafe extern "C" fn raw_set(state: *mut ffi::lua_State) -> c_int {
+            ffi::lua_rawset(state, -3);
+            1
+        }
+        self.extend_impl(iter, raw_set)
+    }
+
+    /// Sets every key-value pair from an iterator into this table, invoking the `__newindex`
+    /// metamethod like [`set`] does.
+    ///
+    /// See [`extend`] for details.
+    ///
+    /// [`set`]: #method.set
+    /// [`extend`]: #method.extend
+    pub fn extend_with_meta<K, V, I>(&self, iter: I) -> Result<()>
+    where
+        K: ToLua<'lua>,
+        V: ToLua<'lua>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        unsafe extern "C" fn set_table(state: *mut ffi::lua_State) -> c_int {
+            ffi::lua_settable(state, -3);
+            1
+        }
+        self.extend_impl(iter, set_table)
+    }
+
+    fn extend_impl<K, V, I>(&self, iter: I, set: ffi::lua_CFunction) -> Result<()>
+    where
+        K: ToLua<'lua>,
+        V: ToLua<'lua>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 6);
+
+            lua.push_ref(&self.0);
+            for (k, v) in iter {
+                let k = k.to_lua(lua)?;
+                let v = v.to_lua(lua)?;
+                lua.push_value(k)?;
+                lua.push_value(v)?;
+                // The setter leaves the table on the stack for the next pair.
+                protect_lua(lua.state, 3, set)?;
+            }
+
+            Ok(())
+        }
+    }
+
     /// Gets the value associated to `key` without invoking metamethods.
     pub fn raw_get<K: ToLua<'lua>, V: FromLua<'lua>>(&self, key: K) -> Result<V> {
         let lua = self.0.lua;
@@ -173,7 +471,7 @@ impl<'lua> Table<'lua> {
             assert_stack(lua.state, 3);
 
             lua.push_ref(&self.0);
-            lua.push_value(key);
+            lua.push_value(key)?;
             ffi::lua_rawget(lua.state, -2);
             lua.pop_value()
         };
@@ -207,6 +505,71 @@ impl<'lua> Table<'lua> {
         }
     }
 
+    /// Returns true if the table is a proper sequence.
+    ///
+    /// A table is a proper sequence if its keys are exactly the integers `1..n` for some `n`, so
+    /// that [`raw_len`] is unambiguous and every value can be reached by [`sequence_values`].  A
+    /// table with holes, with integer keys outside of `1..n` or with any non-integer key is not a
+    /// sequence.  The empty table is considered a sequence.
+    ///
+    /// This never invokes metamethods, so it only looks at the raw contents of the table.  It is
+    /// useful when converting Lua tables to structured data, for example to decide whether a table
+    /// should become an array or a map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let array: Table = lua.eval("{ 'a', 'b', 'c' }", None)?;
+    /// assert!(array.is_sequence()?);
+    ///
+    /// let holes: Table = lua.eval("{ 'a', nil, 'c' }", None)?;
+    /// assert!(!holes.is_sequence()?);
+    ///
+    /// let mixed: Table = lua.eval("{ 'a', 'b', name = 'c' }", None)?;
+    /// assert!(!mixed.is_sequence()?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`raw_len`]: #method.raw_len
+    /// [`sequence_values`]: #method.sequence_values
+    pub fn is_sequence(&self) -> Result<bool> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 5);
+
+            lua.push_ref(&self.0);
+            protect_lua_closure(lua.state, 1, 0, |state| {
+                let len = ffi::lua_rawlen(state, -1) as Integer;
+                // Keys are unique, so if every key is an integer in `1..=len` and there are
+                // exactly `len` of them, then there can be no holes.
+                let mut count: Integer = 0;
+                ffi::lua_pushnil(state);
+                while ffi::lua_next(state, -2) != 0 {
+                    ffi::lua_pop(state, 1);
+                    if ffi::lua_isinteger(state, -1) == 0 {
+                        return false;
+                    }
+                    let key = ffi::lua_tointeger(state, -1);
+                    if key < 1 || key > len {
+                        return false;
+                    }
+                    count += 1;
+                }
+                count == len
+            })
+        }
+    }
+
     /// Returns a reference to the metatable of this table, or `None` if no metatable is set.
     ///
     /// Unlike the `getmetatable` Lua function, this method ignores the `__metatable` field.
@@ -229,19 +592,569 @@ impl<'lua> Table<'lua> {
     ///
     /// If `metatable` is `None`, the metatable is removed (if no metatable is set, this does
     /// nothing).
-    pub fn set_metatable(&self, metatable: Option<Table<'lua>>) {
+    ///
+    /// Returns `Error::MismatchedLuaState` if `metatable` was created by a `Lua` instance which is
+    /// not compatible with the one that created this table, see [`Lua::compatible_with`].
+    ///
+    /// [`Lua::compatible_with`]: struct.Lua.html#method.compatible_with
+    pub fn set_metatable(&self, metatable: Option<Table<'lua>>) -> Result<()> {
         let lua = self.0.lua;
         unsafe {
             let _sg = StackGuard::new(lua.state);
-            assert_stack(lua.state, 1);
+            assert_stack(lua.state, 2);
             lua.push_ref(&self.0);
             if let Some(metatable) = metatable {
-                lua.push_ref(&metatable.0);
+                lua.push_checked_ref(&metatable.0)?;
             } else {
                 ffi::lua_pushnil(lua.state);
             }
             ffi::lua_setmetatable(lua.state, -2);
         }
+        Ok(())
+    }
+
+    /// Returns an iterator over the prototype chain of this table.
+    ///
+    /// The iterator first yields this table, then the `__index` field of its metatable, then the
+    /// `__index` field of that table's metatable, and so on.  This is how "classes" and
+    /// inheritance are commonly built in Lua, so this is useful for inspecting inherited members.
+    ///
+    /// Both the metatables and their `__index` fields are accessed raw, so no metamethods are
+    /// invoked and the `__metatable` field is ignored.  Iteration stops at the first table without
+    /// a metatable, or whose `__index` is not a table (for example a function), or when a table
+    /// would be visited a second time because the chain contains a cycle.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let object: Table = lua.eval(r#"
+    ///     local Base = { describe = function() return "base" end }
+    ///     local Derived = setmetatable({ name = "derived" }, { __index = Base })
+    ///     return setmetatable({}, { __index = Derived })
+    /// "#, None)?;
+    ///
+    /// let chain: Vec<Table> = object.index_chain().collect();
+    /// assert_eq!(chain.len(), 3);
+    /// assert_eq!(chain[1].get::<_, String>("name")?, "derived");
+    /// assert!(chain[2].contains_key("describe")?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn index_chain(&self) -> TableIndexChain<'lua> {
+        TableIndexChain {
+            next: Some(self.clone()),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Collects all keys of the table into a `Vec`, in the same order as [`pairs`].
+    ///
+    /// Returns the first conversion error, with the offending key described in the error message.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let handlers: Table = lua.eval("{ on_load = print, on_exit = print }", None)?;
+    ///
+    /// let mut names = handlers.keys::<String>()?;
+    /// names.sort();
+    /// assert_eq!(names, vec!["on_exit", "on_load"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`pairs`]: #method.pairs
+    pub fn keys<K: FromLua<'lua>>(&self) -> Result<Vec<K>> {
+        let lua = self.0.lua;
+        let mut keys = Vec::new();
+        let mut pairs = self.clone().pairs::<Value, Value>();
+        while let Some(pair) = pairs.next() {
+            let (key, _) = pair?;
+            keys.push(K::from_lua(key, lua).map_err(|e| key_context(e, &pairs))?);
+        }
+        Ok(keys)
+    }
+
+    /// Collects all values of the table into a `Vec`, in the same order as [`pairs`].
+    ///
+    /// Returns the first conversion error, with the key of the offending value described in the
+    /// error message.
+    ///
+    /// [`pairs`]: #method.pairs
+    pub fn values<V: FromLua<'lua>>(&self) -> Result<Vec<V>> {
+        let lua = self.0.lua;
+        let mut values = Vec::new();
+        for pair in self.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            values.push(V::from_lua(value, lua).map_err(|e| {
+                with_context(e, &format!("value for key {}", describe_key(&key)))
+            })?);
+        }
+        Ok(values)
+    }
+
+    /// Collects all pairs of the table into a `Vec`, sorted by key.
+    ///
+    /// Unlike [`pairs`], which streams the pairs in the unspecified order of the Lua table, this
+    /// gives a deterministic order, for example to serialize a configuration or to compare
+    /// against expected output in tests.  All pairs are converted and buffered before they are
+    /// sorted, which takes O(n log n) time and O(n) memory.  Distinct Lua keys which convert to
+    /// equal keys, such as `1` and `"1"` converted to `String`, keep their unspecified relative
+    /// order.
+    ///
+    /// Returns the first conversion error, with the offending key described in the error message.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config: Table = lua.eval("{ port = 80, host = 'localhost', debug = false }", None)?;
+    ///
+    /// let keys: Vec<String> = config
+    ///     .pairs_sorted::<String, rlua::Value>()?
+    ///     .into_iter()
+    ///     .map(|(key, _)| key)
+    ///     .collect();
+    /// assert_eq!(keys, vec!["debug", "host", "port"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`pairs`]: #method.pairs
+    pub fn pairs_sorted<K, V>(&self) -> Result<Vec<(K, V)>>
+    where
+        K: FromLua<'lua> + Ord,
+        V: FromLua<'lua>,
+    {
+        let mut sorted: Vec<(K, V)> = Vec::new();
+        let mut pairs = self.clone().pairs::<Value, Value>();
+        while let Some(pair) = next_pair(&mut pairs) {
+            sorted.push(pair?);
+        }
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(sorted)
+    }
+
+    /// Converts the table into a `HashMap`, consuming the handle.
+    ///
+    /// This is the same conversion as `FromLua` for `HashMap`, iterating over all pairs like
+    /// [`pairs`], but a conversion error describes the key of the offending pair in its message.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let limits: Table = lua.eval("{ cpu = 2, memory = 512 }", None)?;
+    ///
+    /// let limits = limits.into_hashmap::<String, u32>()?;
+    /// assert_eq!(limits["memory"], 512);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`pairs`]: #method.pairs
+    pub fn into_hashmap<K, V>(self) -> Result<HashMap<K, V>>
+    where
+        K: Eq + Hash + FromLua<'lua>,
+        V: FromLua<'lua>,
+    {
+        let mut map = HashMap::new();
+        let mut pairs = self.pairs::<Value, Value>();
+        while let Some(pair) = next_pair(&mut pairs) {
+            let (key, value) = pair?;
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+
+    /// Converts the sequence part of the table into a `Vec`, consuming the handle.
+    ///
+    /// This is the same conversion as `FromLua` for `Vec`, reading the elements like
+    /// [`sequence_values`], but a conversion error gives the index of the offending element in its
+    /// message.
+    ///
+    /// [`sequence_values`]: #method.sequence_values
+    pub fn into_vec<V: FromLua<'lua>>(self) -> Result<Vec<V>> {
+        let lua = self.0.lua;
+        let mut vec = Vec::new();
+        for (i, value) in self.sequence_values::<Value>().enumerate() {
+            vec.push(
+                V::from_lua(value?, lua)
+                    .map_err(|e| with_context(e, &format!("element {}", i + 1)))?,
+            );
+        }
+        Ok(vec)
+    }
+
+    /// Builds a new table from the pairs of this table, transformed by `f`.
+    ///
+    /// Every pair is converted to `K` and `V` and passed to `f`, in the same order as [`pairs`].
+    /// The pair returned by `f` is stored in the new table, and pairs for which `f` returns `None`
+    /// are left out.  The new table has no metatable, and pairs are stored without invoking
+    /// metamethods, like [`Lua::create_table_from`].
+    ///
+    /// Returns the first error from `f` or from the conversions, with the key of the offending
+    /// pair described in the error message for conversion errors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let prices: Table = lua.eval("{ apple = 3, pear = 0, plum = 5 }", None)?;
+    ///
+    /// // Drop the free items, and convert the prices to cents.
+    /// let cents = prices.filter_map(|name: String, price: i64| {
+    ///     Ok(if price > 0 { Some((name, price * 100)) } else { None })
+    /// })?;
+    ///
+    /// assert_eq!(cents.get::<_, i64>("apple")?, 300);
+    /// assert_eq!(cents.get::<_, i64>("plum")?, 500);
+    /// assert!(!cents.contains_key("pear")?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`pairs`]: #method.pairs
+    /// [`Lua::create_table_from`]: struct.Lua.html#method.create_table_from
+    pub fn filter_map<K, V, K2, V2, F>(&self, mut f: F) -> Result<Table<'lua>>
+    where
+        K: FromLua<'lua>,
+        V: FromLua<'lua>,
+        K2: ToLua<'lua>,
+        V2: ToLua<'lua>,
+        F: FnMut(K, V) -> Result<Option<(K2, V2)>>,
+    {
+        let lua = self.0.lua;
+        let table = lua.create_table()?;
+        let mut pairs = self.clone().pairs::<Value, Value>();
+        while let Some(pair) = next_pair(&mut pairs) {
+            let (key, value) = pair?;
+            if let Some((key, value)) = f(key, value)? {
+                table.raw_set(key, value)?;
+            }
+        }
+        Ok(table)
+    }
+
+    /// Sorts the elements `1..n` of the table in place with a Rust comparator, where `n` is the
+    /// result of [`raw_len`].
+    ///
+    /// This is the counterpart of Lua's `table.sort` for sorting by keys which are easier to
+    /// compute in Rust.  The sort is stable, and elements are read and written back without
+    /// invoking metamethods.  The elements are collected before sorting and only written back
+    /// once the sort has finished, so if `compare` panics, the table is left unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let words: Table = lua.eval(r#"{ "pear", "Fig", "apple" }"#, None)?;
+    ///
+    /// words.sort_by(|a, b| match (a, b) {
+    ///     (&Value::String(ref a), &Value::String(ref b)) => {
+    ///         let a = a.to_str().unwrap_or("").to_lowercase();
+    ///         let b = b.to_str().unwrap_or("").to_lowercase();
+    ///         a.cmp(&b)
+    ///     }
+    ///     _ => std::cmp::Ordering::Equal,
+    /// })?;
+    ///
+    /// let words = words.sequence_values::<String>().collect::<Result<Vec<_>>>()?;
+    /// assert_eq!(words, vec!["apple", "Fig", "pear"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`raw_len`]: #method.raw_len
+    pub fn sort_by<F>(&self, compare: F) -> Result<()>
+    where
+        F: FnMut(&Value<'lua>, &Value<'lua>) -> Ordering,
+    {
+        let len = self.raw_len();
+        let mut values = Vec::with_capacity(len as usize);
+        for i in 1..=len {
+            values.push(self.raw_get::<_, Value>(i)?);
+        }
+        values.sort_by(compare);
+        for (i, value) in values.into_iter().enumerate() {
+            self.raw_set(i as Integer + 1, value)?;
+        }
+        Ok(())
+    }
+
+    /// Merges every key-value pair of `other` into this table, calling `resolve` for the keys
+    /// present in both tables.
+    ///
+    /// Keys only present in `other` are copied to this table.  For a key present in both tables,
+    /// `resolve` is called with the key, the value in this table and the value in `other`, and the
+    /// returned value is stored in this table (returning `Nil` removes the key).  Values are looked
+    /// up and stored with [`get`] and [`set`], so metamethods are invoked.  Tables are copied by
+    /// reference, so a table copied from `other` is shared between both tables afterwards.
+    ///
+    /// Keys are passed to `resolve` as strings written like in Lua code: string keys which are
+    /// valid names as they are, and other keys in brackets, such as `[1]` or `["a key"]`, so that
+    /// the integer key `1` and the string key `"1"` can be told apart.  Keys which are not strings,
+    /// numbers or booleans are described by their type name.  See [`deep_merge`] for recursively
+    /// merging nested tables.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config: Table = lua.eval("{ retries = 3, verbose = false }", None)?;
+    /// let overlay: Table = lua.eval("{ retries = 5, timeout = 30 }", None)?;
+    ///
+    /// // Keep the larger number of retries, let the overlay win otherwise.
+    /// config.merge(overlay, |key, old, new| match (key, &old, &new) {
+    ///     ("retries", &Value::Integer(a), &Value::Integer(b)) => Ok(Value::Integer(a.max(b))),
+    ///     _ => Ok(new),
+    /// })?;
+    ///
+    /// assert_eq!(config.get::<_, i64>("retries")?, 5);
+    /// assert_eq!(config.get::<_, i64>("timeout")?, 30);
+    /// assert_eq!(config.get::<_, bool>("verbose")?, false);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`get`]: #method.get
+    /// [`set`]: #method.set
+    /// [`deep_merge`]: #method.deep_merge
+    pub fn merge<F>(&self, other: Table<'lua>, mut resolve: F) -> Result<()>
+    where
+        F: FnMut(&str, Value<'lua>, Value<'lua>) -> Result<Value<'lua>>,
+    {
+        self.merge_impl(other, false, &mut resolve, "", &mut Vec::new())
+    }
+
+    /// Merges `other` into this table like [`merge`], but recursively merges nested tables.
+    ///
+    /// When the values for a key are tables in both tables, the table from `other` is merged into
+    /// the table of this one instead of calling `resolve`.  In that case, the keys passed to
+    /// `resolve` are paths of keys like in Lua code, such as `"server.port"` or
+    /// `"servers[1].port"`.  A pair of tables which is already being merged (because of a cycle)
+    /// is skipped, as is a table merged into itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config: Table = lua.eval("{ server = { host = 'localhost', port = 80 } }", None)?;
+    /// let overlay: Table = lua.eval("{ server = { port = 8080 } }", None)?;
+    ///
+    /// let mut conflicts = Vec::new();
+    /// config.deep_merge(overlay, |key, _, new| {
+    ///     conflicts.push(key.to_owned());
+    ///     Ok(new)
+    /// })?;
+    ///
+    /// assert_eq!(conflicts, vec!["server.port"]);
+    /// let server: Table = config.get("server")?;
+    /// assert_eq!(server.get::<_, String>("host")?, "localhost");
+    /// assert_eq!(server.get::<_, u16>("port")?, 8080);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`merge`]: #method.merge
+    pub fn deep_merge<F>(&self, other: Table<'lua>, mut resolve: F) -> Result<()>
+    where
+        F: FnMut(&str, Value<'lua>, Value<'lua>) -> Result<Value<'lua>>,
+    {
+        self.merge_impl(other, true, &mut resolve, "", &mut Vec::new())
+    }
+
+    // `merging` holds the pairs of tables between the root and the tables being merged, so that
+    // cycles are detected.
+    fn merge_impl<F>(
+        &self,
+        other: Table<'lua>,
+        deep: bool,
+        resolve: &mut F,
+        path: &str,
+        merging: &mut Vec<(*const c_void, *const c_void)>,
+    ) -> Result<()>
+    where
+        F: FnMut(&str, Value<'lua>, Value<'lua>) -> Result<Value<'lua>>,
+    {
+        let pair = (table_pointer(self), table_pointer(&other));
+        if pair.0 == pair.1 || merging.contains(&pair) {
+            return Ok(());
+        }
+        merging.push(pair);
+
+        // The pairs are collected first, since setting new keys during a traversal is not allowed
+        // and `other` may be reachable from this table.
+        let pairs = other
+            .pairs::<Value, Value>()
+            .collect::<Result<Vec<_>>>()?;
+        for (key, new) in pairs {
+            let key_path = key_path(path, &key);
+            let value = match self.get::<_, Value>(key.clone())? {
+                Nil => new,
+                Value::Table(ref old) if deep => match new {
+                    Value::Table(new) => {
+                        old.merge_impl(new, deep, resolve, &key_path, merging)?;
+                        continue;
+                    }
+                    new => resolve(&key_path, Value::Table(old.clone()), new)?,
+                },
+                old => resolve(&key_path, old, new)?,
+            };
+            self.set(key, value)?;
+        }
+
+        merging.pop();
+        Ok(())
+    }
+
+    /// Returns `true` if this table and `other` have the same contents, comparing nested tables
+    /// recursively rather than by identity.
+    ///
+    /// The tables are equal when they have the same set of keys, and the values of each key are
+    /// equal.  Values which are both tables are compared with `deep_equals` again, and other values
+    /// compare like [`HashableValue`]s: integers and floats compare by numeric value (so `1` and
+    /// `1.0` are equal), strings by their bytes, and functions, threads and userdata by identity.
+    /// Keys are looked up in `other` like with [`raw_get`], so a float key with an exact integer
+    /// value matches the integer key, as Lua normalizes such keys, while table keys must be the
+    /// same table.
+    ///
+    /// Metatables are ignored, as are metamethods such as `__eq` and `__index`.  A pair of tables
+    /// which is already being compared (because of a cycle) is assumed to be equal, so that
+    /// structurally identical cyclic tables compare equal.  A pair of tables already found equal is
+    /// not compared again, so subtables shared by several keys are only compared once.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` if the tables being compared are nested more than 200 levels
+    /// deep.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let a: Table = lua.eval("{ 1, 2, nested = { name = 'x' } }", None)?;
+    /// let b: Table = lua.eval("{ 1.0, 2, nested = { name = 'x' } }", None)?;
+    /// let c: Table = lua.eval("{ 1, 2, nested = { name = 'y' } }", None)?;
+    ///
+    /// assert!(a.deep_equals(&b)?);
+    /// assert!(!a.deep_equals(&c)?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`HashableValue`]: struct.HashableValue.html
+    /// [`raw_get`]: #method.raw_get
+    pub fn deep_equals(&self, other: &Table<'lua>) -> Result<bool> {
+        self.deep_equals_impl(other, &mut Vec::new(), &mut HashSet::new())
+    }
+
+    // `comparing` holds the pairs of tables between the root and the tables being compared, so
+    // that cycles are detected.
+    fn deep_equals_impl(
+        &self,
+        other: &Table<'lua>,
+        comparing: &mut Vec<(*const c_void, *const c_void)>,
+        equal_pairs: &mut HashSet<(*const c_void, *const c_void)>,
+    ) -> Result<bool> {
+        // Pairs which were already found equal are skipped, so that subtables shared by several
+        // keys are only compared once.
+        let pair = (table_pointer(self), table_pointer(other));
+        if pair.0 == pair.1 || comparing.contains(&pair) || equal_pairs.contains(&pair) {
+            return Ok(true);
+        }
+        if comparing.len() >= MAX_DEEP_EQUALS_DEPTH {
+            return Err(Error::RuntimeError(format!(
+                "tables nested more than {} levels deep",
+                MAX_DEEP_EQUALS_DEPTH
+            )));
+        }
+        comparing.push(pair);
+
+        let mut len = 0;
+        for entry in self.clone().pairs::<Value, Value>() {
+            let (key, value) = entry?;
+            len += 1;
+            let equal = match (value, other.raw_get::<_, Value>(key)?) {
+                (Value::Table(a), Value::Table(b)) => {
+                    a.deep_equals_impl(&b, comparing, equal_pairs)?
+                }
+                (a, b) => HashableValue::new(a) == HashableValue::new(b),
+            };
+            if !equal {
+                comparing.pop();
+                return Ok(false);
+            }
+        }
+
+        // Every key of this table is in `other`, so they are equal if `other` has no more keys.
+        let mut other_len = 0;
+        for entry in other.clone().pairs::<Value, Value>() {
+            entry?;
+            other_len += 1;
+        }
+
+        comparing.pop();
+        if len == other_len {
+            equal_pairs.insert(pair);
+        }
+        Ok(len == other_len)
     }
 
     /// Consume this table and return an iterator over the pairs of the table.
@@ -336,6 +1249,46 @@ impl<'lua> Table<'lua> {
     }
 }
 
+/// An iterator over a chain of tables linked through `__index` metatable fields.
+///
+/// This struct is created by the [`Table::index_chain`] method.
+///
+/// [`Table::index_chain`]: struct.Table.html#method.index_chain
+pub struct TableIndexChain<'lua> {
+    next: Option<Table<'lua>>,
+    visited: HashSet<*const c_void>,
+}
+
+impl<'lua> Iterator for TableIndexChain<'lua> {
+    type Item = Table<'lua>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let table = self.next.take()?;
+        let lua = table.0.lua;
+
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 3);
+
+            lua.push_ref(&table.0);
+            if !self.visited.insert(ffi::lua_topointer(lua.state, -1)) {
+                return None;
+            }
+
+            if ffi::lua_getmetatable(lua.state, -1) != 0 {
+                // "__index" is always interned by Lua as a metamethod name, so this cannot
+                // allocate.
+                ffi::lua_pushstring(lua.state, cstr!("__index"));
+                if ffi::lua_rawget(lua.state, -2) == ffi::LUA_TTABLE {
+                    self.next = Some(Table(lua.pop_ref()));
+                }
+            }
+        }
+
+        Some(table)
+    }
+}
+
 /// An iterator over the pairs of a Lua table.
 ///
 /// This struct is created by the [`Table::pairs`] method.
@@ -364,7 +1317,7 @@ where
                     assert_stack(lua.state, 6);
 
                     lua.push_ref(&self.table);
-                    lua.push_value(next_key);
+                    lua.push_value(next_key)?;
 
                     if protect_lua_closure(lua.state, 2, ffi::LUA_MULTRET, |state| {
                         ffi::lua_next(state, -2) != 0
@@ -446,3 +1399,127 @@ where
         }
     }
 }
+
+// Reinstalls a hook replaced by `Table::with_fuel` when dropped, along with the fuel state of an
+// enclosing call on the same thread.
+struct RestoreHook {
+    state: *mut ffi::lua_State,
+    hook: Option<ffi::lua_Hook>,
+    mask: c_int,
+    count: c_int,
+    exhausted: Option<bool>,
+}
+
+impl Drop for RestoreHook {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::lua_sethook(self.state, self.hook, self.mask, self.count);
+            let fueled = fueled_threads(self.state);
+            match self.exhausted {
+                Some(exhausted) => {
+                    (*fueled).insert(self.state, exhausted);
+                }
+                None => {
+                    (*fueled).remove(&self.state);
+                }
+            }
+        }
+    }
+}
+
+// Describes a table key for use in error messages.
+fn describe_key(key: &Value) -> StdString {
+    match *key {
+        Value::String(ref s) => match s.to_str() {
+            Ok(s) => format!("{:?}", s),
+            Err(_) => "of type string".to_owned(),
+        },
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        ref key => format!("of type {}", key.type_name()),
+    }
+}
+
+// Appends a table key to a key path like it would be written in Lua code: string keys which are
+// names after a dot, and other keys in brackets, described by `describe_key`.
+fn key_path(path: &str, key: &Value) -> StdString {
+    if let Value::String(ref s) = *key {
+        if let Ok(name) = s.to_str() {
+            if is_name(name) {
+                return if path.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+            }
+        }
+    }
+    format!("{}[{}]", path, describe_key(key))
+}
+
+// Returns true if the string has the form of a Lua name, which can be used after a dot.
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+// Returns the address of the table, which identifies it for as long as it is alive.
+pub(crate) fn table_pointer(table: &Table) -> *const c_void {
+    let lua = table.0.lua;
+    unsafe {
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 1);
+
+        lua.push_ref(&table.0);
+        ffi::lua_topointer(lua.state, -1)
+    }
+}
+
+// Describes the key most recently produced by `pairs` in the message of an error converting that
+// key.  The iterator keeps its own copy of the key to continue the traversal, so the key is only
+// described when a conversion actually fails.
+fn key_context(err: Error, pairs: &TablePairs<Value, Value>) -> Error {
+    match pairs.next_key {
+        Some(ref key) => with_context(err, &format!("key {}", describe_key(key))),
+        None => err,
+    }
+}
+
+// Converts the next pair of `pairs`, describing the key in the message of a conversion error.
+fn next_pair<'lua, K, V>(
+    pairs: &mut TablePairs<'lua, Value<'lua>, Value<'lua>>,
+) -> Option<Result<(K, V)>>
+where
+    K: FromLua<'lua>,
+    V: FromLua<'lua>,
+{
+    let lua = pairs.table.lua;
+    let pair = pairs.next()?;
+    Some(pair.and_then(|(key, value)| {
+        let value = V::from_lua(value, lua)
+            .map_err(|e| with_context(e, &format!("value for key {}", describe_key(&key))))?;
+        let key = K::from_lua(key, lua).map_err(|e| key_context(e, pairs))?;
+        Ok((key, value))
+    }))
+}
+
+// Adds a description of the location of a value which failed to convert to the error message.
+fn with_context(err: Error, context: &str) -> Error {
+    match err {
+        Error::FromLuaConversionError { from, to, message } => Error::FromLuaConversionError {
+            from,
+            to,
+            message: Some(match message {
+                Some(message) => format!("{}: {}", context, message),
+                None => context.to_owned(),
+            }),
+        },
+        err => err,
+    }
+}
@@ -0,0 +1,144 @@
+use error::Result;
+use ffi;
+use function::Function;
+use lua::Lua;
+use table::Table;
+use util::{assert_stack, StackGuard};
+use value::{FromLuaMulti, ToLuaMulti};
+
+/// A chunk of Lua code which is loaded once and run any number of times, each time in a fresh
+/// environment.
+///
+/// Created with [`Lua::load_template`] or [`Lua::load_template_with_env`].  Every [`run`] gives
+/// the chunk a new, empty environment table which falls back to the base environment (the
+/// globals, unless another base was given) for reading, like the tables made with
+/// [`Lua::create_environment`].  Global variables assigned by one run are therefore not visible
+/// to the next, without having to compile the chunk again.
+///
+/// Functions defined by a run keep the environment of that run, even when they are called after
+/// later runs.  Only the environment table itself is fresh: values reachable from the base
+/// environment, such as the `string` table, are shared by all runs and can still be modified.
+///
+/// [`Lua::load_template`]: struct.Lua.html#method.load_template
+/// [`Lua::load_template_with_env`]: struct.Lua.html#method.load_template_with_env
+/// [`Lua::create_environment`]: struct.Lua.html#method.create_environment
+/// [`run`]: #method.run
+#[derive(Clone, Debug)]
+pub struct ScriptTemplate<'lua> {
+    chunk: Function<'lua>,
+    base: Table<'lua>,
+    // The metatable of every run's environment, which falls back to `base`.
+    env_metatable: Table<'lua>,
+    // Returns two closures whose only upvalues are new variables, the first one holding the
+    // argument.  Joining the `_ENV` upvalue of the chunk to such a variable gives it a fresh
+    // environment without changing the environment of the functions defined by earlier runs, and
+    // the second variable keeps the previous `_ENV` variable of the chunk.
+    make_upvalues: Function<'lua>,
+}
+
+impl<'lua> ScriptTemplate<'lua> {
+    pub(crate) fn new(
+        lua: &'lua Lua,
+        chunk: Function<'lua>,
+        base: Table<'lua>,
+    ) -> Result<ScriptTemplate<'lua>> {
+        let env_metatable = lua.create_table()?;
+        env_metatable.raw_set("__index", base.clone())?;
+        // The metatable is shared by all runs, so it must not be reachable from scripts.
+        env_metatable.raw_set("__metatable", false)?;
+        let make_upvalues = lua.load(
+            "local value, saved = ... \
+             return function() return value end, function() return saved end",
+            Some("script template"),
+        )?;
+        Ok(ScriptTemplate {
+            chunk,
+            base,
+            env_metatable,
+            make_upvalues,
+        })
+    }
+
+    /// Runs the chunk with the given arguments in a fresh environment, returning its results.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let handler = lua.load_template(
+    ///     r#"
+    ///         local request = ...
+    ///         count = (count or 0) + 1
+    ///         return request .. " " .. count
+    ///     "#,
+    ///     Some("handler"),
+    /// )?;
+    ///
+    /// // Each run starts without the globals assigned by the previous runs.
+    /// assert_eq!(handler.run::<_, String>("first")?, "first 1");
+    /// assert_eq!(handler.run::<_, String>("second")?, "second 1");
+    /// assert_eq!(lua.globals().get::<_, Option<i64>>("count")?, None);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn run<A, R>(&self, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.chunk.0.lua;
+        let env = lua.create_table()?;
+        env.raw_set("_G", env.clone())?;
+        env.set_metatable(Some(self.env_metatable.clone()))?;
+
+        // `saved` keeps the current `_ENV` variable of the chunk, which is restored after the run
+        // so that a run started from within another run does not replace the environment of the
+        // outer run.
+        let (fresh, saved): (Function, Function) = self.make_upvalues.call(env)?;
+        unsafe {
+            join_env(lua, &saved, &self.chunk);
+            join_env(lua, &self.chunk, &fresh);
+        }
+        let _restore = RestoreEnv {
+            chunk: &self.chunk,
+            saved,
+        };
+        self.chunk.call(args)
+    }
+
+    /// Returns the base environment that the environment of every run falls back to.
+    pub fn base(&self) -> &Table<'lua> {
+        &self.base
+    }
+}
+
+// Restores the `_ENV` variable of the chunk saved by `ScriptTemplate::run` when dropped, even if
+// the run panics.
+struct RestoreEnv<'a, 'lua: 'a> {
+    chunk: &'a Function<'lua>,
+    saved: Function<'lua>,
+}
+
+impl<'a, 'lua> Drop for RestoreEnv<'a, 'lua> {
+    fn drop(&mut self) {
+        unsafe {
+            join_env(self.chunk.0.lua, self.chunk, &self.saved);
+        }
+    }
+}
+
+// Makes the first upvalue of `f` refer to the first upvalue of `g`.
+unsafe fn join_env<'lua>(lua: &'lua Lua, f: &Function<'lua>, g: &Function<'lua>) {
+    let _sg = StackGuard::new(lua.state);
+    assert_stack(lua.state, 2);
+
+    lua.push_ref(&f.0);
+    lua.push_ref(&g.0);
+    ffi::lua_upvaluejoin(lua.state, -2, 1, -1, 1);
+}
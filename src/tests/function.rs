@@ -1,4 +1,11 @@
-use {Function, Lua, String};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::string::String as StdString;
+
+use ffi;
+use {
+    Error, Function, Lua, Nil, String, Table, UserData, UserDataMethods, Value, Variadic,
+};
 
 #[test]
 fn test_function() {
@@ -44,6 +51,52 @@ fn test_bind() {
     );
 }
 
+#[test]
+fn test_apply() {
+    let lua = Lua::new();
+
+    let count: Function = lua
+        .eval("function(...) return select('#', ...), ... end", None)
+        .unwrap();
+
+    let args = lua.create_sequence_from(vec!["a", "b", "c"]).unwrap();
+    let (n, a, b, c): (i64, String, String, String) = count.apply(args).unwrap();
+    assert_eq!(n, 3);
+    assert_eq!(a, "a");
+    assert_eq!(b, "b");
+    assert_eq!(c, "c");
+
+    let holes = lua.eval::<Table>("{ 1, 2, nil, 4 }", None).unwrap();
+    assert_eq!(count.apply::<i64>(holes).unwrap(), 2);
+
+    let empty = lua.create_table().unwrap();
+    assert_eq!(count.apply::<i64>(empty).unwrap(), 0);
+}
+
+#[test]
+fn test_call_packed() {
+    let lua = Lua::new();
+
+    let f: Function = lua.eval("function(...) return ... end", None).unwrap();
+    let packed = f.call_packed((1, Nil, "three", Nil)).unwrap();
+    assert_eq!(packed.get::<_, i64>("n").unwrap(), 4);
+    assert_eq!(packed.get::<_, i64>(1).unwrap(), 1);
+    assert!(packed.get::<_, Value>(2).unwrap().is_nil());
+    assert_eq!(packed.get::<_, StdString>(3).unwrap(), "three");
+    assert!(packed.get::<_, Value>(4).unwrap().is_nil());
+
+    let empty = f.call_packed(()).unwrap();
+    assert_eq!(empty.get::<_, i64>("n").unwrap(), 0);
+    assert_eq!(empty.raw_len(), 0);
+
+    // The packed results can be passed back with `apply`, up to the first nil.
+    let packed = f.call_packed((1, 2, 3)).unwrap();
+    assert_eq!(f.apply::<(i64, i64, i64)>(packed).unwrap(), (1, 2, 3));
+
+    let fail: Function = lua.eval("function() error('fail') end", None).unwrap();
+    assert!(fail.call_packed(()).is_err());
+}
+
 #[test]
 fn test_rust_function() {
     let lua = Lua::new();
@@ -66,3 +119,572 @@ fn test_rust_function() {
     globals.set("rust_function", rust_function).unwrap();
     assert_eq!(lua_function.call::<_, String>(()).unwrap(), "hello");
 }
+
+#[test]
+fn test_simple_function() {
+    let lua = Lua::new();
+
+    let on_tick = lua
+        .create_simple_function(|lua| {
+            let ticks = lua.globals().get::<_, i64>("ticks")?;
+            if ticks >= 2 {
+                return Err(Error::RuntimeError("too many ticks".to_owned()));
+            }
+            lua.globals().set("ticks", ticks + 1)
+        }).unwrap();
+    lua.globals().set("ticks", 0).unwrap();
+    lua.globals().set("on_tick", on_tick.clone()).unwrap();
+
+    // Arguments are ignored and nothing is returned.
+    assert_eq!(
+        lua.eval::<usize>("return select('#', on_tick(1, 2, 3))", None)
+            .unwrap(),
+        0
+    );
+    on_tick.call::<_, ()>(()).unwrap();
+    assert_eq!(lua.globals().get::<_, i64>("ticks").unwrap(), 2);
+
+    match on_tick.call::<_, ()>(()) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::RuntimeError(ref msg) => assert_eq!(msg, "too many ticks"),
+            ref other => panic!("incorrect result: {:?}", other),
+        },
+        other => panic!("incorrect result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_call_mutating() {
+    struct Output(Vec<i64>);
+
+    impl UserData for Output {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method_mut("push", |_, output, value: i64| {
+                output.0.push(value);
+                Ok(())
+            });
+        }
+    }
+
+    let lua = Lua::new();
+    let fill: Function = lua
+        .eval(
+            r#"
+                function(out, a, b)
+                    out:push(a)
+                    out:push(b)
+                    return a + b
+                end
+            "#,
+            None,
+        ).unwrap();
+
+    let output = lua.create_userdata(Output(Vec::new())).unwrap();
+    let sum: i64 = fill.call_mutating::<Output, _, _>((1, 2), &output).unwrap();
+    assert_eq!(sum, 3);
+    assert_eq!(output.borrow::<Output>().unwrap().0, vec![1, 2]);
+
+    {
+        let _borrow = output.borrow::<Output>().unwrap();
+        match fill.call_mutating::<Output, _, i64>((3, 4), &output) {
+            Err(Error::UserDataBorrowMutError) => {}
+            r => panic!("expected UserDataBorrowMutError, got {:?}", r),
+        }
+    }
+    assert_eq!(output.borrow::<Output>().unwrap().0, vec![1, 2]);
+
+    lua.scope(|scope| {
+        let scoped = scope.create_userdata(Output(Vec::new())).unwrap();
+        lua.globals().set("scoped", scoped).unwrap();
+    });
+    let scoped = lua.globals().get("scoped").unwrap();
+    match fill.call_mutating::<Output, _, i64>((5, 6), &scoped) {
+        Err(Error::ScopeExpired { .. }) => {}
+        r => panic!("expected ScopeExpired, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_source_line() {
+    let lua = Lua::new();
+
+    let func = lua.load("return 1", Some("not_retained")).unwrap();
+    assert_eq!(func.source_line(1), None);
+
+    lua.set_source_retention(true);
+    let func = lua
+        .load(
+            "local function inner()\n  error('boom')\nend\nreturn inner",
+            Some("script"),
+        ).unwrap();
+    assert_eq!(func.source_line(1).unwrap(), "local function inner()");
+    assert_eq!(func.source_line(0), None);
+    assert_eq!(func.source_line(5), None);
+
+    // Functions defined inside the chunk share its source.
+    let inner = func.call::<_, Function>(()).unwrap();
+    assert_eq!(inner.source_line(2).unwrap(), "  error('boom')");
+
+    let unnamed = lua.load("local x = 1\nreturn x", None).unwrap();
+    assert_eq!(unnamed.source_line(2), None);
+
+    let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+    assert_eq!(callback.source_line(1), None);
+
+    // Reloading a chunk with the same name does not change the source of the earlier chunk.
+    let reloaded = lua.load("return 2", Some("script")).unwrap();
+    assert_eq!(reloaded.source_line(1).unwrap(), "return 2");
+    assert_eq!(func.source_line(1).unwrap(), "local function inner()");
+    assert_eq!(inner.source_line(2).unwrap(), "  error('boom')");
+
+    // `eval` retains the source it was given, not its "return" form.  The collector is stopped
+    // so that the chunk's function, which is not kept by `eval`, is not collected yet.
+    lua.exec::<()>("collectgarbage('stop')", None).unwrap();
+    let returned = lua
+        .eval::<Function>("function()\n  return tostring(1)\nend", Some("expression"))
+        .unwrap();
+    assert_eq!(returned.source_line(1).unwrap(), "function()");
+    lua.exec::<()>("collectgarbage('restart')", None).unwrap();
+
+    // The source is discarded once the chunk's function is collected.
+    drop(func);
+    lua.gc_collect().unwrap();
+    assert_eq!(inner.source_line(2), None);
+
+    lua.set_source_retention(false);
+    assert_eq!(reloaded.source_line(1), None);
+}
+
+#[test]
+fn test_call_labeled() {
+    let lua = Lua::new();
+
+    let f: Function = lua
+        .eval(
+            r#"
+                function(a, b)
+                    if a == nil then
+                        error("missing argument")
+                    end
+                    return a + b
+                end
+            "#,
+            None,
+        ).unwrap();
+
+    for _ in 0..2 {
+        assert_eq!(f.call_labeled::<_, i64>("rust:update", (1, 2)).unwrap(), 3);
+        match f.call_labeled::<_, ()>("rust:update", ()) {
+            Err(Error::RuntimeError(msg)) => {
+                assert!(msg.contains("rust:update:1: in function <rust:update:1>"))
+            }
+            r => panic!("expected RuntimeError, got {:?}", r),
+        }
+    }
+
+    let callback = lua
+        .create_function(|_, ()| Err::<(), _>(Error::RuntimeError("failed".to_owned())))
+        .unwrap();
+    match callback.call_labeled::<_, ()>("rust:callback", ()) {
+        Err(Error::CallbackError { traceback, .. }) => {
+            assert!(traceback.contains("rust:callback:1:"))
+        }
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+
+    let multi: Function = lua.eval("function(...) return ... end", None).unwrap();
+    assert_eq!(
+        multi
+            .call_labeled::<_, (i64, Option<i64>, i64)>("multi", (1, Nil, 3))
+            .unwrap(),
+        (1, None, 3)
+    );
+    assert!(f.call_labeled::<_, ()>("bad\0label", (1, 2)).is_err());
+
+    // Labels beyond the cached ones still label the call.
+    for i in 0..300 {
+        let label = format!("label{}", i);
+        match f.call_labeled::<_, ()>(&label, ()) {
+            Err(Error::RuntimeError(msg)) => assert!(msg.contains(&format!("<{}:1>", label))),
+            r => panic!("expected RuntimeError, got {:?}", r),
+        }
+    }
+    assert_eq!(f.call_labeled::<_, i64>("label0", (1, 2)).unwrap(), 3);
+    assert_eq!(f.call_labeled::<_, i64>("label299", (1, 2)).unwrap(), 3);
+}
+
+#[test]
+fn test_call_catching() {
+    let lua = Lua::new();
+
+    let f: Function = lua.eval(
+        r#"
+        function(kind)
+            if kind == "table" then
+                error({ code = 42 })
+            elseif kind == "string" then
+                error("plain", 0)
+            end
+            return kind
+        end
+    "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(f.call_catching::<_, i64>(7).unwrap().unwrap(), 7);
+    match f.call_catching::<_, ()>("table").unwrap() {
+        Err(Value::Table(t)) => assert_eq!(t.get::<_, i64>("code").unwrap(), 42),
+        _ => panic!("expected a table error"),
+    }
+    match f.call_catching::<_, ()>("string").unwrap() {
+        Err(Value::String(s)) => assert_eq!(s.to_str().unwrap(), "plain"),
+        _ => panic!("expected a string error"),
+    }
+
+    let rust_err = lua
+        .create_function(|_, ()| -> Result<(), Error> { Err(Error::RuntimeError("rust".into())) })
+        .unwrap();
+    match rust_err.call_catching::<_, ()>(()).unwrap() {
+        Err(Value::Error(Error::RuntimeError(msg))) => assert_eq!(msg, "rust"),
+        _ => panic!("expected a wrapped rust error"),
+    }
+
+    // Failing to convert the results is a Rust-side error.
+    assert!(f.call_catching::<_, Table>(7).is_err());
+}
+
+#[test]
+fn test_reproducible_dump() {
+    fn hash(bytes: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    let source = r#"
+        local t = { "a", "b", 1.5, 2 }
+        return function(x) return t[x] .. "!" end
+    "#;
+    let dump = |name: &str, strip: bool| {
+        let lua = Lua::new();
+        let f = lua.load_reproducible(source, Some(name)).unwrap();
+        f.dump(strip)
+    };
+
+    let a = dump("@/home/a/build/lib.lua", false);
+    let b = dump("@C:\\b\\lib.lua", false);
+    assert_eq!(hash(&a), hash(&b));
+    assert_ne!(hash(&a), hash(&dump("@/home/a/build/other.lua", false)));
+    assert_eq!(
+        hash(&dump("@/x/lib.lua", true)),
+        hash(&dump("@/y/other.lua", true))
+    );
+
+    // The dumped chunk loads back with the normalized name.
+    let lua = Lua::new();
+    let load: Function = lua.globals().get("load").unwrap();
+    let chunk: Function = load.call(lua.create_byte_string(&a).unwrap()).unwrap();
+    let f: Function = chunk.call(()).unwrap();
+    assert_eq!(f.call::<_, StdString>(1).unwrap(), "a!");
+    match f.call::<_, ()>(5) {
+        Err(Error::RuntimeError(msg)) => assert!(msg.starts_with("lib.lua:3:")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    assert!(lua.create_function(|_, ()| Ok(())).unwrap().dump(false).is_empty());
+}
+
+#[test]
+fn test_callback_stack_imbalance() {
+    let lua = Lua::new();
+
+    let dirty = lua
+        .create_function(|lua, ()| {
+            unsafe { ffi::lua_pushnil(lua.state) };
+            Ok(())
+        })
+        .unwrap();
+    match dirty.call::<_, ()>(()) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::StackError => {}
+            ref other => panic!("expected StackError, got {:?}", other),
+        },
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+
+    // The state is still usable afterwards.
+    assert_eq!(lua.eval::<i64>("return 1 + 1", None).unwrap(), 2);
+}
+
+#[test]
+fn test_named_function_bad_argument() {
+    let lua = Lua::new();
+
+    let add = lua
+        .create_named_function("add", |_, (a, b): (i64, i64)| Ok(a + b))
+        .unwrap();
+    match add.call::<_, i64>((1, "x")) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::BadArgument {
+                ref function,
+                position,
+                ..
+            } => {
+                assert_eq!(function.as_ref().map(|f| f.as_str()), Some("add"));
+                assert_eq!(position, 2);
+            }
+            ref other => panic!("expected BadArgument, got {:?}", other),
+        },
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+
+    let sum = lua
+        .create_named_function("sum", |_, (s, rest): (StdString, Variadic<i64>)| {
+            Ok(format!("{}{}", s, rest.iter().sum::<i64>()))
+        })
+        .unwrap();
+    lua.globals().set("sum", sum).unwrap();
+    let message = lua
+        .eval::<StdString>("tostring(select(2, pcall(sum, 'n', 1, 2, {})))", None)
+        .unwrap();
+    assert!(message.starts_with("bad argument #4 to 'sum' ("));
+    assert_eq!(lua.eval::<StdString>("sum('n', 1, 2)", None).unwrap(), "n3");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_function() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+
+    // Pending on the first poll, then ready with the given value.
+    struct Delayed(Option<i64>, bool);
+
+    impl Future for Delayed {
+        type Output = ::Result<i64>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::Result<i64>> {
+            if self.1 {
+                Poll::Ready(Ok(self.0.take().unwrap()))
+            } else {
+                self.1 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let lua = Lua::new();
+    let double = lua
+        .create_async_function(|_, n: i64| Delayed(Some(n * 2), false))
+        .unwrap();
+    lua.globals().set("double", double.clone()).unwrap();
+
+    let script: Function = lua
+        .eval(
+            r#"
+            function(n)
+                local a = double(n)
+                coroutine.yield("ignored")
+                return a + double(a)
+            end
+        "#,
+            None,
+        ).unwrap();
+
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = counter.clone().into();
+    let mut cx = Context::from_waker(&waker);
+    let mut call = script.call_async::<_, i64>(5);
+    let mut polls = 0;
+    let result = loop {
+        polls += 1;
+        if let Poll::Ready(result) = Pin::new(&mut call).poll(&mut cx) {
+            break result.unwrap();
+        }
+    };
+    assert_eq!(result, 10 + 20);
+    // Two pending futures and one yield from the script.
+    assert_eq!(polls, 4);
+    assert_eq!(counter.0.load(Ordering::SeqCst), 3);
+
+    match double.call::<_, i64>(1) {
+        Err(Error::CallbackError { .. }) => {}
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+
+    // Coroutines created by the script cannot wait for the future.
+    let nested: Function = lua
+        .eval(
+            r#"
+            function()
+                local ok = coroutine.wrap(function() return pcall(double, 1) end)()
+                return ok
+            end
+        "#,
+            None,
+        ).unwrap();
+    let mut call = nested.call_async::<_, bool>(());
+    match Pin::new(&mut call).poll(&mut cx) {
+        Poll::Ready(Ok(ok)) => assert!(!ok),
+        _ => panic!("expected the nested call to fail"),
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_function_panic() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    struct Explode;
+
+    impl Future for Explode {
+        type Output = ::Result<()>;
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<::Result<()>> {
+            panic!("handler exploded")
+        }
+    }
+
+    fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+        let waker = Arc::new(NoopWaker).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    let lua = Lua::new();
+    let explode = lua
+        .create_async_function(|_, ()| Explode)
+        .unwrap();
+    lua.globals().set("explode", explode).unwrap();
+
+    let uncaught: Function = lua.eval("function() explode() end", None).unwrap();
+    match block_on(uncaught.call_async::<_, ()>(())) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::AsyncPanic { ref message } => {
+                assert_eq!(message.as_ref().unwrap(), "handler exploded")
+            }
+            ref e => panic!("expected AsyncPanic, got {:?}", e),
+        },
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+
+    // The panic can be caught by the script, and the state is still usable.
+    let caught: Function = lua
+        .eval("function() return (pcall(explode)), 1 + 2 end", None)
+        .unwrap();
+    assert_eq!(
+        block_on(caught.call_async::<_, (bool, i64)>(())).unwrap(),
+        (false, 3)
+    );
+}
+
+#[test]
+fn test_call_with_env() {
+    let lua = Lua::new();
+    lua.globals().set("x", "global").unwrap();
+
+    let chunk = lua
+        .load(
+            r#"
+            if x == "fail" then
+                error("failed")
+            end
+            y = x
+            return x
+        "#,
+            None,
+        ).unwrap();
+
+    let env = lua.create_table().unwrap();
+    env.set("x", "first").unwrap();
+    assert_eq!(chunk.call_with_env::<_, StdString>(env.clone(), ()).unwrap(), "first");
+    assert_eq!(env.get::<_, StdString>("y").unwrap(), "first");
+    assert_eq!(lua.globals().get::<_, Option<StdString>>("y").unwrap(), None);
+
+    // The environment is restored after an error as well.
+    let failing = lua.create_table().unwrap();
+    failing.set("x", "fail").unwrap();
+    assert!(chunk.call_with_env::<_, ()>(failing, ()).is_err());
+    assert_eq!(chunk.call::<_, StdString>(()).unwrap(), "global");
+
+    // Functions without an `_ENV` upvalue are called normally.
+    let add: Function = lua.eval("function(a, b) return a + b end", None).unwrap();
+    assert_eq!(add.call_with_env::<_, i64>(env.clone(), (1, 2)).unwrap(), 3);
+    assert_eq!(add.call_with_env::<_, i64>(env.clone(), (3, 4)).unwrap(), 7);
+
+    // Stripped main chunks still get the environment, and other stripped functions with upvalues
+    // are refused rather than called with their own environment.
+    let load: Function = lua.globals().get("load").unwrap();
+    let stripped: Function = load
+        .call(lua.create_byte_string(&chunk.dump(true)).unwrap())
+        .unwrap();
+    assert_eq!(stripped.call_with_env::<_, StdString>(env.clone(), ()).unwrap(), "first");
+    let inner = lua
+        .load("return function() return x end", None)
+        .unwrap()
+        .dump(true);
+    let inner: Function = load
+        .call::<_, Function>(lua.create_byte_string(&inner).unwrap())
+        .unwrap()
+        .call(())
+        .unwrap();
+    match inner.call_with_env::<_, StdString>(env, ()) {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_call_slice() {
+    let lua = Lua::new();
+    let concat: Function = lua
+        .eval("function(...) return select('#', ...), table.concat({...}, ',') end", None)
+        .unwrap();
+
+    let s = lua.create_string("b").unwrap();
+    let mut args = vec![Value::Integer(1), Value::String(s)];
+    for i in 2..5 {
+        args[0] = Value::Integer(i);
+        let (n, joined): (usize, StdString) = concat.call_slice(&args).unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(joined, format!("{},b", i));
+    }
+    // The buffer still owns its values after the calls.
+    match args[1] {
+        Value::String(ref s) => assert_eq!(s.to_str().unwrap(), "b"),
+        _ => panic!("string argument was replaced"),
+    }
+    let none: usize = concat.call_slice(&[]).unwrap();
+    assert_eq!(none, 0);
+
+    let error: Function = lua.eval("function() error('boom') end", None).unwrap();
+    match error.call_slice::<()>(&args) {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::{Arc, Mutex};
+
+use {Error, HookEvent, HookTriggers, Lua};
+
+#[test]
+fn test_hook_events() {
+    let lua = Lua::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    let recorder = events.clone();
+    lua.set_hook(
+        HookTriggers {
+            on_calls: true,
+            on_returns: true,
+            every_line: true,
+            ..Default::default()
+        },
+        move |debug| {
+            let source = debug.source();
+            if source.what != "C" {
+                recorder.lock().unwrap().push((
+                    debug.event(),
+                    debug.name(),
+                    source.source,
+                    debug.current_line(),
+                ));
+            }
+            Ok(())
+        },
+    );
+    lua.exec::<()>(
+        r#"
+            local function add(a, b)
+                return a + b
+            end
+            local x = add(1, 2)
+        "#,
+        Some("chunk"),
+    ).unwrap();
+    lua.remove_hook();
+    lua.exec::<()>("local y = 1", None).unwrap();
+
+    let chunk = Some("chunk".to_owned());
+    let add = Some("add".to_owned());
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (HookEvent::Call, None, chunk.clone(), Some(4)),
+            (HookEvent::Line, None, chunk.clone(), Some(4)),
+            (HookEvent::Line, None, chunk.clone(), Some(5)),
+            (HookEvent::Call, add.clone(), chunk.clone(), Some(3)),
+            (HookEvent::Line, add.clone(), chunk.clone(), Some(3)),
+            (HookEvent::Return, add.clone(), chunk.clone(), Some(3)),
+            (HookEvent::Return, None, chunk.clone(), Some(5)),
+        ]
+    );
+}
+
+#[test]
+fn test_hook_error() {
+    let lua = Lua::new();
+    lua.set_hook(
+        HookTriggers {
+            every_nth_instruction: Some(100),
+            ..Default::default()
+        },
+        |debug| {
+            assert_eq!(debug.event(), HookEvent::Count);
+            Err(Error::RuntimeError("too many instructions".to_owned()))
+        },
+    );
+
+    match lua.exec::<()>("while true do end", None) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::RuntimeError(ref msg) => assert_eq!(msg, "too many instructions"),
+            ref e => panic!("unexpected cause {:?}", e),
+        },
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+
+    // Coroutines created after the hook was set inherit it.
+    match lua.exec::<()>("coroutine.wrap(function() while true do end end)()", None) {
+        Err(Error::CallbackError { .. }) => {}
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_breakpoints() {
+    let lua = Lua::new();
+    let hits = Arc::new(Mutex::new(Vec::new()));
+
+    let recorder = hits.clone();
+    lua.set_breakpoint_handler(move |debug| {
+        let source = debug.source().source.unwrap();
+        recorder
+            .lock()
+            .unwrap()
+            .push((source, debug.current_line().unwrap()));
+        if debug.current_line() == Some(4) {
+            Err(Error::RuntimeError("stopped".to_owned()))
+        } else {
+            Ok(())
+        }
+    });
+    lua.set_breakpoint("a", 2);
+    lua.set_breakpoint("a", 3);
+    lua.set_breakpoint("b", 2);
+    assert!(lua.clear_breakpoint("a", 3));
+    assert!(!lua.clear_breakpoint("a", 3));
+
+    let code = "local x = 1\nx = x + 1\nx = x + 1\nreturn x";
+    assert_eq!(lua.exec::<i64>(code, Some("a")).unwrap(), 3);
+    assert_eq!(lua.exec::<i64>(code, Some("c")).unwrap(), 3);
+    assert_eq!(*hits.lock().unwrap(), vec![("a".to_owned(), 2)]);
+
+    // An error returned from the handler aborts the script.
+    lua.set_breakpoint("b", 4);
+    match lua.exec::<i64>(code, Some("b")) {
+        Err(Error::CallbackError { .. }) => {}
+        r => panic!("expected CallbackError, got {:?}", r),
+    }
+    assert_eq!(
+        *hits.lock().unwrap(),
+        vec![
+            ("a".to_owned(), 2),
+            ("b".to_owned(), 2),
+            ("b".to_owned(), 4)
+        ]
+    );
+}
+
+#[test]
+fn test_hook_locals() {
+    let lua = Lua::new();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let recorder = seen.clone();
+    lua.set_breakpoint_handler(move |debug| {
+        let locals = debug
+            .locals()?
+            .into_iter()
+            .map(|(name, value)| (name, debug.lua().unpack::<Option<i64>>(value).unwrap()))
+            .collect::<Vec<_>>();
+        let upvalues = debug
+            .upvalues()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        recorder.lock().unwrap().push((locals, upvalues));
+        assert!(debug.set_local("b", 10)?);
+        assert!(!debug.set_local("missing", 10)?);
+        Ok(())
+    });
+    lua.set_breakpoint("chunk", 6);
+
+    let result = lua
+        .exec::<i64>(
+            r#"
+                local offset = 100
+                local function f(a)
+                    for i = 1, 1 do
+                        local b = a + i
+                        return b + offset
+                    end
+                end
+                return f(1)
+            "#,
+            Some("chunk"),
+        ).unwrap();
+    assert_eq!(result, 110);
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![(
+            vec![
+                ("a".to_owned(), Some(1)),
+                ("i".to_owned(), Some(1)),
+                ("b".to_owned(), Some(2)),
+            ],
+            vec!["offset".to_owned()],
+        )]
+    );
+}
+
+#[test]
+fn test_hook_set_internal_local() {
+    let lua = Lua::new();
+
+    lua.set_breakpoint_handler(|debug| {
+        assert!(!debug.set_local("(for index)", vec![1, 2, 3])?);
+        assert!(!debug.set_local("(for limit)", "limit")?);
+        assert!(debug.set_local("x", 1)?);
+        Ok(())
+    });
+    lua.set_breakpoint("chunk", 5);
+
+    let sum = lua
+        .eval::<f64>(
+            r#"
+                local sum = 0
+                for i = 1.0, 3.0 do
+                    local x = i
+                    sum = sum + x
+                    collectgarbage()
+                end
+                return sum
+            "#,
+            Some("chunk"),
+        ).unwrap();
+    assert_eq!(sum, 3.0);
+}
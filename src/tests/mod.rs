@@ -1,4 +1,5 @@
 mod function;
+mod hook;
 mod scope;
 mod string;
 mod table;
@@ -7,11 +8,16 @@ mod types;
 mod userdata;
 
 use std::iter::FromIterator;
-use std::panic::catch_unwind;
-use std::sync::Arc;
-use std::{error, fmt};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::string::String as StdString;
+use std::sync::{Arc, Mutex};
+use std::{env, error, fmt, fs, process};
 
-use {Error, ExternalError, Function, Lua, Nil, Result, String, Table, UserData, Value, Variadic};
+use {
+    Error, ErrorPosition, ExternalError, Function, HashableValue, Lua, Nil, Result, SourceMap,
+    String, Table, ToLua, TupleTable, UserData, Value, Variadic,
+};
 
 #[test]
 fn test_load() {
@@ -23,6 +29,63 @@ fn test_load() {
     assert!(lua.load("§$%§&$%&", None).is_err());
 }
 
+#[test]
+fn test_load_shebang() {
+    let lua = Lua::new();
+
+    let func = lua
+        .load("#!/usr/bin/env lua\nreturn 1+2", Some("script"))
+        .unwrap();
+    assert_eq!(func.call::<_, i32>(()).unwrap(), 3);
+
+    assert!(lua.load("#!/usr/bin/env lua", None).is_ok());
+
+    // Line numbers are preserved.
+    match lua.exec::<()>("#!/usr/bin/env lua\n\nerror('boom')", Some("script")) {
+        Err(Error::RuntimeError(message)) => assert!(message.contains("\"script\"]:3:")),
+        r => panic!("expected runtime error, got {:?}", r),
+    }
+    match lua.load("#!/usr/bin/env lua\n\nlocal x = = 1", Some("script")) {
+        Err(Error::SyntaxError { message, .. }) => assert!(message.contains("\"script\"]:3:")),
+        r => panic!("expected syntax error, got {:?}", r),
+    }
+
+    // Only a leading '#' line is skipped.
+    assert!(lua.load("return 1\n#!/usr/bin/env lua", None).is_err());
+}
+
+#[test]
+fn test_load_bom() {
+    let lua = Lua::new();
+
+    assert_eq!(lua.eval::<i32>("\u{feff}return 1+2", None).unwrap(), 3);
+    assert_eq!(lua.eval::<i64>("\u{feff}1+2", None).unwrap(), 3);
+    assert_eq!(
+        lua.eval::<i32>("\u{feff}#!/usr/bin/env lua\nreturn 1+2", None).unwrap(),
+        3
+    );
+
+    // "return 1" encoded in UTF-16, which is valid UTF-8 without a byte order mark.
+    match lua.load("r\0e\0t\0u\0r\0n\0 \01\0", None) {
+        Err(Error::UnsupportedEncoding { encoding }) => assert_eq!(encoding, "UTF-16LE"),
+        r => panic!("expected UnsupportedEncoding, got {:?}", r),
+    }
+    match lua.load("\0r\0e\0t\0u\0r\0n\0 \01", None) {
+        Err(Error::UnsupportedEncoding { encoding }) => assert_eq!(encoding, "UTF-16BE"),
+        r => panic!("expected UnsupportedEncoding, got {:?}", r),
+    }
+    match lua.eval::<i64>("1\0+\02\0", None) {
+        Err(Error::UnsupportedEncoding { encoding }) => assert_eq!(encoding, "UTF-16LE"),
+        r => panic!("expected UnsupportedEncoding, got {:?}", r),
+    }
+
+    // Line numbers are preserved after skipping the byte order mark.
+    match lua.exec::<()>("\u{feff}\nerror('boom')", Some("script")) {
+        Err(Error::RuntimeError(message)) => assert!(message.contains("\"script\"]:2:")),
+        r => panic!("expected runtime error, got {:?}", r),
+    }
+}
+
 #[test]
 fn test_debug() {
     let lua = unsafe { Lua::new_with_debug() };
@@ -37,6 +100,70 @@ fn test_debug() {
     );
 }
 
+#[test]
+fn test_safe_debug() {
+    let lua = Lua::new_with_safe_debug();
+    let mut names = lua
+        .eval::<Table>("debug", None)
+        .unwrap()
+        .keys::<StdString>()
+        .unwrap();
+    names.sort();
+    assert_eq!(names, vec!["getinfo", "traceback"]);
+    assert!(lua.eval::<bool>("require('debug') == debug", None).unwrap());
+
+    let traceback = lua
+        .eval::<StdString>("debug.traceback('message')", None)
+        .unwrap();
+    assert!(traceback.starts_with("message\nstack traceback:"));
+
+    // Levels count from the caller of `getinfo`, as with the full debug library.
+    let (line, what, current, name) = lua
+        .eval::<(i64, StdString, i64, StdString)>(
+            r#"
+                local function f()
+                    local info = debug.getinfo(1)
+                    local caller = debug.getinfo(2, "n")
+                    return info.linedefined, info.what, info.currentline, caller.name or "main"
+                end
+                local results = { f() }
+                return table.unpack(results)
+            "#,
+            Some("chunk"),
+        )
+        .unwrap();
+    assert_eq!((line, what.as_str(), current), (2, "Lua", 3));
+    assert_eq!(name, "main");
+
+    // Functions are never returned.
+    assert!(lua
+        .eval::<bool>(
+            r#"
+                local function f() return debug.getinfo(1, "fSL") end
+                local info = f()
+                return info.func == nil and info.activelines ~= nil and info.what == "Lua"
+            "#,
+            None,
+        )
+        .unwrap());
+    assert!(lua
+        .eval::<bool>("debug.getinfo(print).func == nil", None)
+        .unwrap());
+    assert!(lua
+        .eval::<bool>("debug.getinfo(print, '>S').what == 'C'", None)
+        .unwrap());
+    assert!(lua.eval::<bool>("debug.getinfo(1000) == nil", None).unwrap());
+    assert!(lua
+        .eval::<bool>(
+            "local co = coroutine.create(function() coroutine.yield() end)
+             coroutine.resume(co)
+             return debug.getinfo(co, 1, 'S').what == 'Lua'",
+            None,
+        )
+        .unwrap());
+    assert!(lua.exec::<()>("debug.getinfo({})", None).is_err());
+}
+
 #[test]
 fn test_exec() {
     let lua = Lua::new();
@@ -377,6 +504,87 @@ fn test_num_conversion() {
     assert!(globals.get::<_, i64>("n").is_err());
 }
 
+#[test]
+fn test_coerce_boolean() {
+    let lua = Lua::new();
+
+    assert!(!lua.coerce_boolean(Nil));
+    assert!(!lua.coerce_boolean(Value::Boolean(false)));
+    assert!(lua.coerce_boolean(Value::Boolean(true)));
+    assert!(lua.coerce_boolean(Value::Integer(0)));
+    assert!(lua.coerce_boolean(Value::Number(0.0)));
+    assert!(lua.coerce_boolean(lua.eval("''", None).unwrap()));
+    assert!(lua.coerce_boolean(lua.eval("{}", None).unwrap()));
+}
+
+#[test]
+fn test_non_finite_conversion() {
+    let lua = Lua::new();
+
+    for source in &["0/0", "1/0", "-1/0", "'1e400'"] {
+        match lua.eval::<i64>(source, None) {
+            Err(Error::FromLuaConversionError {
+                to: "integer",
+                message: Some(_),
+                ..
+            }) => {}
+            r => panic!("expected FromLuaConversionError for {}, got {:?}", source, r),
+        }
+        assert!(lua.eval::<u8>(source, None).is_err());
+        assert!(lua.eval::<usize>(source, None).is_err());
+        assert!(lua.coerce_integer(lua.eval(source, None).unwrap()).is_err());
+    }
+
+    let nan = lua.eval::<f64>("0/0", None).unwrap();
+    assert!(nan.is_nan());
+    assert_eq!(lua.eval::<f64>("1/0", None).unwrap(), f64::INFINITY);
+}
+
+#[test]
+fn test_integer_range_conversion() {
+    let lua = Lua::new();
+
+    assert_eq!(lua.eval::<u8>("255", None).unwrap(), 255);
+    assert!(lua.eval::<u8>("256", None).is_err());
+    assert!(lua.eval::<u32>("-1", None).is_err());
+    assert!(lua.eval::<u64>("-1", None).is_err());
+    assert_eq!(lua.eval::<i8>("-128", None).unwrap(), -128);
+    assert!(lua.eval::<i8>("-129", None).is_err());
+    assert_eq!(lua.eval::<i64>("math.mininteger", None).unwrap(), i64::min_value());
+}
+
+#[test]
+fn test_path_conversion() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("path", Path::new("/tmp/some dir/file.lua")).unwrap();
+    assert_eq!(
+        lua.eval::<StdString>("return path:match('[^/]+$')", None).unwrap(),
+        "file.lua"
+    );
+    assert_eq!(
+        lua.eval::<PathBuf>("return path .. '.bak'", None).unwrap(),
+        PathBuf::from("/tmp/some dir/file.lua.bak")
+    );
+    match lua.eval::<PathBuf>("return path .. '\\0.bak'", None) {
+        Err(Error::FromLuaConversionError { to: "PathBuf", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Paths which are not valid UTF-8 round-trip through Lua unchanged.
+        let path = PathBuf::from(OsStr::from_bytes(b"/tmp/\xff\xfe"));
+        globals.set("binary", path.clone()).unwrap();
+        assert_eq!(lua.eval::<usize>("return #binary", None).unwrap(), 7);
+        assert_eq!(globals.get::<_, PathBuf>("binary").unwrap(), path);
+    }
+}
+
 #[test]
 fn test_pcall_xpcall() {
     let lua = Lua::new();
@@ -535,6 +743,13 @@ fn test_named_registry_value() {
         Nil => {}
         val => panic!("registry value was not Nil, was {:?}", val),
     };
+    assert_eq!(lua.named_registry_value_or::<i32>("test", 7).unwrap(), 7);
+
+    lua.set_named_registry_value("test", "not a number").unwrap();
+    match lua.named_registry_value_or::<i32>("test", 7) {
+        Err(Error::FromLuaConversionError { .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
 }
 
 #[test]
@@ -556,6 +771,65 @@ fn test_registry_value() {
     f.call::<_, ()>(()).unwrap();
 }
 
+#[test]
+fn test_registry_stored_functions() {
+    let lua = Lua::new();
+
+    let keys = (0..50)
+        .map(|i| {
+            let f = lua.create_function(move |_, x: i64| Ok(x * i)).unwrap();
+            lua.create_registry_value(f).unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    // Install the library from the registry into the globals from a callback, which receives a
+    // different `Lua` handle sharing the same main state.
+    let install = lua
+        .create_function(move |lua, ()| {
+            let globals = lua.globals();
+            for (i, key) in keys.iter().enumerate() {
+                assert!(lua.owns_registry_value(key));
+                let f: Function = lua.registry_value(key)?;
+                globals.set(format!("native_{}", i), f)?;
+            }
+            Ok(())
+        }).unwrap();
+    install.call::<_, ()>(()).unwrap();
+    drop(install);
+    lua.expire_registry_values();
+    lua.gc_collect().unwrap();
+
+    for i in 0..50 {
+        let f: Function = lua.globals().get(format!("native_{}", i)).unwrap();
+        assert_eq!(f.call::<_, i64>(2).unwrap(), 2 * i);
+    }
+    lua.exec::<()>(
+        r#"
+            local co = coroutine.wrap(function()
+                for i = 0, 49 do
+                    assert(_G["native_" .. i](3) == 3 * i)
+                end
+            end)
+            co()
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_create_registered_function() {
+    let lua = Lua::new();
+
+    let key = lua
+        .create_registered_function(|_, s: StdString| Ok(s.len()))
+        .unwrap();
+    assert!(lua.owns_registry_value(&key));
+    let f: Function = lua.registry_value(&key).unwrap();
+    assert_eq!(f.call::<_, usize>("four").unwrap(), 4);
+    assert!(lua.registry_value::<StdString>(&key).is_err());
+    lua.remove_registry_value(key).unwrap();
+}
+
 #[test]
 fn test_drop_registry_value() {
     struct MyUserdata(Arc<()>);
@@ -579,15 +853,31 @@ fn test_drop_registry_value() {
 }
 
 #[test]
-#[should_panic]
 fn test_mismatched_lua_ref() {
     let lua1 = Lua::new();
     let lua2 = Lua::new();
+    assert!(lua1.compatible_with(&lua1));
+    assert!(!lua1.compatible_with(&lua2));
 
     let s = lua1.create_string("hello").unwrap();
     let f = lua2.create_function(|_, _: String| Ok(())).unwrap();
 
-    f.call::<_, ()>(s).unwrap();
+    match f.call::<_, ()>(s.clone()) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    }
+    match lua2.globals().set("s", s) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    }
+    match lua2.create_thread(lua1.create_function(|_, ()| Ok(())).unwrap()) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    };
+    match lua2.globals().set_metatable(Some(lua1.create_table().unwrap())) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    };
 }
 
 #[test]
@@ -616,6 +906,75 @@ fn test_mismatched_registry_key() {
     };
 }
 
+#[test]
+fn test_registry_value_type() {
+    let lua = Lua::new();
+
+    let string = lua.create_registry_value("hello").unwrap();
+    let integer = lua.create_registry_value(1).unwrap();
+    let number = lua.create_registry_value(1.5).unwrap();
+    let table = lua.create_registry_value(lua.create_table().unwrap()).unwrap();
+    let nil = lua.create_registry_value(Nil).unwrap();
+    assert_eq!(lua.registry_value_type(&string).unwrap(), "string");
+    assert_eq!(lua.registry_value_type(&integer).unwrap(), "integer");
+    assert_eq!(lua.registry_value_type(&number).unwrap(), "number");
+    assert_eq!(lua.registry_value_type(&table).unwrap(), "table");
+    assert_eq!(lua.registry_value_type(&nil).unwrap(), "nil");
+
+    let other = Lua::new();
+    match other.registry_value_type(&string) {
+        Err(Error::MismatchedRegistryKey) => {}
+        r => panic!("wrong result type for mismatched registry key, {:?}", r),
+    };
+}
+
+#[test]
+fn test_registry_data() {
+    struct Counter(u32);
+
+    let lua = Lua::new();
+    let first = Arc::new(lua.create_registry_data(Counter(0)).unwrap());
+    let second = lua.create_registry_data(Counter(10)).unwrap();
+
+    let key = first.clone();
+    let increment = lua
+        .create_function(move |lua, ()| {
+            let mut counter = lua.registry_data_mut::<Counter>(&key)?;
+            counter.0 += 1;
+            Ok(counter.0)
+        })
+        .unwrap();
+    lua.globals().set("increment", increment).unwrap();
+    assert_eq!(lua.eval::<u32>("increment(); return increment()", None).unwrap(), 2);
+    assert_eq!(lua.registry_data_ref::<Counter>(&first).unwrap().0, 2);
+    assert_eq!(lua.registry_data_ref::<Counter>(&second).unwrap().0, 10);
+
+    {
+        let _borrowed = lua.registry_data_ref::<Counter>(&first).unwrap();
+        match lua.eval::<u32>("return increment()", None) {
+            Err(Error::CallbackError { ref cause, .. }) => match **cause {
+                Error::UserDataBorrowMutError => {}
+                ref other => panic!("wrong error cause {:?}", other),
+            },
+            r => panic!("expected a borrow error, got {:?}", r),
+        };
+    }
+
+    match lua.registry_data_ref::<StdString>(&second) {
+        Err(Error::UserDataTypeMismatch { .. }) => {}
+        r => panic!("expected a type mismatch, got {:?}", r.map(|_| ())),
+    };
+    let value = lua.create_registry_value("hello").unwrap();
+    assert!(lua.registry_data_ref::<Counter>(&value).is_err());
+    let other = Lua::new();
+    match other.registry_data_ref::<Counter>(&second) {
+        Err(Error::MismatchedRegistryKey) => {}
+        r => panic!("wrong result type for mismatched registry key, {:?}", r.map(|_| ())),
+    };
+
+    lua.remove_registry_value(second).unwrap();
+}
+
 #[test]
 fn too_many_returns() {
     let lua = Lua::new();
@@ -729,3 +1088,1442 @@ fn large_args_ref() {
     f.call::<_, ()>((0..100).map(|i| i.to_string()).collect::<Variadic<_>>())
         .unwrap();
 }
+
+#[test]
+fn test_analyze() {
+    let lua = Lua::new();
+
+    let analysis = lua
+        .analyze(
+            r#"
+                local io = "shadowed"
+                result = string.format("%s", io)
+                local function nested()
+                    return os.time()
+                end
+                function global_function() end
+                local t = {}
+                t.field = 1
+                return _ENV["dynamic"]
+            "#,
+            None,
+        ).unwrap();
+
+    assert!(analysis.globals_read.contains("string"));
+    assert!(analysis.globals_read.contains("os"));
+    assert!(analysis.globals_written.contains("result"));
+    assert!(analysis.globals_written.contains("global_function"));
+    assert!(!analysis.references("io"));
+    assert!(!analysis.references("t"));
+    assert!(!analysis.references("format"));
+    assert!(!analysis.references("time"));
+
+    // Chunks with more constants than fit in an instruction operand are still scanned.
+    let mut source = "local t = {".to_owned();
+    for i in 0..300 {
+        source.push_str(&format!("'constant{}', ", i));
+    }
+    source.push_str("}\nreturn late_global");
+    let analysis = lua.analyze(&source, None).unwrap();
+    assert!(analysis.globals_read.contains("late_global"));
+
+    lua.globals().set("late_global", 3).unwrap();
+    assert_eq!(analysis.function.call::<_, i32>(()).unwrap(), 3);
+
+    assert!(lua.analyze("this is not lua", None).is_err());
+}
+
+#[test]
+fn test_app_data() {
+    struct Counter(u32);
+
+    let lua = Lua::new();
+    assert!(lua.app_data_ref::<Counter>().unwrap().is_none());
+    assert!(lua.set_app_data(Counter(1)).unwrap().is_none());
+    assert_eq!(lua.set_app_data(Counter(2)).unwrap().unwrap().0, 1);
+
+    let increment = lua
+        .create_function(|lua, ()| {
+            let mut counter = lua.app_data_mut::<Counter>()?.unwrap();
+            counter.0 += 1;
+            Ok(counter.0)
+        }).unwrap();
+    assert_eq!(increment.call::<_, u32>(()).unwrap(), 3);
+    assert_eq!(lua.app_data_ref::<Counter>().unwrap().unwrap().0, 3);
+
+    // A callback holding a mutable borrow that re-enters a callback borrowing the same data gets
+    // an error rather than a panic.
+    lua.globals().set("increment", increment).unwrap();
+    let reentrant = lua
+        .create_function(|lua, ()| {
+            let _counter = lua.app_data_mut::<Counter>()?.unwrap();
+            lua.globals().get::<_, Function>("increment")?.call::<_, ()>(())
+        }).unwrap();
+    match reentrant.call::<_, ()>(()) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::CallbackError { ref cause, .. } => match **cause {
+                Error::RecursiveCallback {
+                    ref resource,
+                    mutable: true,
+                } => assert!(resource.contains("Counter")),
+                ref other => panic!("incorrect result: {:?}", other),
+            },
+            ref other => panic!("incorrect result: {:?}", other),
+        },
+        other => panic!("incorrect result: {:?}", other),
+    }
+
+    {
+        let _counter = lua.app_data_ref::<Counter>().unwrap();
+        assert!(lua.app_data_ref::<Counter>().is_ok());
+        match lua.app_data_mut::<Counter>() {
+            Err(Error::RecursiveCallback { mutable: true, .. }) => {}
+            _ => panic!("expected RecursiveCallback"),
+        }
+        match lua.set_app_data(Counter(0)) {
+            Err(Error::RecursiveCallback { mutable: true, .. }) => {}
+            _ => panic!("expected RecursiveCallback"),
+        }
+        match lua.remove_app_data::<Counter>() {
+            Err(Error::RecursiveCallback { mutable: true, .. }) => {}
+            _ => panic!("expected RecursiveCallback"),
+        }
+    }
+
+    {
+        let _counter = lua.app_data_mut::<Counter>().unwrap();
+        match lua.app_data_ref::<Counter>() {
+            Err(err @ Error::RecursiveCallback { mutable: false, .. }) => {
+                assert!(err.to_string().contains("already mutably borrowed"))
+            }
+            _ => panic!("expected RecursiveCallback"),
+        }
+    }
+
+    assert_eq!(lua.remove_app_data::<Counter>().unwrap().unwrap().0, 3);
+    assert!(lua.remove_app_data::<Counter>().unwrap().is_none());
+}
+
+#[test]
+fn test_host_function() {
+    struct Log(Vec<StdString>);
+    struct Missing;
+
+    let lua = Lua::new();
+    lua.set_app_data(Log(Vec::new())).unwrap();
+
+    let log = lua
+        .create_host_function(|host, message: StdString| {
+            host.with_mut(|log: &mut Log| log.0.push(message))
+        }).unwrap();
+    let log_count = lua
+        .create_host_function(|host, ()| host.with(|log: &Log| log.0.len()))
+        .unwrap();
+    lua.globals().set("log", log).unwrap();
+    lua.globals().set("log_count", log_count).unwrap();
+    assert_eq!(
+        lua.eval::<usize>("log('a'); log('b'); return log_count()", None)
+            .unwrap(),
+        2
+    );
+
+    // Calling back into Lua while a service is borrowed, reaching a callback that needs the same
+    // service, is an error.
+    let reentrant = lua
+        .create_host_function(|host, ()| {
+            host.with_mut(|_: &mut Log| host.lua().eval::<usize>("return log_count()", None))?
+        }).unwrap();
+    match reentrant.call::<_, usize>(()) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::CallbackError { ref cause, .. } => match **cause {
+                Error::RecursiveCallback { mutable: false, .. } => {}
+                ref other => panic!("incorrect result: {:?}", other),
+            },
+            ref other => panic!("incorrect result: {:?}", other),
+        },
+        other => panic!("incorrect result: {:?}", other),
+    }
+
+    let missing = lua
+        .create_host_function(|host, ()| host.with(|_: &Missing| ()))
+        .unwrap();
+    match missing.call::<_, ()>(()) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::MissingAppData { type_name } => assert!(type_name.ends_with("Missing")),
+            ref other => panic!("incorrect result: {:?}", other),
+        },
+        other => panic!("incorrect result: {:?}", other),
+    }
+    assert_eq!(lua.app_data_ref::<Log>().unwrap().unwrap().0, vec!["a", "b"]);
+}
+
+#[test]
+fn test_panic_hook() {
+    let lua = Lua::new();
+    let panics = Arc::new(Mutex::new(Vec::new()));
+
+    let recorder = panics.clone();
+    lua.set_panic_hook(move |message, name| {
+        recorder
+            .lock()
+            .unwrap()
+            .push((message.to_owned(), name.map(|n| n.to_owned())));
+    });
+
+    let globals = lua.globals();
+    globals
+        .set(
+            "format_panic",
+            lua.create_function(|_, n: i32| -> Result<()> { panic!("formatted {}", n) })
+                .unwrap(),
+        ).unwrap();
+    globals
+        .set(
+            "payload_panic",
+            lua.create_function(|_, ()| -> Result<()> { ::std::panic::panic_any(5) })
+                .unwrap(),
+        ).unwrap();
+
+    assert!(catch_unwind(AssertUnwindSafe(|| lua.exec::<()>("format_panic(3)", None))).is_err());
+    assert!(
+        catch_unwind(AssertUnwindSafe(|| {
+            lua.exec::<()>("local t = { f = payload_panic }; t.f()", None)
+        })).is_err()
+    );
+    assert_eq!(
+        *panics.lock().unwrap(),
+        vec![
+            ("formatted 3".to_owned(), Some("format_panic".to_owned())),
+            ("Box<Any>".to_owned(), Some("f".to_owned())),
+        ]
+    );
+
+    // A panicking hook does not change how the original panic propagates.
+    lua.set_panic_hook(|_, _| panic!("hook panic"));
+    match catch_unwind(AssertUnwindSafe(|| lua.exec::<()>("format_panic(4)", None))) {
+        Err(p) => assert_eq!(
+            p.downcast_ref::<::std::string::String>().unwrap(),
+            "formatted 4"
+        ),
+        Ok(_) => panic!("no panic was detected"),
+    }
+
+    lua.remove_panic_hook();
+    assert!(catch_unwind(AssertUnwindSafe(|| lua.exec::<()>("format_panic(5)", None))).is_err());
+    assert_eq!(panics.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_environment() {
+    let lua = Lua::new();
+    lua.globals().set("shared", 1).unwrap();
+
+    let run = |env: &Table, source: &str| {
+        lua.load_with_env(source, None, env.clone())
+            .unwrap()
+            .call::<_, ()>(())
+            .unwrap()
+    };
+
+    let first = lua.create_environment().unwrap();
+    let second = lua.create_environment().unwrap();
+    run(&first, "counter = shared + 1; _G.leaked = true");
+    run(&second, "counter = shared + 10; function get() return counter end");
+
+    assert_eq!(first.get::<_, i64>("counter").unwrap(), 2);
+    assert_eq!(second.get::<_, i64>("counter").unwrap(), 11);
+    assert_eq!(first.get::<_, bool>("leaked").unwrap(), true);
+    assert_eq!(second.get::<_, Option<bool>>("leaked").unwrap(), None);
+
+    let globals = lua.globals();
+    assert_eq!(globals.get::<_, Option<i64>>("counter").unwrap(), None);
+    assert_eq!(globals.get::<_, Option<bool>>("leaked").unwrap(), None);
+    assert!(!globals.contains_key("get").unwrap());
+
+    // Functions defined by a script keep using its environment.
+    let get: Function = second.get("get").unwrap();
+    second.set("counter", 42).unwrap();
+    assert_eq!(get.call::<_, i64>(()).unwrap(), 42);
+}
+
+#[test]
+fn test_exec_capturing_globals() {
+    let lua = Lua::new();
+    lua.exec::<()>("unchanged = 1; changed = 1; removed = 1; shared = {}", None)
+        .unwrap();
+
+    let defined = lua
+        .exec_capturing_globals(
+            r#"
+                changed = 2
+                removed = nil
+                shared.field = 1
+                unchanged = 1
+                added = "new"
+                local hidden = true
+                return 42
+            "#,
+            None,
+        ).unwrap();
+
+    let mut names = defined
+        .clone()
+        .pairs::<StdString, Value>()
+        .map(|pair| pair.map(|(name, _)| name))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    names.sort();
+    assert_eq!(names, vec!["added", "changed"]);
+    assert_eq!(defined.get::<_, i64>("changed").unwrap(), 2);
+    assert_eq!(defined.get::<_, StdString>("added").unwrap(), "new");
+
+    assert!(lua.exec_capturing_globals("error('boom')", None).is_err());
+}
+
+#[test]
+fn test_exec_with_diagnostics() {
+    let lua = Lua::new();
+    let warn = lua
+        .create_function(|lua, message: StdString| {
+            lua.warn(&message);
+            Ok(())
+        })
+        .unwrap();
+    lua.globals().set("warn", warn).unwrap();
+    let nested = lua
+        .create_function(|lua, source: StdString| {
+            let (result, diagnostics) = lua.exec_with_diagnostics::<()>(&source, Some("nested"));
+            result?;
+            Ok(diagnostics.len())
+        })
+        .unwrap();
+    lua.globals().set("nested", nested).unwrap();
+
+    // Diagnostics reported outside of `exec_with_diagnostics` are ignored.
+    lua.exec::<()>("warn('ignored')", None).unwrap();
+    lua.warn("ignored");
+
+    let (result, diagnostics) = lua.exec_with_diagnostics::<i64>(
+        r#"
+            warn("first")
+            local count = nested("warn('inner'); warn('inner')")
+            coroutine.wrap(function()
+                warn("in coroutine")
+            end)()
+            return count
+        "#,
+        Some("script"),
+    );
+    assert_eq!(result.unwrap(), 2);
+    let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(messages, vec!["first", "in coroutine"]);
+    let position = diagnostics[0].position.as_ref().unwrap();
+    assert_eq!(position.chunk_name, "script");
+    assert_eq!(position.line, 2);
+    assert_eq!(diagnostics[1].position.as_ref().unwrap().line, 5);
+
+    // Positions keep chunk names which Lua shortens in its messages.
+    let long_name = "a_chunk_name_which_is_too_long_to_fit_in_the_messages_generated_by_lua";
+    let (_, diagnostics) = lua.exec_with_diagnostics::<()>("warn('long')", Some(long_name));
+    assert_eq!(diagnostics[0].position.as_ref().unwrap().chunk_name, long_name);
+
+    lua.warn("ignored again");
+    let (result, diagnostics) = lua.exec_with_diagnostics::<()>("", None);
+    assert!(result.is_ok());
+    assert!(diagnostics.is_empty());
+
+    // The diagnostics reported before an error are kept.
+    let (result, diagnostics) =
+        lua.exec_with_diagnostics::<()>("warn('before') error('fail')", None);
+    assert!(result.is_err());
+    let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(messages, vec!["before"]);
+}
+
+#[test]
+fn test_script_template() {
+    let lua = Lua::new();
+    lua.globals().set("shared", 1).unwrap();
+
+    let template = lua
+        .load_template(
+            r#"
+                local arg = ...
+                count = (count or shared) + arg
+                function get() return count end
+                return count, get
+            "#,
+            None,
+        )
+        .unwrap();
+
+    let (first, get_first) = template.run::<_, (i64, Function)>(10).unwrap();
+    let (second, get_second) = template.run::<_, (i64, Function)>(20).unwrap();
+    assert_eq!(first, 11);
+    assert_eq!(second, 21);
+
+    // Functions defined by a run keep the environment of that run.
+    assert_eq!(get_first.call::<_, i64>(()).unwrap(), 11);
+    assert_eq!(get_second.call::<_, i64>(()).unwrap(), 21);
+
+    let globals = lua.globals();
+    assert_eq!(globals.get::<_, Option<i64>>("count").unwrap(), None);
+    assert!(!globals.contains_key("get").unwrap());
+
+    // A run from within a run does not replace the environment of the outer run, and a failed run
+    // does not affect the next one.
+    let nested = lua
+        .load_template("value = ...; if value > 0 then run(value - 1) end; return value", None)
+        .unwrap();
+    lua.scope(|scope| {
+        let run = scope
+            .create_function(|_, n: i64| nested.run::<_, i64>(n))
+            .unwrap();
+        lua.globals().set("run", run).unwrap();
+        assert_eq!(nested.run::<_, i64>(3).unwrap(), 3);
+    });
+    assert!(nested.run::<_, i64>("oops").is_err());
+    assert!(nested.run::<_, i64>(0).is_ok());
+
+    // The environment of the outer run is restored when an inner run panics.
+    let nested = lua
+        .load_template(
+            "value = ...; if value > 0 then run(value - 1) else boom() end; return value",
+            None,
+        )
+        .unwrap();
+    lua.scope(|scope| {
+        let run = scope
+            .create_function(|_, n: i64| {
+                match catch_unwind(AssertUnwindSafe(|| nested.run::<_, i64>(n))) {
+                    Ok(result) => result,
+                    Err(_) => Ok(-1),
+                }
+            })
+            .unwrap();
+        let boom = scope
+            .create_function(|_, ()| -> Result<()> { panic!("boom") })
+            .unwrap();
+        lua.globals().set("run", run).unwrap();
+        lua.globals().set("boom", boom).unwrap();
+        assert_eq!(nested.run::<_, i64>(1).unwrap(), 1);
+    });
+
+    let base = lua.create_table().unwrap();
+    base.set("shared", 100).unwrap();
+    let template = lua
+        .load_template_with_env("return shared, print", None, base)
+        .unwrap();
+    let (shared, print) = template.run::<_, (i64, Option<Function>)>(()).unwrap();
+    assert_eq!(shared, 100);
+    assert!(print.is_none());
+
+    // The metatable shared by the environments of all runs is protected.
+    let template = lua
+        .load_template("return getmetatable(_ENV)", None)
+        .unwrap();
+    assert!(!template.run::<_, bool>(()).unwrap());
+}
+
+#[test]
+fn test_freeze_globals() {
+    let lua = Lua::new();
+    lua.globals().set("value", 1).unwrap();
+    lua.freeze_globals().unwrap();
+    lua.freeze_globals().unwrap();
+
+    for source in &[
+        "value = 2",
+        "new_value = 1",
+        "_G.value = 2",
+        "function f() end",
+        "setmetatable(_G, nil)",
+    ] {
+        match lua.exec::<()>(source, None) {
+            Err(Error::RuntimeError(_)) | Err(Error::CallbackError { .. }) => {}
+            r => panic!("expected an error for {:?}, got {:?}", source, r),
+        }
+    }
+    match lua.exec::<()>("value = 2", Some("frozen")) {
+        Err(Error::RuntimeError(msg)) => {
+            assert!(msg.starts_with("[string \"frozen\"]:1: attempt to assign to frozen global"))
+        }
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+
+    assert_eq!(
+        lua.eval::<i64>("local v = value + 1; return v", None).unwrap(),
+        2
+    );
+    assert_eq!(lua.eval::<i64>("#string.rep('x', value)", None).unwrap(), 1);
+
+    let globals = lua.globals();
+    globals.raw_set("value", 5).unwrap();
+    assert_eq!(lua.eval::<i64>("value", None).unwrap(), 5);
+    assert_eq!(globals.get::<_, i64>("value").unwrap(), 5);
+
+    let env = lua.create_environment().unwrap();
+    lua.load_with_env("scratch = value * 2", None, env.clone())
+        .unwrap()
+        .call::<_, ()>(())
+        .unwrap();
+    assert_eq!(env.get::<_, i64>("scratch").unwrap(), 10);
+}
+
+#[test]
+fn test_disable_globals() {
+    let lua = Lua::new();
+    lua.freeze_globals().unwrap();
+    lua.disable_globals(&["os", "io", "never_existed"]).unwrap();
+
+    for source in &[
+        "os.exit()",
+        "io.write('x')",
+        "os.x = 1",
+        "never_existed()",
+        "return #io",
+        "for k in pairs(os) do end",
+    ] {
+        match lua.exec::<()>(source, Some("sandboxed")) {
+            Err(Error::RuntimeError(msg)) => {
+                assert!(msg.contains("` is disabled in this sandbox"), "{}", msg);
+            }
+            r => panic!("expected RuntimeError for {:?}, got {:?}", source, r),
+        }
+    }
+    for source in &["package.loaded.os.exit()", "require('io').write('x')"] {
+        match lua.exec::<()>(source, Some("sandboxed")) {
+            Err(Error::RuntimeError(msg)) => {
+                assert!(msg.contains("` is disabled in this sandbox"), "{}", msg);
+            }
+            r => panic!("expected RuntimeError for {:?}, got {:?}", source, r),
+        }
+    }
+    assert!(lua.eval::<bool>("package.loaded.never_existed == nil", None).unwrap());
+    assert!(lua.eval::<bool>("getmetatable(os) == false", None).unwrap());
+    assert_eq!(lua.eval::<StdString>("string.upper('ok')", None).unwrap(), "OK");
+}
+
+#[test]
+fn test_restrict_collectgarbage() {
+    let lua = Lua::new();
+    let original = lua.restrict_collectgarbage().unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(collectgarbage() == 0)
+            assert(collectgarbage("stop") == 0)
+            assert(collectgarbage("setpause", 1000) == 0)
+            assert(collectgarbage("isrunning"))
+            assert(collectgarbage("count") > 0)
+            assert(not pcall(collectgarbage, "invalid"))
+        "#,
+        None,
+    ).unwrap();
+
+    // The original function still controls the collector.
+    original.call::<_, ()>("stop").unwrap();
+    assert!(!lua.eval::<bool>("collectgarbage('isrunning')", None).unwrap());
+    original.call::<_, ()>("restart").unwrap();
+}
+
+#[test]
+fn test_max_chunk_size() {
+    use ReplSession;
+
+    let lua = Lua::new();
+    let source = "return 1 + 2";
+
+    lua.set_max_chunk_size(source.len());
+    assert_eq!(lua.exec::<i64>(source, None).unwrap(), 3);
+
+    lua.set_max_chunk_size(source.len() - 1);
+    match lua.exec::<i64>(source, None) {
+        Err(Error::ChunkTooLarge { size, limit }) => {
+            assert_eq!(size, source.len());
+            assert_eq!(limit, source.len() - 1);
+        }
+        r => panic!("expected ChunkTooLarge, got {:?}", r),
+    }
+
+    // The limit also applies to the expression form tried first by `eval`, which does not fall
+    // back to the statement form on errors other than syntax errors.
+    lua.set_max_chunk_size(3);
+    match lua.eval::<i64>("1+2", None) {
+        Err(Error::ChunkTooLarge { size: 10, limit: 3 }) => {}
+        r => panic!("expected ChunkTooLarge, got {:?}", r),
+    }
+
+    let mut session = ReplSession::new(&lua);
+    match session.feed("1+2") {
+        Err(Error::ChunkTooLarge { size: 10, limit: 3 }) => {}
+        r => panic!("expected ChunkTooLarge, got {:?}", r),
+    }
+    assert!(!session.is_incomplete());
+
+    lua.set_max_chunk_size(0);
+    assert_eq!(lua.eval::<i64>(source, None).unwrap(), 3);
+}
+
+#[test]
+fn test_custom_allocator() {
+    use std::alloc::{self, Layout};
+    use std::mem;
+    use LuaAllocator;
+
+    #[derive(Default)]
+    struct Stats {
+        in_use: usize,
+        allocations: usize,
+        dropped: bool,
+    }
+
+    struct Counting(Arc<Mutex<Stats>>);
+
+    impl Counting {
+        fn layout(size: usize) -> Layout {
+            Layout::from_size_align(size, mem::align_of::<u64>()).unwrap()
+        }
+    }
+
+    unsafe impl LuaAllocator for Counting {
+        fn alloc(&mut self, size: usize) -> *mut u8 {
+            let mut stats = self.0.lock().unwrap();
+            stats.in_use += size;
+            stats.allocations += 1;
+            unsafe { alloc::alloc(Counting::layout(size)) }
+        }
+
+        fn realloc(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+            let mut stats = self.0.lock().unwrap();
+            stats.in_use = stats.in_use - old_size + new_size;
+            unsafe { alloc::realloc(ptr, Counting::layout(old_size), new_size) }
+        }
+
+        fn free(&mut self, ptr: *mut u8, size: usize) {
+            self.0.lock().unwrap().in_use -= size;
+            unsafe { alloc::dealloc(ptr, Counting::layout(size)) }
+        }
+    }
+
+    impl Drop for Counting {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().dropped = true;
+        }
+    }
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let lua = Lua::new_with_allocator(Box::new(Counting(stats.clone())));
+    lua.exec::<()>(
+        r#"
+            local t = {}
+            for i = 1, 1000 do
+                t[i] = tostring(i)
+            end
+            big = table.concat(t, ",")
+        "#,
+        None,
+    ).unwrap();
+    assert!(lua.globals().get::<_, String>("big").unwrap().as_bytes().len() > 1000);
+    {
+        let stats = stats.lock().unwrap();
+        assert!(stats.in_use > 1000);
+        assert!(stats.allocations > 1000);
+        assert!(!stats.dropped);
+        assert_eq!(lua.used_memory(), stats.in_use);
+        assert!(lua.peak_memory() >= stats.in_use);
+    }
+
+    drop(lua);
+    let stats = stats.lock().unwrap();
+    assert_eq!(stats.in_use, 0);
+    assert!(stats.dropped);
+}
+
+#[test]
+fn test_gc_collect_reporting() {
+    let lua = Lua::new();
+    lua.gc_collect().unwrap();
+    lua.gc_collect().unwrap();
+
+    let baseline = lua.used_memory();
+    lua.exec::<()>(
+        "kept = string.rep('k', 50000); local dropped = string.rep('d', 200000)",
+        None,
+    ).unwrap();
+    assert!(lua.used_memory() > baseline + 250000);
+
+    let freed = lua.gc_collect_reporting().unwrap();
+    assert!(freed >= 200000);
+    assert!(lua.used_memory() >= baseline + 50000);
+    assert!(lua.gc_collect_reporting().unwrap() < 1024);
+
+    lua.exec::<()>(
+        "setmetatable({}, { __gc = function() error('finalizer failed') end })",
+        None,
+    ).unwrap();
+    match lua.gc_collect_reporting() {
+        Err(Error::GarbageCollectorError(_)) => {}
+        r => panic!("expected GarbageCollectorError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_peak_memory() {
+    let lua = Lua::new();
+    lua.gc_collect().unwrap();
+    lua.reset_peak();
+    assert_eq!(lua.peak_memory(), lua.used_memory());
+
+    let baseline = lua.used_memory();
+    lua.exec::<()>(
+        r#"
+            local t = {}
+            for i = 1, 3 do
+                t[i] = string.rep(tostring(i), 100000)
+            end
+        "#,
+        None,
+    ).unwrap();
+    lua.gc_collect().unwrap();
+    assert!(lua.used_memory() < baseline + 10000);
+    assert!(lua.peak_memory() >= baseline + 300000);
+
+    lua.reset_peak();
+    assert_eq!(lua.peak_memory(), lua.used_memory());
+}
+
+#[test]
+fn test_repl_session() {
+    use {ReplSession, ReplStatus};
+
+    let lua = Lua::new();
+    let mut session = ReplSession::new(&lua);
+
+    let mut feed = |line: &str| match session.feed(line) {
+        Ok(ReplStatus::Complete(values)) => Ok(Some(session.format_values(values).unwrap())),
+        Ok(ReplStatus::Incomplete) => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    assert_eq!(feed("function fib(n)").unwrap(), None);
+    assert_eq!(feed("  if n < 2 then return n end").unwrap(), None);
+    assert_eq!(feed("  return fib(n - 1) +").unwrap(), None);
+    assert_eq!(feed("    fib(n - 2)").unwrap(), None);
+    assert_eq!(feed("end").unwrap(), Some("".to_owned()));
+    assert_eq!(feed("x = fib(10)").unwrap(), Some("".to_owned()));
+    assert!(
+        feed("x, x * 2, 'str', nil, {}")
+            .unwrap()
+            .unwrap()
+            .starts_with("55\t110\tstr\tnil\ttable: ")
+    );
+
+    match feed("local t = {)") {
+        Err(Error::SyntaxError {
+            incomplete_input: false,
+            ..
+        }) => {}
+        r => panic!("expected SyntaxError, got {:?}", r),
+    }
+    match feed("error('boom')") {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+    assert_eq!(feed("x + 1").unwrap(), Some("56".to_owned()));
+}
+
+#[test]
+fn test_value_downcasting() {
+    let lua = Lua::new();
+
+    let (table, func, num, float, s): (Value, Value, Value, Value, Value) = lua
+        .eval(r#"{}, print, 1, 1.5, "str""#, None)
+        .unwrap();
+    assert!(table.is_table() && !table.is_nil());
+    assert!(func.is_function() && !func.is_table());
+    assert!(num.is_integer() && num.is_number());
+    assert!(!float.is_integer() && float.is_number());
+    assert!(s.is_string() && !s.is_number());
+    assert!(Nil.is_nil());
+
+    table.into_table().unwrap();
+    func.into_function().unwrap();
+    s.into_string().unwrap();
+    match num.into_string() {
+        Err(Error::FromLuaConversionError {
+            from: "integer",
+            to: "string",
+            ..
+        }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    match float.into_userdata() {
+        Err(Error::FromLuaConversionError {
+            from: "number",
+            to: "userdata",
+            ..
+        }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+
+    let error = Value::Error(Error::CoroutineInactive);
+    assert!(error.is_error() && !error.is_userdata());
+    assert!(error.into_thread().is_err());
+}
+
+#[test]
+fn test_number_to_string() {
+    let lua = Lua::new();
+    let tostring: Function = lua.globals().get("tostring").unwrap();
+
+    for &n in &[
+        0.0, -0.0, 1.0, -2.5, 0.1, 1.0 / 3.0, 1e15, 1e16, 123456789012345.0, 1e-300, 1e300,
+        1.0 / 0.0, -1.0 / 0.0,
+    ] {
+        let expected = tostring.call::<_, StdString>(n).unwrap();
+        assert_eq!(Lua::number_to_string(n), expected);
+    }
+    for &i in &[0, -1, 42, i64::max_value(), i64::min_value()] {
+        let expected = tostring.call::<_, StdString>(i).unwrap();
+        assert_eq!(Lua::integer_to_string(i), expected);
+    }
+}
+
+#[test]
+fn test_load_cached_file() {
+    use bytecode_cache::{self, cache_path, SourceStamp};
+
+    let dir = env::temp_dir().join(format!("rlua-test-cache-{}", process::id()));
+    let cache_dir = dir.join("cache");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("script.lua");
+
+    let lua = Lua::new();
+    let cached_files = || fs::read_dir(&cache_dir).unwrap().count();
+
+    fs::write(&script, "#!/usr/bin/env lua\nreturn 1 + 2").unwrap();
+    let f = lua.load_cached_file(&script, &cache_dir).unwrap();
+    assert_eq!(f.call::<_, i64>(()).unwrap(), 3);
+    assert_eq!(cached_files(), 1);
+    let f = lua.load_cached_file(&script, &cache_dir).unwrap();
+    assert_eq!(f.call::<_, i64>(()).unwrap(), 3);
+    assert_eq!(cached_files(), 1);
+
+    // Changing the source invalidates the cache.
+    fs::write(&script, "return 'changed', error ~= nil").unwrap();
+    let f = lua.load_cached_file(&script, &cache_dir).unwrap();
+    assert_eq!(
+        f.call::<_, (StdString, bool)>(()).unwrap(),
+        ("changed".to_owned(), true)
+    );
+
+    // A cache file compiled from different contents is not used, even if the modification time
+    // and size of the source file match.
+    let first = "return 'first'";
+    fs::write(&script, first).unwrap();
+    let bytecode = lua.load_cached_file(&script, &cache_dir).unwrap().dump(false);
+    fs::write(&script, "return 'other'").unwrap();
+    let stamp = SourceStamp::new(&fs::metadata(&script).unwrap(), first.as_bytes()).unwrap();
+    bytecode_cache::write(&cache_path(&script, &cache_dir), &stamp, &bytecode).unwrap();
+    let f = lua.load_cached_file(&script, &cache_dir).unwrap();
+    assert_eq!(f.call::<_, StdString>(()).unwrap(), "other");
+
+    // A corrupt cache file is replaced.
+    fs::write(&script, "return 'changed'").unwrap();
+    lua.load_cached_file(&script, &cache_dir).unwrap();
+    let cache_file = fs::read_dir(&cache_dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut corrupt = fs::read(&cache_file).unwrap();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    fs::write(&cache_file, &corrupt).unwrap();
+    let f = lua.load_cached_file(&script, &cache_dir).unwrap();
+    assert_eq!(f.call::<_, StdString>(()).unwrap(), "changed");
+    assert_ne!(fs::read(&cache_file).unwrap(), corrupt);
+    assert_eq!(cached_files(), 1);
+
+    fs::write(&script, "error('oops')").unwrap();
+    match lua.load_cached_file(&script, &cache_dir).unwrap().call::<_, ()>(()) {
+        Err(Error::RuntimeError(msg)) => assert!(msg.contains("script.lua:1: oops")),
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+
+    match lua.load_cached_file(&dir.join("missing.lua"), &cache_dir) {
+        Err(Error::ExternalError(_)) => {}
+        r => panic!("expected ExternalError, got {:?}", r),
+    }
+
+    fs::write(&script, b"\xff\xfer\0e\0t\0u\0r\0n\0").unwrap();
+    match lua.load_cached_file(&script, &cache_dir) {
+        Err(Error::UnsupportedEncoding { encoding }) => assert_eq!(encoding, "UTF-16LE"),
+        r => panic!("expected UnsupportedEncoding, got {:?}", r),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_package_path() {
+    let dir = env::temp_dir().join(format!("rlua-test-package-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    fs::write(dir.join("greeting.lua"), "return 'hello'").unwrap();
+    fs::write(dir.join("nested").join("init.lua"), "return 'nested'").unwrap();
+
+    let lua = Lua::new();
+    lua.set_package_path("").unwrap();
+    lua.set_package_cpath("").unwrap();
+    assert!(lua.exec::<()>("require 'greeting'", None).is_err());
+
+    lua.prepend_package_path(&dir).unwrap();
+    assert_eq!(lua.eval::<StdString>("require 'greeting'", None).unwrap(), "hello");
+    assert_eq!(lua.eval::<StdString>("require 'nested'", None).unwrap(), "nested");
+    assert_eq!(lua.eval::<StdString>("package.cpath", None).unwrap(), "");
+
+    // The package table is found even without the global.
+    lua.exec::<()>("package = nil", None).unwrap();
+    lua.set_package_cpath("./?.so").unwrap();
+    let loaded: Table = lua.named_registry_value("_LOADED").unwrap();
+    let package: Table = loaded.get("package").unwrap();
+    assert_eq!(package.get::<_, StdString>("cpath").unwrap(), "./?.so");
+
+    let path = package.get::<_, StdString>("path").unwrap();
+    for dir in &["scripts;lib", "scripts/?"] {
+        match lua.prepend_package_path(Path::new(dir)) {
+            Err(Error::ToLuaConversionError { .. }) => {}
+            r => panic!("expected ToLuaConversionError, got {:?}", r),
+        }
+    }
+    assert_eq!(package.get::<_, StdString>("path").unwrap(), path);
+
+    loaded.set("package", Nil).unwrap();
+    match lua.set_package_path("./?.lua") {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+
+    lua.unset_named_registry_value("_LOADED").unwrap();
+    match lua.prepend_package_path(&dir) {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_error_position() {
+    let lua = Lua::new();
+    let position = |chunk_name: &str, line| {
+        Some(ErrorPosition {
+            chunk_name: chunk_name.to_owned(),
+            line,
+        })
+    };
+
+    let err = lua.load("x = \n\n)", Some("syntax:1:")).unwrap_err();
+    assert_eq!(err.position(), position("syntax:1:", 3));
+    let err = lua.load("x = (", Some("=stdin")).unwrap_err();
+    assert_eq!(err.position(), position("stdin", 1));
+
+    let err = lua.exec::<()>("\nerror('boom')", Some("runtime")).unwrap_err();
+    assert_eq!(err.position(), position("runtime", 2));
+    let err = lua
+        .exec::<()>("local t = nil\nreturn t.x", Some("index"))
+        .unwrap_err();
+    assert_eq!(err.position(), position("index", 2));
+
+    // Without a position in the message, the traceback is used.
+    let err = lua
+        .exec::<()>("local function f()\n error({}) end\nf()", Some("table"))
+        .unwrap_err();
+    assert_eq!(err.position(), position("table", 2));
+    let err = lua
+        .exec::<()>("local function f()\n error('x', 0) end\nf()", Some("level"))
+        .unwrap_err();
+    assert_eq!(err.position(), position("level", 2));
+    let err = lua
+        .exec::<()>("\nerror('http://host:80: unreachable', 0)", Some("url"))
+        .unwrap_err();
+    assert_eq!(err.position(), position("url", 2));
+    let err = Error::RuntimeError("request to host:80:path failed".to_owned());
+    assert_eq!(err.position(), None);
+
+    let fail = lua
+        .create_function(|_, ()| Err::<(), _>(Error::RuntimeError("failed".to_owned())))
+        .unwrap();
+    lua.globals().set("fail", fail).unwrap();
+    let err = lua.exec::<()>("\n\nfail()", Some("callback")).unwrap_err();
+    assert_eq!(err.position(), position("callback", 3));
+}
+
+#[test]
+fn test_source_map() {
+    let lua = Lua::new();
+    let position = |chunk_name: &str, line| {
+        Some(ErrorPosition {
+            chunk_name: chunk_name.to_owned(),
+            line,
+        })
+    };
+
+    let mut map = SourceMap::new("app.moon");
+    map.add_line(1, 10);
+    map.add_line(3, 20);
+    lua.set_source_map("app", map.clone());
+    let mut file_map = SourceMap::new("lib.fnl");
+    file_map.add_line(2, 5);
+    lua.set_source_map("@lib.lua", file_map);
+
+    let source = "local function f()\n error('boom')\nend\nf()";
+    let err = lua.exec::<()>(source, Some("app")).unwrap_err();
+    assert_eq!(err.position(), position("app.moon", 10));
+    let message = err.to_string();
+    assert!(message.contains("app.moon:10: boom"), "{}", message);
+    assert!(message.contains("app.moon:20: in main chunk"), "{}", message);
+    assert!(!message.contains("[string \"app\"]"), "{}", message);
+
+    // Lines before the first mapping are left unchanged.
+    let err = lua.exec::<()>("\n\nerror('boom')", Some("@lib.lua")).unwrap_err();
+    assert_eq!(err.position(), position("lib.fnl", 5));
+    let err = lua.exec::<()>("error('boom')", Some("@lib.lua")).unwrap_err();
+    assert_eq!(err.position(), position("lib.lua", 1));
+
+    let err = lua.load("x = \n\n\n)", Some("app")).unwrap_err();
+    assert_eq!(err.position(), position("app.moon", 20));
+
+    let fail = lua
+        .create_function(|_, ()| Err::<(), _>(Error::RuntimeError("failed".to_owned())))
+        .unwrap();
+    lua.globals().set("fail", fail).unwrap();
+    match lua.exec::<()>("\n\n\nfail()", Some("app")) {
+        Err(Error::CallbackError { ref traceback, .. }) => {
+            assert!(traceback.contains("app.moon:20:"), "{}", traceback)
+        }
+        r => panic!("expected CallbackError, got {:?}", r),
+    };
+
+    let err = lua.exec::<()>("error('boom')", Some("other")).unwrap_err();
+    assert_eq!(err.position(), position("other", 1));
+
+    let warn = lua
+        .create_function(|lua, message: StdString| {
+            lua.warn(&message);
+            Ok(())
+        })
+        .unwrap();
+    lua.globals().set("warn", warn).unwrap();
+    let (_, diagnostics) = lua.exec_with_diagnostics::<()>("\n\nwarn('x')", Some("app"));
+    assert_eq!(diagnostics[0].position, position("app.moon", 20));
+
+    // Only whole chunk names starting a position are translated.
+    let mut short_map = SourceMap::new("short.moon");
+    short_map.add_line(1, 99);
+    lua.set_source_map("=a", short_map);
+    let err = lua
+        .exec::<()>("error('data:12: bad')", Some("=a"))
+        .unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("short.moon:99: data:12: bad"), "{}", message);
+
+    assert_eq!(lua.remove_source_map("app"), Some(map));
+    assert_eq!(lua.remove_source_map("app"), None);
+    let err = lua.exec::<()>("error('boom')", Some("app")).unwrap_err();
+    assert_eq!(err.position(), position("app", 1));
+}
+
+#[test]
+fn test_hashable_value() {
+    use std::collections::HashMap;
+
+    let lua = Lua::new();
+    let key = |source: &str| HashableValue::new(lua.eval::<Value>(source, None).unwrap());
+
+    assert_eq!(key("1"), key("1.0"));
+    assert_eq!(key("0"), key("-0.0"));
+    assert_eq!(key("2^53"), key("math.tointeger(2^53)"));
+    assert_ne!(key("1"), key("1.5"));
+    assert_ne!(key("1"), key("'1'"));
+    assert_eq!(key("1.5"), key("3 / 2"));
+    assert_eq!(key("0/0"), key("-(0/0)"));
+    assert_eq!(key("'a' .. ('b'):rep(50)"), key("'a' .. ('b'):rep(50)"));
+    assert_ne!(key("'a'"), key("'b'"));
+    assert_eq!(key("true"), key("true"));
+    assert_ne!(key("true"), key("false"));
+    assert_eq!(key("nil"), key("nil"));
+    assert_ne!(key("{}"), key("{}"));
+    assert_ne!(key("print"), key("tostring"));
+    assert_eq!(key("print"), key("print"));
+
+    let table: Table = lua.eval("{}", None).unwrap();
+    let same = HashableValue::new(Value::Table(table.clone()));
+    assert_eq!(same, HashableValue::from(Value::Table(table)));
+
+    // The map has the same keys as the Lua table it is built from.
+    let source: Table = lua
+        .eval("{ [1] = 'a', [2.5] = 'b', x = 'c', [true] = 'd', [print] = 'e' }", None)
+        .unwrap();
+    let mut map = HashMap::new();
+    for pair in source.pairs::<HashableValue, StdString>() {
+        let (key, value) = pair.unwrap();
+        map.insert(key, value);
+    }
+    assert_eq!(map.len(), 5);
+    assert_eq!(map[&key("1.0")], "a");
+    assert_eq!(map[&key("5 / 2")], "b");
+    assert_eq!(map[&key("'x'")], "c");
+    assert_eq!(map[&key("true")], "d");
+    assert_eq!(map[&key("print")], "e");
+
+    let globals = lua.globals();
+    globals.set("key", map.keys().find(|k| k.value().is_function()).cloned()).unwrap();
+    assert!(lua.eval::<bool>("key == print", None).unwrap());
+}
+
+#[test]
+fn test_serialize_value() {
+    let lua = Lua::new();
+    let other = Lua::new();
+
+    let value = lua
+        .eval::<Value>(
+            r#"
+                local shared = { "shared" }
+                local t = {
+                    1, 2.0, -3, "four\0five", true, false,
+                    nested = { x = 1.5, [10] = "ten", [false] = {} },
+                    a = shared,
+                    b = shared,
+                    [math.mininteger] = math.maxinteger,
+                }
+                t.self = t
+                return t
+            "#,
+            None,
+        ).unwrap();
+    let bytes = lua.serialize_value(value).unwrap();
+    let t: Table = other.unpack(other.deserialize_value(&bytes).unwrap()).unwrap();
+    other.globals().set("t", t).unwrap();
+    assert!(
+        other
+            .eval::<bool>(
+                r#"
+                    return #t == 6 and math.type(t[1]) == "integer" and math.type(t[2]) == "float"
+                        and t[3] == -3 and t[4] == "four\0five" and t[5] == true and t[6] == false
+                        and t.nested.x == 1.5 and t.nested[10] == "ten"
+                        and type(t.nested[false]) == "table"
+                        and t.a[1] == "shared" and rawequal(t.a, t.b) and rawequal(t.self, t)
+                        and t[math.mininteger] == math.maxinteger
+                "#,
+                None,
+            ).unwrap()
+    );
+
+    let f: Value = lua.eval("{ f = print }", None).unwrap();
+    match lua.serialize_value(f) {
+        Err(Error::FromLuaConversionError { from: "function", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+
+    let bytes = lua.serialize_value(Value::Integer(300)).unwrap();
+    for malformed in &[
+        &[][..],
+        &[2, 0][..],
+        &bytes[..bytes.len() - 1],
+        &[bytes.as_slice(), &[0]].concat()[..],
+        &[1, 7, 0][..],
+        &[1, 5, 10, b'a'][..],
+    ] {
+        match lua.deserialize_value(malformed) {
+            Err(Error::ToLuaConversionError { .. }) => {}
+            r => panic!("expected ToLuaConversionError, got {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_import_value() {
+    let worker = Lua::new();
+    let main = Lua::new();
+
+    let value = worker
+        .eval::<Value>(
+            r#"
+                local shared = {}
+                local t = { 1, 2.5, "three", inner = { shared, shared } }
+                t.self = t
+                return t
+            "#,
+            None,
+        ).unwrap();
+    let t: Table = main.unpack(main.import_value(&worker, value).unwrap()).unwrap();
+    main.globals().set("t", t).unwrap();
+    assert!(
+        main.eval::<bool>(
+            r#"
+                return t[1] == 1 and math.type(t[2]) == "float" and t[3] == "three"
+                    and rawequal(t.self, t) and rawequal(t.inner[1], t.inner[2])
+            "#,
+            None,
+        ).unwrap()
+    );
+
+    let f: Value = worker.eval("{ 1, { print } }", None).unwrap();
+    match main.import_value(&worker, f) {
+        Err(Error::FromLuaConversionError { from: "function", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+
+    let t: Value = worker.eval("{}", None).unwrap();
+    match worker.import_value(&main, t) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    };
+}
+
+#[test]
+fn test_deeply_nested_chunks() {
+    fn check(lua: &Lua) -> Result<()> {
+        let constructs = [
+            ("{", "}"),
+            ("(", ")"),
+            ("- ", ""),
+            ("not ", ""),
+            ("#", ""),
+            ("function() return ", " end"),
+            ("1 .. ", ""),
+            ("2 ^ ", ""),
+        ];
+        let mut depth = 1;
+        while depth <= 1_000_000 {
+            for &(open, close) in &constructs {
+                let source = format!("return {}1{}", open.repeat(depth), close.repeat(depth));
+                match lua.load(&source, None) {
+                    Ok(_) => assert!(depth < 200, "{:?} nested {} deep loaded", open, depth),
+                    Err(Error::SyntaxError { ref message, .. }) => {
+                        assert!(message.contains("C levels"), "unexpected error {}", message)
+                    }
+                    Err(err) => panic!("unexpected error {:?}", err),
+                }
+            }
+            depth *= 4;
+        }
+        Ok(())
+    }
+
+    let lua = Lua::new();
+    check(&lua).unwrap();
+
+    // Loading from inside a callback starts with a deeper C stack.
+    let check_callback = lua.create_function(|lua, ()| check(lua)).unwrap();
+    lua.globals().set("check", check_callback).unwrap();
+    lua.exec::<()>("check()", None).unwrap();
+    lua.exec::<()>("coroutine.wrap(check)()", None).unwrap();
+}
+
+#[test]
+fn test_register() {
+    let lua = Lua::new();
+
+    lua.register(|api| {
+        api.value("answer", 41).value("answer", 42);
+        api.table("util")
+            .function("double", |_, x: i64| Ok(x * 2))
+            .table("inner")
+            .value("flag", true);
+        api.table("util").value("name", "util");
+        api.table("string").function("twice", |_, s: StdString| Ok(s.repeat(2)));
+    }).unwrap();
+
+    assert_eq!(
+        lua.eval::<i64>("return util.double(answer)", None).unwrap(),
+        84
+    );
+    assert!(lua.eval::<bool>("return util.inner.flag", None).unwrap());
+    assert_eq!(lua.eval::<StdString>("return util.name", None).unwrap(), "util");
+    assert_eq!(lua.eval::<StdString>("return ('ab'):twice()", None).unwrap(), "abab");
+    // The existing `string` table was extended rather than replaced.
+    assert_eq!(lua.eval::<StdString>("return ('ab'):upper()", None).unwrap(), "AB");
+
+    // Entry errors are returned, and nothing is registered.
+    struct Failing;
+    impl<'lua> ToLua<'lua> for Failing {
+        fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+            Err(Error::RuntimeError("bad entry".to_owned()))
+        }
+    }
+    match lua.register(|api| {
+        api.value("unregistered", true);
+        api.table("nested").value("bad", Failing);
+    }) {
+        Err(Error::RuntimeError(ref msg)) if msg == "bad entry" => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(lua.globals().get::<_, Value>("unregistered").unwrap().is_nil());
+}
+
+#[test]
+fn test_print_handler_streams() {
+    let lua = Lua::new();
+    let output = Arc::new(Mutex::new(Vec::<StdString>::new()));
+
+    let lines = output.clone();
+    lua.set_print_handler(move |line| {
+        lines.lock().unwrap().push(line.to_owned());
+        Ok(())
+    }).unwrap();
+
+    // Checks from within the script that every line printed so far has already been handled.
+    let lines = output.clone();
+    let printed = lua
+        .create_function(move |_, ()| Ok(lines.lock().unwrap().len()))
+        .unwrap();
+    lua.globals().set("printed", printed).unwrap();
+
+    lua.exec::<()>(
+        r#"
+        for i = 1, 3 do
+            print("progress", i, i == 3)
+            assert(printed() == i)
+        end
+        print(setmetatable({}, { __tostring = function() return "custom" end }), nil)
+    "#,
+        None,
+    ).unwrap();
+    assert_eq!(
+        *output.lock().unwrap(),
+        vec![
+            "progress\t1\tfalse",
+            "progress\t2\tfalse",
+            "progress\t3\ttrue",
+            "custom\tnil",
+        ]
+    );
+
+    lua.set_print_handler(|_| Err(Error::RuntimeError("closed".to_owned()))).unwrap();
+    assert!(lua.exec::<()>("print('lost')", None).is_err());
+}
+
+#[test]
+fn test_result_multi_conversion() {
+    use std::result::Result as StdResult;
+
+    let lua = Lua::new();
+    let parse = lua
+        .create_function(|_, s: StdString| {
+            Ok(s.parse::<i64>().map_err(|_| format!("invalid number {:?}", s)))
+        })
+        .unwrap();
+    lua.globals().set("parse", parse).unwrap();
+    lua.exec::<()>(
+        r#"
+        local value, err = parse("12")
+        assert(value == 12 and err == nil)
+        value, err = parse("x")
+        assert(value == nil and err == 'invalid number "x"')
+    "#,
+        None,
+    ).unwrap();
+
+    let open: Function = lua
+        .eval(
+            r#"
+            function(name)
+                if name == "missing" then
+                    return nil, name .. ": not found"
+                elseif name == "empty" then
+                    return nil
+                end
+                return "contents of " .. name
+            end
+        "#,
+            None,
+        ).unwrap();
+    let result = open.call::<_, StdResult<StdString, StdString>>("a").unwrap();
+    assert_eq!(result, Ok("contents of a".to_owned()));
+    let result = open.call::<_, StdResult<StdString, StdString>>("missing").unwrap();
+    assert_eq!(result, Err("missing: not found".to_owned()));
+    let result = open.call::<_, StdResult<Option<StdString>, StdString>>("empty").unwrap();
+    assert_eq!(result, Ok(None));
+}
+
+#[test]
+fn test_tuple_table() {
+    let lua = Lua::new();
+
+    let record = TupleTable((7, "seven".to_owned(), true));
+    let value = record.clone().to_lua(&lua).unwrap();
+    let table = match value {
+        Value::Table(ref table) => table.clone(),
+        ref v => panic!("expected table, got {:?}", v),
+    };
+    assert_eq!(table.raw_len(), 3);
+    assert_eq!(table.get::<_, StdString>(2).unwrap(), "seven");
+    let back = lua
+        .unpack::<TupleTable<(i32, StdString, bool)>>(value)
+        .unwrap();
+    assert_eq!(back, record);
+
+    // A tuple table is one value, unlike a plain tuple.
+    let count: Function = lua
+        .eval("function(...) return select('#', ...) end", None)
+        .unwrap();
+    assert_eq!(count.call::<_, i64>(record.clone()).unwrap(), 1);
+    assert_eq!(count.call::<_, i64>(record.0).unwrap(), 3);
+
+    for source in &["{1, 'one'}", "{1, 'one', true, 4}", "'not a table'"] {
+        match lua.eval::<TupleTable<(i32, StdString, bool)>>(source, None) {
+            Err(Error::FromLuaConversionError { to: "TupleTable", .. }) => {}
+            r => panic!("expected FromLuaConversionError, got {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_number_subtype_round_trip() {
+    let lua = Lua::new();
+    let identity: Function = lua.eval("function(...) return ... end", None).unwrap();
+    let math_type: Function = lua.eval("math.type", None).unwrap();
+
+    let check = |value: Value, integer: bool| {
+        let result = identity.call::<_, Value>(value.clone()).unwrap();
+        let ty = math_type.call::<_, StdString>(result.clone()).unwrap();
+        match (result, integer) {
+            (Value::Integer(_), true) => assert_eq!(ty, "integer"),
+            (Value::Number(_), false) => assert_eq!(ty, "float"),
+            (result, _) => panic!("subtype changed: {:?} became {:?}", value, result),
+        }
+    };
+    check(Value::Integer(2), true);
+    check(Value::Integer(0), true);
+    check(Value::Integer(i64::min_value()), true);
+    check(Value::Number(2.0), false);
+    check(Value::Number(-0.0), false);
+    check(Value::Number(1e15), false);
+    check(lua.pack(2.0f32).unwrap(), false);
+    check(lua.pack(2u8).unwrap(), true);
+
+    let (int, float) = lua.eval::<(Value, Value)>("1 + 1, 1 + 1.0", None).unwrap();
+    match (int, float) {
+        (Value::Integer(2), Value::Number(f)) => assert_eq!(f, 2.0),
+        r => panic!("unexpected values {:?}", r),
+    }
+
+    let (int, float) = lua
+        .unpack_multi::<(Value, Value)>(lua.pack_multi((3, 3.0)).unwrap())
+        .unwrap();
+    assert!(int.is_integer() && !float.is_integer());
+    let sequence = lua
+        .unpack::<Vec<Value>>(lua.pack(vec![Value::Integer(4), Value::Number(4.0)]).unwrap())
+        .unwrap();
+    match (&sequence[0], &sequence[1]) {
+        (&Value::Integer(4), &Value::Number(f)) => assert_eq!(f, 4.0),
+        r => panic!("unexpected values {:?}", r),
+    }
+}
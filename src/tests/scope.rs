@@ -1,7 +1,9 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
-use {Error, Function, Lua, String, UserData, UserDataMethods};
+use {
+    AnyUserData, Error, Function, Lua, String, Thread, ThreadStatus, UserData, UserDataMethods,
+};
 
 #[test]
 fn scope_func() {
@@ -29,7 +31,13 @@ fn scope_func() {
         .unwrap()
         .call::<_, ()>(())
     {
-        Err(Error::CallbackError { .. }) => {}
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::ScopeExpired {
+                kind: "function",
+                type_name: None,
+            } => {}
+            ref err => panic!("expected ScopeExpired, got {:?}", err),
+        },
         r => panic!("improper return for destructed function: {:?}", r),
     };
 }
@@ -59,7 +67,28 @@ fn scope_drop() {
     assert_eq!(Rc::strong_count(&rc), 1);
 
     match lua.exec::<()>("test:method()", None) {
-        Err(Error::CallbackError { .. }) => {}
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::ScopeExpired {
+                kind: "userdata",
+                type_name: Some(type_name),
+            } => assert!(type_name.ends_with("MyUserdata")),
+            ref err => panic!("expected ScopeExpired, got {:?}", err),
+        },
+        r => panic!("improper return for destructed userdata: {:?}", r),
+    };
+
+    let test = lua.globals().get::<_, AnyUserData>("test").unwrap();
+    match test.borrow::<MyUserdata>() {
+        Err(Error::ScopeExpired {
+            kind: "userdata", ..
+        }) => {}
+        r => panic!("improper borrow of destructed userdata: {:?}", r.map(|_| ())),
+    };
+    match lua.exec::<()>("return 1 + test", None) {
+        Err(Error::CallbackError { ref cause, .. }) => {
+            let message = cause.to_string();
+            assert!(message.ends_with("MyUserdata was used after its scope ended"));
+        }
         r => panic!("improper return for destructed userdata: {:?}", r),
     };
 }
@@ -98,3 +127,203 @@ fn outer_lua_access() {
     });
     assert_eq!(table.get::<_, String>("a").unwrap(), "b");
 }
+
+#[test]
+fn nested_scopes() {
+    struct Counter(Rc<Cell<i32>>);
+    impl UserData for Counter {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("inc", |_, this, ()| {
+                this.0.set(this.0.get() + 1);
+                Ok(())
+            });
+        }
+    }
+
+    let lua = Lua::new();
+    let rc = Rc::new(Cell::new(0));
+
+    lua.scope(|outer| {
+        let counter = outer.create_userdata(Counter(rc.clone())).unwrap();
+        lua.globals().set("counter", counter.clone()).unwrap();
+
+        lua.scope(|inner| {
+            let inc = inner
+                .create_function(|_, ()| {
+                    counter.borrow::<Counter>()?.0.set(rc.get() + 10);
+                    Ok(())
+                })
+                .unwrap();
+            lua.globals().set("inner_inc", inc).unwrap();
+            lua.exec::<()>("counter:inc() inner_inc() counter:inc()", None)
+                .unwrap();
+        });
+        assert_eq!(rc.get(), 12);
+
+        // The inner scope has ended, but the outer scoped userdata is still alive.
+        match lua.exec::<()>("inner_inc()", None) {
+            Err(Error::CallbackError { .. }) => {}
+            r => panic!("improper return for destructed function: {:?}", r),
+        };
+        lua.exec::<()>("counter:inc()", None).unwrap();
+        assert_eq!(rc.get(), 13);
+        assert_eq!(Rc::strong_count(&rc), 2);
+    });
+
+    assert_eq!(Rc::strong_count(&rc), 1);
+    match lua.exec::<()>("counter:inc()", None) {
+        Err(Error::CallbackError { .. }) => {}
+        r => panic!("improper return for destructed userdata: {:?}", r),
+    };
+}
+
+#[test]
+fn scope_accumulate() {
+    let lua = Lua::new();
+
+    let mut events = Vec::new();
+    let counts = RefCell::new(Vec::new());
+    lua.scope(|scope| {
+        let globals = lua.globals();
+        globals
+            .set(
+                "emit",
+                scope
+                    .create_function_mut(|_, event: i64| {
+                        events.push(event);
+                        Ok(())
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+        for &name in &["a", "b"] {
+            let counts = &counts;
+            globals
+                .set(
+                    name,
+                    scope
+                        .create_function(move |_, ()| {
+                            counts.borrow_mut().push(name);
+                            Ok(())
+                        })
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+        let reenter = scope
+            .create_function_mut(|lua, ()| lua.exec::<()>("reenter()", None))
+            .unwrap();
+        globals.set("reenter", reenter).unwrap();
+
+        lua.exec::<()>("for i = 1, 3 do emit(i) a() b() end emit(4)", None)
+            .unwrap();
+        match lua.exec::<()>("reenter()", None) {
+            Err(Error::CallbackError { ref cause, .. }) => match **cause {
+                Error::CallbackError { ref cause, .. } => match **cause {
+                    Error::RecursiveMutCallback => {}
+                    ref other => panic!("incorrect result: {:?}", other),
+                },
+                ref other => panic!("incorrect result: {:?}", other),
+            },
+            r => panic!("incorrect result: {:?}", r),
+        };
+    });
+
+    assert_eq!(events, vec![1, 2, 3, 4]);
+    assert_eq!(counts.into_inner(), vec!["a", "b", "a", "b", "a", "b"]);
+}
+
+#[test]
+fn try_scope_drop_panic() {
+    struct Panicky(Rc<Cell<i32>>);
+    impl UserData for Panicky {}
+    impl Drop for Panicky {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+            panic!("panicky drop");
+        }
+    }
+
+    let lua = Lua::new();
+    let drops = Rc::new(Cell::new(0));
+
+    let r = lua.try_scope(|scope| {
+        for name in &["a", "b"] {
+            let ud = scope.create_userdata(Panicky(drops.clone()))?;
+            lua.globals().set(*name, ud)?;
+        }
+        Ok(7)
+    });
+    match r {
+        Err(Error::ScopeDropPanic {
+            count: 2,
+            message: Some(ref message),
+        }) if message == "panicky drop" => {}
+        r => panic!("expected ScopeDropPanic, got {:?}", r),
+    }
+    assert_eq!(drops.get(), 2);
+    assert!(lua.exec::<()>("return a + b", None).is_err());
+
+    let r = lua.try_scope(|scope| {
+        scope.create_userdata(Panicky(drops.clone()))?;
+        Err::<(), _>(Error::RuntimeError("body failed".to_owned()))
+    });
+    match r {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+    assert_eq!(drops.get(), 3);
+
+    assert_eq!(
+        lua.try_scope(|scope| scope.create_function(|_, ()| Ok(1))?.call::<_, i32>(()))
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+fn scope_thread() {
+    let lua = Lua::new();
+
+    let rc = Rc::new(Cell::new(0));
+    lua.scope(|scope| {
+        let r = rc.clone();
+        let mut squares = (1..).map(|i: i64| i * i);
+        let thread = scope
+            .create_thread(move |_, ()| {
+                r.set(r.get() + 1);
+                Ok(squares.next())
+            })
+            .unwrap();
+        lua.globals().set("squares", thread).unwrap();
+        let sum = lua
+            .eval::<i64>(
+                r#"
+                    local sum = 0
+                    for _ = 1, 4 do
+                        local ok, square = coroutine.resume(squares)
+                        assert(ok)
+                        sum = sum + square
+                    end
+                    return sum
+                "#,
+                None,
+            ).unwrap();
+        assert_eq!(sum, 1 + 4 + 9 + 16);
+        assert_eq!(Rc::strong_count(&rc), 2);
+    });
+    assert_eq!(rc.get(), 4);
+    assert_eq!(Rc::strong_count(&rc), 1);
+
+    let thread = lua.globals().get::<_, Thread>("squares").unwrap();
+    match thread.resume::<_, ()>(()) {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::ScopeExpired {
+                kind: "function", ..
+            } => {}
+            ref err => panic!("expected ScopeExpired, got {:?}", err),
+        },
+        r => panic!("improper return for expired thread: {:?}", r),
+    }
+    assert_eq!(thread.status(), ThreadStatus::Error);
+}
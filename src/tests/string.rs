@@ -56,5 +56,47 @@ fn string_views() {
 
     assert_eq!(empty.to_str().unwrap(), "");
     assert_eq!(empty.as_bytes_with_nul(), &[0]);
-    assert_eq!(empty.as_bytes(), &[]);
+    assert_eq!(empty.as_bytes(), &[] as &[u8]);
+}
+
+#[test]
+fn string_cache() {
+    let lua = Lua::new();
+    lua.set_string_cache_capacity(2);
+
+    let a1 = lua.create_string("a").unwrap();
+    let a2 = lua.create_string("a").unwrap();
+    let b = lua.create_string("b").unwrap();
+    // Past capacity, strings are still created, just not cached.
+    let c1 = lua.create_string("c").unwrap();
+    let c2 = lua.create_string("c").unwrap();
+    assert_eq!(a1, "a");
+    assert_eq!(a2, "a");
+    assert_eq!(b, "b");
+    assert_eq!(c1, "c");
+    assert_eq!(c2, "c");
+
+    let table = lua.create_table().unwrap();
+    table.set(a1, 1).unwrap();
+    assert_eq!(table.get::<_, i32>(a2).unwrap(), 1);
+
+    lua.set_string_cache_capacity(0);
+    assert_eq!(lua.create_string("a").unwrap(), "a");
+}
+
+#[test]
+fn borrowed_string_to_lua() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let owned = "owned".to_owned();
+    globals.set("by_ref", &owned).unwrap();
+    globals.set("cow_borrowed", Cow::Borrowed("borrowed")).unwrap();
+    globals
+        .set("cow_owned", Cow::Owned::<str>(owned.clone()))
+        .unwrap();
+
+    assert_eq!(globals.get::<_, String>("by_ref").unwrap(), "owned");
+    assert_eq!(globals.get::<_, String>("cow_borrowed").unwrap(), "borrowed");
+    assert_eq!(globals.get::<_, String>("cow_owned").unwrap(), "owned");
 }
@@ -1,4 +1,7 @@
-use {Lua, Nil, Result, Table, Value};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::string::String as StdString;
+
+use {Error, Lua, Nil, Result, Table, ToLua, Value};
 
 #[test]
 fn test_set_get() {
@@ -70,7 +73,7 @@ fn test_table() {
             .sequence_values()
             .collect::<Result<Vec<i64>>>()
             .unwrap(),
-        vec![]
+        Vec::<i64>::new()
     );
 
     // sequence_values should only iterate until the first border
@@ -132,19 +135,200 @@ fn test_metatable() {
             lua.create_function(|_, ()| Ok("index_value")).unwrap(),
         )
         .unwrap();
-    table.set_metatable(Some(metatable));
+    table.set_metatable(Some(metatable)).unwrap();
     assert_eq!(table.get::<_, String>("any_key").unwrap(), "index_value");
     match table.raw_get::<_, Value>("any_key").unwrap() {
         Nil => {}
         _ => panic!(),
     }
-    table.set_metatable(None);
+    table.set_metatable(None).unwrap();
     match table.get::<_, Value>("any_key").unwrap() {
         Nil => {}
         _ => panic!(),
     };
 }
 
+#[test]
+fn test_create_table_with_metatable() {
+    let other = Lua::new();
+    let lua = Lua::new();
+
+    let class: Table = lua
+        .eval("{ __index = { kind = 'instance' } }", None)
+        .unwrap();
+    let instances = (0..3)
+        .map(|_| lua.create_table_with_metatable(&class).unwrap())
+        .collect::<Vec<_>>();
+    for instance in &instances {
+        assert_eq!(instance.get::<_, StdString>("kind").unwrap(), "instance");
+        assert_eq!(instance.raw_len(), 0);
+    }
+    class
+        .get::<_, Table>("__index")
+        .unwrap()
+        .set("kind", "changed")
+        .unwrap();
+    assert_eq!(instances[2].get::<_, StdString>("kind").unwrap(), "changed");
+
+    match other.create_table_with_metatable(&class) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    };
+}
+
+#[test]
+fn test_index_chain() {
+    let lua = Lua::new();
+
+    let object = lua
+        .eval::<Table>(
+            r#"
+                local base = setmetatable({ name = "base" }, { __index = function() end })
+                local derived = setmetatable(
+                    { name = "derived" },
+                    { __index = base, __metatable = false }
+                )
+                return setmetatable({ name = "object" }, { __index = derived })
+            "#,
+            None,
+        ).unwrap();
+    let names = object
+        .index_chain()
+        .map(|t| t.raw_get::<_, StdString>("name"))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(names, vec!["object", "derived", "base"]);
+
+    let cycle = lua
+        .eval::<Table>(
+            r#"
+                local a, b = {}, {}
+                setmetatable(a, { __index = b })
+                setmetatable(b, { __index = a })
+                return a
+            "#,
+            None,
+        ).unwrap();
+    assert_eq!(cycle.index_chain().count(), 2);
+
+    let plain = lua.create_table().unwrap();
+    assert_eq!(plain.index_chain().count(), 1);
+}
+
+#[test]
+fn test_table_extend() {
+    let lua = Lua::new();
+
+    let table = lua
+        .eval::<Table>(
+            r#"
+                return setmetatable({ a = 1 }, {
+                    __newindex = function(t, k, v)
+                        rawset(t, k, v * 10)
+                    end
+                })
+            "#,
+            None,
+        ).unwrap();
+
+    table.extend(vec![("a", 2), ("b", 3)]).unwrap();
+    assert_eq!(table.get::<_, i64>("a").unwrap(), 2);
+    assert_eq!(table.get::<_, i64>("b").unwrap(), 3);
+
+    table.extend_with_meta(vec![("a", 4), ("c", 5)]).unwrap();
+    // `a` already exists, so `__newindex` is only invoked for `c`.
+    assert_eq!(table.get::<_, i64>("a").unwrap(), 4);
+    assert_eq!(table.get::<_, i64>("c").unwrap(), 50);
+
+    let failing = lua
+        .eval::<Table>(
+            "setmetatable({}, { __newindex = function() error('read only') end })",
+            None,
+        ).unwrap();
+    assert!(failing.extend_with_meta(vec![(1, 1)]).is_err());
+    failing.extend(vec![(1, 1)]).unwrap();
+    assert_eq!(failing.raw_len(), 1);
+
+    assert!(table.extend(vec![(Nil, 1)]).is_err());
+}
+
+#[test]
+fn test_is_sequence() {
+    let lua = Lua::new();
+
+    let is_sequence = |source: &str| {
+        lua.eval::<Table>(source, None)
+            .unwrap()
+            .is_sequence()
+            .unwrap()
+    };
+    assert!(is_sequence("{}"));
+    assert!(is_sequence("{ 1, 2, 3 }"));
+    assert!(is_sequence("{ [1] = 'a', [2.0] = 'b' }"));
+    assert!(!is_sequence("{ 1, nil, 3 }"));
+    assert!(!is_sequence("{ [2] = 'b' }"));
+    assert!(!is_sequence("{ [0] = 'a', 'b' }"));
+    assert!(!is_sequence("{ 1, 2, [4] = 4 }"));
+    assert!(!is_sequence("{ 1, 2, [2.5] = 3 }"));
+    assert!(!is_sequence("{ 1, 2, x = 3 }"));
+    assert!(is_sequence("setmetatable({ 1 }, { __index = function() return 0 end })"));
+}
+
+#[test]
+fn test_create_table_from_error() {
+    struct FailAt {
+        index: usize,
+        fail_at: usize,
+        panic: bool,
+    }
+
+    impl<'lua> ToLua<'lua> for FailAt {
+        fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+            if self.index == self.fail_at {
+                if self.panic {
+                    panic!("conversion panic");
+                }
+                Err(Error::ToLuaConversionError {
+                    from: "FailAt",
+                    to: "string",
+                    message: None,
+                })
+            } else {
+                lua.create_string(&format!("value{}", self.index))
+                    .map(Value::String)
+            }
+        }
+    }
+
+    for &panic in &[false, true] {
+        for &fail_at in &[0, 1, 50, 999] {
+            let lua = Lua::new();
+            let build = || {
+                lua.create_table_from((0..1000).map(|index| {
+                    (
+                        format!("key{}", index),
+                        FailAt {
+                            index,
+                            fail_at,
+                            panic,
+                        },
+                    )
+                }))
+            };
+            if panic {
+                assert!(catch_unwind(AssertUnwindSafe(build)).is_err());
+            } else {
+                assert!(build().is_err());
+            }
+
+            // The state is still usable and drops cleanly, which checks for leaked references.
+            let table = lua.create_table_from(vec![("a", 1), ("b", 2)]).unwrap();
+            assert_eq!(table.get::<_, i64>("b").unwrap(), 2);
+            assert_eq!(lua.eval::<i64>("1 + 1", None).unwrap(), 2);
+        }
+    }
+}
+
 #[test]
 fn test_table_error() {
     let lua = Lua::new();
@@ -175,3 +359,484 @@ fn test_table_error() {
     assert!(bad_table.raw_get::<_, i32>(1).is_ok());
     assert_eq!(bad_table.raw_len(), 1);
 }
+
+#[test]
+fn test_keys_values() {
+    let lua = Lua::new();
+
+    let table = lua
+        .eval::<Table>("{ alpha = 1, beta = 2, gamma = 3 }", None)
+        .unwrap();
+    let mut keys = table.keys::<StdString>().unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["alpha", "beta", "gamma"]);
+    let mut values = table.values::<i64>().unwrap();
+    values.sort();
+    assert_eq!(values, vec![1, 2, 3]);
+
+    let mixed = lua
+        .eval::<Table>("{ port = 'not a number', [true] = 1 }", None)
+        .unwrap();
+    match mixed.values::<i64>() {
+        Err(Error::FromLuaConversionError {
+            message: Some(ref message),
+            ..
+        }) => assert!(message.contains("\"port\"")),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    match mixed.keys::<StdString>() {
+        Err(Error::FromLuaConversionError {
+            message: Some(ref message),
+            ..
+        }) => assert!(message.contains("key true")),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_into_hashmap_vec() {
+    let lua = Lua::new();
+
+    let table = lua
+        .eval::<Table>("{ alpha = 1, beta = 2 }", None)
+        .unwrap();
+    let map = table.into_hashmap::<StdString, i64>().unwrap();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map["beta"], 2);
+
+    let table = lua.eval::<Table>("{ 10, 20, 30, x = 'ignored' }", None).unwrap();
+    assert_eq!(table.into_vec::<i64>().unwrap(), vec![10, 20, 30]);
+
+    let table = lua.eval::<Table>("{ 1, 2, {} }", None).unwrap();
+    match table.into_vec::<i64>() {
+        Err(Error::FromLuaConversionError {
+            message: Some(ref message),
+            ..
+        }) => assert!(message.contains("element 3")),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    let table = lua.eval::<Table>("{ limit = 'none' }", None).unwrap();
+    match table.into_hashmap::<StdString, i64>() {
+        Err(Error::FromLuaConversionError {
+            message: Some(ref message),
+            ..
+        }) => assert!(message.contains("\"limit\"")),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_filter_map() {
+    let lua = Lua::new();
+
+    let table = lua
+        .eval::<Table>("setmetatable({ 1, 2, 3, 4, x = 10 }, { __newindex = error })", None)
+        .unwrap();
+    let squares = table
+        .filter_map(|k: Value, v: i64| match k {
+            Value::Integer(i) if v % 2 == 0 => Ok(Some((i / 2, v * v))),
+            Value::Integer(_) => Ok(None),
+            _ => Ok(Some((0, v))),
+        })
+        .unwrap();
+    assert_eq!(squares.get::<_, i64>(1).unwrap(), 4);
+    assert_eq!(squares.get::<_, i64>(2).unwrap(), 16);
+    assert_eq!(squares.get::<_, i64>(0).unwrap(), 10);
+    assert_eq!(squares.keys::<i64>().unwrap().len(), 3);
+    assert!(squares.get_metatable().is_none());
+
+    match table.filter_map(|_: i64, v: i64| Ok(Some((v, v)))) {
+        Err(Error::FromLuaConversionError {
+            message: Some(ref message),
+            ..
+        }) => assert!(message.contains("\"x\"")),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    match table.filter_map(|_: Value, _: i64| -> Result<Option<(i64, i64)>> {
+        Err(Error::RuntimeError("stop".to_owned()))
+    }) {
+        Err(Error::RuntimeError(ref message)) => assert_eq!(message, "stop"),
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+    assert!(table
+        .filter_map(|_: Value, v: i64| Ok(Some((Nil, v))))
+        .is_err());
+}
+
+#[test]
+fn test_merge() {
+    let lua = Lua::new();
+
+    let base: Table = lua
+        .eval(
+            r#"
+                local base = { name = "base", list = { 1, 2 }, nested = { a = 1, b = 2 } }
+                base.nested.self = base.nested
+                return base
+            "#,
+            None,
+        ).unwrap();
+    let overlay: Table = lua
+        .eval(
+            r#"
+                local overlay = { name = "overlay", extra = true, nested = { b = 3, c = 4 } }
+                overlay.nested.self = overlay.nested
+                return overlay
+            "#,
+            None,
+        ).unwrap();
+
+    let shallow: Table = lua.eval("{ name = 'shallow', nested = {} }", None).unwrap();
+    shallow
+        .merge(overlay.clone(), |key, old, new| {
+            assert!(key == "name" || key == "nested");
+            Ok(if key == "name" { old } else { new })
+        }).unwrap();
+    assert_eq!(shallow.get::<_, StdString>("name").unwrap(), "shallow");
+    assert_eq!(
+        shallow.get::<_, Table>("nested").unwrap().get::<_, i64>("c").unwrap(),
+        4
+    );
+
+    let mut keys = Vec::new();
+    base.deep_merge(overlay, |key, _, new| {
+        keys.push(key.to_owned());
+        Ok(new)
+    }).unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["name", "nested.b"]);
+
+    assert_eq!(base.get::<_, StdString>("name").unwrap(), "overlay");
+    assert_eq!(base.get::<_, bool>("extra").unwrap(), true);
+    assert_eq!(base.get::<_, Table>("list").unwrap().raw_len(), 2);
+    let nested: Table = base.get("nested").unwrap();
+    assert_eq!(nested.get::<_, i64>("a").unwrap(), 1);
+    assert_eq!(nested.get::<_, i64>("b").unwrap(), 3);
+    assert_eq!(nested.get::<_, i64>("c").unwrap(), 4);
+
+    // Keys which are not names are written in brackets, so that 1 and "1" are told apart.
+    let target: Table = lua
+        .eval("{ [1] = { x = 1 }, ['1'] = 1, ['a key'] = { [2.5] = 1 }, [true] = 1 }", None)
+        .unwrap();
+    let source: Table = lua
+        .eval("{ [1] = { x = 2 }, ['1'] = 2, ['a key'] = { [2.5] = 2 }, [true] = 2 }", None)
+        .unwrap();
+    let mut keys = Vec::new();
+    target
+        .deep_merge(source, |key, _, new| {
+            keys.push(key.to_owned());
+            Ok(new)
+        }).unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["[\"1\"]", "[\"a key\"][2.5]", "[1].x", "[true]"]);
+
+    match base.merge(base.clone(), |_, _, _| Err(Error::RuntimeError("called".to_owned()))) {
+        Ok(()) => {}
+        r => panic!("merging a table into itself should do nothing, got {:?}", r),
+    }
+    let conflict: Table = lua.eval("{ name = 1 }", None).unwrap();
+    assert!(base
+        .merge(conflict, |_, _, _| Err(Error::RuntimeError("conflict".to_owned())))
+        .is_err());
+}
+
+#[test]
+fn test_get_path() {
+    let lua = Lua::new();
+    let config: Table = lua
+        .eval(
+            r#"{
+                server = { port = 8080, tls = { enabled = true } },
+                ["dotted.key"] = { value = 1 },
+                name = "app",
+            }"#,
+            None,
+        ).unwrap();
+
+    assert_eq!(config.get_path::<i64>("server.port").unwrap(), 8080);
+    assert_eq!(config.get_path::<bool>("server.tls.enabled").unwrap(), true);
+    assert_eq!(config.get_path::<StdString>("name").unwrap(), "app");
+    assert_eq!(config.get_path::<Option<i64>>("server.missing").unwrap(), None);
+    assert_eq!(
+        config
+            .get_path_parts::<i64>(&["dotted.key", "value"])
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        config.get_path_parts::<Table>(&[]).unwrap().raw_len(),
+        0
+    );
+
+    match config.get_path::<i64>("server.tls.enabled.value") {
+        Err(Error::FromLuaConversionError {
+            from: "boolean",
+            to: "table",
+            message: Some(ref message),
+        }) => assert_eq!(
+            message,
+            "at \"server.tls.enabled\" in path \"server.tls.enabled.value\""
+        ),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    match config.get_path::<i64>("client.port") {
+        Err(Error::FromLuaConversionError { from: "nil", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    match config.get_path::<i64>("name") {
+        Err(Error::FromLuaConversionError {
+            message: Some(ref message),
+            ..
+        }) => assert!(message.starts_with("value at path \"name\"")),
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_try_get() {
+    let lua = Lua::new();
+    let table: Table = lua
+        .eval(
+            r#"
+                setmetatable({ enabled = false, name = "x", count = 3 }, {
+                    __index = function(_, k) if k == "default" then return 7 end end
+                })
+            "#,
+            None,
+        ).unwrap();
+
+    assert_eq!(table.try_get::<_, bool>("enabled").unwrap(), Some(false));
+    assert_eq!(
+        table.try_get::<_, StdString>("name").unwrap(),
+        Some("x".to_owned())
+    );
+    assert_eq!(table.try_get::<_, i64>("missing").unwrap(), None);
+    assert_eq!(table.try_get::<_, i64>("default").unwrap(), Some(7));
+    match table.try_get::<_, Table>("count") {
+        Err(Error::FromLuaConversionError { from: "integer", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    };
+}
+
+#[test]
+fn test_sort_by() {
+    let lua = Lua::new();
+    let table: Table = lua
+        .eval(r#"{ 3, 1, 2, 10, n = "not sorted" }"#, None)
+        .unwrap();
+
+    let key = |v: &Value| match *v {
+        Value::Integer(i) => i,
+        _ => panic!("not an integer"),
+    };
+    table.sort_by(|a, b| key(b).cmp(&key(a))).unwrap();
+    let values = table
+        .clone()
+        .sequence_values::<i64>()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(values, vec![10, 3, 2, 1]);
+    assert_eq!(table.get::<_, StdString>("n").unwrap(), "not sorted");
+
+    // A panicking comparator leaves the table unchanged.
+    table.raw_set(5, "five").unwrap();
+    assert!(catch_unwind(AssertUnwindSafe(|| {
+        table.sort_by(|a, b| key(a).cmp(&key(b))).unwrap();
+    })).is_err());
+    assert_eq!(table.raw_get::<_, i64>(1).unwrap(), 10);
+    assert_eq!(table.raw_get::<_, StdString>(5).unwrap(), "five");
+}
+
+#[test]
+fn test_deep_equals() {
+    let lua = Lua::new();
+
+    let (a, b, c) = lua
+        .eval::<(Table, Table, Table)>(
+            r#"
+            local f = function() end
+            local a = { 1, 2.5, f = f, nested = { list = { "x", "y" } } }
+            local b = { 1.0, 2.5, f = f, nested = { list = { "x", "y" } } }
+            local c = { 1, 2.5, f = f, nested = { list = { "x", "z" } } }
+            setmetatable(b, { __index = { extra = true } })
+            return a, b, c
+        "#,
+            None,
+        ).unwrap();
+    assert!(a.deep_equals(&b).unwrap());
+    assert!(b.deep_equals(&a).unwrap());
+    assert!(!a.deep_equals(&c).unwrap());
+
+    a.raw_set("extra", true).unwrap();
+    assert!(!a.deep_equals(&b).unwrap());
+    assert!(!b.deep_equals(&a).unwrap());
+
+    // Different functions are never equal, even with the same code.
+    let (f, g) = lua
+        .eval::<(Table, Table)>("{ f = function() end }, { f = function() end }", None)
+        .unwrap();
+    assert!(!f.deep_equals(&g).unwrap());
+
+    let (x, y) = lua
+        .eval::<(Table, Table)>(
+            r#"
+            local x = { name = "node" }
+            x.self = x
+            local y = { name = "node" }
+            y.self = { name = "node", self = y }
+            return x, y
+        "#,
+            None,
+        ).unwrap();
+    assert!(x.deep_equals(&y).unwrap());
+    assert!(x.deep_equals(&x).unwrap());
+
+    let (a, b, c, d) = lua
+        .eval::<(Table, Table, Table, Table)>(
+            r#"
+            local function chain(depth)
+                local t = {}
+                for i = 1, depth do t = { t } end
+                return t
+            end
+            return chain(100), chain(100), chain(200000), chain(200000)
+        "#,
+            None,
+        ).unwrap();
+    assert!(a.deep_equals(&b).unwrap());
+    match c.deep_equals(&d) {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected RuntimeError, got {:?}", r),
+    }
+
+    // Shared subtables are only compared once, rather than once per path reaching them.
+    let (a, b, c) = lua
+        .eval::<(Table, Table, Table)>(
+            r#"
+            local function shared(leaf)
+                local t = { leaf }
+                for i = 1, 20 do t = { t, t } end
+                return t
+            end
+            return shared("x"), shared("x"), shared("y")
+        "#,
+            None,
+        ).unwrap();
+    assert!(a.deep_equals(&b).unwrap());
+    assert!(!a.deep_equals(&c).unwrap());
+}
+
+#[test]
+fn test_pairs_sorted() {
+    let lua = Lua::new();
+
+    let table: Table = lua
+        .eval("{ [10] = 'j', [2] = 'b', [-1] = 'z', [1] = 'a', [100] = 'c' }", None)
+        .unwrap();
+    assert_eq!(
+        table.pairs_sorted::<i64, StdString>().unwrap(),
+        vec![
+            (-1, "z".to_owned()),
+            (1, "a".to_owned()),
+            (2, "b".to_owned()),
+            (10, "j".to_owned()),
+            (100, "c".to_owned()),
+        ]
+    );
+
+    let table: Table = lua.eval("{ b = 1, a = 2, [true] = 3 }", None).unwrap();
+    match table.pairs_sorted::<StdString, i64>() {
+        Err(Error::FromLuaConversionError { message, .. }) => {
+            assert_eq!(message.unwrap(), "key true: expected string or number")
+        }
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_get_with_fuel() {
+    let lua = Lua::new();
+    let (a, looping): (Table, Table) = lua
+        .eval(
+            r#"
+            local a, b = {}, {}
+            setmetatable(a, { __index = function(_, k) return b[k] end })
+            setmetatable(b, { __index = function(_, k) return a[k] end })
+            local looping = setmetatable({}, {
+                __index = function() while true do end end,
+                __newindex = function() while true do end end,
+            })
+            return a, looping
+        "#,
+            None,
+        ).unwrap();
+
+    for r in vec![
+        a.get_with_fuel::<_, Value>("key", 100),
+        looping.get_with_fuel::<_, Value>("key", 1000),
+        looping.set_with_fuel("key", 1, 1000).map(|()| Nil),
+    ] {
+        match r {
+            Err(Error::RuntimeError(ref message)) if message.contains("too deep") => {}
+            r => panic!("expected RuntimeError, got {:?}", r),
+        }
+    }
+
+    // Cheap metamethods and plain tables are unaffected, and the count hook is removed.
+    let defaults: Table = lua
+        .eval("setmetatable({}, { __index = function(_, k) return k * 2 end })", None)
+        .unwrap();
+    assert_eq!(defaults.get_with_fuel::<_, i64>(21, 1000).unwrap(), 42);
+    defaults.set_with_fuel("x", 1, 1000).unwrap();
+    assert_eq!(defaults.get_with_fuel::<_, i64>("x", 1).unwrap(), 1);
+    lua.exec::<()>("for i = 1, 10000 do end", None).unwrap();
+}
+
+#[test]
+fn test_get_with_fuel_exhaustion() {
+    let lua = Lua::new();
+    let (catching, in_coroutine, leaking): (Table, Table, Table) = lua
+        .eval(
+            r#"
+            local function spin()
+                while true do
+                    pcall(function() while true do end end)
+                end
+            end
+            local catching = setmetatable({}, { __index = spin })
+            local in_coroutine = setmetatable({}, {
+                __index = function() return coroutine.wrap(spin)() end,
+            })
+            local leaking = setmetatable({}, {
+                __index = function()
+                    leaked = coroutine.create(function()
+                        local sum = 0
+                        for i = 1, 100000 do sum = sum + i end
+                        return sum
+                    end)
+                    return 1
+                end,
+            })
+            return catching, in_coroutine, leaking
+        "#,
+            None,
+        ).unwrap();
+
+    // Running out of fuel cannot be caught by the metamethods, even in coroutines.
+    for r in vec![
+        catching.get_with_fuel::<_, Value>("key", 10000),
+        in_coroutine.get_with_fuel::<_, Value>("key", 10000),
+    ] {
+        match r {
+            Err(Error::RuntimeError(ref message)) if message.contains("too deep") => {}
+            r => panic!("expected RuntimeError, got {:?}", r),
+        }
+    }
+
+    // Coroutines which outlive the call are not limited afterwards.
+    assert_eq!(leaking.get_with_fuel::<_, i64>("key", 1000).unwrap(), 1);
+    assert_eq!(
+        lua.eval::<i64>("select(2, assert(coroutine.resume(leaked)))", None).unwrap(),
+        5000050000
+    );
+}
@@ -1,6 +1,6 @@
 use std::panic::catch_unwind;
 
-use {Error, Function, Lua, Result, Thread, ThreadStatus};
+use {Error, Function, Lua, Nil, Result, Resumed, Thread, ThreadStatus, Variadic};
 
 #[test]
 fn test_thread() {
@@ -117,3 +117,198 @@ fn coroutine_panic() {
         Err(p) => assert!(*p.downcast::<&str>().unwrap() == "test_panic"),
     }
 }
+
+#[test]
+fn test_thread_resume_limit() {
+    let lua = Lua::new();
+    let thread = lua
+        .create_thread(
+            lua.eval::<Function>(
+                r#"
+                function(n)
+                    local sum = 0
+                    for i = 1, n do
+                        sum = sum + i
+                    end
+                    return sum
+                end
+            "#,
+                None,
+            ).unwrap(),
+        ).unwrap();
+
+    thread.set_resume_limit(Some(100));
+    let mut resumes = 1;
+    let mut result = thread.resume::<_, Option<i64>>(10000).unwrap();
+    while thread.status() == ThreadStatus::Resumable {
+        assert_eq!(result, None);
+        result = thread.resume::<_, Option<i64>>(()).unwrap();
+        resumes += 1;
+    }
+    assert_eq!(result, Some(50005000));
+    assert!(resumes > 10);
+
+    let counting_thread = |result: i64| {
+        let source = format!("function() for i = 1, 10000 do end return {} end", result);
+        lua.create_thread(lua.eval::<Function>(&source, None).unwrap())
+            .unwrap()
+    };
+
+    // Removing the limit lets the thread run to completion in one resume.
+    let thread = counting_thread(1);
+    thread.set_resume_limit(Some(100));
+    thread.set_resume_limit(None);
+    assert_eq!(thread.resume::<_, i64>(()).unwrap(), 1);
+
+    // The limit only applies to the thread it was set on.
+    let other = counting_thread(2);
+    let limited = counting_thread(3);
+    limited.set_resume_limit(Some(100));
+    assert_eq!(other.resume::<_, i64>(()).unwrap(), 2);
+    assert_eq!(limited.resume::<_, Option<i64>>(()).unwrap(), None);
+
+    // Coroutines created by a limited thread are not preempted.
+    let thread = lua
+        .create_thread(
+            lua.eval::<Function>(
+                r#"
+                function()
+                    local gen = coroutine.wrap(function()
+                        for i = 1, 100000 do
+                            coroutine.yield(i)
+                        end
+                    end)
+                    local sum = 0
+                    for _ = 1, 100000 do
+                        sum = sum + gen()
+                    end
+                    return sum
+                end
+            "#,
+                None,
+            ).unwrap(),
+        ).unwrap();
+    thread.set_resume_limit(Some(1000));
+    let mut result = None;
+    while thread.status() == ThreadStatus::Resumable {
+        result = thread.resume::<_, Option<i64>>(()).unwrap();
+    }
+    assert_eq!(result, Some(5000050000));
+}
+
+#[test]
+fn test_exec_in_coroutine() {
+    let lua = Lua::new();
+
+    let thread = lua
+        .exec_in_coroutine(
+            r#"
+                local total = 0
+                while true do
+                    local n = coroutine.yield(total)
+                    if n == nil then break end
+                    total = total + n
+                end
+                return total
+            "#,
+            None,
+        ).unwrap();
+    assert_eq!(thread.resume::<_, i64>(()).unwrap(), 0);
+    assert_eq!(thread.resume::<_, i64>(3).unwrap(), 3);
+    assert_eq!(thread.resume::<_, i64>(4).unwrap(), 7);
+    assert_eq!(thread.resume::<_, i64>(Nil).unwrap(), 7);
+    assert_eq!(thread.status(), ThreadStatus::Unresumable);
+
+    // Plain `exec` cannot yield from the top level.
+    assert!(lua.exec::<()>("coroutine.yield()", None).is_err());
+
+    assert!(lua.exec_in_coroutine("not lua", None).is_err());
+}
+
+#[test]
+fn test_thread_resume_step() {
+    let lua = Lua::new();
+
+    let thread = lua
+        .exec_in_coroutine(
+            r#"
+                coroutine.yield(1)
+                coroutine.yield(2, 3)
+                coroutine.yield()
+                return 4
+            "#,
+            None,
+        ).unwrap();
+    let step = || match thread.resume_step::<_, Variadic<i64>>(()).unwrap() {
+        Resumed::Yielded(values) => Resumed::Yielded(values.to_vec()),
+        Resumed::Returned(values) => Resumed::Returned(values.to_vec()),
+    };
+    assert_eq!(step(), Resumed::Yielded(vec![1]));
+    assert_eq!(step(), Resumed::Yielded(vec![2, 3]));
+    assert_eq!(step(), Resumed::Yielded(vec![]));
+    assert_eq!(step(), Resumed::Returned(vec![4]));
+    match thread.resume_step::<_, ()>(()) {
+        Err(Error::CoroutineInactive) => {}
+        r => panic!("expected CoroutineInactive, got {:?}", r),
+    }
+
+    // A function which returns nothing is distinguishable from one which yields nothing.
+    let thread = lua.exec_in_coroutine("", None).unwrap();
+    assert_eq!(
+        thread.resume_step::<_, ()>(()).unwrap(),
+        Resumed::Returned(())
+    );
+}
+
+#[test]
+fn test_thread_yields() {
+    let lua = Lua::new();
+
+    let thread = lua
+        .exec_in_coroutine(
+            r#"
+                coroutine.yield(1)
+                coroutine.yield(2, 3)
+                coroutine.yield("four")
+                coroutine.yield(5)
+                error("stopped", 0)
+            "#,
+            None,
+        ).unwrap();
+    let mut values = thread.yields::<i64>();
+    assert_eq!(values.next().unwrap().unwrap(), 1);
+    assert_eq!(values.next().unwrap().unwrap(), 2);
+    assert!(values.next().unwrap().is_err());
+    assert_eq!(values.next().unwrap().unwrap(), 5);
+    match values.next() {
+        Some(Err(Error::RuntimeError(ref msg))) if msg.starts_with("stopped") => {}
+        r => panic!("expected the error of the thread, got {:?}", r),
+    }
+    assert!(values.next().is_none());
+
+    // A thread which finishes immediately produces no values.
+    let thread = lua.exec_in_coroutine("return 1", None).unwrap();
+    assert_eq!(thread.yields::<i64>().count(), 0);
+
+    // Yields forced by a resume limit are reported as errors, unlike yields without values.
+    let thread = lua
+        .exec_in_coroutine(
+            r#"
+                coroutine.yield()
+                for i = 1, 10000 do end
+                coroutine.yield(1)
+            "#,
+            None,
+        ).unwrap();
+    thread.set_resume_limit(Some(100));
+    let mut preempted = 0;
+    let mut values = Vec::new();
+    for value in thread.yields::<Option<i64>>() {
+        match value {
+            Err(Error::CoroutinePreempted) => preempted += 1,
+            value => values.push(value.unwrap()),
+        }
+    }
+    assert_eq!(values, vec![None, Some(1)]);
+    assert!(preempted > 10);
+}
@@ -1,6 +1,11 @@
-use std::sync::Arc;
+use std::any;
+use std::string::String as StdString;
+use std::sync::{Arc, Mutex};
 
-use {ExternalError, Function, Lua, MetaMethod, String, UserData, UserDataMethods};
+use {
+    AnyUserData, Error, ExternalError, Function, Lua, MetaMethod, Nil, String, Table, UserData,
+    UserDataBorrowState, UserDataMethods,
+};
 
 #[test]
 fn test_user_data() {
@@ -170,6 +175,78 @@ fn detroys_userdata() {
     assert_eq!(Arc::strong_count(&rc), 1);
 }
 
+#[test]
+fn userdata_finalizer() {
+    struct Tracked {
+        id: i64,
+        log: Arc<Mutex<Vec<StdString>>>,
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.log.lock().unwrap().push(format!("drop {}", self.id));
+        }
+    }
+
+    impl UserData for Tracked {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("id", |_, this, ()| Ok(this.id));
+            methods.add_meta_function(MetaMethod::Gc, |lua, ud: AnyUserData| {
+                let this = ud.borrow::<Tracked>()?;
+                this.log.lock().unwrap().push(format!("finalize {}", this.id));
+                lua.globals().get::<_, Table>("live")?.set(this.id, Nil)?;
+                match this.id {
+                    2 => Err(Error::RuntimeError("finalizer failed".to_owned())),
+                    3 => lua.globals().set("resurrected", ud.clone()),
+                    _ => Ok(()),
+                }
+            });
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let lua = Lua::new();
+    let live = lua.create_table().unwrap();
+    lua.globals().set("live", live.clone()).unwrap();
+    for id in 1..4 {
+        live.set(id, true).unwrap();
+        lua.create_userdata(Tracked {
+            id,
+            log: log.clone(),
+        }).unwrap();
+    }
+
+    // The error returned by the second finalizer is ignored.
+    lua.exec::<()>("collectgarbage('collect')", None).unwrap();
+    {
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 6);
+        for id in 1..4 {
+            let finalized = log.iter().position(|l| *l == format!("finalize {}", id));
+            let dropped = log.iter().position(|l| *l == format!("drop {}", id));
+            assert!(finalized.unwrap() < dropped.unwrap(), "{:?}", *log);
+        }
+    }
+    assert!(lua.eval::<bool>("next(live) == nil", None).unwrap());
+    match lua.exec::<()>("resurrected:id()", None) {
+        Err(Error::CallbackError { .. }) | Err(Error::RuntimeError(_)) => {}
+        r => panic!("expected an error using a finalized userdata, got {:?}", r),
+    };
+
+    // Finalizers are not called when the Lua state is dropped.
+    log.lock().unwrap().clear();
+    let tracked = lua
+        .create_userdata(Tracked {
+            id: 4,
+            log: log.clone(),
+        })
+        .unwrap();
+    lua.globals().set("tracked", tracked).unwrap();
+    drop(live);
+    drop(lua);
+    assert_eq!(*log.lock().unwrap(), vec!["drop 4".to_owned()]);
+}
+
 #[test]
 fn user_value() {
     let lua = Lua::new();
@@ -182,3 +259,415 @@ fn user_value() {
     assert_eq!(ud.get_user_value::<String>().unwrap(), "hello");
     assert!(ud.get_user_value::<u32>().is_err());
 }
+
+#[test]
+fn user_data_type_name() {
+    struct MyUserData;
+    struct OtherUserData;
+
+    impl UserData for MyUserData {}
+    impl UserData for OtherUserData {}
+
+    let lua = Lua::new();
+    let userdata = lua.create_userdata(MyUserData).unwrap();
+    let type_name = userdata.type_name().unwrap();
+    assert!(type_name.ends_with("MyUserData"));
+
+    lua.globals().set("ud", userdata.clone()).unwrap();
+    let tostring = lua.eval::<StdString>("tostring(ud)", None).unwrap();
+    assert!(tostring.starts_with(type_name));
+
+    match userdata.borrow::<OtherUserData>() {
+        Err(Error::UserDataTypeMismatch { expected, found }) => {
+            assert!(expected.ends_with("OtherUserData"));
+            assert_eq!(found, Some(type_name));
+        }
+        _ => panic!("expected UserDataTypeMismatch"),
+    }
+
+    let file = lua.eval::<AnyUserData>("io.stdout", None).unwrap();
+    assert_eq!(file.type_name(), None);
+}
+
+#[test]
+fn user_data_equals() {
+    struct Id(i64);
+
+    impl UserData for Id {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_meta_function(MetaMethod::Eq, |_, (a, b): (AnyUserData, AnyUserData)| {
+                Ok(a.borrow::<Id>()?.0 == b.borrow::<Id>()?.0)
+            });
+        }
+    }
+
+    let lua = Lua::new();
+    let first = lua.create_userdata(Id(1)).unwrap();
+    let same_id = lua.create_userdata(Id(1)).unwrap();
+    let other_id = lua.create_userdata(Id(2)).unwrap();
+
+    lua.globals().set("first", first.clone()).unwrap();
+    let first_again: AnyUserData = lua.globals().get("first").unwrap();
+    assert!(first.equals(&first_again));
+    assert!(first.equals_with_meta(&first_again).unwrap());
+
+    assert!(!first.equals(&same_id));
+    assert!(first.equals_with_meta(&same_id).unwrap());
+
+    assert!(!first.equals(&other_id));
+    assert!(!first.equals_with_meta(&other_id).unwrap());
+
+    let other_lua = Lua::new();
+    let foreign = other_lua.create_userdata(Id(1)).unwrap();
+    assert!(!first.equals(&foreign));
+    match first.equals_with_meta(&foreign) {
+        Err(Error::MismatchedLuaState) => {}
+        r => panic!("expected MismatchedLuaState, got {:?}", r),
+    }
+}
+
+#[test]
+fn user_data_across_callbacks() {
+    struct Counter(i64);
+    struct Token;
+
+    impl UserData for Counter {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method_mut("inc", |_, counter, ()| {
+                counter.0 += 1;
+                Ok(counter.0)
+            });
+        }
+    }
+    impl UserData for Token {}
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    // `Counter` is registered from the main state, and then used from the ephemeral `Lua` of a
+    // callback, both directly and through Lua code.  `Token` is registered for the first time from
+    // inside a callback running in a coroutine, and then used from the main state.
+    globals
+        .set("counter", lua.create_userdata(Counter(0)).unwrap())
+        .unwrap();
+    let callback = lua
+        .create_function(|lua, counter: AnyUserData| {
+            assert!(counter.is::<Counter>().unwrap());
+            counter.borrow_mut::<Counter>()?.0 += 10;
+            let value = lua.eval::<i64>("counter:inc()", None)?;
+            Ok((value, lua.create_userdata(Token)?))
+        }).unwrap();
+    globals.set("callback", callback).unwrap();
+
+    let (value, token) = lua
+        .eval::<(i64, AnyUserData)>(
+            r#"
+                local co = coroutine.wrap(function() return callback(counter) end)
+                return co()
+            "#,
+            None,
+        ).unwrap();
+    assert_eq!(value, 11);
+    assert!(token.is::<Token>().unwrap());
+    assert!(!token.is::<Counter>().unwrap());
+    assert_eq!(
+        lua.create_userdata(Token).unwrap().type_name(),
+        token.type_name()
+    );
+
+    let counter: AnyUserData = globals.get("counter").unwrap();
+    assert_eq!(counter.borrow::<Counter>().unwrap().0, 11);
+    assert_eq!(lua.eval::<i64>("counter:inc()", None).unwrap(), 12);
+}
+
+#[test]
+fn user_data_debug_info() {
+    struct Counter(i64);
+    struct Plain;
+
+    impl UserData for Counter {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("info", |_, _, ud: AnyUserData| {
+                let info = ud.debug_info().unwrap();
+                Ok((format!("{:?}", info.borrow_state), info.debug_string))
+            });
+            methods.add_method_mut("info_mut", |_, _, ud: AnyUserData| {
+                let info = ud.debug_info().unwrap();
+                Ok((format!("{:?}", info.borrow_state), info.debug_string))
+            });
+        }
+
+        fn debug_string(&self) -> Option<StdString> {
+            Some(format!("counter at {}", self.0))
+        }
+    }
+
+    impl UserData for Plain {}
+
+    let lua = Lua::new();
+    let counter = lua.create_userdata(Counter(3)).unwrap();
+    let info = counter.debug_info().unwrap();
+    assert!(info.type_name.ends_with("Counter"));
+    assert_eq!(info.borrow_state, UserDataBorrowState::Unborrowed);
+    assert_eq!(info.debug_string, Some("counter at 3".to_owned()));
+
+    lua.globals().set("counter", counter).unwrap();
+    assert_eq!(
+        lua.eval::<(StdString, Option<StdString>)>("return counter:info(counter)", None)
+            .unwrap(),
+        ("Borrowed".to_owned(), Some("counter at 3".to_owned()))
+    );
+    assert_eq!(
+        lua.eval::<(StdString, Option<StdString>)>("return counter:info_mut(counter)", None)
+            .unwrap(),
+        ("MutablyBorrowed".to_owned(), None)
+    );
+
+    let plain = lua.create_userdata(Plain).unwrap();
+    assert_eq!(plain.debug_info().unwrap().debug_string, None);
+
+    let file: AnyUserData = lua.eval("io.stdout", None).unwrap();
+    assert!(file.debug_info().is_none());
+
+    lua.scope(|scope| {
+        let scoped = scope.create_userdata(Plain).unwrap();
+        assert!(scoped.debug_info().is_some());
+        lua.globals().set("scoped", scoped).unwrap();
+    });
+    let scoped: AnyUserData = lua.globals().get("scoped").unwrap();
+    assert!(scoped.debug_info().is_none());
+}
+
+#[test]
+fn user_data_derived_metatable() {
+    struct Proxy(Arc<Mutex<i64>>);
+
+    impl UserData for Proxy {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("get", |_, this, ()| Ok(*this.0.lock().unwrap()));
+        }
+    }
+
+    struct Other;
+    impl UserData for Other {}
+
+    let lua = Lua::new();
+    let dropped = Arc::new(Mutex::new(0));
+    let plain = lua.create_userdata(Proxy(dropped.clone())).unwrap();
+    let special = lua.create_userdata(Proxy(dropped.clone())).unwrap();
+    let other = lua.create_userdata(Other).unwrap();
+
+    let metatable = special.derive_metatable().unwrap();
+    metatable
+        .set(
+            "__len",
+            lua.create_function(|_, _: AnyUserData| Ok(42)).unwrap(),
+        ).unwrap();
+    special.set_metatable(Some(metatable.clone())).unwrap();
+    assert!(special.is::<Proxy>().unwrap());
+    assert!(special.type_name().unwrap().ends_with("Proxy"));
+
+    lua.globals().set("plain", plain.clone()).unwrap();
+    lua.globals().set("special", special.clone()).unwrap();
+    *dropped.lock().unwrap() = 7;
+    assert_eq!(lua.eval::<i64>("special:get() + #special", None).unwrap(), 49);
+    assert!(lua.eval::<i64>("#plain", None).is_err());
+    assert!(lua.eval::<bool>("getmetatable(special) == false", None).unwrap());
+
+    // Derived metatables only apply to the type they were derived from.
+    match other.set_metatable(Some(metatable.clone())) {
+        Err(Error::UserDataTypeMismatch { .. }) => {}
+        r => panic!("expected UserDataTypeMismatch, got {:?}", r),
+    }
+    match plain.set_metatable(Some(lua.create_table().unwrap())) {
+        Err(Error::UserDataTypeMismatch { .. }) => {}
+        r => panic!("expected UserDataTypeMismatch, got {:?}", r),
+    }
+    plain.set_metatable(Some(metatable)).unwrap();
+    assert_eq!(lua.eval::<i64>("#plain", None).unwrap(), 42);
+
+    special.set_metatable(None).unwrap();
+    assert!(lua.eval::<i64>("#special", None).is_err());
+    assert_eq!(lua.eval::<i64>("special:get()", None).unwrap(), 7);
+
+    // The `__gc` of a derived metatable only drops userdata which use that metatable.
+    let foreign = lua.create_userdata(Proxy(dropped.clone())).unwrap();
+    lua.globals().set("foreign", foreign).unwrap();
+    lua.globals().set("mt", special.derive_metatable().unwrap()).unwrap();
+    for chunk in &["mt.__gc(foreign)", "mt.__gc(plain)", "mt.__gc('x')", "mt.__gc()"] {
+        match lua.exec::<()>(chunk, None) {
+            Err(Error::CallbackError { .. }) => {}
+            r => panic!("expected CallbackError, got {:?}", r),
+        }
+    }
+    lua.exec::<()>("setmetatable({}, mt)", None).unwrap();
+    lua.globals().set("foreign", Nil).unwrap();
+
+    // Calling `__gc` explicitly refuses to drop a value which is still borrowed.
+    let metatable = special.derive_metatable().unwrap();
+    special.set_metatable(Some(metatable.clone())).unwrap();
+    {
+        let proxy = special.borrow::<Proxy>().unwrap();
+        let gc = metatable.get::<_, Function>("__gc").unwrap();
+        match gc.call::<_, ()>(special.clone()) {
+            Err(Error::CallbackError { ref cause, .. }) => match **cause {
+                Error::UserDataBorrowMutError => {}
+                ref other => panic!("expected UserDataBorrowMutError, got {:?}", other),
+            },
+            r => panic!("expected CallbackError, got {:?}", r),
+        }
+        assert_eq!(*proxy.0.lock().unwrap(), 7);
+    }
+    assert_eq!(lua.eval::<i64>("special:get()", None).unwrap(), 7);
+
+    assert!(lua.gc_collect().is_err());
+    assert_eq!(lua.eval::<i64>("plain:get()", None).unwrap(), 7);
+
+    // The Rust values are still dropped.
+    drop((plain, special));
+    lua.globals().set("plain", Nil).unwrap();
+    lua.globals().set("special", Nil).unwrap();
+    lua.gc_collect().unwrap();
+    lua.gc_collect().unwrap();
+    assert_eq!(Arc::strong_count(&dropped), 1);
+}
+
+#[test]
+fn user_data_finalize_order() {
+    struct Resource(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    impl UserData for Resource {}
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let lua = Lua::new();
+    let globals = lua.globals();
+    for &name in &["parent", "child", "temporary"] {
+        globals.set(name, Resource(name, dropped.clone())).unwrap();
+    }
+    let key = lua
+        .create_registry_value(Resource("registered", dropped.clone()))
+        .unwrap();
+
+    globals.set("temporary", Nil).unwrap();
+    drop(key);
+    lua.finalize().unwrap();
+    assert_eq!(*dropped.lock().unwrap(), vec!["registered", "temporary"]);
+
+    drop(globals);
+    drop(lua);
+    assert_eq!(
+        *dropped.lock().unwrap(),
+        vec!["registered", "temporary", "child", "parent"]
+    );
+}
+
+#[test]
+fn user_data_report_cycles() {
+    struct Node;
+
+    impl UserData for Node {}
+
+    let lua = Lua::new();
+    assert!(lua.report_cycles().unwrap().is_empty());
+
+    let node = lua.create_userdata(Node).unwrap();
+    let _key = lua.create_registry_value(node.clone()).unwrap();
+    assert!(lua.report_cycles().unwrap().is_empty());
+
+    let children = lua
+        .eval::<Function>("function(parent) return { { parent = parent }, 'leaf' } end", None)
+        .unwrap()
+        .call::<_, Table>(node.clone())
+        .unwrap();
+    node.set_user_value(children).unwrap();
+    let name = any::type_name::<Node>();
+    assert_eq!(
+        lua.report_cycles().unwrap(),
+        vec![format!(
+            r#"userdata {} -user value-> table -[1]-> table -["parent"]-> userdata {}"#,
+            name, name
+        )]
+    );
+
+    // Cycles without userdata are not reported.
+    lua.exec::<()>("local t = {} t.t = t", None).unwrap();
+    node.set_user_value(Nil).unwrap();
+    assert!(lua.report_cycles().unwrap().is_empty());
+
+    // Deeply nested values do not overflow the Rust stack.
+    let chain = lua
+        .eval::<Function>(
+            "function(node) local t = { node } for i = 1, 200000 do t = { t } end return t end",
+            None,
+        ).unwrap()
+        .call::<_, Table>(node.clone())
+        .unwrap();
+    node.set_user_value(chain).unwrap();
+    assert_eq!(lua.report_cycles().unwrap().len(), 1);
+}
+
+#[test]
+fn user_data_call_method() {
+    struct Account(i64);
+
+    impl UserData for Account {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method_mut("deposit", |_, this, (amount, note): (i64, StdString)| {
+                this.0 += amount;
+                Ok(format!("{}: {}", note, this.0))
+            });
+            methods.add_method("balance", |_, this, ()| Ok(this.0));
+        }
+    }
+
+    let lua = Lua::new();
+    let account = lua.create_userdata(Account(10)).unwrap();
+    assert_eq!(
+        account
+            .call_method::<_, StdString>("deposit", (5, "salary"))
+            .unwrap(),
+        "salary: 15"
+    );
+    assert_eq!(account.call_method::<_, i64>("balance", ()).unwrap(), 15);
+
+    // Methods added to a derived metatable's `__index` table, including callable tables.
+    let metatable = account.derive_metatable().unwrap();
+    let extend: Function = lua
+        .eval(
+            r#"
+            function(methods)
+                return setmetatable({
+                    double = function(self) return self:balance() * 2 end,
+                    callable = setmetatable({}, {
+                        __call = function(_, self, n) return self:balance() + n end,
+                    }),
+                    value = 42,
+                }, { __index = methods })
+            end
+        "#,
+            None,
+        ).unwrap();
+    let methods: Table = extend
+        .call(metatable.get::<_, Table>("__index").unwrap())
+        .unwrap();
+    metatable.set("__index", methods).unwrap();
+    account.set_metatable(Some(metatable)).unwrap();
+    assert_eq!(account.call_method::<_, i64>("double", ()).unwrap(), 30);
+    assert_eq!(account.call_method::<_, i64>("callable", 1).unwrap(), 16);
+
+    for &(name, expected) in &[
+        ("missing", "no method 'missing' on userdata"),
+        ("value", "method 'value' of userdata"),
+    ] {
+        match account.call_method::<_, ()>(name, ()) {
+            Err(Error::RuntimeError(ref message)) => assert!(message.starts_with(expected)),
+            r => panic!("expected RuntimeError, got {:?}", r),
+        }
+    }
+}
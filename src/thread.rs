@@ -1,10 +1,13 @@
-use std::os::raw::c_int;
+use std::cmp;
+use std::marker::PhantomData;
+use std::os::raw::{c_int, c_void};
 
 use error::{Error, Result};
 use ffi;
+use lua::reset_inherited_hook;
 use types::LuaRef;
-use util::{assert_stack, check_stack, error_traceback, pop_error, StackGuard};
-use value::{FromLuaMulti, MultiValue, ToLuaMulti};
+use util::{assert_stack, check_stack, error_traceback, gc_guard, pop_error, StackGuard};
+use value::{FromLua, FromLuaMulti, MultiValue, Nil, ToLuaMulti};
 
 /// Status of a Lua thread (or coroutine).
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -21,6 +24,17 @@ pub enum ThreadStatus {
     Error,
 }
 
+/// The outcome of [`Thread::resume_step`].
+///
+/// [`Thread::resume_step`]: struct.Thread.html#method.resume_step
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resumed<R> {
+    /// The thread yielded these values, and can be resumed again.
+    Yielded(R),
+    /// The thread's main function returned these values, and the thread has finished.
+    Returned(R),
+}
+
 /// Handle to an internal Lua thread (or coroutine).
 #[derive(Clone, Debug)]
 pub struct Thread<'lua>(pub(crate) LuaRef<'lua>);
@@ -75,12 +89,101 @@ impl<'lua> Thread<'lua> {
     where
         A: ToLuaMulti<'lua>,
         R: FromLuaMulti<'lua>,
+    {
+        let (results, _) = self.resume_impl(args)?;
+        R::from_lua_multi(results, self.0.lua)
+    }
+
+    /// Resumes execution of this thread, telling apart values passed to `coroutine.yield` from the
+    /// values returned by the thread's main function.
+    ///
+    /// This behaves like [`resume`], but wraps the results in [`Resumed::Yielded`] if the thread
+    /// yielded and can be resumed again, or in [`Resumed::Returned`] if it finished.  This makes
+    /// it easy to use coroutines as generators, where every yield produces an item and the final
+    /// return ends the stream.  A yield forced by [`set_resume_limit`] produces an empty
+    /// `Yielded`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Resumed, Result, Thread, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let generator: Thread = lua.eval(r#"
+    ///     coroutine.create(function(n)
+    ///         for i = 1, n do
+    ///             coroutine.yield(i * i)
+    ///         end
+    ///         return "done"
+    ///     end)
+    /// "#, None)?;
+    ///
+    /// let mut squares = Vec::new();
+    /// let mut args = Some(3);
+    /// loop {
+    ///     match generator.resume_step::<_, Value>(args.take())? {
+    ///         Resumed::Yielded(square) => squares.push(lua.unpack::<i64>(square)?),
+    ///         Resumed::Returned(last) => {
+    ///             assert_eq!(lua.unpack::<String>(last)?, "done");
+    ///             break;
+    ///         }
+    ///     }
+    /// }
+    /// assert_eq!(squares, vec![1, 4, 9]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`resume`]: #method.resume
+    /// [`Resumed::Yielded`]: enum.Resumed.html#variant.Yielded
+    /// [`Resumed::Returned`]: enum.Resumed.html#variant.Returned
+    /// [`set_resume_limit`]: #method.set_resume_limit
+    pub fn resume_step<A, R>(&self, args: A) -> Result<Resumed<R>>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.0.lua;
+        let (results, yielded) = self.resume_impl(args)?;
+        let results = R::from_lua_multi(results, lua)?;
+        Ok(if yielded {
+            Resumed::Yielded(results)
+        } else {
+            Resumed::Returned(results)
+        })
+    }
+
+    // Returns true if the last resume of this thread was forced to yield by its resume limit.
+    fn was_preempted(&self) -> bool {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 3);
+
+            lua.push_ref(&self.0);
+            push_limited_threads_key(lua.state);
+            ffi::lua_rawget(lua.state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TTABLE && {
+                ffi::lua_pushvalue(lua.state, -2);
+                ffi::lua_rawget(lua.state, -2) == ffi::LUA_TBOOLEAN
+                    && ffi::lua_toboolean(lua.state, -1) == 0
+            }
+        }
+    }
+
+    // Resumes the thread, returning its results and whether it yielded.
+    fn resume_impl<A>(&self, args: A) -> Result<(MultiValue<'lua>, bool)>
+    where
+        A: ToLuaMulti<'lua>,
     {
         let lua = self.0.lua;
         let args = args.to_lua_multi(lua)?;
-        let results = unsafe {
+        unsafe {
             let _sg = StackGuard::new(lua.state);
-            assert_stack(lua.state, 1);
+            assert_stack(lua.state, 4);
 
             lua.push_ref(&self.0);
             let thread_state = ffi::lua_tothread(lua.state, -1);
@@ -90,6 +193,10 @@ impl<'lua> Thread<'lua> {
                 return Err(Error::CoroutineInactive);
             }
 
+            let limited = is_resume_limited(lua.state, -1);
+            if limited {
+                set_preempted(lua.state, -1, false);
+            }
             ffi::lua_pop(lua.state, 1);
 
             let nargs = args.len() as c_int;
@@ -97,10 +204,20 @@ impl<'lua> Thread<'lua> {
             check_stack(thread_state, nargs + 1)?;
 
             for arg in args {
-                lua.push_value(arg);
+                lua.push_value(arg)?;
             }
             ffi::lua_xmove(lua.state, thread_state, nargs);
 
+            // Every resume gets a fresh instruction budget; `lua_sethook` resets the hook count.
+            if limited {
+                ffi::lua_sethook(
+                    thread_state,
+                    Some(budget_hook),
+                    ffi::LUA_MASKCOUNT,
+                    ffi::lua_gethookcount(thread_state),
+                );
+            }
+
             let ret = ffi::lua_resume(thread_state, lua.state, nargs);
             if ret != ffi::LUA_OK && ret != ffi::LUA_YIELD {
                 error_traceback(thread_state);
@@ -115,9 +232,124 @@ impl<'lua> Thread<'lua> {
             for _ in 0..nresults {
                 results.push_front(lua.pop_value());
             }
-            results
-        };
-        R::from_lua_multi(results, lua)
+            Ok((results, ret == ffi::LUA_YIELD))
+        }
+    }
+
+    /// Consumes the thread, returning an iterator over the values it yields.
+    ///
+    /// Every call to `next` resumes the thread without arguments and converts the first yielded
+    /// value to `V` (a yield without values produces `Nil`, and further values are ignored).  The
+    /// iterator ends when the thread returns from its main function, discarding the returned
+    /// values, so a thread which finishes without yielding produces no items at all.  If resuming
+    /// the thread raises an error, the error is produced as the last item.
+    ///
+    /// A yield forced by [`set_resume_limit`] produces an `Err(CoroutinePreempted)` item instead
+    /// of a value.  The iteration does not end there, and the next call to `next` resumes the
+    /// thread again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Thread};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let generator: Thread = lua.eval(r#"
+    ///     coroutine.create(function()
+    ///         for i = 1, 4 do
+    ///             coroutine.yield(i * i)
+    ///         end
+    ///     end)
+    /// "#, None)?;
+    ///
+    /// let squares = generator.yields::<i64>().collect::<Result<Vec<_>>>()?;
+    /// assert_eq!(squares, vec![1, 4, 9, 16]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_resume_limit`]: #method.set_resume_limit
+    pub fn yields<V: FromLua<'lua>>(self) -> ThreadIter<'lua, V> {
+        ThreadIter {
+            thread: self,
+            finished: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Limits how many Lua VM instructions this thread may execute during a single `resume`.
+    ///
+    /// Once a call to [`resume`] has executed roughly `limit` instructions, the thread is forced to
+    /// yield as if it had called `coroutine.yield()` with no arguments, and `resume` returns
+    /// control to the caller.  The thread stays resumable and the next `resume` continues where it
+    /// left off with a fresh budget.  This makes it possible to schedule many coroutines fairly
+    /// without relying on each script to yield voluntarily.
+    ///
+    /// The limit is implemented as a count hook on this thread's own Lua state, so it does not
+    /// affect any other thread, including coroutines created by this thread.  A thread can only be
+    /// preempted while it is yieldable: while it is inside a Rust callback, or inside a Lua
+    /// function called from a Rust callback, the budget is not enforced until control returns to
+    /// the coroutine itself.
+    ///
+    /// Passing `None` removes the limit.  A limit of `Some(0)` is treated as `Some(1)`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Thread, ThreadStatus, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let thread: Thread = lua.eval(r#"
+    ///     coroutine.create(function()
+    ///         local i = 0
+    ///         while i < 100000 do i = i + 1 end
+    ///         return i
+    ///     end)
+    /// "#, None)?;
+    ///
+    /// thread.set_resume_limit(Some(1000));
+    /// let mut resumes = 0;
+    /// while thread.status() == ThreadStatus::Resumable {
+    ///     thread.resume::<_, ()>(())?;
+    ///     resumes += 1;
+    /// }
+    /// assert!(resumes > 1);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`resume`]: #method.resume
+    pub fn set_resume_limit(&self, limit: Option<u32>) {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 5);
+
+            lua.push_ref(&self.0);
+            let thread_state = ffi::lua_tothread(lua.state, -1);
+
+            match limit {
+                Some(limit) => {
+                    let count = cmp::min(cmp::max(limit, 1), c_int::max_value() as u32) as c_int;
+                    gc_guard(lua.state, || set_resume_limited(lua.state, true));
+                    ffi::lua_sethook(thread_state, Some(budget_hook), ffi::LUA_MASKCOUNT, count);
+                }
+                None => {
+                    if is_resume_limited(lua.state, -1) {
+                        set_resume_limited(lua.state, false);
+                        ffi::lua_sethook(thread_state, None, 0, 0);
+                    }
+                }
+            }
+        }
     }
 
     /// Gets the status of the thread.
@@ -142,3 +374,130 @@ impl<'lua> Thread<'lua> {
         }
     }
 }
+
+/// An iterator over the values yielded by a Lua thread.
+///
+/// This struct is created by the [`Thread::yields`] method.
+///
+/// [`Thread::yields`]: struct.Thread.html#method.yields
+pub struct ThreadIter<'lua, V> {
+    thread: Thread<'lua>,
+    finished: bool,
+    _phantom: PhantomData<V>,
+}
+
+impl<'lua, V> Iterator for ThreadIter<'lua, V>
+where
+    V: FromLua<'lua>,
+{
+    type Item = Result<V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished || self.thread.status() != ThreadStatus::Resumable {
+            return None;
+        }
+
+        let lua = self.thread.0.lua;
+        match self.thread.resume_impl(()) {
+            Ok(_) if self.thread.was_preempted() => Some(Err(Error::CoroutinePreempted)),
+            Ok((values, true)) => {
+                let value = values.into_iter().next().unwrap_or(Nil);
+                Some(V::from_lua(value, lua))
+            }
+            Ok((_, false)) => {
+                self.finished = true;
+                None
+            }
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// Coroutines inherit the hook of the thread they are created in, so the hook removes itself from
+// threads which were not limited with `Thread::set_resume_limit` instead of preempting them.
+unsafe extern "C" fn budget_hook(state: *mut ffi::lua_State, _ar: *mut ffi::lua_Debug) {
+    ffi::lua_pushthread(state);
+    let limited = is_resume_limited(state, -1);
+    ffi::lua_pop(state, 1);
+    if !limited {
+        reset_inherited_hook(state);
+    } else if ffi::lua_isyieldable(state) != 0 {
+        ffi::lua_pushthread(state);
+        set_preempted(state, -1, true);
+        ffi::lua_pop(state, 1);
+        // Yielding from a count hook does not unwind through this function, Lua performs the
+        // actual yield once the hook has returned.
+        ffi::lua_yield(state, 0);
+    }
+}
+
+// Checks whether the thread at the given index was limited with `Thread::set_resume_limit`.  Uses
+// 2 stack spaces, does not call checkstack.
+unsafe fn is_resume_limited(state: *mut ffi::lua_State, index: c_int) -> bool {
+    let index = ffi::lua_absindex(state, index);
+    let top = ffi::lua_gettop(state);
+    push_limited_threads_key(state);
+    let limited = ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TTABLE && {
+        ffi::lua_pushvalue(state, index);
+        ffi::lua_rawget(state, -2) != ffi::LUA_TNIL
+    };
+    ffi::lua_settop(state, top);
+    limited
+}
+
+// Records whether the last resume of the limited thread at the given index was forced to yield by
+// `budget_hook`.  The value of a limited thread in the table of limited threads is `true`, or
+// `false` after a forced yield.  The thread is already a key of the table, so this does not
+// allocate.  Uses 3 stack spaces, does not call checkstack.
+unsafe fn set_preempted(state: *mut ffi::lua_State, index: c_int, preempted: bool) {
+    let index = ffi::lua_absindex(state, index);
+    push_limited_threads_key(state);
+    ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX);
+    ffi::lua_pushvalue(state, index);
+    ffi::lua_pushboolean(state, if preempted { 0 } else { 1 });
+    ffi::lua_rawset(state, -3);
+    ffi::lua_pop(state, 1);
+}
+
+// Adds the thread at the top of the stack to the threads limited with `Thread::set_resume_limit`,
+// or removes it.  Adding may allocate, so it must be done with the garbage collector stopped.
+// Uses 4 stack spaces, does not call checkstack.
+unsafe fn set_resume_limited(state: *mut ffi::lua_State, limited: bool) {
+    push_limited_threads_key(state);
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) != ffi::LUA_TTABLE {
+        ffi::lua_pop(state, 1);
+        if !limited {
+            return;
+        }
+        // The table has weak keys, so that limited threads can still be collected.
+        ffi::lua_newtable(state);
+        ffi::lua_newtable(state);
+        ffi::lua_pushstring(state, cstr!("__mode"));
+        ffi::lua_pushstring(state, cstr!("k"));
+        ffi::lua_rawset(state, -3);
+        ffi::lua_setmetatable(state, -2);
+        push_limited_threads_key(state);
+        ffi::lua_pushvalue(state, -2);
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+    }
+    ffi::lua_pushvalue(state, -2);
+    if limited {
+        ffi::lua_pushboolean(state, 1);
+    } else {
+        ffi::lua_pushnil(state);
+    }
+    ffi::lua_rawset(state, -3);
+    ffi::lua_pop(state, 1);
+}
+
+unsafe fn push_limited_threads_key(state: *mut ffi::lua_State) {
+    ffi::lua_pushlightuserdata(
+        state,
+        &LIMITED_THREADS_REGISTRY_KEY as *const u8 as *mut c_void,
+    );
+}
+
+static LIMITED_THREADS_REGISTRY_KEY: u8 = 0;
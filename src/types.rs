@@ -1,9 +1,12 @@
+use std::cell::RefCell;
 use std::os::raw::{c_int, c_void};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::{fmt, mem, ptr};
 
 use error::Result;
 use ffi;
+use hook::Debug;
 use lua::Lua;
 use value::MultiValue;
 
@@ -19,6 +22,8 @@ pub struct LightUserData(pub *mut c_void);
 pub(crate) type Callback<'lua, 'a> =
     Box<Fn(&'lua Lua, MultiValue<'lua>) -> Result<MultiValue<'lua>> + 'a>;
 
+pub(crate) type HookCallback = Rc<RefCell<FnMut(&Debug) -> Result<()> + Send>>;
+
 /// An auto generated key into the Lua registry.
 ///
 /// This is a handle to a value stored inside the Lua registry.  It is not directly usable like the
@@ -1,21 +1,26 @@
+use std::any;
 use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::os::raw::{c_int, c_void};
 use std::string::String as StdString;
 
 use error::{Error, Result};
 use ffi;
 use lua::Lua;
+use table::Table;
 use types::{Callback, LuaRef};
-use util::{assert_stack, get_userdata, StackGuard};
-use value::{FromLua, FromLuaMulti, ToLua, ToLuaMulti};
+use util::{
+    assert_stack, callback_error, get_userdata, protect_lua_closure, push_string, StackGuard,
+};
+use value::{FromLua, FromLuaMulti, ToLua, ToLuaMulti, Value};
 
 /// Kinds of metamethods that can be overridden.
 ///
-/// Currently, this mechanism does not allow overriding the `__gc` metamethod, since there is
-/// generally no need to do so: [`UserData`] implementors can instead just implement `Drop`.
+/// The `__gc` metamethod cannot be replaced, since it is what drops the Rust value.  [`Gc`] instead
+/// adds a finalizer which runs before the value is dropped, for cleanup that needs the Lua state.
 ///
-/// [`UserData`]: trait.UserData.html
+/// [`Gc`]: #variant.Gc
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum MetaMethod {
     /// The `+` operator.
@@ -66,6 +71,24 @@ pub enum MetaMethod {
     ///
     /// This is not an operator, but will be called by methods such as `tostring` and `print`.
     ToString,
+    /// A finalizer, called with the userdata when it is about to be garbage collected.
+    ///
+    /// Unlike `Drop`, the finalizer has access to the Lua state, so it can for example remove the
+    /// userdata from a table that tracks live objects.  Rlua keeps its own `__gc` metamethod, which
+    /// calls the finalizer first, and then drops the Rust value regardless of how the finalizer
+    /// went.  Note that:
+    ///
+    /// - The finalizer runs in the middle of whatever Lua operation triggered the collection, and
+    ///   Lua pauses the collector while it runs.  Finalizers of objects collected in the same
+    ///   cycle run in the reverse order in which the objects were marked for finalization.
+    /// - Errors returned by the finalizer are ignored, since Lua has nowhere to report them.  A
+    ///   panic in the finalizer is also ignored, after calling the hook set with
+    ///   `Lua::set_panic_hook`.
+    /// - If the finalizer stores the userdata somewhere reachable, it is not collected again: once
+    ///   the value is dropped, any further use of the userdata is an error.
+    /// - The finalizer is not called when the Lua state itself is dropped, nor for userdata created
+    ///   with `Scope::create_userdata`, which are dropped at the end of the scope instead.
+    Gc,
 }
 
 /// Method registry for [`UserData`] implementors.
@@ -354,6 +377,106 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
 pub trait UserData: 'static + Sized {
     /// Adds custom methods and operators specific to this userdata.
     fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+
+    /// Returns a human readable description of this value for debugging, which is exposed by
+    /// [`AnyUserData::debug_info`] without the caller having to know the type.
+    ///
+    /// [`AnyUserData::debug_info`]: struct.AnyUserData.html#method.debug_info
+    fn debug_string(&self) -> Option<StdString> {
+        None
+    }
+}
+
+/// Information about a userdata which is available without knowing its type, see
+/// [`AnyUserData::debug_info`].
+///
+/// [`AnyUserData::debug_info`]: struct.AnyUserData.html#method.debug_info
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserDataInfo {
+    /// The name of the Rust type stored in the userdata.
+    pub type_name: &'static str,
+    /// Whether the value is currently borrowed.
+    pub borrow_state: UserDataBorrowState,
+    /// The result of [`UserData::debug_string`], or `None` if the value is currently mutably
+    /// borrowed.
+    ///
+    /// [`UserData::debug_string`]: trait.UserData.html#method.debug_string
+    pub debug_string: Option<StdString>,
+}
+
+/// Whether a userdata value is currently borrowed, see [`UserDataInfo`].
+///
+/// [`UserDataInfo`]: struct.UserDataInfo.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UserDataBorrowState {
+    /// The value is not borrowed.
+    Unborrowed,
+    /// The value is borrowed immutably, such as by a method taking `&self` which is running.
+    Borrowed,
+    /// The value is borrowed mutably, such as by a method taking `&mut self` which is running.
+    MutablyBorrowed,
+}
+
+// Describes the `RefCell<T>` stored in a userdata created by rlua.
+pub(crate) unsafe fn describe_userdata<T: UserData>(ud: *const c_void) -> UserDataInfo {
+    let cell = &*(ud as *const RefCell<T>);
+    let (borrow_state, debug_string) = match cell.try_borrow_mut() {
+        Ok(data) => (UserDataBorrowState::Unborrowed, data.debug_string()),
+        Err(_) => match cell.try_borrow() {
+            Ok(data) => (UserDataBorrowState::Borrowed, data.debug_string()),
+            Err(_) => (UserDataBorrowState::MutablyBorrowed, None),
+        },
+    };
+    UserDataInfo {
+        type_name: any::type_name::<T>(),
+        borrow_state,
+        debug_string,
+    }
+}
+
+fn rlua_userdata_expected() -> Error {
+    Error::UserDataTypeMismatch {
+        expected: "userdata created by rlua",
+        found: None,
+    }
+}
+
+// Pushes the metatable which the metatable at the given index was derived from with
+// `AnyUserData::derive_metatable`, or the metatable itself if it was not derived.  Uses 1 stack
+// space, does not call checkstack.
+unsafe fn push_base_metatable(lua: &Lua, index: c_int) {
+    match lua.derived_metatable_base(index) {
+        Some(id) => {
+            ffi::lua_rawgeti(lua.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+        }
+        None => ffi::lua_pushvalue(lua.state, index),
+    }
+}
+
+// The `__gc` metamethod of metatables created with `AnyUserData::derive_metatable`.  Unlike the
+// type's own metatable, a derived metatable is an ordinary table which scripts may get hold of, so
+// the type's `__gc` metamethod (the second upvalue) is only called for userdata whose metatable is
+// the derived metatable itself (the first upvalue), and never for other values.
+unsafe extern "C" fn derived_metatable_gc(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 1);
+    callback_error(state, || {
+        let is_instance = ffi::lua_type(state, 1) == ffi::LUA_TUSERDATA
+            && ffi::lua_getmetatable(state, 1) != 0
+            && ffi::lua_rawequal(state, -1, ffi::lua_upvalueindex(1)) != 0;
+        if is_instance {
+            ffi::lua_settop(state, 1);
+            Ok(())
+        } else {
+            Err(Error::UserDataTypeMismatch {
+                expected: "userdata using this metatable",
+                found: None,
+            })
+        }
+    });
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+    ffi::lua_insert(state, 1);
+    ffi::lua_call(state, 1, 0);
+    0
 }
 
 /// Handle to an internal Lua userdata for any type that implements [`UserData`].
@@ -380,7 +503,7 @@ impl<'lua> AnyUserData<'lua> {
     pub fn is<T: UserData>(&self) -> Result<bool> {
         match self.inspect(|_: &RefCell<T>| Ok(())) {
             Ok(()) => Ok(true),
-            Err(Error::UserDataTypeMismatch) => Ok(false),
+            Err(Error::UserDataTypeMismatch { .. }) => Ok(false),
             Err(err) => Err(err),
         }
     }
@@ -421,7 +544,7 @@ impl<'lua> AnyUserData<'lua> {
             let _sg = StackGuard::new(lua.state);
             assert_stack(lua.state, 2);
             lua.push_ref(&self.0);
-            lua.push_value(v);
+            lua.push_value(v)?;
             ffi::lua_setuservalue(lua.state, -2);
             Ok(())
         }
@@ -442,6 +565,360 @@ impl<'lua> AnyUserData<'lua> {
         V::from_lua(res, lua)
     }
 
+    /// Calls the method `name` of this userdata with the given arguments, like `ud:name(...)` in
+    /// Lua.
+    ///
+    /// The method is looked up by indexing the userdata, which goes through the `__index`
+    /// metamethod where the methods added by [`UserData::add_methods`] are found, and is then
+    /// called with the userdata prepended to `args`.  Like in Lua, the method may be any value
+    /// with a `__call` metamethod instead of a function.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` if the userdata has no method `name`, or if the method is neither
+    /// a function nor callable through `__call`.  Errors raised while looking up or calling the
+    /// method are returned as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{AnyUserData, Lua, Result, UserData, UserDataMethods};
+    /// # fn try_main() -> Result<()> {
+    /// struct Counter(i64);
+    ///
+    /// impl UserData for Counter {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_method_mut("add", |_, this, n: i64| {
+    ///             this.0 += n;
+    ///             Ok(this.0)
+    ///         });
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let counter: AnyUserData = lua.create_userdata(Counter(1))?;
+    /// assert_eq!(counter.call_method::<_, i64>("add", 2)?, 3);
+    /// assert!(counter.call_method::<_, ()>("missing", ()).is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`UserData::add_methods`]: trait.UserData.html#method.add_methods
+    pub fn call_method<A, R>(&self, name: &str, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.0.lua;
+        let method = unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 4);
+
+            lua.push_ref(&self.0);
+            push_string(lua.state, name)?;
+            protect_lua_closure(lua.state, 2, 1, |state| {
+                ffi::lua_gettable(state, -2);
+            })?;
+            lua.pop_value()
+        };
+
+        let describe = || match self.type_name() {
+            Some(type_name) => format!("userdata {}", type_name),
+            None => "userdata".to_owned(),
+        };
+        match method {
+            Value::Function(function) => function.call((self.clone(), args)),
+            Value::Nil => Err(Error::RuntimeError(format!(
+                "no method '{}' on {}",
+                name,
+                describe()
+            ))),
+            method => {
+                let call = unsafe {
+                    let _sg = StackGuard::new(lua.state);
+                    assert_stack(lua.state, 4);
+
+                    lua.push_value(method.clone())?;
+                    if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                        None
+                    } else {
+                        push_string(lua.state, "__call")?;
+                        ffi::lua_rawget(lua.state, -2);
+                        match lua.pop_value() {
+                            Value::Function(call) => Some(call),
+                            _ => None,
+                        }
+                    }
+                };
+                match call {
+                    Some(call) => call.call((method, self.clone(), args)),
+                    None => Err(Error::RuntimeError(format!(
+                        "method '{}' of {} is not callable (a {} value)",
+                        name,
+                        describe(),
+                        method.type_name()
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Returns the name of the Rust type stored in this userdata.
+    ///
+    /// This is the same name that is set as the `__name` field of the userdata's metatable, so it
+    /// also shows up when the userdata is converted with `tostring`.  Returns `None` if the
+    /// userdata was not created by rlua, for example for file handles from the `io` library.
+    pub fn type_name(&self) -> Option<&'static str> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 2);
+
+            lua.push_ref(&self.0);
+            if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                None
+            } else {
+                lua.userdata_type_name(-1)
+            }
+        }
+    }
+
+    /// Returns debugging information about this userdata, without having to know its type.
+    ///
+    /// This includes the name of the Rust type, whether the value is currently borrowed, and the
+    /// description returned by [`UserData::debug_string`].  Returns `None` if the userdata was not
+    /// created by rlua, or if it was created in a scope which has ended.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{AnyUserData, Lua, Result, UserData, UserDataBorrowState};
+    /// # fn try_main() -> Result<()> {
+    /// struct Player {
+    ///     name: String,
+    /// }
+    ///
+    /// impl UserData for Player {
+    ///     fn debug_string(&self) -> Option<String> {
+    ///         Some(format!("player {}", self.name))
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let player: AnyUserData = lua.create_userdata(Player { name: "ann".to_owned() })?;
+    ///
+    /// let info = player.debug_info().unwrap();
+    /// assert!(info.type_name.ends_with("Player"));
+    /// assert_eq!(info.borrow_state, UserDataBorrowState::Unborrowed);
+    /// assert_eq!(info.debug_string, Some("player ann".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`UserData::debug_string`]: trait.UserData.html#method.debug_string
+    pub fn debug_info(&self) -> Option<UserDataInfo> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 2);
+
+            lua.push_ref(&self.0);
+            if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                return None;
+            }
+            let describe = lua.userdata_describe_fn(-1)?;
+            Some(describe(ffi::lua_touserdata(lua.state, -2)))
+        }
+    }
+
+    /// Returns true if `other` is a handle to the same userdata object as `self`.
+    ///
+    /// This compares identity without invoking the `__eq` metamethod, so it can be used to
+    /// correlate the same object being passed to several Rust functions, even if its type defines
+    /// a custom equality.  Use [`equals_with_meta`] to compare like the Lua `==` operator does.
+    ///
+    /// [`equals_with_meta`]: #method.equals_with_meta
+    pub fn equals(&self, other: &AnyUserData<'lua>) -> bool {
+        let lua = self.0.lua;
+        if !lua.compatible_with(other.0.lua) {
+            return false;
+        }
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 2);
+
+            lua.push_ref(&self.0);
+            lua.push_ref(&other.0);
+            ffi::lua_rawequal(lua.state, -1, -2) != 0
+        }
+    }
+
+    /// Compares this userdata with `other` like the Lua `==` operator, invoking the `__eq`
+    /// metamethod if the two are not the same object.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error raised by the `__eq` metamethod, if any, and a `MismatchedLuaState` error
+    /// if `other` belongs to an unrelated Lua state.
+    pub fn equals_with_meta(&self, other: &AnyUserData<'lua>) -> Result<bool> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 5);
+
+            lua.push_ref(&self.0);
+            lua.push_checked_ref(&other.0)?;
+            protect_lua_closure(lua.state, 2, 0, |state| {
+                ffi::lua_compare(state, -2, -1, ffi::LUA_OPEQ) != 0
+            })
+        }
+    }
+
+    /// Creates a copy of the metatable of this userdata which can be given to individual
+    /// instances of the same type with [`set_metatable`].
+    ///
+    /// All instances of a `UserData` type normally share the metatable built from its
+    /// [`add_methods`].  The returned table starts out with the same fields, including the
+    /// methods, metamethods and the `__gc` metamethod which drops the Rust value, and can then be
+    /// modified to add or override metamethods for the instances it is set on.  Such instances
+    /// are still recognized as their type by [`is`] and [`borrow`], and by the methods of the
+    /// type.
+    ///
+    /// Derived metatables are kept alive for as long as the Lua state, so they should be created
+    /// once and shared rather than created for every instance.  Removing or replacing `__gc` in a
+    /// derived metatable leaks the Rust values of the instances using it.  The `__gc` metamethod of
+    /// a derived metatable raises an error when it is called with anything but a userdata using
+    /// that metatable.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `UserDataTypeMismatch` error if this userdata was not created by rlua, or was
+    /// created in a scope which has ended.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{AnyUserData, Lua, Result, UserData, UserDataMethods};
+    /// # fn try_main() -> Result<()> {
+    /// struct Counter(i64);
+    ///
+    /// impl UserData for Counter {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_method("get", |_, this, ()| Ok(this.0));
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let counter: AnyUserData = lua.create_userdata(Counter(7))?;
+    ///
+    /// let metatable = counter.derive_metatable()?;
+    /// metatable.set("__call", lua.create_function(|_, this: AnyUserData| {
+    ///     Ok(this.borrow::<Counter>()?.0 * 2)
+    /// })?)?;
+    /// counter.set_metatable(Some(metatable))?;
+    ///
+    /// lua.globals().set("counter", counter)?;
+    /// assert_eq!(lua.eval::<i64>("counter:get() + counter()", None)?, 21);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_metatable`]: #method.set_metatable
+    /// [`add_methods`]: trait.UserData.html#method.add_methods
+    /// [`is`]: #method.is
+    /// [`borrow`]: #method.borrow
+    pub fn derive_metatable(&self) -> Result<Table<'lua>> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 6);
+
+            lua.push_ref(&self.0);
+            if ffi::lua_getmetatable(lua.state, -1) == 0 || lua.userdata_type_name(-1).is_none() {
+                return Err(rlua_userdata_expected());
+            }
+
+            ffi::lua_pushvalue(lua.state, -1);
+            protect_lua_closure(lua.state, 1, 1, |state| {
+                ffi::lua_newtable(state);
+                ffi::lua_pushnil(state);
+                while ffi::lua_next(state, -3) != 0 {
+                    ffi::lua_pushvalue(state, -2);
+                    ffi::lua_insert(state, -2);
+                    ffi::lua_rawset(state, -4);
+                }
+
+                ffi::lua_pushstring(state, cstr!("__gc"));
+                ffi::lua_pushvalue(state, -2);
+                ffi::lua_pushstring(state, cstr!("__gc"));
+                ffi::lua_rawget(state, -4);
+                ffi::lua_pushcclosure(state, derived_metatable_gc, 2);
+                ffi::lua_rawset(state, -3);
+
+                ffi::lua_remove(state, -2);
+            })?;
+            lua.register_derived_metatable(-2);
+            Ok(Table(lua.pop_ref()))
+        }
+    }
+
+    /// Sets the metatable of this userdata to a metatable derived from its type's metatable with
+    /// [`derive_metatable`], or back to the type's own metatable if `metatable` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `UserDataTypeMismatch` error if `metatable` was not derived from the metatable of
+    /// this userdata's type, if this userdata was not created by rlua, or if it was created in a
+    /// scope which has ended.
+    ///
+    /// [`derive_metatable`]: #method.derive_metatable
+    pub fn set_metatable(&self, metatable: Option<Table<'lua>>) -> Result<()> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::new(lua.state);
+            assert_stack(lua.state, 5);
+
+            lua.push_ref(&self.0);
+            if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                return Err(rlua_userdata_expected());
+            }
+            let type_name = match lua.userdata_type_name(-1) {
+                Some(type_name) => type_name,
+                None => return Err(rlua_userdata_expected()),
+            };
+            push_base_metatable(lua, -1);
+
+            match metatable {
+                Some(metatable) => {
+                    lua.push_checked_ref(&metatable.0)?;
+                    push_base_metatable(lua, -1);
+                    if ffi::lua_rawequal(lua.state, -1, -3) == 0 {
+                        return Err(Error::UserDataTypeMismatch {
+                            expected: type_name,
+                            found: lua.userdata_type_name(-2),
+                        });
+                    }
+                    ffi::lua_pop(lua.state, 1);
+                }
+                None => ffi::lua_pushvalue(lua.state, -1),
+            }
+            ffi::lua_setmetatable(lua.state, -4);
+            Ok(())
+        }
+    }
+
     fn inspect<'a, T, R, F>(&'a self, func: F) -> Result<R>
     where
         T: UserData,
@@ -455,16 +932,27 @@ impl<'lua> AnyUserData<'lua> {
             lua.push_ref(&self.0);
 
             if ffi::lua_getmetatable(lua.state, -1) == 0 {
-                Err(Error::UserDataTypeMismatch)
+                Err(Error::UserDataTypeMismatch {
+                    expected: any::type_name::<T>(),
+                    found: None,
+                })
             } else {
-                ffi::lua_rawgeti(
-                    lua.state,
-                    ffi::LUA_REGISTRYINDEX,
-                    lua.userdata_metatable::<T>()? as ffi::lua_Integer,
-                );
-
-                if ffi::lua_rawequal(lua.state, -1, -2) == 0 {
-                    Err(Error::UserDataTypeMismatch)
+                let id = lua.userdata_metatable::<T>()?;
+                ffi::lua_rawgeti(lua.state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+
+                if ffi::lua_rawequal(lua.state, -1, -2) == 0
+                    && lua.derived_metatable_base(-2) != Some(id)
+                {
+                    if let Some(type_name) = lua.scope_expired_type_name(-2) {
+                        return Err(Error::ScopeExpired {
+                            kind: "userdata",
+                            type_name: Some(type_name),
+                        });
+                    }
+                    Err(Error::UserDataTypeMismatch {
+                        expected: any::type_name::<T>(),
+                        found: lua.userdata_type_name(-2),
+                    })
                 } else {
                     func(&*get_userdata::<RefCell<T>>(lua.state, -3))
                 }
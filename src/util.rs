@@ -1,12 +1,14 @@
 use std::any::Any;
+use std::cell::RefCell;
 use std::ffi::CStr;
 use std::os::raw::{c_char, c_int, c_void};
 use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::sync::Arc;
-use std::{mem, ptr};
+use std::{mem, ptr, slice};
 
 use error::{Error, Result};
 use ffi;
+use lua::{call_panic_hook, translate_source_positions};
 
 // Checks that Lua has enough free stack space for future stack operations.  On failure, this will
 // panic with an internal error message.
@@ -59,6 +61,17 @@ impl Drop for StackGuard {
     }
 }
 
+// Checks that a Rust callback, whose arguments have all been popped, left its stack empty.  A
+// callback which leaves values behind is reported with `Error::StackError`, so that the imbalance
+// is attributed to the call of the callback rather than surfacing later somewhere else.
+pub unsafe fn check_callback_stack(state: *mut ffi::lua_State) -> Result<()> {
+    if ffi::lua_gettop(state) != 0 {
+        Err(Error::StackError)
+    } else {
+        Ok(())
+    }
+}
+
 // Call a function that calls into the Lua API and may trigger a Lua error (longjmp) in a safe way.
 // Wraps the inner function in a call to `lua_pcall`, so the inner function only has access to a
 // limited lua stack.  `nargs` is the same as the the parameter to `lua_pcall`, and `nresults` is
@@ -184,6 +197,7 @@ pub unsafe fn pop_error(state: *mut ffi::lua_State, err_code: c_int) -> Error {
             }
         });
         ffi::lua_pop(state, 1);
+        let err_string = translate_source_positions(state, err_string);
 
         match err_code {
             ffi::LUA_ERRRUN => Error::RuntimeError(err_string),
@@ -214,7 +228,11 @@ pub unsafe fn pop_error(state: *mut ffi::lua_State, err_code: c_int) -> Error {
 }
 
 // Internally uses 4 stack spaces, does not call checkstack
-pub unsafe fn push_string(state: *mut ffi::lua_State, s: &str) -> Result<()> {
+pub unsafe fn push_string<S>(state: *mut ffi::lua_State, s: &S) -> Result<()>
+where
+    S: ?Sized + AsRef<[u8]>,
+{
+    let s = s.as_ref();
     protect_lua_closure(state, 0, 1, |state| {
         ffi::lua_pushlstring(state, s.as_ptr() as *const c_char, s.len());
     })
@@ -243,6 +261,12 @@ pub unsafe fn take_userdata<T>(state: *mut ffi::lua_State) -> T {
     // dropped, and also so that it cannot be used or identified as any particular userdata type
     // after the first call to __gc.
     get_destructed_userdata_metatable(state);
+    take_userdata_with_metatable(state)
+}
+
+// Like `take_userdata`, but pops the table on the top of the stack first and sets it as the new
+// metatable of the userdata below it, instead of the destructed userdata metatable.
+pub unsafe fn take_userdata_with_metatable<T>(state: *mut ffi::lua_State) -> T {
     ffi::lua_setmetatable(state, -2);
     let ud = ffi::lua_touserdata(state, -1) as *mut T;
     rlua_debug_assert!(!ud.is_null(), "userdata pointer is null");
@@ -257,6 +281,39 @@ pub unsafe extern "C" fn userdata_destructor<T>(state: *mut ffi::lua_State) -> c
     })
 }
 
+// The `__gc` metamethod of `UserData` values, which are stored as `RefCell<T>`.  Through a derived
+// metatable, `__gc` can also be called explicitly while the value is borrowed, so this refuses to
+// drop the value until every borrow has ended.
+pub unsafe extern "C" fn userdata_cell_destructor<T>(state: *mut ffi::lua_State) -> c_int {
+    callback_error(state, || {
+        check_userdata_cell_unborrowed::<T>(state, -1)?;
+        take_userdata::<RefCell<T>>(state);
+        Ok(0)
+    })
+}
+
+// Returns `UserDataBorrowMutError` if the `RefCell<T>` userdata at the given index is borrowed.
+pub unsafe fn check_userdata_cell_unborrowed<T>(
+    state: *mut ffi::lua_State,
+    index: c_int,
+) -> Result<()> {
+    match (*get_userdata::<RefCell<T>>(state, index)).try_borrow_mut() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::UserDataBorrowMutError),
+    }
+}
+
+// Returns the message of a panic payload, if it is a string as passed to `panic!`.
+pub fn panic_message(payload: &(Any + Send)) -> Option<String> {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        Some((*message).to_owned())
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        Some(message.clone())
+    } else {
+        None
+    }
+}
+
 // In the context of a lua callback, this will call the given function and if the given function
 // returns an error, *or if the given function panics*, this will result in a call to lua_error (a
 // longjmp).  The error or panic is wrapped in such a way that when calling pop_error back on
@@ -274,6 +331,7 @@ where
             ffi::lua_error(state)
         }
         Err(p) => {
+            call_panic_hook(state, &*p);
             ffi::lua_settop(state, 0);
             if ffi::lua_checkstack(state, 2) == 0 {
                 rlua_abort!("not enough stack space to propagate panic");
@@ -303,7 +361,7 @@ pub unsafe extern "C" fn error_traceback(state: *mut ffi::lua_State) -> c_int {
                 .to_string_lossy()
                 .into_owned();
             ffi::lua_pop(state, 1);
-            traceback
+            translate_source_positions(state, traceback)
         } else {
             "not enough stack space for traceback".to_owned()
         };
@@ -443,6 +501,29 @@ pub unsafe fn get_wrapped_error(state: *mut ffi::lua_State, index: c_int) -> *co
     }
 }
 
+// Dumps the Lua function at the top of the stack to binary chunk form.  Does not pop the function.
+pub unsafe fn dump_function(state: *mut ffi::lua_State, strip: bool) -> Vec<u8> {
+    unsafe extern "C" fn writer(
+        _state: *mut ffi::lua_State,
+        p: *const c_void,
+        sz: usize,
+        ud: *mut c_void,
+    ) -> c_int {
+        let buf = &mut *(ud as *mut Vec<u8>);
+        buf.extend_from_slice(slice::from_raw_parts(p as *const u8, sz));
+        0
+    }
+
+    let mut buf = Vec::new();
+    ffi::lua_dump(
+        state,
+        writer,
+        &mut buf as *mut Vec<u8> as *mut c_void,
+        if strip { 1 } else { 0 },
+    );
+    buf
+}
+
 // Runs the given function with the Lua garbage collector disabled.  `rlua` assumes that all
 // allocation failures are aborts, so when the garbage collector is disabled, 'm' functions that can
 // cause either an allocation error or a a `__gc` metamethod error are prevented from causing errors
@@ -540,6 +621,14 @@ pub unsafe fn init_error_metatables(state: *mut ffi::lua_State) {
     );
     ffi::lua_newtable(state);
 
+    set_error_metamethods(state, destructed_error);
+
+    ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+}
+
+// Sets every metamethod that can be triggered by using a userdata to `error_fn` in the table on
+// the top of the stack.  Uses 2 stack spaces and does not call lua_checkstack.
+pub unsafe fn set_error_metamethods(state: *mut ffi::lua_State, error_fn: ffi::lua_CFunction) {
     for &method in &[
         cstr!("__add"),
         cstr!("__sub"),
@@ -568,11 +657,9 @@ pub unsafe fn init_error_metatables(state: *mut ffi::lua_State) {
         cstr!("__ipairs"),
     ] {
         ffi::lua_pushstring(state, method);
-        ffi::lua_pushcfunction(state, destructed_error);
+        ffi::lua_pushcfunction(state, error_fn);
         ffi::lua_rawset(state, -3);
     }
-
-    ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
 }
 
 struct WrappedError(pub Error);
@@ -592,7 +679,7 @@ unsafe fn push_wrapped_panic(state: *mut ffi::lua_State, panic: Box<Any + Send>)
 
 // Checks if the value at the given index is a WrappedPanic.  Uses 2 stack spaces and does not call
 // lua_checkstack.
-unsafe fn is_wrapped_panic(state: *mut ffi::lua_State, index: c_int) -> bool {
+pub unsafe fn is_wrapped_panic(state: *mut ffi::lua_State, index: c_int) -> bool {
     let userdata = ffi::lua_touserdata(state, index);
     if userdata.is_null() {
         return false;
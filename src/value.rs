@@ -1,14 +1,19 @@
+use std::hash::{Hash, Hasher};
 use std::iter::{self, FromIterator};
+use std::os::raw::c_void;
+use std::string::String as StdString;
 use std::{slice, str, vec};
 
 use error::{Error, Result};
+use ffi;
 use function::Function;
 use lua::Lua;
 use string::String;
 use table::Table;
 use thread::Thread;
-use types::{Integer, LightUserData, Number};
+use types::{Integer, LightUserData, LuaRef, Number};
 use userdata::AnyUserData;
+use util::{assert_stack, StackGuard};
 
 /// A dynamically typed Lua value.  The `String`, `Table`, `Function`, `Thread`, and `UserData`
 /// variants contain handle types into the internal Lua state.  It is a logic error to mix handle
@@ -22,11 +27,13 @@ pub enum Value<'lua> {
     Boolean(bool),
     /// A "light userdata" object, equivalent to a raw pointer.
     LightUserData(LightUserData),
-    /// An integer number.
+    /// An integer number, which is a Lua number of the integer subtype (see `math.type`).
     ///
-    /// Any Lua number convertible to a `Integer` will be represented as this variant.
+    /// The subtype of numbers is preserved when values are passed between Rust and Lua, so a
+    /// float with an integral value such as `2.0` is represented as a `Number`, not as this
+    /// variant.
     Integer(Integer),
-    /// A floating point number.
+    /// A floating point number, which is a Lua number of the float subtype.
     Number(Number),
     /// An interned string, managed by Lua.
     ///
@@ -47,7 +54,8 @@ pub enum Value<'lua> {
 pub use self::Value::Nil;
 
 impl<'lua> Value<'lua> {
-    pub(crate) fn type_name(&self) -> &'static str {
+    /// Returns the name of the Lua type of this value.
+    pub fn type_name(&self) -> &'static str {
         match *self {
             Value::Nil => "nil",
             Value::Boolean(_) => "boolean",
@@ -61,6 +69,342 @@ impl<'lua> Value<'lua> {
             Value::UserData(_) | Value::Error(_) => "userdata",
         }
     }
+
+    /// Returns `true` if the value is `nil`.
+    pub fn is_nil(&self) -> bool {
+        match *self {
+            Value::Nil => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a boolean.
+    pub fn is_boolean(&self) -> bool {
+        match *self {
+            Value::Boolean(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a light userdata.
+    pub fn is_light_userdata(&self) -> bool {
+        match *self {
+            Value::LightUserData(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is an `Integer`.
+    pub fn is_integer(&self) -> bool {
+        match *self {
+            Value::Integer(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is either an `Integer` or a `Number`, which are both numbers in
+    /// Lua.
+    pub fn is_number(&self) -> bool {
+        match *self {
+            Value::Integer(_) | Value::Number(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a string.
+    pub fn is_string(&self) -> bool {
+        match *self {
+            Value::String(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a table.
+    pub fn is_table(&self) -> bool {
+        match *self {
+            Value::Table(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a function.
+    pub fn is_function(&self) -> bool {
+        match *self {
+            Value::Function(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a thread.
+    pub fn is_thread(&self) -> bool {
+        match *self {
+            Value::Thread(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is a userdata holding a `UserData` type.
+    ///
+    /// This is `false` for an `Error`, even though its Lua type is also userdata.
+    pub fn is_userdata(&self) -> bool {
+        match *self {
+            Value::UserData(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the value is an `Error`.
+    pub fn is_error(&self) -> bool {
+        match *self {
+            Value::Error(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Converts the value into a `String` if it is a string.
+    ///
+    /// Unlike the `FromLua` conversion, numbers are not coerced into strings.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FromLuaConversionError` naming the type of the value if it is not a string.
+    pub fn into_string(self) -> Result<String<'lua>> {
+        match self {
+            Value::String(s) => Ok(s),
+            value => Err(value.expected("string")),
+        }
+    }
+
+    /// Converts the value into a `Table` if it is a table.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FromLuaConversionError` naming the type of the value if it is not a table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let config: Value = lua.eval("{ depth = 3 }", None)?;
+    /// assert!(config.is_table());
+    /// assert_eq!(config.into_table()?.get::<_, i64>("depth")?, 3);
+    ///
+    /// let depth: Value = lua.eval("3", None)?;
+    /// assert_eq!(
+    ///     depth.into_table().unwrap_err().to_string(),
+    ///     "error converting Lua integer to table"
+    /// );
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn into_table(self) -> Result<Table<'lua>> {
+        match self {
+            Value::Table(t) => Ok(t),
+            value => Err(value.expected("table")),
+        }
+    }
+
+    /// Converts the value into a `Function` if it is a function.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FromLuaConversionError` naming the type of the value if it is not a function.
+    pub fn into_function(self) -> Result<Function<'lua>> {
+        match self {
+            Value::Function(f) => Ok(f),
+            value => Err(value.expected("function")),
+        }
+    }
+
+    /// Converts the value into a `Thread` if it is a thread.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FromLuaConversionError` naming the type of the value if it is not a thread.
+    pub fn into_thread(self) -> Result<Thread<'lua>> {
+        match self {
+            Value::Thread(t) => Ok(t),
+            value => Err(value.expected("thread")),
+        }
+    }
+
+    /// Converts the value into an `AnyUserData` if it is a userdata holding a `UserData` type.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FromLuaConversionError` naming the type of the value if it is not such a
+    /// userdata.
+    pub fn into_userdata(self) -> Result<AnyUserData<'lua>> {
+        match self {
+            Value::UserData(ud) => Ok(ud),
+            value => Err(value.expected("userdata")),
+        }
+    }
+
+    fn expected(&self, to: &'static str) -> Error {
+        Error::FromLuaConversionError {
+            from: self.type_name(),
+            to,
+            message: None,
+        }
+    }
+}
+
+/// A `Value` which implements `Hash` and `Eq`, so that it can be used as a key in Rust collections
+/// such as `HashMap`.
+///
+/// Values compare equal when they would be the same key in a Lua table:
+///
+/// - Booleans, strings and light userdata compare by value.  Strings compare by their bytes.
+/// - Integers and floats compare by numeric value, so a float with an exact integer value (such
+///   as `1.0` or `-0.0`) is equal to the corresponding integer, as Lua normalizes such float keys
+///   to integers.  Lua does not allow NaN as a table key; here every NaN is equal to every other
+///   NaN, so that equality stays reflexive.
+/// - Tables, functions, threads and userdata compare by identity, like with `rawequal`.
+/// - `Nil`, which cannot be a table key in Lua, is equal to itself.  An `Error` compares by its
+///   message, since it is copied when it is received from Lua.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use std::collections::HashMap;
+/// # use rlua::{HashableValue, Lua, Result, Table, Value};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let table: Table = lua.eval("{ [1] = 'one', name = 'value' }", None)?;
+///
+/// let mut map = HashMap::new();
+/// for pair in table.pairs::<HashableValue, String>() {
+///     let (key, value) = pair?;
+///     map.insert(key, value);
+/// }
+///
+/// let one = HashableValue::new(Value::Number(1.0));
+/// assert_eq!(map[&one], "one");
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct HashableValue<'lua> {
+    value: Value<'lua>,
+    key: Key,
+}
+
+// What a `HashableValue` is compared by, apart from the bytes of strings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Nil,
+    Boolean(bool),
+    LightUserData(*mut c_void),
+    Integer(Integer),
+    // The bits of a float without an exact integer value, other than NaN.
+    Float(u64),
+    NaN,
+    String,
+    Reference(*const c_void),
+    Error(StdString),
+}
+
+impl<'lua> HashableValue<'lua> {
+    /// Wraps a value.
+    pub fn new(value: Value<'lua>) -> HashableValue<'lua> {
+        let key = match value {
+            Value::Nil => Key::Nil,
+            Value::Boolean(b) => Key::Boolean(b),
+            Value::LightUserData(ud) => Key::LightUserData(ud.0),
+            Value::Integer(i) => Key::Integer(i),
+            Value::Number(n) => number_key(n),
+            Value::String(_) => Key::String,
+            Value::Table(ref t) => Key::Reference(ref_pointer(&t.0)),
+            Value::Function(ref f) => Key::Reference(ref_pointer(&f.0)),
+            Value::Thread(ref t) => Key::Reference(ref_pointer(&t.0)),
+            Value::UserData(ref ud) => Key::Reference(ref_pointer(&ud.0)),
+            Value::Error(ref err) => Key::Error(err.to_string()),
+        };
+        HashableValue { value, key }
+    }
+
+    /// Returns the wrapped value.
+    pub fn value(&self) -> &Value<'lua> {
+        &self.value
+    }
+
+    /// Unwraps the value.
+    pub fn into_value(self) -> Value<'lua> {
+        self.value
+    }
+}
+
+impl<'lua> From<Value<'lua>> for HashableValue<'lua> {
+    fn from(value: Value<'lua>) -> HashableValue<'lua> {
+        HashableValue::new(value)
+    }
+}
+
+impl<'lua> PartialEq for HashableValue<'lua> {
+    fn eq(&self, other: &HashableValue<'lua>) -> bool {
+        match (&self.value, &other.value) {
+            (&Value::String(ref a), &Value::String(ref b)) => a.as_bytes() == b.as_bytes(),
+            _ => self.key == other.key,
+        }
+    }
+}
+
+impl<'lua> Eq for HashableValue<'lua> {}
+
+impl<'lua> Hash for HashableValue<'lua> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+        if let Value::String(ref s) = self.value {
+            s.as_bytes().hash(state);
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for HashableValue<'lua> {
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(self.value)
+    }
+}
+
+impl<'lua> FromLua<'lua> for HashableValue<'lua> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<HashableValue<'lua>> {
+        Ok(HashableValue::new(value))
+    }
+}
+
+// Normalizes floats like Lua does for table keys (see `luaV_flttointns`).
+fn number_key(n: Number) -> Key {
+    if n.is_nan() {
+        Key::NaN
+    } else if n.floor() == n && n >= -9_223_372_036_854_775_808.0 && n < 9_223_372_036_854_775_808.0
+    {
+        Key::Integer(n as Integer)
+    } else {
+        Key::Float(n.to_bits())
+    }
+}
+
+fn ref_pointer(lref: &LuaRef) -> *const c_void {
+    let lua = lref.lua;
+    unsafe {
+        let _sg = StackGuard::new(lua.state);
+        assert_stack(lua.state, 1);
+
+        lua.push_ref(lref);
+        ffi::lua_topointer(lua.state, -1)
+    }
 }
 
 /// Trait for types convertible to `Value`.
@@ -164,4 +508,15 @@ pub trait FromLuaMulti<'lua>: Sized {
     /// assigning values. Similarly, if not enough values are given, conversions should assume that
     /// any missing values are nil.
     fn from_lua_multi(values: MultiValue<'lua>, lua: &'lua Lua) -> Result<Self>;
+
+    /// Performs the conversion of the arguments of a Rust function, where `position` is the
+    /// position of the first of `values` among the arguments.
+    ///
+    /// Failures to convert a single argument are reported as `Error::BadArgument`.  The default
+    /// implementation does not know about single arguments, and just calls `from_lua_multi`.
+    #[doc(hidden)]
+    fn from_lua_args(values: MultiValue<'lua>, position: usize, lua: &'lua Lua) -> Result<Self> {
+        let _ = position;
+        Self::from_lua_multi(values, lua)
+    }
 }
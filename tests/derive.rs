@@ -0,0 +1,159 @@
+#![cfg(feature = "derive")]
+
+extern crate rlua;
+
+use rlua::{rlua_methods, Error, FromLua, Lua, Table, ToLua, UserData};
+
+#[derive(Debug, PartialEq, FromLua, ToLua)]
+enum Mode {
+    Fast,
+    #[rlua(rename = "slow")]
+    Slow,
+    Custom {
+        level: u32,
+        name: String,
+    },
+}
+
+#[test]
+fn derive_unit_variants() {
+    let lua = Lua::new();
+
+    assert_eq!(lua.eval::<Mode>("'Fast'", None).unwrap(), Mode::Fast);
+    assert_eq!(lua.eval::<Mode>("'slow'", None).unwrap(), Mode::Slow);
+
+    let fast: String = lua.unpack(Mode::Fast.to_lua(&lua).unwrap()).unwrap();
+    assert_eq!(fast, "Fast");
+    let slow: String = lua.unpack(Mode::Slow.to_lua(&lua).unwrap()).unwrap();
+    assert_eq!(slow, "slow");
+
+    match lua.eval::<Mode>("'Slow'", None) {
+        Err(Error::FromLuaConversionError { to: "Mode", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    match lua.eval::<Mode>("42", None) {
+        Err(Error::FromLuaConversionError { to: "Mode", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+}
+
+#[test]
+fn derive_struct_variants() {
+    let lua = Lua::new();
+
+    assert_eq!(
+        lua.eval::<Mode>("{ Custom = { level = 3, name = 'turbo' } }", None)
+            .unwrap(),
+        Mode::Custom {
+            level: 3,
+            name: "turbo".to_owned(),
+        }
+    );
+
+    let table: Table = lua
+        .unpack(
+            Mode::Custom {
+                level: 7,
+                name: "eco".to_owned(),
+            }.to_lua(&lua)
+                .unwrap(),
+        )
+        .unwrap();
+    let fields: Table = table.get("Custom").unwrap();
+    assert_eq!(fields.get::<_, u32>("level").unwrap(), 7);
+    assert_eq!(fields.get::<_, String>("name").unwrap(), "eco");
+
+    let value = Mode::Custom {
+        level: 1,
+        name: "roundtrip".to_owned(),
+    }.to_lua(&lua)
+        .unwrap();
+    assert_eq!(
+        Mode::from_lua(value, &lua).unwrap(),
+        Mode::Custom {
+            level: 1,
+            name: "roundtrip".to_owned(),
+        }
+    );
+
+    match lua.eval::<Mode>("{ Unknown = {} }", None) {
+        Err(Error::FromLuaConversionError { to: "Mode", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+}
+
+#[derive(UserData)]
+struct Marker;
+
+#[test]
+fn derive_user_data() {
+    let lua = Lua::new();
+    lua.globals().set("marker", Marker).unwrap();
+    assert_eq!(
+        lua.eval::<String>("type(marker)", None).unwrap(),
+        "userdata"
+    );
+}
+
+struct Counter {
+    count: i64,
+}
+
+#[rlua_methods]
+impl Counter {
+    pub fn get(&self) -> i64 {
+        self.count
+    }
+
+    pub fn add(&mut self, amount: i64) {
+        self.count += amount;
+    }
+
+    #[rlua(rename = "add_both")]
+    pub fn add_two(&mut self, a: i64, b: i64) -> i64 {
+        self.count += a + b;
+        self.count
+    }
+
+    pub fn checked_sub(&mut self, amount: i64) -> rlua::Result<i64> {
+        if amount > self.count {
+            Err(Error::RuntimeError("underflow".to_owned()))
+        } else {
+            self.count -= amount;
+            Ok(self.count)
+        }
+    }
+
+    #[rlua(skip)]
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    #[allow(dead_code)]
+    fn hidden(&self) {}
+}
+
+#[test]
+fn rlua_methods() {
+    let lua = Lua::new();
+    lua.globals().set("counter", Counter { count: 1 }).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(counter:get() == 1)
+            counter:add(4)
+            assert(counter:get() == 5)
+            assert(counter:add_both(2, 3) == 10)
+            assert(counter:checked_sub(3) == 7)
+            assert(not pcall(counter.checked_sub, counter, 100))
+            assert(counter.reset == nil)
+            assert(counter.add_two == nil)
+            assert(counter.hidden == nil)
+        "#,
+        None,
+    ).unwrap();
+
+    let mut counter = Counter { count: 3 };
+    counter.reset();
+    assert_eq!(counter.get(), 0);
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "json")]
+
+extern crate rlua;
+#[macro_use]
+extern crate serde_json;
+
+use rlua::{Error, Lua, Table, Value};
+
+#[test]
+fn from_json() {
+    let lua = Lua::new();
+
+    let value = lua
+        .from_json(&json!({
+            "int": 3,
+            "float": 2.5,
+            "big": 18446744073709551615u64,
+            "flag": true,
+            "missing": null,
+            "list": [1, null, "three"],
+        }))
+        .unwrap();
+    lua.globals().set("value", value).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(math.type(value.int) == "integer" and value.int == 3)
+            assert(math.type(value.float) == "float" and value.float == 2.5)
+            assert(math.type(value.big) == "float")
+            assert(value.flag == true)
+            assert(value.missing == nil)
+            assert(value.list[1] == 1 and value.list[2] == nil and value.list[3] == "three")
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn to_json() {
+    let lua = Lua::new();
+
+    let value: Value = lua
+        .eval(
+            r#"
+                local shared = { 1.5 }
+                return {
+                    int = 1,
+                    float = 1.0,
+                    empty = {},
+                    array = { "a", "b" },
+                    holes = { [1] = "a", [3] = "c" },
+                    a = shared,
+                    b = shared,
+                }
+            "#,
+            None,
+        ).unwrap();
+    assert_eq!(
+        lua.to_json(value).unwrap(),
+        json!({
+            "int": 1,
+            "float": 1.0,
+            "empty": {},
+            "array": ["a", "b"],
+            "holes": { "1": "a", "3": "c" },
+            "a": [1.5],
+            "b": [1.5],
+        })
+    );
+
+    let original = json!({ "nested": { "values": [1, 2.5, "x", false] } });
+    let value = lua.from_json(&original).unwrap();
+    assert_eq!(lua.to_json(value).unwrap(), original);
+}
+
+#[test]
+fn to_json_errors() {
+    let lua = Lua::new();
+
+    let cycle: Table = lua.eval("local t = {}; t.self = t; return t", None).unwrap();
+    match lua.to_json(Value::Table(cycle)) {
+        Err(Error::FromLuaConversionError { from: "table", .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+
+    let function: Value = lua.eval("{ callback = print }", None).unwrap();
+    match lua.to_json(function) {
+        Err(Error::FromLuaConversionError {
+            from: "function", ..
+        }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+
+    let nan: Value = lua.eval("0/0", None).unwrap();
+    assert!(lua.to_json(nan).is_err());
+    let bad_key: Value = lua.eval("{ [true] = 1 }", None).unwrap();
+    assert!(lua.to_json(bad_key).is_err());
+}